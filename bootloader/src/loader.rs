@@ -0,0 +1,195 @@
+//! Loading of the kernel and the switch to long mode.
+//!
+//! The kernel runs in a 4-level page table which identity maps physical
+//! memory with 2 MiB pages, except for the 2 MiB aligned window the kernel is
+//! linked at. The window maps the kernel segments instead, and the physical
+//! memory behind it is never handed out. The stack of the bootstrap processor
+//! is mapped at `KERNEL_STACKS_BASE`, above an unmapped guard page.
+
+use core::alloc::Layout;
+use alloc::vec::Vec;
+use alloc::alloc::alloc_zeroed;
+use range_set::Range;
+use elf_parser::ElfParser;
+use boot_kern_common::{ KERNEL_STACKS_BASE, STACK_GUARD_SIZE, BSP_STACK_SIZE };
+use crate::{ realmode, BOOT_KERN };
+
+/// The entry is present
+const PTE_PRESENT: u64 = 1 << 0;
+
+/// The page is writable
+const PTE_WRITE: u64 = 1 << 1;
+
+/// The entry maps a large page instead of referencing a table
+const PTE_HUGE: u64 = 1 << 7;
+
+/// Mask of the physical address in an entry
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Size of a 4 KiB page, also the size of a table
+const PAGE_SIZE: u64 = 4096;
+
+/// Size of a 2 MiB page
+const LARGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Physical memory below this is always identity mapped, even if it isn't
+/// free, for the ACPI tables and the firmware structures in it
+const MIN_IDENTITY_MAP: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Allocate a zeroed 4 KiB frame and return its physical address
+fn alloc_frame() -> u64 {
+    let layout = Layout::from_size_align(PAGE_SIZE as usize,
+                                         PAGE_SIZE as usize).unwrap();
+    let frame = unsafe { alloc_zeroed(layout) };
+    assert!(!frame.is_null(), "Out of memory while loading the kernel.");
+    frame as u64
+}
+
+/// A 4-level page table under construction. Protected mode runs without
+/// paging, so the tables are accessed through their physical addresses.
+struct PageTable {
+    /// Physical address of the PML4
+    pml4: u64,
+}
+
+impl PageTable {
+    /// Returns a new, empty page table
+    fn new() -> Self {
+        Self { pml4: alloc_frame() }
+    }
+
+    /// Returns a pointer to the entry translating `vaddr` after `depth` table
+    /// walks, creating the missing tables on the way
+    unsafe fn entry(&mut self, vaddr: u64, depth: usize) -> *mut u64 {
+        let index = |level: usize| ((vaddr >> (39 - 9 * level)) & 511) as usize;
+        let mut table = self.pml4;
+
+        for level in 0..depth {
+            let entry = (table as usize as *mut u64).add(index(level));
+            if *entry & PTE_PRESENT == 0 {
+                *entry = alloc_frame() | PTE_PRESENT | PTE_WRITE;
+            }
+            assert!(*entry & PTE_HUGE == 0, "Kernel mappings overlap.");
+
+            table = *entry & PTE_ADDR_MASK;
+        }
+
+        (table as usize as *mut u64).add(index(depth))
+    }
+
+    /// Identity map the 2 MiB page at `addr`
+    fn map_identity(&mut self, addr: u64) {
+        unsafe {
+            *self.entry(addr, 2) = addr | PTE_PRESENT | PTE_WRITE | PTE_HUGE;
+        }
+    }
+
+    /// Back the 4 KiB page at `vaddr` with a zeroed frame, unless it is
+    /// already mapped, and make it writable if `write` is set.
+    ///
+    /// Returns the physical address of the frame.
+    fn map_page(&mut self, vaddr: u64, write: bool) -> u64 {
+        unsafe {
+            let entry = self.entry(vaddr, 3);
+            if *entry & PTE_PRESENT == 0 {
+                *entry = alloc_frame() | PTE_PRESENT;
+            }
+            if write {
+                *entry |= PTE_WRITE;
+            }
+
+            *entry & PTE_ADDR_MASK
+        }
+    }
+}
+
+/// Load the kernel ELF `image`, switch to long mode and jump to the kernel
+/// entry point with a reference to `BOOT_KERN` as its argument
+pub fn load(image: Vec<u8>) -> ! {
+    let elf = ElfParser::parse(&image).expect("Invalid kernel image.");
+
+    // Find the 2 MiB aligned window the kernel is linked into
+    let mut window: Option<(u64, u64)> = None;
+    elf.headers(|vaddr, memsz, _, _, _, _| {
+        if memsz == 0 {
+            return Some(());
+        }
+
+        let start = vaddr as u64 & !(LARGE_PAGE_SIZE - 1);
+        let end   = (vaddr as u64 + memsz as u64)
+            .next_multiple_of(LARGE_PAGE_SIZE);
+        window = Some(window.map_or((start, end), |(low, high)| {
+            (low.min(start), high.max(end))
+        }));
+        Some(())
+    }).expect("Invalid kernel program headers.");
+    let (low, high) = window.expect("The kernel image has nothing to load.");
+
+    // The physical memory behind the window isn't reachable through the
+    // identity map. Take it out of the free memory and find how much memory
+    // has to be identity mapped.
+    let top = {
+        let mut free_memory = unsafe { BOOT_KERN.free_memory_ref().lock() };
+        let free_memory = free_memory.as_mut()
+            .expect("The memory manager isn't initialized.");
+        free_memory.remove(Range::new(low, high - 1));
+
+        free_memory.entries().iter()
+            .map(|range| range.end.saturating_add(1))
+            .fold(MIN_IDENTITY_MAP, u64::max)
+            .next_multiple_of(LARGE_PAGE_SIZE)
+    };
+
+    // Identity map the physical memory around the window
+    let mut table = PageTable::new();
+    for addr in (0..top).step_by(LARGE_PAGE_SIZE as usize) {
+        if addr < low || addr >= high {
+            table.map_identity(addr);
+        }
+    }
+
+    // Map the kernel segments into the window. Segments sharing a page get
+    // the union of their permissions.
+    elf.headers(|vaddr, memsz, bytes, _, write, _| {
+        let start = vaddr as u64;
+        let end   = start + memsz as u64;
+
+        let first = start & !(PAGE_SIZE - 1);
+        for page in (first..end).step_by(PAGE_SIZE as usize) {
+            let frame = table.map_page(page, write);
+
+            // Copy the initialized part of the segment in this page. The rest
+            // stays zeroed.
+            let from = page.max(start);
+            let to   = (page + PAGE_SIZE).min(start + bytes.len() as u64);
+            if from < to {
+                let src = &bytes[(from - start) as usize..
+                                 (to - start) as usize];
+                unsafe {
+                    core::ptr::copy_nonoverlapping(src.as_ptr(),
+                        (frame + (from - page)) as usize as *mut u8,
+                        src.len());
+                }
+            }
+        }
+
+        Some(())
+    }).expect("Invalid kernel program headers.");
+
+    // Map the stack of the bootstrap processor above its guard page
+    let stack = KERNEL_STACKS_BASE + STACK_GUARD_SIZE;
+    for page in (stack..stack + BSP_STACK_SIZE).step_by(PAGE_SIZE as usize) {
+        table.map_page(page, true);
+    }
+
+    // The image has been copied, give its memory to the kernel
+    let entry = elf.entry;
+    drop(image);
+
+    print!("Entering the kernel at {:#x}, window {:#x}-{:#x}\n",
+           entry, low, high);
+    unsafe {
+        realmode::enter64(entry, stack + BSP_STACK_SIZE,
+                          &BOOT_KERN as *const _ as u64, table.pml4 as u32);
+    }
+}
@@ -16,6 +16,7 @@ use serial_driver::Serial;
 use boot_kern_common::BootKernCommon;
 
 #[macro_use] pub mod print;
+mod loader;
 
 pub static BOOT_KERN: BootKernCommon = BootKernCommon::new();
 
@@ -62,13 +63,22 @@ extern fn entry() -> ! {
     // Initialize the physical memory manager
     mm::init();
 
-    // Download the kernel ELF image
-    let kernel = pxe::download(b"kernel").unwrap();
+    // Calibrate the clock
+    let tsc_hz = time::calibrate();
+    print!("TSC at {} MHz, RTC reads {}\n",
+           tsc_hz / 1_000_000, time::rtc::read(None));
 
-    // Validate the kernel
-    if &kernel[..4] != b"\x7FELF" {
-        panic!("Invalid kernel image.");
-    } else {
-        panic!("VALID kernel image.");
+    // Find the ACPI tables for the kernel
+    if let Some(rsdp) = acpi::find_rsdp(&mm::PhysicalMemory) {
+        BOOT_KERN.set_rsdp(rsdp);
     }
+
+    // Download the kernel ELF image
+    let started = time::Instant::now();
+    let kernel  = pxe::download(b"kernel").unwrap();
+    print!("Downloaded the kernel, {} bytes in {} ms\n",
+           kernel.len(), started.elapsed().as_millis());
+
+    // Load the kernel and hand off to it
+    loader::load(kernel);
 }
@@ -39,6 +39,19 @@ unsafe impl GlobalAlloc for GlobalAllocator {
     }
 }
 
+/// Direct access to physical memory. Protected mode runs with flat segments
+/// and without paging, so physical addresses can be used as pointers.
+pub struct PhysicalMemory;
+
+impl acpi::PhysMem<'static> for PhysicalMemory {
+    fn bytes(&self, addr: u64, len: usize) -> Option<&'static [u8]> {
+        let addr: usize = addr.try_into().ok()?;
+        addr.checked_add(len)?;
+
+        Some(unsafe { core::slice::from_raw_parts(addr as *const u8, len) })
+    }
+}
+
 /// Initialize the bootloader physical memory manager.
 ///
 /// The initial memory map is retrieved through E820 and the first 1 MiB of
@@ -5,6 +5,8 @@
 
 use alloc::vec::Vec;
 use spinlock::SpinLock;
+use boot_kern_common::PxeLease;
+use crate::BOOT_KERN;
 use crate::realmode;
 use crate::realmode::pxe_invoke;
 
@@ -81,7 +83,7 @@ pub fn download(filename: &[u8]) -> Option<Vec<u8>> {
     }
 
     // Retrieve the server IP address from the packet that was cached during
-    // the PXE boot process.
+    // the PXE boot process, recording the lease for the kernel to reuse.
     let server_ip: [u8; 4] = {
         const GET_CACHED_INFO: u16 = 0x71;
         const PACKET_TYPE_DHCP_ACK: u16 = 2;
@@ -117,8 +119,12 @@ pub fn download(filename: &[u8]) -> Option<Vec<u8>> {
                 request.buf_size as usize)
         };
 
-        // Extract the IP
-        packet[0x14..0x18].try_into().ok()?
+        // Hand the lease over to the kernel if it parses, the boot server
+        // comes from the fixed part of the packet either way
+        if let Some(lease) = PxeLease::from_dhcp_ack(packet) {
+            BOOT_KERN.set_pxe_lease(lease);
+        }
+        packet.get(0x14..0x18)?.try_into().ok()?
     };
 
     // Get the file size
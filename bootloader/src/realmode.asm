@@ -269,3 +269,56 @@ pxe_invoke:
 protected_mode_return:
     popad
     ret
+
+global enter64
+
+; Switch to long mode with the page table `cr3` and jump to `entry` on
+; `stack`, passing `param` as the first argument. Never returns.
+; fn enter64(entry: u64, stack: u64, param: u64, cr3: u32) -> !;
+enter64:
+    ; Disable interrupts, the kernel sets up its own IDT
+    cli
+
+    ; Enable PAE
+    mov eax, cr4
+    or  eax, 1 << 5
+    mov cr4, eax
+
+    ; Load the page table
+    mov eax, dword [esp + 0x1c] ; cr3
+    mov cr3, eax
+
+    ; Enable long mode in the EFER
+    mov ecx, 0xc0000080
+    rdmsr
+    or  eax, 1 << 8
+    wrmsr
+
+    ; Enable paging, which activates long mode
+    mov eax, cr0
+    or  eax, 1 << 31
+    mov cr0, eax
+
+    ; Start executing 64-bit instructions
+    jmp 0x28:.long_mode ; 0x28 is the 64-bit code entry in the GDT
+
+[bits 64]
+
+.long_mode:
+    ; Set up the data selectors
+    mov ax, 0x30 ; 0x30 is the 64-bit data entry in the GDT
+    mov es, ax
+    mov ds, ax
+    mov gs, ax
+    mov fs, ax
+    mov ss, ax
+
+    ; Get the arguments passed to `enter64()` from the 32-bit stack
+    mov esp, esp
+    mov rax, qword [rsp + 0x4]  ; entry
+    mov rdi, qword [rsp + 0x14] ; param
+    mov rsp, qword [rsp + 0xc]  ; stack
+
+    ; Push a null return address, so the stack is aligned like after a call
+    push 0
+    jmp rax
@@ -28,4 +28,8 @@ extern {
     /// Invokes a PXE routine `pxe_opcode`.
     pub fn pxe_invoke(entry_segment: u16, entry_offset: u16, pxe_opcode: u16,
                       parameter_segment: u16, parameter_offset: u16);
+
+    /// Switches to long mode with the page table `cr3` and jumps to `entry`
+    /// on `stack`, passing `param` as the first argument.
+    pub fn enter64(entry: u64, stack: u64, param: u64, cr3: u32) -> !;
 }
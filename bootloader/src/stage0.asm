@@ -55,7 +55,7 @@ gdt_base:
     dq 0x000092000000ffff ; 0x10, 16-bit data, present, base 0x0
     dq 0x00cf9a000000ffff ; 0x18, 32-bit code, present, base 0x0
     dq 0x00cf92000000ffff ; 0x20, 32-bit data, present, base 0x0
-    dq 0x00009a0000000000 ; 0x28, 64-bit code, present, base 0x0
+    dq 0x00209a0000000000 ; 0x28, 64-bit code, present, base 0x0
     dq 0x0000920000000000 ; 0x30, 64-bit data, present, base 0x0
 
 gdt:
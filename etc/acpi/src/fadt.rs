@@ -0,0 +1,151 @@
+//! Fixed ACPI description table, describing the fixed hardware features.
+
+use crate::{ Table, get_bytes };
+
+/// Boot architecture flag signalling that there are legacy ISA devices
+pub const BOOT_LEGACY_DEVICES: u16 = 1 << 0;
+
+/// Boot architecture flag signalling that there is an 8042 keyboard
+/// controller
+pub const BOOT_8042: u16 = 1 << 1;
+
+/// Boot architecture flag signalling that there is no VGA hardware
+pub const BOOT_NO_VGA: u16 = 1 << 2;
+
+/// Boot architecture flag signalling that the CMOS RTC isn't present
+pub const BOOT_NO_CMOS_RTC: u16 = 1 << 5;
+
+/// Flag signalling that the PM timer is 32 bits wide instead of 24
+pub const TMR_VAL_EXT: u32 = 1 << 8;
+
+/// Flag signalling that the reset register is supported
+pub const RESET_REG_SUP: u32 = 1 << 10;
+
+/// A register location in a generic address space
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GenericAddress {
+    /// Address space of the register, 0 for memory and 1 for I/O ports
+    pub space: u8,
+
+    /// Width of the register in bits
+    pub bit_width: u8,
+
+    /// Offset of the register in bits
+    pub bit_offset: u8,
+
+    /// Access size, 1 for bytes up to 4 for qwords
+    pub access_size: u8,
+
+    /// Address of the register
+    pub addr: u64,
+}
+
+impl GenericAddress {
+    /// Address space of system memory
+    pub const MEMORY: u8 = 0;
+
+    /// Address space of I/O ports
+    pub const IO: u8 = 1;
+
+    /// Parse a generic address at `offset` in `bytes`
+    pub fn parse(bytes: &[u8], offset: usize) -> Option<Self> {
+        Some(Self {
+            space:       *bytes.get(offset)?,
+            bit_width:   *bytes.get(offset + 1)?,
+            bit_offset:  *bytes.get(offset + 2)?,
+            access_size: *bytes.get(offset + 3)?,
+            addr:        get_bytes!(u64, bytes, offset + 4),
+        })
+    }
+}
+
+/// The parsed FADT
+#[derive(Clone, Copy, Debug)]
+pub struct Fadt {
+    /// Physical address of the DSDT
+    pub dsdt: u64,
+
+    /// Interrupt the SCI is wired to in 8259 mode
+    pub sci_interrupt: u16,
+
+    /// I/O port of the SMI command register, 0 if ACPI is always enabled
+    pub smi_command: u32,
+
+    /// Value to write to the SMI command register to enable ACPI
+    pub acpi_enable: u8,
+
+    /// Value to write to the SMI command register to disable ACPI
+    pub acpi_disable: u8,
+
+    /// I/O port of the PM1a event register block
+    pub pm1a_event: u32,
+
+    /// I/O port of the PM1a control register block
+    pub pm1a_control: u32,
+
+    /// I/O port of the PM timer, 0 if there is none
+    pub pm_timer: u32,
+
+    /// Index of the century register in the CMOS RTC, 0 if there is none
+    pub century: u8,
+
+    /// IA-PC boot architecture flags
+    pub boot_arch: u16,
+
+    /// Fixed feature flags
+    pub flags: u32,
+
+    /// The reset register and the value to write to it, if supported
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    /// Parse the FADT from `table`
+    pub fn parse(table: Table) -> Option<Self> {
+        if &table.signature() != b"FACP" {
+            return None;
+        }
+        let bytes = table.bytes;
+
+        // Fields past the ACPI 1.0 FADT may not be present
+        let boot_arch = if table.revision() >= 2 {
+            get_bytes!(u16, bytes, 109)
+        } else {
+            0
+        };
+        let flags = get_bytes!(u32, bytes, 112);
+
+        // Prefer the 64-bit DSDT address
+        let dsdt = bytes.get(140..148)
+            .map(|dsdt| u64::from_le_bytes(dsdt.try_into().unwrap()))
+            .filter(|&dsdt| dsdt != 0)
+            .unwrap_or(get_bytes!(u32, bytes, 40) as u64);
+
+        // Get the reset register
+        let reset = if flags & RESET_REG_SUP != 0 {
+            GenericAddress::parse(bytes, 116).zip(bytes.get(128).copied())
+        } else {
+            None
+        };
+
+        Some(Self {
+            dsdt,
+            sci_interrupt: get_bytes!(u16, bytes, 46),
+            smi_command:   get_bytes!(u32, bytes, 48),
+            acpi_enable:   *bytes.get(52)?,
+            acpi_disable:  *bytes.get(53)?,
+            pm1a_event:    get_bytes!(u32, bytes, 56),
+            pm1a_control:  get_bytes!(u32, bytes, 64),
+            pm_timer:      get_bytes!(u32, bytes, 76),
+            century:       *bytes.get(108)?,
+            boot_arch,
+            flags,
+            reset,
+        })
+    }
+
+    /// Returns whether the PM timer is 32 bits wide instead of 24
+    pub fn pm_timer_32bit(&self) -> bool {
+        self.flags & TMR_VAL_EXT != 0
+    }
+}
@@ -0,0 +1,38 @@
+//! HPET description table, describing the high precision event timer.
+
+use crate::{ Table, get_bytes };
+use crate::fadt::GenericAddress;
+
+/// The parsed HPET table
+#[derive(Clone, Copy, Debug)]
+pub struct Hpet {
+    /// Hardware ID of the event timer block, a copy of the low 32 bits of
+    /// its capabilities register
+    pub block_id: u32,
+
+    /// Location of the registers of the event timer block
+    pub base: GenericAddress,
+
+    /// Sequence number of the HPET
+    pub number: u8,
+
+    /// Minimum clock tick in periodic mode without losing interrupts
+    pub min_tick: u16,
+}
+
+impl Hpet {
+    /// Parse the HPET table from `table`
+    pub fn parse(table: Table) -> Option<Self> {
+        if &table.signature() != b"HPET" {
+            return None;
+        }
+        let bytes = table.bytes;
+
+        Some(Self {
+            block_id: get_bytes!(u32, bytes, 36),
+            base:     GenericAddress::parse(bytes, 40)?,
+            number:   *bytes.get(52)?,
+            min_tick: get_bytes!(u16, bytes, 53),
+        })
+    }
+}
@@ -0,0 +1,308 @@
+//! Discovery and parsing of the ACPI tables.
+//!
+//! The tables are accessed through the `PhysMem` trait, so the same code runs
+//! in the bootloader, in the kernel, and on the host against table dumps.
+//! Nothing here allocates, the parsed tables borrow from the memory they were
+//! read from.
+#![no_std]
+
+pub mod madt;
+pub mod fadt;
+pub mod hpet;
+pub mod mcfg;
+pub mod srat;
+
+use core::mem::size_of;
+
+pub use madt::Madt;
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use mcfg::Mcfg;
+pub use srat::{ Srat, Slit };
+
+/// Size of the header common to all the system description tables
+pub const HEADER_SIZE: usize = 36;
+
+/// Physical address of the BIOS data area word holding the EBDA segment
+const EBDA_SEGMENT: u64 = 0x40e;
+
+/// Size of the region of the EBDA which may hold the RSDP
+const EBDA_SEARCH_SIZE: u64 = 1024;
+
+/// Region of the BIOS ROM which may hold the RSDP
+const BIOS_ROM_START: u64 = 0xe0000;
+const BIOS_ROM_END:   u64 = 0x100000;
+
+/// Size of an ACPI 1.0 RSDP
+const RSDP_V1_SIZE: usize = 20;
+
+/// Size of an ACPI 2.0+ RSDP
+const RSDP_V2_SIZE: usize = 36;
+
+/// Read bytes and little-endian interpret them as a given type
+macro_rules! get_bytes {
+    ($type:ty, $bytes:expr, $offset:expr) => {{
+        use core::mem::size_of;
+        let offset: usize = $offset;
+        let range = offset..(offset.checked_add(size_of::<$type>())?);
+        <$type>::from_le_bytes($bytes.get(range)?.try_into().ok()?)
+    }}
+}
+pub(crate) use get_bytes;
+
+/// Access to the physical memory holding the ACPI tables
+pub trait PhysMem<'a> {
+    /// Returns `len` bytes of physical memory at `addr`, or `None` if they
+    /// can't be accessed
+    fn bytes(&self, addr: u64, len: usize) -> Option<&'a [u8]>;
+}
+
+/// Physical memory captured into buffers, eg. table dumps from a VM
+#[derive(Clone, Copy)]
+pub struct MemoryDump<'a> {
+    /// The captured regions as (physical address, contents)
+    pub regions: &'a [(u64, &'a [u8])],
+}
+
+impl<'a> PhysMem<'a> for MemoryDump<'a> {
+    fn bytes(&self, addr: u64, len: usize) -> Option<&'a [u8]> {
+        self.regions.iter().find_map(|&(base, bytes)| {
+            let offset: usize = addr.checked_sub(base)?.try_into().ok()?;
+            bytes.get(offset..offset.checked_add(len)?)
+        })
+    }
+}
+
+/// Check whether all the `bytes` sum up to zero
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) == 0
+}
+
+/// A validated root system description pointer
+#[derive(Clone, Copy, Debug)]
+pub struct Rsdp {
+    /// ACPI revision of the RSDP, 0 for ACPI 1.0
+    pub revision: u8,
+
+    /// Physical address of the RSDT
+    pub rsdt: u32,
+
+    /// Physical address of the XSDT, ACPI 2.0+ only
+    pub xsdt: Option<u64>,
+}
+
+impl Rsdp {
+    /// Parse and validate the RSDP at the start of `bytes`
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        // Check the signature and the checksum of the ACPI 1.0 part
+        let v1 = bytes.get(..RSDP_V1_SIZE)?;
+        if &v1[..8] != b"RSD PTR " || !checksum(v1) {
+            return None;
+        }
+
+        let revision = v1[15];
+        let rsdt     = get_bytes!(u32, v1, 16);
+
+        // ACPI 2.0+ RSDPs are longer and have an extended checksum
+        let xsdt = if revision >= 2 {
+            let len = get_bytes!(u32, bytes, 20) as usize;
+            if len < RSDP_V2_SIZE || !checksum(bytes.get(..len)?) {
+                return None;
+            }
+
+            Some(get_bytes!(u64, bytes, 24)).filter(|&xsdt| xsdt != 0)
+        } else {
+            None
+        };
+
+        Some(Self { revision, rsdt, xsdt })
+    }
+}
+
+/// Look for a valid RSDP in the physical memory `start..end` and return its
+/// address. The RSDP is always 16-byte aligned.
+fn scan_rsdp<'a>(mem: &impl PhysMem<'a>, start: u64, end: u64)
+        -> Option<u64> {
+    (start..end).step_by(16).find(|&addr| {
+        // An ACPI 2.0+ RSDP may not fit if it's at the very end
+        mem.bytes(addr, RSDP_V2_SIZE)
+            .or_else(|| mem.bytes(addr, RSDP_V1_SIZE))
+            .and_then(Rsdp::parse)
+            .is_some()
+    })
+}
+
+/// Find the RSDP in the EBDA or the BIOS ROM and return its address
+pub fn find_rsdp<'a>(mem: &impl PhysMem<'a>) -> Option<u64> {
+    // Get the EBDA address from the BIOS data area
+    let ebda = mem.bytes(EBDA_SEGMENT, 2)
+        .and_then(|bytes| Some(get_bytes!(u16, bytes, 0) as u64 * 16));
+
+    ebda.and_then(|ebda| scan_rsdp(mem, ebda, ebda + EBDA_SEARCH_SIZE))
+        .or_else(|| scan_rsdp(mem, BIOS_ROM_START, BIOS_ROM_END))
+}
+
+/// A system description table with a validated checksum
+#[derive(Clone, Copy, Debug)]
+pub struct Table<'a> {
+    /// The raw bytes of the table, including the header
+    pub bytes: &'a [u8],
+}
+
+impl<'a> Table<'a> {
+    /// Parse and validate the table at the start of `bytes`
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let len = get_bytes!(u32, bytes, 4) as usize;
+        if len < HEADER_SIZE {
+            return None;
+        }
+
+        let bytes = bytes.get(..len)?;
+        checksum(bytes).then_some(Self { bytes })
+    }
+
+    /// Read the table at physical address `addr` from `mem`
+    pub fn read(mem: &impl PhysMem<'a>, addr: u64) -> Option<Self> {
+        let len = get_bytes!(u32, mem.bytes(addr, HEADER_SIZE)?, 4) as usize;
+        Self::parse(mem.bytes(addr, len)?)
+    }
+
+    /// Returns the signature of the table
+    pub fn signature(&self) -> [u8; 4] {
+        self.bytes[..4].try_into().unwrap()
+    }
+
+    /// Returns the revision of the table
+    pub fn revision(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    /// Returns the bytes of the table following the header
+    pub fn data(&self) -> &'a [u8] {
+        &self.bytes[HEADER_SIZE..]
+    }
+}
+
+/// Iterator over variable-length entries made of a type byte and a length
+/// byte, as used by the MADT and the SRAT
+#[derive(Clone)]
+pub(crate) struct RawEntries<'a> {
+    /// The remaining bytes holding the entries
+    bytes: &'a [u8],
+}
+
+impl<'a> RawEntries<'a> {
+    /// Returns an iterator over the entries in `bytes`
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+impl<'a> Iterator for RawEntries<'a> {
+    /// The type of the entry and its bytes, including the type and length
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let typ = *self.bytes.first()?;
+        let len = *self.bytes.get(1)? as usize;
+
+        // Stop at malformed entries
+        let entry = self.bytes.get(..len).filter(|_| len >= 2);
+        let Some(entry) = entry else {
+            self.bytes = &[];
+            return None;
+        };
+
+        self.bytes = &self.bytes[len..];
+        Some((typ, entry))
+    }
+}
+
+/// The ACPI tables of a system
+#[derive(Clone, Copy)]
+pub struct Acpi<'a, M: PhysMem<'a>> {
+    /// The memory holding the tables
+    mem: M,
+
+    /// The RSDP the tables were found through
+    pub rsdp: Rsdp,
+
+    /// The root table, either the XSDT or the RSDT
+    root: Table<'a>,
+}
+
+impl<'a, M: PhysMem<'a>> Acpi<'a, M> {
+    /// Get the tables through the RSDP at physical address `rsdp` in `mem`.
+    /// The XSDT is preferred over the RSDT.
+    pub fn new(mem: M, rsdp: u64) -> Option<Self> {
+        let rsdp = mem.bytes(rsdp, RSDP_V2_SIZE)
+            .or_else(|| mem.bytes(rsdp, RSDP_V1_SIZE))
+            .and_then(Rsdp::parse)?;
+
+        // Get the root table and check its signature
+        let root = match rsdp.xsdt {
+            Some(xsdt) => Table::read(&mem, xsdt)?,
+            None       => Table::read(&mem, rsdp.rsdt as u64)?,
+        };
+        let expected = if rsdp.xsdt.is_some() { b"XSDT" } else { b"RSDT" };
+        if &root.signature() != expected {
+            return None;
+        }
+
+        Some(Self { mem, rsdp, root })
+    }
+
+    /// Returns an iterator over the valid tables listed in the root table
+    pub fn tables(&self) -> impl Iterator<Item = Table<'a>> + '_ {
+        let entry_size = if self.rsdp.xsdt.is_some() {
+            size_of::<u64>()
+        } else {
+            size_of::<u32>()
+        };
+
+        self.root.data().chunks_exact(entry_size).filter_map(move |entry| {
+            let addr = if entry_size == size_of::<u64>() {
+                get_bytes!(u64, entry, 0)
+            } else {
+                get_bytes!(u32, entry, 0) as u64
+            };
+
+            Table::read(&self.mem, addr)
+        })
+    }
+
+    /// Find the table with `signature`
+    pub fn find(&self, signature: &[u8; 4]) -> Option<Table<'a>> {
+        self.tables().find(|table| &table.signature() == signature)
+    }
+
+    /// Returns the parsed MADT
+    pub fn madt(&self) -> Option<Madt<'a>> {
+        Madt::parse(self.find(b"APIC")?)
+    }
+
+    /// Returns the parsed FADT
+    pub fn fadt(&self) -> Option<Fadt> {
+        Fadt::parse(self.find(b"FACP")?)
+    }
+
+    /// Returns the parsed HPET table
+    pub fn hpet(&self) -> Option<Hpet> {
+        Hpet::parse(self.find(b"HPET")?)
+    }
+
+    /// Returns the parsed MCFG
+    pub fn mcfg(&self) -> Option<Mcfg<'a>> {
+        Mcfg::parse(self.find(b"MCFG")?)
+    }
+
+    /// Returns the parsed SRAT
+    pub fn srat(&self) -> Option<Srat<'a>> {
+        Srat::parse(self.find(b"SRAT")?)
+    }
+
+    /// Returns the parsed SLIT
+    pub fn slit(&self) -> Option<Slit<'a>> {
+        Slit::parse(self.find(b"SLIT")?)
+    }
+}
@@ -0,0 +1,177 @@
+//! Multiple APIC description table, describing the interrupt controllers.
+
+use crate::{ Table, RawEntries, get_bytes };
+
+/// Flag of the MADT signalling that the system also has dual 8259 PICs
+pub const PCAT_COMPAT: u32 = 1 << 0;
+
+/// Flag of a local APIC signalling that the processor is usable
+pub const LAPIC_ENABLED: u32 = 1 << 0;
+
+/// Flag of a local APIC signalling that a disabled processor can be brought
+/// online
+pub const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Offset of the first entry in the MADT
+const MADT_ENTRIES: usize = 44;
+
+/// An entry of the MADT
+#[derive(Clone, Copy, Debug)]
+pub enum MadtEntry<'a> {
+    /// A processor and its local APIC
+    LocalApic {
+        processor_id: u8,
+        apic_id:      u8,
+        flags:        u32,
+    },
+
+    /// An I/O APIC
+    IoApic {
+        id:       u8,
+        addr:     u32,
+        gsi_base: u32,
+    },
+
+    /// An ISA interrupt routed to a different global system interrupt
+    InterruptOverride {
+        bus:    u8,
+        source: u8,
+        gsi:    u32,
+        flags:  u16,
+    },
+
+    /// A global system interrupt which should be an NMI
+    NmiSource {
+        flags: u16,
+        gsi:   u32,
+    },
+
+    /// A local APIC LINT pin connected to NMI. A `processor_id` of 0xff
+    /// means all processors.
+    LocalApicNmi {
+        processor_id: u8,
+        flags:        u16,
+        lint:         u8,
+    },
+
+    /// The 64-bit address of the local APICs
+    LocalApicOverride {
+        addr: u64,
+    },
+
+    /// A processor and its local x2APIC
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags:     u32,
+        uid:       u32,
+    },
+
+    /// An entry this parser doesn't know about
+    Unknown {
+        typ:   u8,
+        bytes: &'a [u8],
+    },
+}
+
+impl<'a> MadtEntry<'a> {
+    /// Parse an entry of type `typ`
+    fn parse(typ: u8, bytes: &'a [u8]) -> Option<Self> {
+        Some(match typ {
+            0 => MadtEntry::LocalApic {
+                processor_id: *bytes.get(2)?,
+                apic_id:      *bytes.get(3)?,
+                flags:        get_bytes!(u32, bytes, 4),
+            },
+            1 => MadtEntry::IoApic {
+                id:       *bytes.get(2)?,
+                addr:     get_bytes!(u32, bytes, 4),
+                gsi_base: get_bytes!(u32, bytes, 8),
+            },
+            2 => MadtEntry::InterruptOverride {
+                bus:    *bytes.get(2)?,
+                source: *bytes.get(3)?,
+                gsi:    get_bytes!(u32, bytes, 4),
+                flags:  get_bytes!(u16, bytes, 8),
+            },
+            3 => MadtEntry::NmiSource {
+                flags: get_bytes!(u16, bytes, 2),
+                gsi:   get_bytes!(u32, bytes, 4),
+            },
+            4 => MadtEntry::LocalApicNmi {
+                processor_id: *bytes.get(2)?,
+                flags:        get_bytes!(u16, bytes, 3),
+                lint:         *bytes.get(5)?,
+            },
+            5 => MadtEntry::LocalApicOverride {
+                addr: get_bytes!(u64, bytes, 4),
+            },
+            9 => MadtEntry::LocalX2Apic {
+                x2apic_id: get_bytes!(u32, bytes, 4),
+                flags:     get_bytes!(u32, bytes, 8),
+                uid:       get_bytes!(u32, bytes, 12),
+            },
+            _ => MadtEntry::Unknown { typ, bytes },
+        })
+    }
+}
+
+/// The parsed MADT
+#[derive(Clone, Copy, Debug)]
+pub struct Madt<'a> {
+    /// 32-bit physical address of the local APICs
+    pub local_apic_addr: u32,
+
+    /// Flags of the MADT
+    pub flags: u32,
+
+    /// The raw bytes of the entries
+    entries: &'a [u8],
+}
+
+impl<'a> Madt<'a> {
+    /// Parse the MADT from `table`
+    pub fn parse(table: Table<'a>) -> Option<Self> {
+        if &table.signature() != b"APIC" {
+            return None;
+        }
+
+        Some(Self {
+            local_apic_addr: get_bytes!(u32, table.bytes, 36),
+            flags:           get_bytes!(u32, table.bytes, 40),
+            entries:         table.bytes.get(MADT_ENTRIES..)?,
+        })
+    }
+
+    /// Returns an iterator over the entries of the MADT. Iteration stops at
+    /// the first malformed entry.
+    pub fn entries(&self) -> impl Iterator<Item = MadtEntry<'a>> + Clone {
+        RawEntries::new(self.entries)
+            .map_while(|(typ, bytes)| MadtEntry::parse(typ, bytes))
+    }
+
+    /// Returns the physical address of the local APICs, taking the 64-bit
+    /// override into account
+    pub fn local_apic_addr(&self) -> u64 {
+        self.entries().find_map(|entry| match entry {
+            MadtEntry::LocalApicOverride { addr } => Some(addr),
+            _ => None,
+        }).unwrap_or(self.local_apic_addr as u64)
+    }
+
+    /// Returns an iterator over the APIC IDs of the processors which are
+    /// enabled or can be brought online
+    pub fn apic_ids(&self) -> impl Iterator<Item = u32> + Clone + 'a {
+        self.entries().filter_map(|entry| {
+            let (apic_id, flags) = match entry {
+                MadtEntry::LocalApic   { apic_id,   flags, .. } =>
+                    (apic_id as u32, flags),
+                MadtEntry::LocalX2Apic { x2apic_id, flags, .. } =>
+                    (x2apic_id, flags),
+                _ => return None,
+            };
+
+            (flags & (LAPIC_ENABLED | LAPIC_ONLINE_CAPABLE) != 0)
+                .then_some(apic_id)
+        })
+    }
+}
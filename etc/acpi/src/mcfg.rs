@@ -0,0 +1,71 @@
+//! PCI express memory mapped configuration table.
+
+use crate::{ Table, get_bytes };
+
+/// Offset of the first entry in the MCFG
+const MCFG_ENTRIES: usize = 44;
+
+/// Size of an entry in the MCFG
+const MCFG_ENTRY_SIZE: usize = 16;
+
+/// The memory mapped configuration space of a range of PCI buses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct McfgEntry {
+    /// Physical address of the configuration space of bus 0 of the segment,
+    /// even if `start_bus` isn't 0
+    pub base: u64,
+
+    /// PCI segment group
+    pub segment: u16,
+
+    /// First bus of the range
+    pub start_bus: u8,
+
+    /// Last bus of the range, inclusive
+    pub end_bus: u8,
+}
+
+impl McfgEntry {
+    /// Returns the physical address of the configuration space of
+    /// `bus:device.function`, if the bus is in the range
+    pub fn config_addr(&self, bus: u8, device: u8, function: u8)
+            -> Option<u64> {
+        if bus < self.start_bus || bus > self.end_bus ||
+                device >= 32 || function >= 8 {
+            return None;
+        }
+
+        Some(self.base + ((bus as u64) << 20 | (device as u64) << 15 |
+                          (function as u64) << 12))
+    }
+}
+
+/// The parsed MCFG
+#[derive(Clone, Copy, Debug)]
+pub struct Mcfg<'a> {
+    /// The raw bytes of the entries
+    entries: &'a [u8],
+}
+
+impl<'a> Mcfg<'a> {
+    /// Parse the MCFG from `table`
+    pub fn parse(table: Table<'a>) -> Option<Self> {
+        if &table.signature() != b"MCFG" {
+            return None;
+        }
+
+        Some(Self { entries: table.bytes.get(MCFG_ENTRIES..)? })
+    }
+
+    /// Returns an iterator over the entries of the MCFG
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> + Clone + 'a {
+        self.entries.chunks_exact(MCFG_ENTRY_SIZE).filter_map(|entry| {
+            Some(McfgEntry {
+                base:      get_bytes!(u64, entry, 0),
+                segment:   get_bytes!(u16, entry, 8),
+                start_bus: entry[10],
+                end_bus:   entry[11],
+            })
+        })
+    }
+}
@@ -0,0 +1,154 @@
+//! System resource affinity and system locality information tables,
+//! describing the NUMA topology.
+
+use crate::{ Table, RawEntries, get_bytes };
+
+/// Flag of an SRAT entry signalling that it's in use
+pub const AFFINITY_ENABLED: u32 = 1 << 0;
+
+/// Flag of a memory affinity entry signalling that the memory is hot
+/// pluggable
+pub const MEMORY_HOT_PLUGGABLE: u32 = 1 << 1;
+
+/// Offset of the first entry in the SRAT
+const SRAT_ENTRIES: usize = 48;
+
+/// Offset of the locality count in the SLIT
+const SLIT_LOCALITIES: usize = 36;
+
+/// Offset of the distance matrix in the SLIT
+const SLIT_MATRIX: usize = 44;
+
+/// An entry of the SRAT
+#[derive(Clone, Copy, Debug)]
+pub enum SratEntry<'a> {
+    /// The proximity domain of a local APIC
+    LocalApic {
+        domain:  u32,
+        apic_id: u8,
+        flags:   u32,
+    },
+
+    /// The proximity domain of a range of physical memory
+    Memory {
+        domain: u32,
+        base:   u64,
+        length: u64,
+        flags:  u32,
+    },
+
+    /// The proximity domain of a local x2APIC
+    LocalX2Apic {
+        domain:    u32,
+        x2apic_id: u32,
+        flags:     u32,
+    },
+
+    /// An entry this parser doesn't know about
+    Unknown {
+        typ:   u8,
+        bytes: &'a [u8],
+    },
+}
+
+impl<'a> SratEntry<'a> {
+    /// Parse an entry of type `typ`
+    fn parse(typ: u8, bytes: &'a [u8]) -> Option<Self> {
+        Some(match typ {
+            0 => SratEntry::LocalApic {
+                // The domain is split between the low byte and 3 high bytes
+                domain:  *bytes.get(2)? as u32 |
+                    (get_bytes!(u32, bytes, 8) & !0xff),
+                apic_id: *bytes.get(3)?,
+                flags:   get_bytes!(u32, bytes, 4),
+            },
+            1 => SratEntry::Memory {
+                domain: get_bytes!(u32, bytes, 2),
+                base:   get_bytes!(u64, bytes, 8),
+                length: get_bytes!(u64, bytes, 16),
+                flags:  get_bytes!(u32, bytes, 28),
+            },
+            2 => SratEntry::LocalX2Apic {
+                domain:    get_bytes!(u32, bytes, 4),
+                x2apic_id: get_bytes!(u32, bytes, 8),
+                flags:     get_bytes!(u32, bytes, 12),
+            },
+            _ => SratEntry::Unknown { typ, bytes },
+        })
+    }
+
+    /// Returns whether the entry is in use. Unknown entries never are.
+    pub fn enabled(&self) -> bool {
+        let flags = match *self {
+            SratEntry::LocalApic   { flags, .. } |
+            SratEntry::Memory      { flags, .. } |
+            SratEntry::LocalX2Apic { flags, .. } => flags,
+            SratEntry::Unknown { .. } => 0,
+        };
+
+        flags & AFFINITY_ENABLED != 0
+    }
+}
+
+/// The parsed SRAT
+#[derive(Clone, Copy, Debug)]
+pub struct Srat<'a> {
+    /// The raw bytes of the entries
+    entries: &'a [u8],
+}
+
+impl<'a> Srat<'a> {
+    /// Parse the SRAT from `table`
+    pub fn parse(table: Table<'a>) -> Option<Self> {
+        if &table.signature() != b"SRAT" {
+            return None;
+        }
+
+        Some(Self { entries: table.bytes.get(SRAT_ENTRIES..)? })
+    }
+
+    /// Returns an iterator over the entries of the SRAT. Iteration stops at
+    /// the first malformed entry.
+    pub fn entries(&self) -> impl Iterator<Item = SratEntry<'a>> + Clone {
+        RawEntries::new(self.entries)
+            .map_while(|(typ, bytes)| SratEntry::parse(typ, bytes))
+    }
+}
+
+/// The parsed SLIT
+#[derive(Clone, Copy, Debug)]
+pub struct Slit<'a> {
+    /// Number of localities in the distance matrix
+    pub localities: usize,
+
+    /// The distance matrix, row-major
+    matrix: &'a [u8],
+}
+
+impl<'a> Slit<'a> {
+    /// Parse the SLIT from `table`
+    pub fn parse(table: Table<'a>) -> Option<Self> {
+        if &table.signature() != b"SLIT" {
+            return None;
+        }
+
+        let localities: usize =
+            get_bytes!(u64, table.bytes, SLIT_LOCALITIES).try_into().ok()?;
+        let size   = localities.checked_mul(localities)?;
+        let matrix = table.bytes
+            .get(SLIT_MATRIX..SLIT_MATRIX.checked_add(size)?)?;
+
+        Some(Self { localities, matrix })
+    }
+
+    /// Returns the relative distance between proximity domains `from` and
+    /// `to`. The distance of a domain to itself is 10.
+    pub fn distance(&self, from: u32, to: u32) -> Option<u8> {
+        let (from, to) = (from as usize, to as usize);
+        if from >= self.localities || to >= self.localities {
+            return None;
+        }
+
+        self.matrix.get(from * self.localities + to).copied()
+    }
+}
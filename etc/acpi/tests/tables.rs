@@ -0,0 +1,207 @@
+//! Parsing of table dumps through `MemoryDump`.
+//!
+//! The `firecracker_*` MADT, FADT and MCFG were captured from a Firecracker
+//! guest, its RSDP and XSDT rebuilt from the headers and addresses the guest
+//! kernel reported. The ACPI 1.0 RSDP, the RSDT, the HPET table and the SRAT
+//! are assembled by hand after the ones QEMU generates.
+//!
+//! The `qemu_*` tables follow the layout of `/sys/firmware/acpi/tables` in a
+//! q35 guest with 2 CPUs and an i440fx guest with 1. They weren't captured
+//! from a guest, but reproduced entry by entry from the MADT and MCFG QEMU
+//! builds for these machines, including its BOCHS/BXPC header.
+
+use acpi::{ Acpi, MemoryDump, Table, Fadt, Madt, Mcfg, find_rsdp };
+use acpi::madt::{ MadtEntry, PCAT_COMPAT };
+use acpi::srat::SratEntry;
+use acpi::fadt::{ GenericAddress, BOOT_NO_VGA };
+
+/// Physical memory of the Firecracker guest, at the addresses it reported
+const FIRECRACKER: MemoryDump = MemoryDump {
+    regions: &[
+        (0xe0000, include_bytes!("data/firecracker_rsdp.bin")),
+        (0xa0e13, include_bytes!("data/firecracker_xsdt.bin")),
+        (0xa0c83, include_bytes!("data/firecracker_fadt.bin")),
+        (0xa0d97, include_bytes!("data/firecracker_madt.bin")),
+        (0xa0dd7, include_bytes!("data/firecracker_mcfg.bin")),
+    ],
+};
+
+/// Physical memory of a two node system with an ACPI 1.0 RSDP
+const TWO_NODES: MemoryDump = MemoryDump {
+    regions: &[
+        (0xf0000,   include_bytes!("data/rsdp_v1.bin")),
+        (0x7fe0000, include_bytes!("data/rsdt.bin")),
+        (0x7fe1000, include_bytes!("data/hpet.bin")),
+        (0x7fe2000, include_bytes!("data/srat.bin")),
+    ],
+};
+
+#[test]
+fn finds_rsdp() {
+    assert_eq!(find_rsdp(&FIRECRACKER), Some(0xe0000));
+    assert_eq!(find_rsdp(&TWO_NODES), Some(0xf0000));
+}
+
+#[test]
+fn xsdt() {
+    let acpi = Acpi::new(FIRECRACKER, 0xe0000).unwrap();
+    assert_eq!(acpi.rsdp.revision, 2);
+    assert_eq!(acpi.rsdp.xsdt, Some(0xa0e13));
+
+    let signatures: Vec<[u8; 4]> =
+        acpi.tables().map(|table| table.signature()).collect();
+    assert_eq!(signatures, [*b"FACP", *b"APIC", *b"MCFG"]);
+}
+
+#[test]
+fn rsdt() {
+    let acpi = Acpi::new(TWO_NODES, 0xf0000).unwrap();
+    assert_eq!(acpi.rsdp.revision, 0);
+    assert_eq!(acpi.rsdp.xsdt, None);
+
+    let signatures: Vec<[u8; 4]> =
+        acpi.tables().map(|table| table.signature()).collect();
+    assert_eq!(signatures, [*b"HPET", *b"SRAT"]);
+}
+
+#[test]
+fn madt() {
+    let madt = Acpi::new(FIRECRACKER, 0xe0000).unwrap().madt().unwrap();
+    assert_eq!(madt.local_apic_addr(), 0xfee0_0000);
+
+    let entries: Vec<MadtEntry> = madt.entries().collect();
+    assert_eq!(entries.len(), 2);
+    assert!(matches!(entries[0], MadtEntry::IoApic {
+        id: 0, addr: 0xfec0_0000, gsi_base: 0 }));
+    assert!(matches!(entries[1], MadtEntry::LocalApic {
+        processor_id: 0, apic_id: 0, flags: 1 }));
+    assert_eq!(madt.apic_ids().collect::<Vec<_>>(), [0]);
+}
+
+/// Check a MADT as QEMU builds it for a PC with `cpus` CPUs
+fn check_qemu_madt(bytes: &[u8], cpus: u8) {
+    let madt = Madt::parse(Table::parse(bytes).unwrap()).unwrap();
+    assert_eq!(madt.local_apic_addr(), 0xfee0_0000);
+    assert_eq!(madt.flags, PCAT_COMPAT);
+    assert_eq!(madt.apic_ids().collect::<Vec<_>>(),
+               (0..cpus as u32).collect::<Vec<_>>());
+
+    let ioapics: Vec<(u8, u32, u32)> = madt.entries()
+        .filter_map(|entry| match entry {
+            MadtEntry::IoApic { id, addr, gsi_base } =>
+                Some((id, addr, gsi_base)),
+            _ => None,
+        }).collect();
+    assert_eq!(ioapics, [(0, 0xfec0_0000, 0)]);
+
+    // The PIT moves to GSI 2, and the PCI interrupt links are level
+    // triggered and active high
+    let overrides: Vec<(u8, u8, u32, u16)> = madt.entries()
+        .filter_map(|entry| match entry {
+            MadtEntry::InterruptOverride { bus, source, gsi, flags } =>
+                Some((bus, source, gsi, flags)),
+            _ => None,
+        }).collect();
+    assert_eq!(overrides, [
+        (0, 0,  2,  0x0000),
+        (0, 5,  5,  0x000d),
+        (0, 9,  9,  0x000d),
+        (0, 10, 10, 0x000d),
+        (0, 11, 11, 0x000d),
+    ]);
+
+    // LINT1 of every processor is wired to NMI
+    assert!(matches!(madt.entries().last(), Some(MadtEntry::LocalApicNmi {
+        processor_id: 0xff, flags: 0, lint: 1 })));
+}
+
+#[test]
+fn qemu_madt() {
+    check_qemu_madt(include_bytes!("data/qemu_q35_madt.bin"), 2);
+    check_qemu_madt(include_bytes!("data/qemu_i440fx_madt.bin"), 1);
+}
+
+#[test]
+fn qemu_mcfg() {
+    let table = Table::parse(include_bytes!("data/qemu_q35_mcfg.bin"));
+    let mcfg = Mcfg::parse(table.unwrap()).unwrap();
+    let entries: Vec<_> = mcfg.entries().collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!((entries[0].base, entries[0].segment), (0xb000_0000, 0));
+    assert_eq!((entries[0].start_bus, entries[0].end_bus), (0, 255));
+    assert_eq!(entries[0].config_addr(255, 31, 7), Some(0xbfff_f000));
+}
+
+#[test]
+fn fadt() {
+    let fadt = Acpi::new(FIRECRACKER, 0xe0000).unwrap().fadt().unwrap();
+    assert_eq!(fadt.dsdt, 0x9fd30);
+    assert_eq!(fadt.boot_arch, BOOT_NO_VGA);
+    assert_eq!(fadt.pm_timer, 0);
+    assert!(fadt.reset.is_none());
+}
+
+#[test]
+fn hpet() {
+    let hpet = Acpi::new(TWO_NODES, 0xf0000).unwrap().hpet().unwrap();
+    assert_eq!(hpet.block_id, 0x8086_a201);
+    assert_eq!(hpet.base, GenericAddress {
+        space:       GenericAddress::MEMORY,
+        bit_width:   0,
+        bit_offset:  0,
+        access_size: 0,
+        addr:        0xfed0_0000,
+    });
+    assert_eq!(hpet.number, 0);
+}
+
+#[test]
+fn mcfg() {
+    let mcfg = Acpi::new(FIRECRACKER, 0xe0000).unwrap().mcfg().unwrap();
+    let entries: Vec<_> = mcfg.entries().collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].base, 0xeec0_0000);
+    assert_eq!(entries[0].config_addr(0, 1, 2), Some(0xeec0_a000));
+    assert_eq!(entries[0].config_addr(1, 0, 0), None);
+}
+
+#[test]
+fn srat() {
+    let srat = Acpi::new(TWO_NODES, 0xf0000).unwrap().srat().unwrap();
+
+    let cores: Vec<(u32, u32)> = srat.entries()
+        .filter(SratEntry::enabled)
+        .filter_map(|entry| match entry {
+            SratEntry::LocalApic { domain, apic_id, .. } =>
+                Some((domain, apic_id as u32)),
+            SratEntry::LocalX2Apic { domain, x2apic_id, .. } =>
+                Some((domain, x2apic_id)),
+            _ => None,
+        }).collect();
+    assert_eq!(cores, [(0, 0), (1, 1), (0x100, 0x100)]);
+
+    let memory: Vec<(u32, u64, u64)> = srat.entries()
+        .filter(SratEntry::enabled)
+        .filter_map(|entry| match entry {
+            SratEntry::Memory { domain, base, length, .. } =>
+                Some((domain, base, length)),
+            _ => None,
+        }).collect();
+    assert_eq!(memory, [
+        (0, 0,             0xa_0000),
+        (0, 0x10_0000,     0x3ff0_0000),
+        (1, 0x4000_0000,   0x4000_0000),
+        (1, 0x1_0000_0000, 0x1_0000_0000),
+    ]);
+}
+
+#[test]
+fn rejects_bad_checksum() {
+    let mut madt = include_bytes!("data/firecracker_madt.bin").to_vec();
+    assert!(Table::parse(&madt).is_some());
+
+    madt[40] ^= 1;
+    assert!(Table::parse(&madt).is_none());
+    assert!(Fadt::parse(Table::parse(
+        include_bytes!("data/firecracker_mcfg.bin")).unwrap()).is_none());
+}
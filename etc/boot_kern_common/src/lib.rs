@@ -3,13 +3,28 @@
 
 #![no_std]
 
+use core::sync::atomic::{ AtomicU64, Ordering };
 use spinlock::SpinLock;
 use serial_driver::Serial;
 use range_set::RangeSet;
 
+/// Start of the virtual region the kernel stacks are mapped into. The stack
+/// of the bootstrap processor comes first, mapped by the bootloader.
+pub const KERNEL_STACKS_BASE: u64 = 0xffff_fe00_0000_0000;
+
+/// Size of the virtual region the kernel stacks are mapped into
+pub const KERNEL_STACKS_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+/// Size of the unmapped guard page below every kernel stack
+pub const STACK_GUARD_SIZE: u64 = 4096;
+
+/// Size of the stack of the bootstrap processor
+pub const BSP_STACK_SIZE: u64 = 256 * 1024;
+
 /// Variables that the kernel and the bootloader commonly share.
 /// Since this structure passes between both the 32-bit and 64-bit modes,
-/// its size must be identical (no pointers, references, usizes).
+/// its layout must be identical (no pointers, references, usizes). The
+/// assertions below check it in both builds.
 #[repr(C)]
 pub struct BootKernCommon {
     /// A spinlock-guarded serial driver.
@@ -19,6 +34,95 @@ pub struct BootKernCommon {
     /// Since the memory can be used by both of them at the same time,
     /// you have to make sure that you do not run into a deadlock!
     free_memory: SpinLock<Option<RangeSet>>,
+
+    /// Physical address of the ACPI RSDP found by the bootloader, 0 if it
+    /// wasn't found
+    rsdp: AtomicU64,
+
+    /// The DHCP lease the PXE ROM got, if the bootloader was PXE booted
+    pxe_lease: SpinLock<Option<PxeLease>>,
+}
+
+// The layout of `BootKernCommon` as both the bootloader and the kernel see it
+const _: () = {
+    use core::mem::{ size_of, offset_of };
+    assert!(size_of::<BootKernCommon>()             == 4232);
+    assert!(offset_of!(BootKernCommon, serial)      == 0);
+    assert!(offset_of!(BootKernCommon, free_memory) == 40);
+    assert!(offset_of!(BootKernCommon, rsdp)        == 4168);
+    assert!(offset_of!(BootKernCommon, pxe_lease)   == 4176);
+};
+
+/// The network configuration from a DHCP ACK. Addresses are in network
+/// order, and fields are all zeroes when the ACK didn't have them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct PxeLease {
+    /// Hardware address of the interface the lease is for
+    pub mac: [u8; 6],
+
+    /// Address leased to the client
+    pub client_ip: [u8; 4],
+
+    /// Address of the boot server
+    pub server_ip: [u8; 4],
+
+    /// Subnet mask of the network
+    pub subnet_mask: [u8; 4],
+
+    /// The first router of the network
+    pub gateway: [u8; 4],
+
+    /// Address of the DHCP server which gave the lease
+    pub server_id: [u8; 4],
+
+    /// Duration of the lease in seconds, all ones if it's infinite
+    pub lease_time: u32,
+}
+
+impl PxeLease {
+    /// Parse the fields of the lease out of the DHCP ACK `packet`. `None` if
+    /// it's too short or not an Ethernet BOOTP reply.
+    pub fn from_dhcp_ack(packet: &[u8]) -> Option<Self> {
+        // The fixed part of the packet, then the magic cookie
+        const OPTIONS: usize = 240;
+        if packet.len() < OPTIONS || packet[0] != 2 || packet[1] != 1 ||
+                packet[2] != 6 || packet[236..240] != [99, 130, 83, 99] {
+            return None;
+        }
+
+        let mut lease = Self {
+            mac:       packet[0x1c..0x22].try_into().ok()?,
+            client_ip: packet[0x10..0x14].try_into().ok()?,
+            server_ip: packet[0x14..0x18].try_into().ok()?,
+            ..Default::default()
+        };
+
+        // Walk the options for the subnet mask, the routers, the lease time
+        // and the server
+        let mut options = &packet[OPTIONS..];
+        while let [code, rest @ ..] = options {
+            match code {
+                0   => { options = rest; continue; }
+                255 => break,
+                _   => {}
+            }
+
+            let [len, rest @ ..] = rest else { break; };
+            let Some(value) = rest.get(..*len as usize) else { break; };
+            match (code, value.get(..4)) {
+                (1,  Some(mask))   => lease.subnet_mask.copy_from_slice(mask),
+                (3,  Some(router)) => lease.gateway.copy_from_slice(router),
+                (51, Some(secs))   => lease.lease_time =
+                    u32::from_be_bytes(secs.try_into().ok()?),
+                (54, Some(server)) => lease.server_id.copy_from_slice(server),
+                _ => {}
+            }
+            options = &rest[*len as usize..];
+        }
+
+        Some(lease)
+    }
 }
 
 impl BootKernCommon {
@@ -27,6 +131,8 @@ impl BootKernCommon {
         Self {
             serial:      SpinLock::new(None),
             free_memory: SpinLock::new(None),
+            rsdp:        AtomicU64::new(0),
+            pxe_lease:   SpinLock::new(None),
         }
     }
 
@@ -37,4 +143,25 @@ impl BootKernCommon {
     pub unsafe fn free_memory_ref(&self) -> &SpinLock<Option<RangeSet>> {
         &self.free_memory
     }
+
+    /// Returns the physical address of the ACPI RSDP, if the bootloader
+    /// found it
+    pub fn rsdp(&self) -> Option<u64> {
+        Some(self.rsdp.load(Ordering::Acquire)).filter(|&rsdp| rsdp != 0)
+    }
+
+    /// Record the physical address of the ACPI RSDP for the kernel
+    pub fn set_rsdp(&self, rsdp: u64) {
+        self.rsdp.store(rsdp, Ordering::Release);
+    }
+
+    /// Returns the DHCP lease the PXE ROM got, if the bootloader recorded one
+    pub fn pxe_lease(&self) -> Option<PxeLease> {
+        *self.pxe_lease.lock()
+    }
+
+    /// Record the DHCP lease the PXE ROM got for the kernel
+    pub fn set_pxe_lease(&self, lease: PxeLease) {
+        *self.pxe_lease.lock() = Some(lease);
+    }
 }
@@ -0,0 +1,101 @@
+//! Parsing of the DHCP ACK the PXE ROM caches, with packets assembled the
+//! way a DHCP server lays them out.
+
+use boot_kern_common::PxeLease;
+
+/// Hardware address of the client
+const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// Returns an ACK leasing 10.0.2.15 from the boot server 10.0.2.2, with
+/// `options` after the magic cookie
+fn ack(options: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0; 240];
+    packet[0] = 2;
+    packet[1] = 1;
+    packet[2] = 6;
+    packet[0x10..0x14].copy_from_slice(&[10, 0, 2, 15]);
+    packet[0x14..0x18].copy_from_slice(&[10, 0, 2, 2]);
+    packet[0x1c..0x22].copy_from_slice(&MAC);
+    packet[236..240].copy_from_slice(&[99, 130, 83, 99]);
+    packet.extend_from_slice(options);
+    packet
+}
+
+#[test]
+fn parses_ack() {
+    let packet = ack(&[
+        53, 1, 5,
+        1,  4, 255, 255, 255, 0,
+        3,  4, 10, 0, 2, 2,
+        51, 4, 0, 0, 0x0e, 0x10,
+        54, 4, 10, 0, 2, 3,
+        255,
+    ]);
+
+    assert_eq!(PxeLease::from_dhcp_ack(&packet), Some(PxeLease {
+        mac:         MAC,
+        client_ip:   [10, 0, 2, 15],
+        server_ip:   [10, 0, 2, 2],
+        subnet_mask: [255, 255, 255, 0],
+        gateway:     [10, 0, 2, 2],
+        server_id:   [10, 0, 2, 3],
+        lease_time:  3600,
+    }));
+}
+
+#[test]
+fn walks_options() {
+    // Pads, unknown options, a second router and a short option, with
+    // nothing read past the end option
+    let packet = ack(&[
+        0, 0,
+        12, 5, b'h', b'o', b's', b't', b'1',
+        3,  8, 192, 168, 0, 1, 192, 168, 0, 2,
+        1,  2, 255, 255,
+        0,
+        51, 4, 0xff, 0xff, 0xff, 0xff,
+        255,
+        54, 4, 10, 0, 2, 3,
+    ]);
+    let lease = PxeLease::from_dhcp_ack(&packet).unwrap();
+
+    assert_eq!(lease.gateway,     [192, 168, 0, 1]);
+    assert_eq!(lease.subnet_mask, [0; 4]);
+    assert_eq!(lease.lease_time,  u32::MAX);
+    assert_eq!(lease.server_id,   [0; 4]);
+}
+
+#[test]
+fn stops_at_truncated_options() {
+    // The options before the truncated one are kept
+    let packet = ack(&[
+        1,  4, 255, 255, 255, 0,
+        54, 4, 10, 0,
+    ]);
+    let lease = PxeLease::from_dhcp_ack(&packet).unwrap();
+    assert_eq!(lease.subnet_mask, [255, 255, 255, 0]);
+    assert_eq!(lease.server_id,   [0; 4]);
+
+    // An option code without its length, or without the end option
+    let lease = PxeLease::from_dhcp_ack(&ack(&[3])).unwrap();
+    assert_eq!(lease.gateway, [0; 4]);
+    let lease = PxeLease::from_dhcp_ack(&ack(&[])).unwrap();
+    assert_eq!(lease.server_ip, [10, 0, 2, 2]);
+}
+
+#[test]
+fn rejects_bad_packets() {
+    // Without the magic cookie
+    let mut packet = ack(&[255]);
+    packet[236..240].copy_from_slice(&[0; 4]);
+    assert_eq!(PxeLease::from_dhcp_ack(&packet), None);
+
+    // Shorter than the fixed part and the cookie
+    let packet = ack(&[]);
+    assert_eq!(PxeLease::from_dhcp_ack(&packet[..239]), None);
+
+    // A request rather than a reply
+    let mut packet = ack(&[255]);
+    packet[0] = 1;
+    assert_eq!(PxeLease::from_dhcp_ack(&packet), None);
+}
@@ -3,6 +3,51 @@
 #![no_std]
 
 use core::arch::asm;
+use core::marker::PhantomData;
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::__cpuid_count;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid_count;
+
+/// Physical address and enable bits of the local APIC
+pub const IA32_APIC_BASE: u32 = 0x1B;
+
+/// x2APIC mode enable bit in the APIC base register
+pub const APIC_BASE_EXTD: u64 = 1 << 10;
+
+/// Global APIC enable bit in the APIC base register
+pub const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Extended Feature Enable Register
+pub const IA32_EFER: u32 = 0xC000_0080;
+
+/// Long mode enable bit in the EFER register
+pub const EFER_LME: u64 = 1 << 8;
+
+/// Long mode active bit in the EFER register, read-only
+pub const EFER_LMA: u64 = 1 << 10;
+
+/// No-execute enable bit in the EFER register
+pub const EFER_NXE: u64 = 1 << 11;
+
+/// Process-context identifier enable bit in CR4, only settable in long mode
+pub const CR4_PCIDE: usize = 1 << 17;
+
+/// Base address of the FS segment
+pub const IA32_FS_BASE: u32 = 0xC000_0100;
+
+/// Base address of the GS segment
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+
+/// Value swapped into the GS base on `swapgs`
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+/// Auxiliary TSC value returned by `rdtscp`
+pub const IA32_TSC_AUX: u32 = 0xC000_0103;
+
+/// Interrupt enable flag in the (R|E)FLAGS register
+pub const FLAGS_IF: usize = 1 << 9;
 
 /// Write a byte to I/O port `addr`
 #[inline]
@@ -31,3 +76,516 @@ pub unsafe fn in32(addr: u16) -> u32 {
     asm!("in eax, dx", in("dx") addr, out("eax") bytes);
     bytes
 }
+
+/// Write 2 bytes to I/O port `addr`
+#[inline]
+pub unsafe fn out16(addr: u16, bytes: u16) {
+    asm!("out dx, ax", in("dx") addr, in("ax") bytes);
+}
+
+/// Read 2 bytes from I/O port `addr`
+#[inline]
+pub unsafe fn in16(addr: u16) -> u16 {
+    let mut bytes: u16;
+    asm!("in ax, dx", in("dx") addr, out("ax") bytes);
+    bytes
+}
+
+/// Fill `buf` with bytes read from I/O port `addr` (`rep insb`)
+#[inline]
+pub unsafe fn ins8(addr: u16, buf: &mut [u8]) {
+    asm!("rep insb", in("dx") addr, inout("edi") buf.as_mut_ptr() => _,
+         inout("ecx") buf.len() => _, options(nostack));
+}
+
+/// Write all of `buf` to I/O port `addr` with the string instruction `$ins`.
+/// LLVM reserves ESI on 32-bit x86, so there the source is swapped into it
+/// around the instruction instead of being passed in it.
+macro_rules! rep_outs {
+    ($ins:literal, $addr:expr, $buf:expr) => {
+        #[cfg(target_arch = "x86_64")]
+        asm!($ins, in("dx") $addr, inout("rsi") $buf.as_ptr() => _,
+             inout("rcx") $buf.len() => _, options(nostack, readonly));
+
+        #[cfg(target_arch = "x86")]
+        asm!("xchg esi, {src}", $ins, "xchg esi, {src}",
+             in("dx") $addr, src = inout(reg) $buf.as_ptr() => _,
+             inout("ecx") $buf.len() => _, options(nostack, readonly));
+    }
+}
+
+/// Write all of `buf` to I/O port `addr` (`rep outsb`)
+#[inline]
+pub unsafe fn outs8(addr: u16, buf: &[u8]) {
+    rep_outs!("rep outsb", addr, buf);
+}
+
+/// Fill `buf` with words read from I/O port `addr` (`rep insw`)
+#[inline]
+pub unsafe fn ins16(addr: u16, buf: &mut [u16]) {
+    asm!("rep insw", in("dx") addr, inout("edi") buf.as_mut_ptr() => _,
+         inout("ecx") buf.len() => _, options(nostack));
+}
+
+/// Write all of `buf` to I/O port `addr` (`rep outsw`)
+#[inline]
+pub unsafe fn outs16(addr: u16, buf: &[u16]) {
+    rep_outs!("rep outsw", addr, buf);
+}
+
+/// Fill `buf` with dwords read from I/O port `addr` (`rep insd`)
+#[inline]
+pub unsafe fn ins32(addr: u16, buf: &mut [u32]) {
+    asm!("rep insd", in("dx") addr, inout("edi") buf.as_mut_ptr() => _,
+         inout("ecx") buf.len() => _, options(nostack));
+}
+
+/// Write all of `buf` to I/O port `addr` (`rep outsd`)
+#[inline]
+pub unsafe fn outs32(addr: u16, buf: &[u32]) {
+    rep_outs!("rep outsd", addr, buf);
+}
+
+/// A value that can be transferred through an I/O port
+pub trait PortValue: Copy {
+    /// Read a value from I/O port `addr`
+    unsafe fn read_port(addr: u16) -> Self;
+
+    /// Write `val` to I/O port `addr`
+    unsafe fn write_port(addr: u16, val: Self);
+}
+
+impl PortValue for u8 {
+    #[inline]
+    unsafe fn read_port(addr: u16) -> Self { in8(addr) }
+
+    #[inline]
+    unsafe fn write_port(addr: u16, val: Self) { out8(addr, val) }
+}
+
+impl PortValue for u16 {
+    #[inline]
+    unsafe fn read_port(addr: u16) -> Self { in16(addr) }
+
+    #[inline]
+    unsafe fn write_port(addr: u16, val: Self) { out16(addr, val) }
+}
+
+impl PortValue for u32 {
+    #[inline]
+    unsafe fn read_port(addr: u16) -> Self { in32(addr) }
+
+    #[inline]
+    unsafe fn write_port(addr: u16, val: Self) { out32(addr, val) }
+}
+
+/// An I/O port which transfers values of type `T`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Port<T: PortValue> {
+    /// Address of the port
+    addr: u16,
+
+    /// The type transferred through the port
+    _phantom: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    /// Returns a new port at address `addr`
+    pub const fn new(addr: u16) -> Self {
+        Self { addr, _phantom: PhantomData }
+    }
+
+    /// Returns the address of this port
+    pub const fn addr(&self) -> u16 {
+        self.addr
+    }
+
+    /// Read a value from the port
+    #[inline]
+    pub unsafe fn read(&self) -> T {
+        T::read_port(self.addr)
+    }
+
+    /// Write `val` to the port
+    #[inline]
+    pub unsafe fn write(&self, val: T) {
+        T::write_port(self.addr, val)
+    }
+}
+
+/// A contiguous range of I/O ports, usually the register block of a device.
+///
+/// The size of this structure is identical in all environments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct PortRange {
+    /// Address of the first port in the range
+    base: u16,
+
+    /// Number of ports in the range
+    len: u16,
+}
+
+impl PortRange {
+    /// Returns a new range of `len` ports starting at `base`
+    pub const fn new(base: u16, len: u16) -> Self {
+        // Make sure the range doesn't wrap around the I/O address space
+        assert!(base as u32 + len as u32 <= 0x10000,
+            "Port range exceeds the I/O address space.");
+
+        Self { base, len }
+    }
+
+    /// Returns the address of the first port in the range
+    pub const fn base(&self) -> u16 {
+        self.base
+    }
+
+    /// Returns the number of ports in the range
+    pub const fn len(&self) -> u16 {
+        self.len
+    }
+
+    /// Returns a port at `offset` bytes from the start of the range.
+    /// The whole port must reside within the range.
+    pub fn port<T: PortValue>(&self, offset: u16) -> Port<T> {
+        // Make sure the port is in bounds of the range
+        let end = offset as usize + core::mem::size_of::<T>();
+        assert!(end <= self.len as usize, "Port out of bounds of the range.");
+
+        Port::new(self.base + offset)
+    }
+}
+
+/// Execute `cpuid` with `leaf` in EAX and `subleaf` in ECX.
+///
+/// Returns (eax, ebx, ecx, edx).
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let res = __cpuid_count(leaf, subleaf);
+    (res.eax, res.ebx, res.ecx, res.edx)
+}
+
+/// Returns the highest supported extended `cpuid` leaf
+#[inline]
+pub fn max_extended_leaf() -> u32 {
+    cpuid(0x8000_0000, 0).0
+}
+
+/// Returns the initial APIC ID of the current core
+pub fn apic_id() -> u32 {
+    // Prefer the 32-bit x2APIC ID from the topology leaf, if there is one
+    if cpuid(0, 0).0 >= 0xb && cpuid(0xb, 0).1 != 0 {
+        return cpuid(0xb, 0).3;
+    }
+
+    cpuid(1, 0).1 >> 24
+}
+
+/// Check whether the CPU has a local APIC
+pub fn has_apic() -> bool {
+    (cpuid(1, 0).3 >> 9) & 1 != 0
+}
+
+/// Check whether the local APIC supports x2APIC mode
+pub fn has_x2apic() -> bool {
+    (cpuid(1, 0).2 >> 21) & 1 != 0
+}
+
+/// Check whether the TSC runs at a constant rate in all power states, so it
+/// can be used as a clock
+pub fn has_invariant_tsc() -> bool {
+    max_extended_leaf() >= 0x8000_0007 &&
+        (cpuid(0x8000_0007, 0).3 >> 8) & 1 != 0
+}
+
+/// Check whether the APIC timer keeps running in deep C-states (ARAT)
+pub fn has_always_running_apic_timer() -> bool {
+    cpuid(0, 0).0 >= 6 && (cpuid(6, 0).0 >> 2) & 1 != 0
+}
+
+/// Check whether the CPU supports the no-execute page protection
+pub fn has_nx() -> bool {
+    max_extended_leaf() >= 0x8000_0001 &&
+        (cpuid(0x8000_0001, 0).3 >> 20) & 1 != 0
+}
+
+/// Check whether the CPU supports 1 GiB pages
+pub fn has_1gib_pages() -> bool {
+    max_extended_leaf() >= 0x8000_0001 &&
+        (cpuid(0x8000_0001, 0).3 >> 26) & 1 != 0
+}
+
+/// Read the CR0 register
+#[inline]
+pub fn read_cr0() -> usize {
+    let cr0: usize;
+    unsafe { asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack)); }
+    cr0
+}
+
+/// Write `cr0` into the CR0 register
+#[inline]
+pub unsafe fn write_cr0(cr0: usize) {
+    asm!("mov cr0, {}", in(reg) cr0, options(nostack));
+}
+
+/// Read the CR2 register, the linear address of the last page fault
+#[inline]
+pub fn read_cr2() -> usize {
+    let cr2: usize;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack)); }
+    cr2
+}
+
+/// Read the CR3 register, the physical address of the current page table
+#[inline]
+pub fn read_cr3() -> usize {
+    let cr3: usize;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack)); }
+    cr3
+}
+
+/// Write `cr3` into the CR3 register. This flushes all non-global TLB entries.
+#[inline]
+pub unsafe fn write_cr3(cr3: usize) {
+    asm!("mov cr3, {}", in(reg) cr3, options(nostack));
+}
+
+/// Read the CR4 register
+#[inline]
+pub fn read_cr4() -> usize {
+    let cr4: usize;
+    unsafe { asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack)); }
+    cr4
+}
+
+/// Write `cr4` into the CR4 register
+#[inline]
+pub unsafe fn write_cr4(cr4: usize) {
+    asm!("mov cr4, {}", in(reg) cr4, options(nostack));
+}
+
+/// Read the model specific register `msr`
+#[inline]
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let low:  u32;
+    let high: u32;
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high,
+         options(nomem, nostack));
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Write `val` into the model specific register `msr`
+#[inline]
+pub unsafe fn wrmsr(msr: u32, val: u64) {
+    asm!("wrmsr", in("ecx") msr, in("eax") val as u32,
+         in("edx") (val >> 32) as u32, options(nostack));
+}
+
+/// Read the EFER register
+#[inline]
+pub unsafe fn read_efer() -> u64 {
+    rdmsr(IA32_EFER)
+}
+
+/// Write `efer` into the EFER register
+#[inline]
+pub unsafe fn write_efer(efer: u64) {
+    wrmsr(IA32_EFER, efer)
+}
+
+/// Read the XCR0 register. CR4.OSXSAVE must be set.
+#[inline]
+pub unsafe fn read_xcr0() -> u64 {
+    let low:  u32;
+    let high: u32;
+    asm!("xgetbv", in("ecx") 0u32, out("eax") low, out("edx") high,
+         options(nomem, nostack));
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Write `xcr0` into the XCR0 register. CR4.OSXSAVE must be set.
+#[inline]
+pub unsafe fn write_xcr0(xcr0: u64) {
+    asm!("xsetbv", in("ecx") 0u32, in("eax") xcr0 as u32,
+         in("edx") (xcr0 >> 32) as u32, options(nostack));
+}
+
+/// Read the time stamp counter
+#[inline]
+pub fn rdtsc() -> u64 {
+    let low:  u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high,
+             options(nomem, nostack));
+    }
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Read the time stamp counter after all previous instructions have executed.
+///
+/// Returns (tsc, aux) where `aux` is the contents of `IA32_TSC_AUX`.
+#[inline]
+pub fn rdtscp() -> (u64, u32) {
+    let low:  u32;
+    let high: u32;
+    let aux:  u32;
+    unsafe {
+        asm!("rdtscp", out("eax") low, out("edx") high, out("ecx") aux,
+             options(nomem, nostack));
+    }
+    (((high as u64) << 32) | (low as u64), aux)
+}
+
+/// The in-memory operand of `lgdt` and `lidt`
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct TableRegister {
+    /// Size of the table in bytes minus one
+    pub limit: u16,
+
+    /// Linear address of the table
+    pub base: usize,
+}
+
+/// Load the GDT described by `gdtr`
+#[inline]
+pub unsafe fn lgdt(gdtr: &TableRegister) {
+    asm!("lgdt [{}]", in(reg) gdtr, options(readonly, nostack));
+}
+
+/// Load the IDT described by `idtr`
+#[inline]
+pub unsafe fn lidt(idtr: &TableRegister) {
+    asm!("lidt [{}]", in(reg) idtr, options(readonly, nostack));
+}
+
+/// Load the task register with the TSS selector `selector`
+#[inline]
+pub unsafe fn ltr(selector: u16) {
+    asm!("ltr {:x}", in(reg) selector, options(nostack));
+}
+
+/// Invalidate the TLB entry for the page containing `addr`
+#[inline]
+pub fn invlpg(addr: usize) {
+    unsafe { asm!("invlpg [{}]", in(reg) addr, options(nostack)); }
+}
+
+/// Halt the CPU until the next interrupt arrives. Also a compiler barrier,
+/// so memory changed by the interrupt is read again after.
+#[inline]
+pub fn hlt() {
+    unsafe { asm!("hlt", options(nostack)); }
+}
+
+/// Read the RFLAGS register
+#[inline]
+#[cfg(target_arch = "x86_64")]
+pub fn read_flags() -> usize {
+    let flags: usize;
+    unsafe { asm!("pushfq", "pop {}", out(reg) flags, options(nomem)); }
+    flags
+}
+
+/// Read the EFLAGS register
+#[inline]
+#[cfg(target_arch = "x86")]
+pub fn read_flags() -> usize {
+    let flags: usize;
+    unsafe { asm!("pushfd", "pop {}", out(reg) flags, options(nomem)); }
+    flags
+}
+
+/// Check whether interrupts are enabled on the current CPU
+#[inline]
+pub fn interrupts_enabled() -> bool {
+    (read_flags() & FLAGS_IF) != 0
+}
+
+/// Enable interrupts. The IDT must be able to handle whatever arrives.
+/// Also a compiler barrier, so memory accesses aren't moved across it.
+#[inline]
+pub unsafe fn enable_interrupts() {
+    asm!("sti", options(nostack));
+}
+
+/// Disable interrupts. Also a compiler barrier, so memory accesses aren't
+/// moved out of the critical section.
+#[inline]
+pub fn disable_interrupts() {
+    unsafe { asm!("cli", options(nostack)); }
+}
+
+/// A guard which disables interrupts on creation and restores the interrupt
+/// flag to its original state when dropped
+pub struct InterruptGuard {
+    /// Whether the interrupts were enabled when this guard was created
+    enabled: bool,
+}
+
+impl InterruptGuard {
+    /// Save the interrupt flag and disable interrupts
+    #[inline]
+    pub fn new() -> Self {
+        let enabled = interrupts_enabled();
+        disable_interrupts();
+        Self { enabled }
+    }
+}
+
+impl Drop for InterruptGuard {
+    #[inline]
+    fn drop(&mut self) {
+        // Only re-enable the interrupts if they were enabled before
+        if self.enabled {
+            unsafe { enable_interrupts(); }
+        }
+    }
+}
+
+/// Read the FS base. Only meaningful in 64-bit mode.
+#[inline]
+pub unsafe fn read_fs_base() -> u64 {
+    rdmsr(IA32_FS_BASE)
+}
+
+/// Write `base` into the FS base. Only meaningful in 64-bit mode.
+#[inline]
+pub unsafe fn write_fs_base(base: u64) {
+    wrmsr(IA32_FS_BASE, base)
+}
+
+/// Read the GS base. Only meaningful in 64-bit mode.
+#[inline]
+pub unsafe fn read_gs_base() -> u64 {
+    rdmsr(IA32_GS_BASE)
+}
+
+/// Write `base` into the GS base. Only meaningful in 64-bit mode.
+#[inline]
+pub unsafe fn write_gs_base(base: u64) {
+    wrmsr(IA32_GS_BASE, base)
+}
+
+/// Read the GS base which will be swapped in on `swapgs`
+#[inline]
+pub unsafe fn read_kernel_gs_base() -> u64 {
+    rdmsr(IA32_KERNEL_GS_BASE)
+}
+
+/// Write `base` into the GS base which will be swapped in on `swapgs`
+#[inline]
+pub unsafe fn write_kernel_gs_base(base: u64) {
+    wrmsr(IA32_KERNEL_GS_BASE, base)
+}
+
+/// Swap the GS base with the kernel GS base
+#[inline]
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn swapgs() {
+    asm!("swapgs", options(nomem, nostack));
+}
@@ -51,9 +51,10 @@ impl Range {
     }
 }
 
-/// A set of non-overlapping inclusive `Range`s.
+/// A set of non-overlapping inclusive `Range`s. It's 8-byte aligned, as the
+/// `u64`s in it are only 4-byte aligned on 32-bit x86.
 #[derive(Clone, Copy, Debug)]
-#[repr(C)]
+#[repr(C, align(8))]
 pub struct RangeSet {
     /// Array of ranges in the set
     ranges: [Range; 256],
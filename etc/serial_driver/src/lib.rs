@@ -3,10 +3,40 @@
 
 #![no_std]
 
+use cpu::PortRange;
+
+/// Number of I/O ports occupied by a single UART
+const UART_PORTS: u16 = 8;
+
+/// Transmit/receive buffer, or the low byte of the divisor when DLAB is set
+const DATA: u16 = 0;
+
+/// Interrupt enable register, or the high byte of the divisor when DLAB is set
+const INTERRUPT_ENABLE: u16 = 1;
+
+/// Line control register
+const LINE_CONTROL: u16 = 3;
+
+/// Modem control register
+const MODEM_CONTROL: u16 = 4;
+
+/// Line status register
+const LINE_STATUS: u16 = 5;
+
+/// Interrupt when a byte has been received
+const INTERRUPT_RECEIVED: u8 = 0x01;
+
+/// DTR and RTS set, IRQs disabled
+const MODEM_POLLED: u8 = 0x03;
+
+/// DTR and RTS set, OUT2 set to connect the IRQ line to the interrupt
+/// controller
+const MODEM_INTERRUPTS: u8 = 0x0b;
+
 /// Serial ports identified by the BIOS.
 #[repr(C)]
 pub struct Serial {
-    pub devices: [Option<u16>; 4],
+    pub devices: [Option<PortRange>; 4],
 }
 
 /// Flag that marks the serial as initialized.
@@ -38,14 +68,21 @@ impl Serial{
             // Check if the port is present. If not, proceed to the next one
             if port == 0 { continue; }
 
+            // Get the registers of the UART
+            let port = PortRange::new(port, UART_PORTS);
+            let data       = port.port::<u8>(DATA);
+            let int_enable = port.port::<u8>(INTERRUPT_ENABLE);
+            let line_ctrl  = port.port::<u8>(LINE_CONTROL);
+            let modem_ctrl = port.port::<u8>(MODEM_CONTROL);
+
             // Initialize the port
             unsafe {
-                cpu::out8(port + 1, 0x00); // Disable all interrupts
-                cpu::out8(port + 3, 0x80); // Enable DLAB (set baud divisor)
-                cpu::out8(port + 0, 0x04); // (low byte) Divisor = 115200 / this
-                cpu::out8(port + 1, 0x00); // (high byte)
-                cpu::out8(port + 3, 0x03); // 8 bits, no parity, one stop bit
-                cpu::out8(port + 4, 0x03); // IRQs disabled, RTS/DSR set
+                int_enable.write(0x00); // Disable all interrupts
+                line_ctrl.write(0x80);  // Enable DLAB (set baud divisor)
+                data.write(0x04);       // (low byte) Divisor = 115200 / this
+                int_enable.write(0x00); // (high byte)
+                line_ctrl.write(0x03);  // 8 bits, no parity, one stop bit
+                modem_ctrl.write(MODEM_POLLED);
             }
 
             // Save the port
@@ -57,18 +94,26 @@ impl Serial{
         ports
     }
 
+    /// Raise an IRQ on all the ports whenever a byte is received
+    pub fn enable_interrupts(&mut self) {
+        for port in self.devices.iter().flatten() {
+            unsafe {
+                port.port::<u8>(MODEM_CONTROL).write(MODEM_INTERRUPTS);
+                port.port::<u8>(INTERRUPT_ENABLE).write(INTERRUPT_RECEIVED);
+            }
+        }
+    }
+
     /// Read a byte from the first COM port that has a byte available
     pub fn read_byte(&mut self) -> Option<u8> {
         // Iterate through the devices
         for port in self.devices.iter() {
             // Check whether the device is present
             if let Some(port) = *port {
-                unsafe {
-                    // Check if there is a byte available.
-                    // If yes, read and return it
-                    if (cpu::in8(port + 5) & 1) != 0 {
-                        return Some(cpu::in8(port));
-                    }
+                // If there is a byte available, return it
+                let byte = unsafe { Self::read_port(port) };
+                if byte.is_some() {
+                    return byte;
                 }
             }
         }
@@ -77,16 +122,32 @@ impl Serial{
         None
     }
 
+    /// Read a byte from the UART at `port` if one is available, without
+    /// holding the driver. Meant for IRQ handlers, which can't wait for the
+    /// driver to be unlocked.
+    ///
+    /// # Safety
+    ///
+    /// `port` must be one of the `devices` of the driver. Bytes are split
+    /// between concurrent readers of the same port.
+    pub unsafe fn read_port(port: PortRange) -> Option<u8> {
+        if port.port::<u8>(LINE_STATUS).read() & 1 == 0 {
+            return None;
+        }
+
+        Some(port.port::<u8>(DATA).read())
+    }
+
     /// Write a byte to a COM port
     fn write_byte(&mut self, port: usize, byte: u8) {
         // Check if this port exists
         if let Some(&Some(port)) = self.devices.get(port) {
             unsafe {
                 // Wait for the transmit to be empty
-                while cpu::in8(port + 5) & 0x20 == 0 {};
+                while port.port::<u8>(LINE_STATUS).read() & 0x20 == 0 {};
 
                 // Write the byte
-                cpu::out8(port, byte);
+                port.port::<u8>(DATA).write(byte);
             }
         }
     }
@@ -1,14 +1,40 @@
 //! A spinlock implementation. Similar to std's Mutex.
+//!
+//! Once a core ID accessor is registered with `set_core_id_fn()`, locks
+//! record the core holding them, and a core locking a lock it already holds
+//! panics instead of deadlocking.
 
 #![no_std]
 
-use core::sync::atomic::{ AtomicU32, Ordering };
+use core::sync::atomic::{ AtomicU32, AtomicUsize, Ordering };
 use core::ops::{ Deref, DerefMut };
 use core::cell::UnsafeCell;
 use core::hint::spin_loop;
 
-/// A spinlock-guarded inner-mutable variable
-#[repr(C)]
+/// Owner of a lock which isn't held, or held by an unknown core
+const NO_OWNER: u32 = u32::MAX;
+
+/// Function returning the ID of the current core, 0 if none is registered
+static CORE_ID_FN: AtomicUsize = AtomicUsize::new(0);
+
+/// Register `core_id` as the function returning the ID of the current core.
+/// It must work on every core which takes locks from now on.
+pub fn set_core_id_fn(core_id: fn() -> u32) {
+    CORE_ID_FN.store(core_id as usize, Ordering::SeqCst);
+}
+
+/// Returns the ID of the current core, or `NO_OWNER` if it isn't known
+fn core_id() -> u32 {
+    match CORE_ID_FN.load(Ordering::Relaxed) {
+        0  => NO_OWNER,
+        id => unsafe { core::mem::transmute::<usize, fn() -> u32>(id)() },
+    }
+}
+
+/// A spinlock-guarded inner-mutable variable. The lock is 8-byte aligned and
+/// the value starts at offset 16 in all environments, so locks shared between
+/// the 32-bit bootloader and the 64-bit kernel are laid out identically.
+#[repr(C, align(8))]
 pub struct SpinLock<T: ?Sized> {
     /// Ticket counter. A ticket is grabbed and when `release` is set to this
     /// ticket, you get your variable
@@ -17,6 +43,13 @@ pub struct SpinLock<T: ?Sized> {
     /// Current `ticket` value which can be released
     release: AtomicU32,
 
+    /// ID of the core holding the lock, `NO_OWNER` if it isn't held or the
+    /// core isn't known
+    owner: AtomicU32,
+
+    /// Padding placing `val` at offset 16
+    _reserved: u32,
+
     /// Value guarded by this lock
     val: UnsafeCell<T>
 }
@@ -29,28 +62,62 @@ impl<T> SpinLock<T> {
     /// Move a `val` into a `SpinLock`
     pub const fn new(val: T) -> Self {
         Self {
-            val:     UnsafeCell::new(val),
-            ticket:  AtomicU32::new(0),
-            release: AtomicU32::new(0),
+            val:       UnsafeCell::new(val),
+            ticket:    AtomicU32::new(0),
+            release:   AtomicU32::new(0),
+            owner:     AtomicU32::new(NO_OWNER),
+            _reserved: 0,
         }
     }
 }
 
 impl<T: ?Sized> SpinLock<T> {
     /// Acquire exclusive acces to the variable
-    pub fn lock(&self) -> SpinLockGuard<T> {
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        // Waiting for a lock we hold ourselves would never end
+        let core = core_id();
+        if core != NO_OWNER && self.owner.load(Ordering::SeqCst) == core {
+            panic!("Deadlock: core {} is locking a lock it holds.", core);
+        }
+
         let ticket = self.ticket.fetch_add(1, Ordering::SeqCst);
 
         while self.release.load(Ordering::SeqCst) != ticket {
             spin_loop();
         }
 
+        self.owner.store(core, Ordering::SeqCst);
         SpinLockGuard {
-            lock: &self,
+            lock: self,
         }
     }
 
+    /// Try to acquire exclusive access to the variable without waiting.
+    /// Returns `None` if the lock is held by someone else.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        // The lock is free if the next ticket is the one being released.
+        // Take that ticket only if nobody else took it in the meantime.
+        let release = self.release.load(Ordering::SeqCst);
+        self.ticket.compare_exchange(release, release.wrapping_add(1),
+                                     Ordering::SeqCst, Ordering::SeqCst)
+            .ok()?;
+
+        self.owner.store(core_id(), Ordering::SeqCst);
+        Some(SpinLockGuard { lock: self })
+    }
+
+    /// Returns the ID of the core holding the lock, if it's held and the
+    /// core is known
+    pub fn owner(&self) -> Option<u32> {
+        Some(self.owner.load(Ordering::SeqCst)).filter(|&core| core != NO_OWNER)
+    }
+
     /// Return a raw pointer to the internal locked value, bypassing the lock.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may access the value while the pointer is used, whether
+    /// it holds the lock or not.
     pub unsafe fn shatter(&self) -> *mut T {
         self.val.get()
     }
@@ -65,6 +132,7 @@ pub struct SpinLockGuard<'a, T: ?Sized> {
 
 impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
     fn drop(&mut self) {
+        self.lock.owner.store(NO_OWNER, Ordering::SeqCst);
         self.lock.release.fetch_add(1, Ordering::SeqCst);
     }
 }
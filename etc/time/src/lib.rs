@@ -0,0 +1,150 @@
+//! Timekeeping based on the TSC.
+//!
+//! The TSC frequency is measured against the PIT once with `calibrate()`,
+//! after which `Instant` works as a monotonic clock. The TSCs of all the
+//! cores are assumed to be synchronized, which holds on CPUs with an
+//! invariant TSC. Wall-clock time comes from the CMOS RTC.
+
+#![no_std]
+
+pub mod rtc;
+
+use core::ops::{ Add, Sub };
+use core::hint::spin_loop;
+use core::sync::atomic::{ AtomicU64, Ordering };
+use cpu::{ Port, InterruptGuard };
+
+pub use core::time::Duration;
+
+/// Frequency of the PIT input clock in Hz
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Data port of PIT channel 2
+const PIT_CHANNEL2: Port<u8> = Port::new(0x42);
+
+/// Mode/command port of the PIT
+const PIT_COMMAND: Port<u8> = Port::new(0x43);
+
+/// Port controlling the gate of PIT channel 2 and reading its output
+const PIT_GATE: Port<u8> = Port::new(0x61);
+
+/// Duration of the calibration in milliseconds. The PIT can't count for much
+/// longer than 54 ms.
+const CALIBRATION_MS: u64 = 50;
+
+/// Nanoseconds in a second
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Frequency of the TSC in Hz, 0 until `calibrate()` is called
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Measure the frequency of the TSC against PIT channel 2 and return it in
+/// Hz
+pub fn calibrate() -> u64 {
+    let _interrupts = InterruptGuard::new();
+
+    let ticks = unsafe {
+        // Disable the gate of channel 2 and the speaker
+        PIT_GATE.write(PIT_GATE.read() & !0x03);
+
+        // Channel 2, low/high byte access, interrupt on terminal count
+        let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+        PIT_COMMAND.write(0b1011_0000);
+        PIT_CHANNEL2.write(count as u8);
+        PIT_CHANNEL2.write((count >> 8) as u8);
+
+        // Start the PIT and wait for its output to go high
+        let start = cpu::rdtsc();
+        PIT_GATE.write(PIT_GATE.read() | 0x01);
+        while PIT_GATE.read() & 0x20 == 0 {
+            spin_loop();
+        }
+        let end = cpu::rdtsc();
+
+        // Stop the PIT
+        PIT_GATE.write(PIT_GATE.read() & !0x01);
+
+        end - start
+    };
+
+    let hz = ticks * 1000 / CALIBRATION_MS;
+    TSC_HZ.store(hz, Ordering::SeqCst);
+    hz
+}
+
+/// Set the frequency of the TSC to `hz`, measured against a more precise
+/// reference than the PIT
+pub fn set_tsc_hz(hz: u64) {
+    assert!(hz != 0, "Invalid TSC frequency.");
+    TSC_HZ.store(hz, Ordering::SeqCst);
+}
+
+/// Returns the frequency of the TSC in Hz
+pub fn tsc_hz() -> u64 {
+    let hz = TSC_HZ.load(Ordering::Relaxed);
+    assert!(hz != 0, "The TSC isn't calibrated.");
+    hz
+}
+
+/// Convert `ticks` of the TSC to a duration
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / tsc_hz() as u128;
+    Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX))
+}
+
+/// Convert `duration` to ticks of the TSC
+fn duration_to_ticks(duration: Duration) -> Option<u64> {
+    (duration.as_nanos().checked_mul(tsc_hz() as u128)? / NANOS_PER_SEC)
+        .try_into().ok()
+}
+
+/// A point in time of the monotonic clock
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns the current time
+    pub fn now() -> Self {
+        Self(cpu::rdtsc())
+    }
+
+    /// Returns the time elapsed from `earlier` to `self`, zero if `earlier`
+    /// is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Returns the time elapsed since `self`
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns the time `duration` after `self`, if it can be represented
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_ticks(duration)?).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("Instant overflow.")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Busy wait for `duration`
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        spin_loop();
+    }
+}
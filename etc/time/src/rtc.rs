@@ -0,0 +1,154 @@
+//! Wall-clock time from the CMOS real-time clock.
+
+use core::fmt;
+use spinlock::SpinLock;
+use cpu::{ Port, InterruptGuard };
+
+/// Port selecting the CMOS register to access
+const CMOS_INDEX: Port<u8> = Port::new(0x70);
+
+/// Port accessing the selected CMOS register
+const CMOS_DATA: Port<u8> = Port::new(0x71);
+
+/// RTC registers
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR:   u8 = 0x04;
+const REG_DAY:    u8 = 0x07;
+const REG_MONTH:  u8 = 0x08;
+const REG_YEAR:   u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+/// The RTC is updating its registers
+const STATUS_A_UPDATING: u8 = 1 << 7;
+
+/// The hours are in 24-hour format instead of 12-hour
+const STATUS_B_24HOUR: u8 = 1 << 1;
+
+/// The registers are binary instead of BCD
+const STATUS_B_BINARY: u8 = 1 << 2;
+
+/// The hour is PM in 12-hour format
+const HOUR_PM: u8 = 1 << 7;
+
+/// Serializes accesses to the CMOS, which need two port accesses
+static CMOS: SpinLock<()> = SpinLock::new(());
+
+/// A date and time, in the time zone the RTC is set to. That's UTC unless
+/// the machine also runs Windows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year:   u16,
+    pub month:  u8,
+    pub day:    u8,
+    pub hour:   u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the number of seconds since 1970-01-01 00:00:00, assuming the
+    /// time is UTC
+    pub fn unix_timestamp(&self) -> u64 {
+        // Count the days with years starting in March, so that the leap day
+        // is the last day of the year
+        let (year, month) = if self.month <= 2 {
+            (self.year as u64 - 1, self.month as u64 + 9)
+        } else {
+            (self.year as u64, self.month as u64 - 3)
+        };
+        let days = year * 365 + year / 4 - year / 100 + year / 400 +
+            (month * 306 + 5) / 10 + self.day as u64 - 1;
+
+        // Days from 0000-03-01 to 1970-01-01
+        let days = days - 719_468;
+
+        days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 +
+            self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day,
+               self.hour, self.minute, self.second)
+    }
+}
+
+/// Read the CMOS register `reg`
+unsafe fn read_cmos(reg: u8) -> u8 {
+    CMOS_INDEX.write(reg);
+    CMOS_DATA.read()
+}
+
+/// Read the raw time registers once the RTC isn't updating them
+unsafe fn read_raw(century_reg: Option<u8>) -> [u8; 7] {
+    while read_cmos(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+
+    [
+        read_cmos(REG_SECOND),
+        read_cmos(REG_MINUTE),
+        read_cmos(REG_HOUR),
+        read_cmos(REG_DAY),
+        read_cmos(REG_MONTH),
+        read_cmos(REG_YEAR),
+        century_reg.map(|reg| read_cmos(reg)).unwrap_or(0),
+    ]
+}
+
+/// Convert the BCD `val` to binary
+fn from_bcd(val: u8) -> u8 {
+    (val >> 4) * 10 + (val & 0xf)
+}
+
+/// Read the current date and time from the RTC. `century_reg` is the CMOS
+/// register holding the century, as given by the ACPI FADT. Without it, the
+/// year is assumed to be in the 21st century.
+pub fn read(century_reg: Option<u8>) -> DateTime {
+    let _interrupts = InterruptGuard::new();
+    let _cmos = CMOS.lock();
+
+    // Read until two reads match, so that an update in between is noticed
+    let (raw, status) = unsafe {
+        let mut raw = read_raw(century_reg);
+        loop {
+            let again = read_raw(century_reg);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        (raw, read_cmos(REG_STATUS_B))
+    };
+
+    // Get the binary values, keeping the PM flag out of the BCD conversion
+    let [second, minute, hour, day, month, year, century] = raw;
+    let pm   = hour & HOUR_PM != 0;
+    let hour = hour & !HOUR_PM;
+    let [second, minute, mut hour, day, month, year, century] =
+        if status & STATUS_B_BINARY == 0 {
+            [second, minute, hour, day, month, year, century].map(from_bcd)
+        } else {
+            [second, minute, hour, day, month, year, century]
+        };
+
+    // Convert 12-hour time to 24-hour time, 12 AM is midnight
+    if status & STATUS_B_24HOUR == 0 {
+        hour = (hour % 12) + if pm { 12 } else { 0 };
+    }
+
+    let century = if century_reg.is_some() { century as u16 } else { 20 };
+    DateTime {
+        year: century * 100 + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
@@ -0,0 +1,112 @@
+//! Virtio devices over modern virtio-pci.
+//!
+//! The transport finds the common, notify, ISR and device configuration
+//! structures through the vendor capabilities of the PCI function, resets
+//! the device and negotiates the features. Queues are split virtqueues in
+//! memory allocated through the `Platform`. Device drivers only deal with
+//! their device configuration and the buffers they put on the queues.
+#![no_std]
+
+pub mod pci;
+pub mod queue;
+
+pub use pci::Transport;
+pub use queue::{ Buffer, Virtqueue };
+
+/// Vendor ID of virtio PCI functions
+pub const VENDOR_ID: u16 = 0x1af4;
+
+/// The device complies with version 1 of the specification. Required, legacy
+/// devices aren't supported.
+pub const F_VERSION_1: u64 = 1 << 32;
+
+/// The device can access the memory through an IOMMU
+pub const F_ACCESS_PLATFORM: u64 = 1 << 33;
+
+/// Device status: the guest noticed the device
+pub const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+
+/// Device status: the guest knows how to drive the device
+pub const STATUS_DRIVER: u8 = 1 << 1;
+
+/// Device status: the driver is set up and ready to drive the device
+pub const STATUS_DRIVER_OK: u8 = 1 << 2;
+
+/// Device status: the feature negotiation is complete
+pub const STATUS_FEATURES_OK: u8 = 1 << 3;
+
+/// Device status: the device hit an error it can't recover from
+pub const STATUS_NEEDS_RESET: u8 = 1 << 6;
+
+/// Device status: the guest gave up on the device
+pub const STATUS_FAILED: u8 = 1 << 7;
+
+/// The kinds of virtio devices
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Other(u16),
+}
+
+impl DeviceType {
+    /// Returns the device type of a virtio PCI function with `device_id`,
+    /// either a transitional or a modern ID. `None` if it isn't a virtio ID.
+    pub fn from_pci(device_id: u16) -> Option<Self> {
+        // Transitional devices use their own IDs for a few types
+        let id = match device_id {
+            0x1000          => 1,
+            0x1001          => 2,
+            0x1003          => 3,
+            0x1005          => 4,
+            0x1040..=0x107f => device_id - 0x1040,
+            _               => return None,
+        };
+
+        Some(match id {
+            1 => DeviceType::Network,
+            2 => DeviceType::Block,
+            3 => DeviceType::Console,
+            4 => DeviceType::Entropy,
+            _ => DeviceType::Other(id),
+        })
+    }
+}
+
+/// Physically contiguous memory the device can access
+#[derive(Clone, Copy, Debug)]
+pub struct Dma {
+    /// Physical address of the memory, as the device sees it
+    pub phys: u64,
+
+    /// Pointer to the memory, as the driver sees it
+    pub ptr: *mut u8,
+
+    /// Size of the memory in bytes
+    pub size: usize,
+}
+
+/// The environment a virtio device is driven from
+pub trait Platform {
+    /// Allocate `size` bytes of zeroed, physically contiguous memory aligned
+    /// to `align` bytes, to be shared with the device
+    fn alloc_dma(&self, size: usize, align: usize) -> Option<Dma>;
+
+    /// Map `size` bytes of device memory at the physical address `phys` as
+    /// uncacheable and return a pointer to them
+    fn map_mmio(&self, phys: u64, size: usize) -> Option<*mut u8>;
+}
+
+/// Access to the configuration space of the PCI function of a device
+pub trait PciFunction {
+    /// Read the byte at `offset` in the configuration space
+    fn read8(&self, offset: u16) -> u8;
+
+    /// Read the dword at `offset` in the configuration space
+    fn read32(&self, offset: u16) -> u32;
+
+    /// Returns the physical address of memory BAR `bar`, if it's one
+    fn memory_bar(&self, bar: u8) -> Option<u64>;
+}
@@ -0,0 +1,340 @@
+//! The modern virtio-pci transport.
+
+use core::ptr;
+use core::hint::spin_loop;
+use crate::{ Platform, PciFunction, Virtqueue };
+use crate::{ F_VERSION_1, STATUS_ACKNOWLEDGE, STATUS_DRIVER };
+use crate::{ STATUS_DRIVER_OK, STATUS_FEATURES_OK, STATUS_FAILED };
+
+/// Status register bit signalling a capabilities list
+const PCI_STATUS_CAPABILITIES: u32 = 1 << 20;
+
+/// Offset of the capabilities pointer in the configuration space
+const PCI_CAPABILITIES: u16 = 0x34;
+
+/// ID of vendor specific capabilities, which virtio uses
+const CAP_VENDOR: u8 = 0x09;
+
+/// Virtio capability types
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_ISR:    u8 = 3;
+const CAP_DEVICE: u8 = 4;
+
+/// Registers of the common configuration structure
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE:        usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE:        usize = 0x0c;
+const COMMON_MSIX_CONFIG:           usize = 0x10;
+const COMMON_NUM_QUEUES:            usize = 0x12;
+const COMMON_DEVICE_STATUS:         usize = 0x14;
+const COMMON_CONFIG_GENERATION:     usize = 0x15;
+const COMMON_QUEUE_SELECT:          usize = 0x16;
+const COMMON_QUEUE_SIZE:            usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR:     usize = 0x1a;
+const COMMON_QUEUE_ENABLE:          usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF:      usize = 0x1e;
+const COMMON_QUEUE_DESC:            usize = 0x20;
+const COMMON_QUEUE_DRIVER:          usize = 0x28;
+const COMMON_QUEUE_DEVICE:          usize = 0x30;
+
+/// Size of the common configuration structure
+const COMMON_SIZE: usize = 0x38;
+
+/// MSI-X vector meaning no interrupt
+pub const NO_VECTOR: u16 = 0xffff;
+
+/// A virtio configuration structure found through a capability
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    /// Pointer to the structure
+    ptr: *mut u8,
+
+    /// Size of the structure in bytes
+    len: usize,
+}
+
+/// A virtio device on the PCI bus
+pub struct Transport<P: Platform> {
+    /// The environment the device is driven from
+    platform: P,
+
+    /// The common configuration structure
+    common: *mut u8,
+
+    /// Base of the notify registers of the queues
+    notify: Region,
+
+    /// Multiplier of the notify offsets of the queues
+    notify_multiplier: u32,
+
+    /// The ISR status register
+    isr: *mut u8,
+
+    /// The device specific configuration structure, if there is one
+    device: Option<Region>,
+
+    /// Features negotiated with the device
+    features: u64,
+}
+
+// The raw pointers are to device memory owned by the transport
+unsafe impl<P: Platform + Send> Send for Transport<P> {}
+
+/// Find the virtio capability of `cfg_type` in the capabilities of `pci`
+/// and map the structure it points to
+fn find_region<P: Platform>(platform: &P, pci: &impl PciFunction,
+                            cfg_type: u8) -> Option<(Region, u8)> {
+    if pci.read32(0x04) & PCI_STATUS_CAPABILITIES == 0 {
+        return None;
+    }
+
+    // Walk the list, capping it in case it loops
+    let mut cap = pci.read8(PCI_CAPABILITIES) & 0xfc;
+    for _ in 0..48 {
+        if cap < 0x40 {
+            return None;
+        }
+
+        let cap16 = cap as u16;
+        if pci.read8(cap16) == CAP_VENDOR && pci.read8(cap16 + 3) == cfg_type
+        {
+            let bar    = pci.read8(cap16 + 4);
+            let offset = pci.read32(cap16 + 8) as u64;
+            let len    = pci.read32(cap16 + 12) as usize;
+
+            // Some types may be in I/O BARs, which aren't supported
+            if let Some(base) = pci.memory_bar(bar) {
+                let ptr = platform.map_mmio(base + offset, len)?;
+                return Some((Region { ptr, len }, cap));
+            }
+        }
+
+        cap = pci.read8(cap16 + 1) & 0xfc;
+    }
+
+    None
+}
+
+impl<P: Platform> Transport<P> {
+    /// Find and map the configuration structures of the virtio device `pci`.
+    /// `None` if they're missing, which is the case for legacy devices.
+    pub fn new(platform: P, pci: &impl PciFunction) -> Option<Self> {
+        let (common, _) = find_region(&platform, pci, CAP_COMMON)?;
+        let (notify, notify_cap) = find_region(&platform, pci, CAP_NOTIFY)?;
+        let (isr, _)    = find_region(&platform, pci, CAP_ISR)?;
+        let device      = find_region(&platform, pci, CAP_DEVICE)
+            .map(|(device, _)| device);
+
+        if common.len < COMMON_SIZE || isr.len < 1 {
+            return None;
+        }
+
+        Some(Self {
+            notify_multiplier: pci.read32(notify_cap as u16 + 16),
+            platform,
+            common: common.ptr,
+            notify,
+            isr: isr.ptr,
+            device,
+            features: 0,
+        })
+    }
+
+    /// Read the `T` at `offset` in the common configuration
+    fn common_read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.common.add(offset) as *const T) }
+    }
+
+    /// Write `val` at `offset` in the common configuration
+    fn common_write<T: Copy>(&self, offset: usize, val: T) {
+        unsafe { ptr::write_volatile(self.common.add(offset) as *mut T, val); }
+    }
+
+    /// Returns the device status
+    pub fn status(&self) -> u8 {
+        self.common_read(COMMON_DEVICE_STATUS)
+    }
+
+    /// Add the bits of `status` to the device status
+    fn add_status(&self, status: u8) {
+        self.common_write(COMMON_DEVICE_STATUS, self.status() | status);
+    }
+
+    /// Mark the device failed, after it broke one of its queues. Returns
+    /// whether it was already marked.
+    pub fn fail(&self) -> bool {
+        let failed = self.status() & STATUS_FAILED != 0;
+        self.add_status(STATUS_FAILED);
+        failed
+    }
+
+    /// Reset the device and wait for the reset to complete. All the queues
+    /// are forgotten, and the memory given to them can be reused.
+    pub fn reset(&mut self) {
+        self.common_write(COMMON_DEVICE_STATUS, 0u8);
+        while self.status() != 0 {
+            spin_loop();
+        }
+        self.features = 0;
+    }
+
+    /// Returns the features the device offers
+    pub fn device_features(&self) -> u64 {
+        let mut features = 0;
+        for select in 0..2u32 {
+            self.common_write(COMMON_DEVICE_FEATURE_SELECT, select);
+            let bits: u32 = self.common_read(COMMON_DEVICE_FEATURE);
+            features |= (bits as u64) << (32 * select);
+        }
+        features
+    }
+
+    /// Reset the device and negotiate the features of `wanted` the device
+    /// offers, along with `F_VERSION_1`. Returns the features negotiated, or
+    /// `None` if the device refused them, in which case it's marked failed.
+    /// The queues are set up next, then `driver_ok()` starts the device.
+    pub fn init(&mut self, wanted: u64) -> Option<u64> {
+        self.reset();
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        // Only version 1 devices are supported
+        let offered = self.device_features();
+        if offered & F_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return None;
+        }
+        let features = (offered & wanted) | F_VERSION_1;
+
+        for select in 0..2u32 {
+            self.common_write(COMMON_DRIVER_FEATURE_SELECT, select);
+            self.common_write(COMMON_DRIVER_FEATURE,
+                              (features >> (32 * select)) as u32);
+        }
+
+        // The device confirms the features by keeping the status bit
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return None;
+        }
+
+        self.features = features;
+        Some(features)
+    }
+
+    /// Returns the features negotiated by `init()`
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Returns the number of queues of the device
+    pub fn num_queues(&self) -> u16 {
+        self.common_read(COMMON_NUM_QUEUES)
+    }
+
+    /// Set up queue `index` with at most `max_size` descriptors and enable
+    /// it. `None` if the queue doesn't exist or its memory can't be
+    /// allocated.
+    pub fn setup_queue(&mut self, index: u16, max_size: u16)
+            -> Option<Virtqueue> {
+        if index >= self.num_queues() {
+            return None;
+        }
+        self.common_write(COMMON_QUEUE_SELECT, index);
+
+        // The device sets the largest size it supports, a size of 0 means
+        // the queue isn't available
+        let device_max: u16 = self.common_read(COMMON_QUEUE_SIZE);
+        let size = device_max.min(max_size).min(crate::queue::MAX_QUEUE_SIZE);
+        if size == 0 {
+            return None;
+        }
+        let size = 1 << (15 - size.leading_zeros());
+
+        // Find the notify register of the queue
+        let notify_off: u16 = self.common_read(COMMON_QUEUE_NOTIFY_OFF);
+        let notify_off = notify_off as usize * self.notify_multiplier as usize;
+        if notify_off + 2 > self.notify.len {
+            return None;
+        }
+        let notify = unsafe { self.notify.ptr.add(notify_off) as *mut u16 };
+
+        let queue = Virtqueue::new(&self.platform, index, size, notify)?;
+        let (desc, driver, device) = queue.addresses();
+        self.common_write(COMMON_QUEUE_SIZE, size);
+        self.common_write(COMMON_QUEUE_DESC, desc);
+        self.common_write(COMMON_QUEUE_DRIVER, driver);
+        self.common_write(COMMON_QUEUE_DEVICE, device);
+        self.common_write(COMMON_QUEUE_ENABLE, 1u16);
+
+        Some(queue)
+    }
+
+    /// Signal queue `index` through MSI-X `entry`, or `NO_VECTOR` for none.
+    /// Returns whether the device accepted the entry.
+    pub fn set_queue_vector(&self, index: u16, entry: u16) -> bool {
+        self.common_write(COMMON_QUEUE_SELECT, index);
+        self.common_write(COMMON_QUEUE_MSIX_VECTOR, entry);
+        self.common_read::<u16>(COMMON_QUEUE_MSIX_VECTOR) == entry
+    }
+
+    /// Signal configuration changes through MSI-X `entry`, or `NO_VECTOR`
+    /// for none. Returns whether the device accepted the entry.
+    pub fn set_config_vector(&self, entry: u16) -> bool {
+        self.common_write(COMMON_MSIX_CONFIG, entry);
+        self.common_read::<u16>(COMMON_MSIX_CONFIG) == entry
+    }
+
+    /// Tell the device the driver is ready, after the queues are set up
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Read and acknowledge the ISR status, for INTx interrupts. Bit 0 is set
+    /// for queue interrupts and bit 1 for configuration changes.
+    pub fn isr_status(&self) -> u8 {
+        unsafe { ptr::read_volatile(self.isr) }
+    }
+
+    /// Returns the configuration generation, which changes when the device
+    /// configuration changes
+    pub fn config_generation(&self) -> u8 {
+        self.common_read(COMMON_CONFIG_GENERATION)
+    }
+
+    /// Read the `T` at `offset` in the device configuration. `None` if
+    /// there's no device configuration or it's too short.
+    pub fn config_read<T: Copy>(&self, offset: usize) -> Option<T> {
+        let device = self.device?;
+        if offset.checked_add(core::mem::size_of::<T>())? > device.len {
+            return None;
+        }
+
+        Some(unsafe {
+            ptr::read_volatile(device.ptr.add(offset) as *const T)
+        })
+    }
+
+    /// Write `val` at `offset` in the device configuration. Returns whether
+    /// there's a device configuration to write to.
+    pub fn config_write<T: Copy>(&self, offset: usize, val: T) -> bool {
+        let Some(device) = self.device else { return false; };
+        match offset.checked_add(core::mem::size_of::<T>()) {
+            Some(end) if end <= device.len => {
+                unsafe {
+                    ptr::write_volatile(device.ptr.add(offset) as *mut T, val);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the platform the device is driven from
+    pub fn platform(&self) -> &P {
+        &self.platform
+    }
+}
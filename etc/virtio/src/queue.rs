@@ -0,0 +1,446 @@
+//! Split virtqueues.
+//!
+//! A queue is a descriptor table, a driver (available) ring and a device
+//! (used) ring in one DMA allocation. Buffers are handed to the device as
+//! chains of descriptors, and come back on the used ring with the number of
+//! bytes the device wrote to them.
+
+use core::ptr;
+use core::sync::atomic::{ fence, Ordering };
+use crate::{ Dma, Platform };
+
+/// Largest queue size supported
+pub const MAX_QUEUE_SIZE: u16 = 1024;
+
+/// Size of a descriptor
+const DESC_SIZE: usize = 16;
+
+/// The descriptor continues in the one in its `next` field
+const DESC_F_NEXT: u16 = 1 << 0;
+
+/// The buffer of the descriptor is written by the device
+const DESC_F_WRITE: u16 = 1 << 1;
+
+/// Size of an element of the used ring
+const USED_ELEM_SIZE: usize = 8;
+
+/// A buffer in a descriptor chain
+#[derive(Clone, Copy, Debug)]
+pub struct Buffer {
+    /// Physical address of the buffer
+    pub addr: u64,
+
+    /// Size of the buffer in bytes
+    pub len: u32,
+
+    /// Whether the device writes the buffer instead of reading it
+    pub writable: bool,
+}
+
+/// A split virtqueue
+pub struct Virtqueue {
+    /// Index of the queue on its device
+    index: u16,
+
+    /// Number of descriptors
+    size: u16,
+
+    /// The memory of the queue
+    dma: Dma,
+
+    /// Offsets of the driver and device rings in the memory
+    avail: usize,
+    used:  usize,
+
+    /// Pointer to the notify register of the queue
+    notify: *mut u16,
+
+    /// First descriptor of the free list, chained through `next`
+    free_head: u16,
+
+    /// Number of free descriptors
+    num_free: u16,
+
+    /// Next index to be filled in the driver ring
+    avail_idx: u16,
+
+    /// Next index to be read from the device ring
+    last_used: u16,
+
+    /// Number of descriptors of every chain the device holds, indexed by
+    /// chain ID. 0 for descriptors which aren't the head of such a chain.
+    chain_len: [u16; MAX_QUEUE_SIZE as usize],
+
+    /// Whether the device returned a chain it didn't hold
+    broken: bool,
+}
+
+// The raw pointers are to memory owned by the queue
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// Returns the number of bytes and the offsets of the driver and device
+    /// rings of a queue of `size` descriptors
+    fn layout(size: u16) -> (usize, usize, usize) {
+        let size  = size as usize;
+        let avail = size * DESC_SIZE;
+        let used  = (avail + 6 + 2 * size).next_multiple_of(4);
+        (used + 6 + USED_ELEM_SIZE * size, avail, used)
+    }
+
+    /// Allocate a queue of `size` descriptors for queue `index`, notified
+    /// through `notify`. `size` has to be a power of two.
+    pub(crate) fn new<P: Platform>(platform: &P, index: u16, size: u16,
+                                   notify: *mut u16) -> Option<Self> {
+        if size == 0 || !size.is_power_of_two() || size > MAX_QUEUE_SIZE {
+            return None;
+        }
+
+        let (bytes, avail, used) = Self::layout(size);
+        let dma = platform.alloc_dma(bytes, 4096)?;
+        let mut queue = Self {
+            index,
+            size,
+            dma,
+            avail,
+            used,
+            notify,
+            free_head: 0,
+            num_free:  size,
+            avail_idx: 0,
+            last_used: 0,
+            chain_len: [0; MAX_QUEUE_SIZE as usize],
+            broken:    false,
+        };
+
+        // Chain all the descriptors into the free list
+        for desc in 0..size - 1 {
+            queue.write_desc(desc, 0, 0, 0, desc + 1);
+        }
+
+        Some(queue)
+    }
+
+    /// Returns the index of the queue on its device
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Returns the number of descriptors of the queue
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the number of free descriptors
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    /// Returns whether the device returned a chain it didn't hold. Nothing
+    /// is taken off a broken queue anymore, the device has to be reset.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Returns the physical addresses of the descriptor table, the driver
+    /// ring and the device ring
+    pub(crate) fn addresses(&self) -> (u64, u64, u64) {
+        (self.dma.phys,
+         self.dma.phys + self.avail as u64,
+         self.dma.phys + self.used as u64)
+    }
+
+    /// Write the 16-bit value `val` at `offset` in the queue memory
+    fn write16(&self, offset: usize, val: u16) {
+        unsafe {
+            ptr::write_volatile(self.dma.ptr.add(offset) as *mut u16, val);
+        }
+    }
+
+    /// Read the 16-bit value at `offset` in the queue memory
+    fn read16(&self, offset: usize) -> u16 {
+        unsafe { ptr::read_volatile(self.dma.ptr.add(offset) as *const u16) }
+    }
+
+    /// Read the 32-bit value at `offset` in the queue memory
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.dma.ptr.add(offset) as *const u32) }
+    }
+
+    /// Fill in descriptor `desc`
+    fn write_desc(&mut self, desc: u16, addr: u64, len: u32, flags: u16,
+                  next: u16) {
+        let offset = desc as usize * DESC_SIZE;
+        unsafe {
+            let desc = self.dma.ptr.add(offset);
+            ptr::write_volatile(desc as *mut u64, addr);
+            ptr::write_volatile(desc.add(8) as *mut u32, len);
+            ptr::write_volatile(desc.add(12) as *mut u16, flags);
+            ptr::write_volatile(desc.add(14) as *mut u16, next);
+        }
+    }
+
+    /// Put the chain of `buffers` on the driver ring. Returns the ID of the
+    /// chain, which comes back from `pop_used()` once the device is done
+    /// with it, or `None` if there aren't enough free descriptors. The
+    /// device isn't notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free as usize {
+            return None;
+        }
+
+        // Take the descriptors off the free list, keeping the chain in place
+        let head = self.free_head;
+        let mut desc = head;
+        for (idx, buffer) in buffers.iter().enumerate() {
+            let next  = self.read16(desc as usize * DESC_SIZE + 14);
+            let last  = idx == buffers.len() - 1;
+            let flags = if last { 0 } else { DESC_F_NEXT } |
+                if buffer.writable { DESC_F_WRITE } else { 0 };
+            self.write_desc(desc, buffer.addr, buffer.len, flags, next);
+
+            if last {
+                self.free_head = next;
+            }
+            desc = next;
+        }
+        self.num_free -= buffers.len() as u16;
+        self.chain_len[head as usize] = buffers.len() as u16;
+
+        // Publish the chain once the descriptors are visible
+        let slot = self.avail_idx % self.size;
+        self.write16(self.avail + 4 + 2 * slot as usize, head);
+        fence(Ordering::Release);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.write16(self.avail + 2, self.avail_idx);
+
+        Some(head)
+    }
+
+    /// Tell the device there are new buffers on the driver ring
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(self.notify, self.index); }
+    }
+
+    /// Returns whether the device returned chains which haven't been popped
+    pub fn has_used(&self) -> bool {
+        self.read16(self.used + 2) != self.last_used
+    }
+
+    /// Take the next chain the device is done with off the device ring and
+    /// free its descriptors. Returns the ID of the chain and the number of
+    /// bytes the device wrote to it, or `None` if there is no such chain or
+    /// the queue is broken.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if self.broken || !self.has_used() {
+            return None;
+        }
+        fence(Ordering::Acquire);
+
+        let slot = self.last_used % self.size;
+        let elem = self.used + 4 + USED_ELEM_SIZE * slot as usize;
+        let head = self.read32(elem);
+        let len  = self.read32(elem + 4);
+
+        // A device returning a descriptor which doesn't exist, a free one or
+        // a chain twice is broken. Freeing the descriptors again would
+        // corrupt the free list, so give up on the queue.
+        let freed = self.chain_len.get(head as usize).copied().unwrap_or(0);
+        if head >= self.size as u32 || freed == 0 {
+            self.broken = true;
+            return None;
+        }
+        let head = head as u16;
+        self.last_used = self.last_used.wrapping_add(1);
+        self.chain_len[head as usize] = 0;
+
+        // Put the chain back on the free list
+        let mut desc = head;
+        for _ in 1..freed {
+            desc = self.read16(desc as usize * DESC_SIZE + 14);
+        }
+        let free_head = self.free_head;
+        self.write16(desc as usize * DESC_SIZE + 14, free_head);
+        self.free_head = head;
+        self.num_free += freed;
+
+        Some((head, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+    use std::alloc::{ alloc_zeroed, Layout };
+    use super::*;
+
+    /// A platform whose physical addresses are the host addresses. The
+    /// memory is never freed.
+    struct HostPlatform;
+
+    impl Platform for HostPlatform {
+        fn alloc_dma(&self, size: usize, align: usize) -> Option<Dma> {
+            let layout = Layout::from_size_align(size, align).ok()?;
+            let ptr = unsafe { alloc_zeroed(layout) };
+            (!ptr.is_null()).then_some(Dma { phys: ptr as u64, ptr, size })
+        }
+
+        fn map_mmio(&self, _phys: u64, _size: usize) -> Option<*mut u8> {
+            None
+        }
+    }
+
+    /// Returns a queue of `size` descriptors notified through `notify`
+    fn queue(size: u16, notify: &mut u16) -> Virtqueue {
+        Virtqueue::new(&HostPlatform, 0, size, notify).unwrap()
+    }
+
+    /// Returns a device-readable buffer of `len` bytes at `addr`
+    fn buffer(addr: u64, len: u32) -> Buffer {
+        Buffer { addr, len, writable: false }
+    }
+
+    /// Return the chain `head` to the driver like the device would, with
+    /// `len` bytes written to it
+    fn complete(queue: &mut Virtqueue, head: u16, len: u32) {
+        let idx  = queue.read16(queue.used + 2);
+        let elem = queue.used + 4 +
+            USED_ELEM_SIZE * (idx % queue.size) as usize;
+        unsafe {
+            let ptr = queue.dma.ptr;
+            ptr::write_volatile(ptr.add(elem) as *mut u32, head as u32);
+            ptr::write_volatile(ptr.add(elem + 4) as *mut u32, len);
+        }
+        queue.write16(queue.used + 2, idx.wrapping_add(1));
+    }
+
+    /// Returns the (addr, len, flags, next) of descriptor `desc`
+    fn desc(queue: &Virtqueue, desc: u16) -> (u64, u32, u16, u16) {
+        let offset = desc as usize * DESC_SIZE;
+        unsafe {
+            let desc = queue.dma.ptr.add(offset);
+            (ptr::read_volatile(desc as *const u64),
+             ptr::read_volatile(desc.add(8) as *const u32),
+             ptr::read_volatile(desc.add(12) as *const u16),
+             ptr::read_volatile(desc.add(14) as *const u16))
+        }
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        let mut notify = 0;
+        for size in [0, 3, MAX_QUEUE_SIZE * 2] {
+            assert!(Virtqueue::new(&HostPlatform, 0, size, &mut notify)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn add_and_pop() {
+        let mut notify = 0;
+        let mut queue = queue(8, &mut notify);
+
+        let writable = Buffer { addr: 0x2000, len: 64, writable: true };
+        let head = queue.add(&[buffer(0x1000, 12), writable]).unwrap();
+        assert_eq!(queue.num_free(), 6);
+
+        // The chain is on the driver ring and linked through NEXT
+        assert_eq!(queue.read16(queue.avail + 2), 1);
+        assert_eq!(queue.read16(queue.avail + 4), head);
+        let (addr, len, flags, next) = desc(&queue, head);
+        assert_eq!((addr, len, flags), (0x1000, 12, DESC_F_NEXT));
+        assert_eq!(desc(&queue, next), (0x2000, 64, DESC_F_WRITE, next + 1));
+
+        // Nothing comes back until the device returns the chain
+        assert!(!queue.has_used());
+        assert_eq!(queue.pop_used(), None);
+
+        complete(&mut queue, head, 40);
+        assert_eq!(queue.pop_used(), Some((head, 40)));
+        assert_eq!(queue.pop_used(), None);
+        assert_eq!(queue.num_free(), 8);
+
+        queue.notify();
+        assert_eq!(notify, queue.index());
+    }
+
+    #[test]
+    fn reuses_freed_descriptors() {
+        let mut notify = 0;
+        let mut queue = queue(4, &mut notify);
+
+        // Fill the queue with single descriptor chains
+        let heads: Vec<u16> = (0..4)
+            .map(|n| queue.add(&[buffer(0x1000 * n, 16)]).unwrap())
+            .collect();
+        assert_eq!(queue.num_free(), 0);
+        assert_eq!(queue.add(&[buffer(0x9000, 16)]), None);
+
+        // Chains come back out of order, their descriptors are reused
+        complete(&mut queue, heads[2], 0);
+        complete(&mut queue, heads[0], 0);
+        assert_eq!(queue.pop_used(), Some((heads[2], 0)));
+        assert_eq!(queue.pop_used(), Some((heads[0], 0)));
+        assert_eq!(queue.num_free(), 2);
+
+        let reused = queue.add(&[buffer(0xa000, 16), buffer(0xb000, 16)])
+            .unwrap();
+        assert!(reused == heads[0] || reused == heads[2]);
+        assert_eq!(queue.num_free(), 0);
+
+        // The driver ring wraps around
+        complete(&mut queue, reused, 32);
+        assert_eq!(queue.pop_used(), Some((reused, 32)));
+        assert_eq!(queue.num_free(), 2);
+        assert_eq!(queue.read16(queue.avail + 2), 5);
+    }
+
+    #[test]
+    fn rejects_bad_used_head() {
+        let mut notify = 0;
+        let mut queue = queue(4, &mut notify);
+        queue.add(&[buffer(0x1000, 16)]).unwrap();
+
+        complete(&mut queue, 4, 0);
+        assert_eq!(queue.pop_used(), None);
+        assert_eq!(queue.num_free(), 3);
+        assert!(queue.is_broken());
+    }
+
+    #[test]
+    fn rejects_double_returned_chain() {
+        let mut notify = 0;
+        let mut queue = queue(4, &mut notify);
+        let head = queue.add(&[buffer(0x1000, 16), buffer(0x2000, 16)])
+            .unwrap();
+        let other = queue.add(&[buffer(0x3000, 16)]).unwrap();
+
+        // A chain returned twice is only freed once
+        complete(&mut queue, head, 0);
+        complete(&mut queue, head, 0);
+        assert_eq!(queue.pop_used(), Some((head, 0)));
+        assert_eq!(queue.num_free(), 3);
+        assert_eq!(queue.pop_used(), None);
+        assert!(queue.is_broken());
+
+        // Nothing is taken off the queue anymore
+        complete(&mut queue, other, 0);
+        assert_eq!(queue.pop_used(), None);
+        assert_eq!(queue.num_free(), 3);
+    }
+
+    #[test]
+    fn rejects_free_descriptor() {
+        let mut notify = 0;
+        let mut queue = queue(4, &mut notify);
+        queue.add(&[buffer(0x1000, 16)]).unwrap();
+
+        complete(&mut queue, 2, 0);
+        assert_eq!(queue.pop_used(), None);
+        assert_eq!(queue.num_free(), 3);
+        assert!(queue.is_broken());
+    }
+}
@@ -0,0 +1,61 @@
+//! Access to the ACPI tables of the system.
+//!
+//! The RSDP is handed over by the bootloader. If it didn't find one, the EBDA
+//! and the BIOS ROM are scanned again.
+
+use core::sync::atomic::{ AtomicU64, Ordering };
+use acpi::{ Acpi, PhysMem };
+use crate::boot_kern;
+use crate::mm::{ self, PhysAddr };
+
+/// Physical address of the RSDP, 0 until `init()` finds it
+static RSDP: AtomicU64 = AtomicU64::new(0);
+
+/// Access to physical memory through the identity map set up by the
+/// bootloader
+#[derive(Clone, Copy)]
+pub struct PhysicalMemory;
+
+impl PhysMem<'static> for PhysicalMemory {
+    fn bytes(&self, addr: u64, len: usize) -> Option<&'static [u8]> {
+        addr.checked_add(len as u64)?;
+
+        Some(unsafe {
+            core::slice::from_raw_parts(mm::phys_ptr(PhysAddr(addr)), len)
+        })
+    }
+}
+
+/// Find the RSDP and print the tables it points to
+pub fn init() {
+    let Some(rsdp) = boot_kern().rsdp()
+            .or_else(|| acpi::find_rsdp(&PhysicalMemory)) else {
+        print!("ACPI: no RSDP found\n");
+        return;
+    };
+    RSDP.store(rsdp, Ordering::Release);
+
+    // Print the tables
+    let Some(tables) = tables() else {
+        print!("ACPI: invalid root table at RSDP {:#x}\n", rsdp);
+        return;
+    };
+
+    print!("ACPI: revision {} RSDP at {:#x}, tables",
+           tables.rsdp.revision, rsdp);
+    for table in tables.tables() {
+        let signature = table.signature();
+        print!(" {}", core::str::from_utf8(&signature).unwrap_or("????"));
+    }
+    print!("\n");
+}
+
+/// Returns the ACPI tables, if the RSDP was found
+pub fn tables() -> Option<Acpi<'static, PhysicalMemory>> {
+    let rsdp = RSDP.load(Ordering::Acquire);
+    if rsdp == 0 {
+        return None;
+    }
+
+    Acpi::new(PhysicalMemory, rsdp)
+}
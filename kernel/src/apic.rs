@@ -0,0 +1,481 @@
+//! Local APIC driver.
+//!
+//! The x2APIC is used whenever the CPU supports it, with its registers
+//! accessed through MSRs. Otherwise the xAPIC registers are accessed through
+//! an uncacheable mapping. The legacy 8259 PICs are masked, all interrupts go
+//! through the APICs.
+
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use cpu::{ Port, InterruptGuard };
+use time::{ Duration, Instant };
+use crate::{ hpet, core_locals };
+use crate::interrupts::{ self, InterruptState };
+use crate::mm::{ self, PhysAddr };
+
+/// Mask of the physical address in the APIC base register
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Size of the xAPIC register page
+const XAPIC_SIZE: u64 = 4096;
+
+/// MSR of the first x2APIC register. The MSR of a register is its xAPIC
+/// offset divided by 16 added to this.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Local APIC ID register
+const REG_ID: u32 = 0x20;
+
+/// Task priority register
+const REG_TPR: u32 = 0x80;
+
+/// End of interrupt register
+const REG_EOI: u32 = 0xb0;
+
+/// Spurious interrupt vector register
+const REG_SVR: u32 = 0xf0;
+
+/// Error status register
+const REG_ESR: u32 = 0x280;
+
+/// Interrupt command register, the whole 64 bits in x2APIC mode
+const REG_ICR_LOW: u32 = 0x300;
+
+/// High half of the interrupt command register in xAPIC mode
+const REG_ICR_HIGH: u32 = 0x310;
+
+/// Local vector table entry of the timer
+const REG_LVT_TIMER: u32 = 0x320;
+
+/// Local vector table entry of the LINT0 pin
+const REG_LVT_LINT0: u32 = 0x350;
+
+/// Local vector table entry of the LINT1 pin
+const REG_LVT_LINT1: u32 = 0x360;
+
+/// Local vector table entry of APIC errors
+const REG_LVT_ERROR: u32 = 0x370;
+
+/// Initial count register of the timer
+const REG_TIMER_INITIAL: u32 = 0x380;
+
+/// Current count register of the timer
+const REG_TIMER_CURRENT: u32 = 0x390;
+
+/// Divide configuration register of the timer
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+/// Software enable bit in the spurious interrupt vector register
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// Masked bit of a local vector table entry
+const LVT_MASKED: u32 = 1 << 16;
+
+/// Periodic mode bit of the timer local vector table entry
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// NMI delivery mode of a local vector table entry
+const LVT_NMI: u32 = 0b100 << 8;
+
+/// Timer divide configuration dividing the bus clock by 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// Delivery modes of an IPI
+const ICR_FIXED:   u32 = 0b000 << 8;
+const ICR_NMI:     u32 = 0b100 << 8;
+const ICR_INIT:    u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+
+/// The xAPIC is still sending the last IPI
+const ICR_PENDING: u32 = 1 << 12;
+
+/// Assert level of an IPI, always set except for the INIT level de-assert
+const ICR_ASSERT: u32 = 1 << 14;
+
+/// Destination shorthands of an IPI
+const ICR_SELF:   u32 = 0b01 << 18;
+const ICR_ALL:    u32 = 0b10 << 18;
+const ICR_OTHERS: u32 = 0b11 << 18;
+
+/// Vector of spurious APIC interrupts, which must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Vector of APIC error interrupts
+pub const ERROR_VECTOR: u8 = 0xfe;
+
+/// Vector of the APIC timer interrupts
+pub const TIMER_VECTOR: u8 = 0xfd;
+
+/// Command and data ports of the master and slave 8259 PICs
+const PIC1_COMMAND: Port<u8> = Port::new(0x20);
+const PIC1_DATA:    Port<u8> = Port::new(0x21);
+const PIC2_COMMAND: Port<u8> = Port::new(0xa0);
+const PIC2_DATA:    Port<u8> = Port::new(0xa1);
+
+/// Vector the PICs are remapped to, so that their spurious interrupts don't
+/// look like exceptions
+const PIC_VECTOR_BASE: u8 = 0x20;
+
+/// Duration of the timer calibration
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+/// Delay of the one-shot fired by `self_test()`
+const TEST_DELAY: Duration = Duration::from_millis(1);
+
+/// Longest time `self_test()` waits for an interrupt
+const TEST_DEADLINE: Duration = Duration::from_millis(50);
+
+/// Whether the APICs are used in x2APIC mode
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+/// Virtual address of the xAPIC registers, 0 in x2APIC mode
+static MMIO: AtomicU64 = AtomicU64::new(0);
+
+/// Timer ticks per millisecond with the timer divided by 16, 0 until the
+/// timer is calibrated
+static TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the timer keeps running in deep C-states. If it doesn't,
+/// one-shots are fired by the HPET instead when possible.
+static TIMER_ALWAYS_RUNNING: AtomicBool = AtomicBool::new(false);
+
+core_local! {
+    /// Number of timer interrupts received by the cores
+    static TIMER_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Number of IPIs received by `self_test()`
+static TEST_IPIS: AtomicU64 = AtomicU64::new(0);
+
+/// Destinations of an IPI
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpiDest {
+    /// The core with the given APIC ID
+    Apic(u32),
+
+    /// The current core
+    Current,
+
+    /// All the cores, including the current one
+    All,
+
+    /// All the cores, except the current one
+    Others,
+}
+
+/// Modes of the APIC timer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Fire once
+    OneShot,
+
+    /// Fire repeatedly
+    Periodic,
+}
+
+/// Read the APIC register `reg`
+unsafe fn read(reg: u32) -> u32 {
+    if X2APIC.load(Ordering::Relaxed) {
+        cpu::rdmsr(X2APIC_MSR_BASE + reg / 16) as u32
+    } else {
+        let mmio = MMIO.load(Ordering::Relaxed) as *const u32;
+        ptr::read_volatile(mmio.add(reg as usize / 4))
+    }
+}
+
+/// Write `val` to the APIC register `reg`
+unsafe fn write(reg: u32, val: u32) {
+    if X2APIC.load(Ordering::Relaxed) {
+        cpu::wrmsr(X2APIC_MSR_BASE + reg / 16, val as u64);
+    } else {
+        let mmio = MMIO.load(Ordering::Relaxed) as *mut u32;
+        ptr::write_volatile(mmio.add(reg as usize / 4), val);
+    }
+}
+
+/// Remap the 8259 PICs away from the exception vectors and mask all of their
+/// interrupts
+fn disable_pic() {
+    unsafe {
+        // Start the initialization sequence in cascade mode
+        PIC1_COMMAND.write(0x11);
+        PIC2_COMMAND.write(0x11);
+
+        // Set the vector offsets
+        PIC1_DATA.write(PIC_VECTOR_BASE);
+        PIC2_DATA.write(PIC_VECTOR_BASE + 8);
+
+        // Tell the master about the slave on IRQ 2 and the slave its identity
+        PIC1_DATA.write(1 << 2);
+        PIC2_DATA.write(2);
+
+        // 8086 mode
+        PIC1_DATA.write(0x01);
+        PIC2_DATA.write(0x01);
+
+        // Mask all the interrupts
+        PIC1_DATA.write(0xff);
+        PIC2_DATA.write(0xff);
+    }
+}
+
+/// Measure the number of timer ticks per millisecond against the TSC
+fn calibrate() -> u64 {
+    let _interrupts = InterruptGuard::new();
+
+    unsafe {
+        // Start the timer from the highest count
+        write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        write(REG_TIMER_INITIAL, u32::MAX);
+
+        time::sleep(CALIBRATION_TIME);
+
+        // Stop the timer
+        let elapsed = u32::MAX - read(REG_TIMER_CURRENT);
+        write(REG_TIMER_INITIAL, 0);
+
+        elapsed as u64 / CALIBRATION_TIME.as_millis() as u64
+    }
+}
+
+/// Print and clear the APIC errors
+fn error_handler(_state: &mut InterruptState) {
+    // The error status register has to be written before it's read
+    let errors = unsafe {
+        write(REG_ESR, 0);
+        read(REG_ESR)
+    };
+
+    print!("APIC error {:#x} on APIC {}\n", errors, id());
+}
+
+/// Count a timer interrupt of the current core
+fn timer_handler(_state: &mut InterruptState) {
+    TIMER_INTERRUPTS.get().fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count an IPI sent by `self_test()`
+fn test_ipi_handler(_state: &mut InterruptState) {
+    TEST_IPIS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Set up the local APICs, mask the legacy PICs and calibrate the timer.
+/// The APIC of the current core is enabled.
+pub fn init() {
+    assert!(cpu::has_apic(), "The CPU doesn't have a local APIC.");
+
+    // Only use the APICs from now on
+    disable_pic();
+
+    // Prefer x2APIC mode, otherwise map the xAPIC registers
+    if cpu::has_x2apic() {
+        X2APIC.store(true, Ordering::SeqCst);
+    } else {
+        let base = unsafe { cpu::rdmsr(cpu::IA32_APIC_BASE) } &
+            APIC_BASE_ADDR_MASK;
+        let mmio = mm::map_mmio(PhysAddr(base), XAPIC_SIZE)
+            .expect("Couldn't map the xAPIC registers.");
+        MMIO.store(mmio.0, Ordering::SeqCst);
+    }
+
+    interrupts::register(ERROR_VECTOR, error_handler);
+    interrupts::register(TIMER_VECTOR, timer_handler);
+    init_core();
+
+    // Calibrate the timer, assuming it runs at the same rate on all cores
+    let ticks = calibrate();
+    TICKS_PER_MS.store(ticks, Ordering::SeqCst);
+    let always_running = cpu::has_always_running_apic_timer();
+    TIMER_ALWAYS_RUNNING.store(always_running, Ordering::SeqCst);
+
+    print!("APIC: {} mode, timer at {} ticks/ms{}\n",
+           if X2APIC.load(Ordering::SeqCst) { "x2APIC" } else { "xAPIC" },
+           ticks, if always_running { "" } else { ", stops when idle" });
+}
+
+/// Enable the local APIC of the current core. `init()` must have been called
+/// on the bootstrap processor.
+pub fn init_core() {
+    unsafe {
+        // Enable the APIC, switching to x2APIC mode has to be done after
+        let mut base = cpu::rdmsr(cpu::IA32_APIC_BASE) | cpu::APIC_BASE_ENABLE;
+        cpu::wrmsr(cpu::IA32_APIC_BASE, base);
+        if X2APIC.load(Ordering::SeqCst) {
+            base |= cpu::APIC_BASE_EXTD;
+            cpu::wrmsr(cpu::IA32_APIC_BASE, base);
+        }
+
+        // Accept all interrupts and software enable the APIC
+        write(REG_TPR, 0);
+        write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+        // LINT0 carried the PIC interrupts, LINT1 is wired to NMI
+        write(REG_LVT_LINT0, LVT_MASKED);
+        write(REG_LVT_LINT1, LVT_NMI);
+
+        // Report errors, clearing the old ones
+        write(REG_LVT_ERROR, ERROR_VECTOR as u32);
+        write(REG_ESR, 0);
+        write(REG_ESR, 0);
+
+        // Stop the timer
+        write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+        write(REG_TIMER_INITIAL, 0);
+    }
+}
+
+/// Returns the APIC ID of the current core
+pub fn id() -> u32 {
+    let id = unsafe { read(REG_ID) };
+    if X2APIC.load(Ordering::Relaxed) { id } else { id >> 24 }
+}
+
+/// Signal the end of the interrupt being handled on the current core
+pub fn eoi() {
+    unsafe { write(REG_EOI, 0); }
+}
+
+/// Returns the number of timer ticks in `micros` microseconds
+fn ticks(micros: u64) -> u32 {
+    let ticks_per_ms = TICKS_PER_MS.load(Ordering::Relaxed);
+    assert!(ticks_per_ms != 0, "The APIC timer isn't calibrated.");
+
+    (ticks_per_ms.saturating_mul(micros) / 1000).clamp(1, u32::MAX as u64)
+        as u32
+}
+
+/// Start the timer of the current core to fire `TIMER_VECTOR` after `micros`
+/// microseconds, once or periodically. If the timer stops in deep C-states,
+/// one-shots are fired by the HPET timer of the core, if it has one.
+pub fn timer_start(mode: TimerMode, micros: u64) {
+    if mode == TimerMode::OneShot &&
+            !TIMER_ALWAYS_RUNNING.load(Ordering::Relaxed) {
+        let timer = core_locals::current().id();
+        let delay = Duration::from_micros(micros);
+        if hpet::oneshot(timer, delay, TIMER_VECTOR).is_some() {
+            // Don't leave a periodic APIC timer running
+            unsafe {
+                write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+                write(REG_TIMER_INITIAL, 0);
+            }
+            return;
+        }
+    }
+
+    let ticks = ticks(micros);
+    let periodic = match mode {
+        TimerMode::OneShot  => 0,
+        TimerMode::Periodic => LVT_TIMER_PERIODIC,
+    };
+
+    unsafe {
+        write(REG_LVT_TIMER, periodic | TIMER_VECTOR as u32);
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        write(REG_TIMER_INITIAL, ticks);
+    }
+}
+
+/// Stop the timer of the current core, and its HPET timer if it's used
+pub fn timer_stop() {
+    unsafe {
+        write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+        write(REG_TIMER_INITIAL, 0);
+    }
+
+    if !TIMER_ALWAYS_RUNNING.load(Ordering::Relaxed) {
+        hpet::cancel(core_locals::current().id());
+    }
+}
+
+/// Returns the number of timer interrupts the current core received
+pub fn timer_interrupts() -> u64 {
+    TIMER_INTERRUPTS.get().load(Ordering::Relaxed)
+}
+
+/// Write the interrupt command `command` for `dest` and wait for it to be
+/// sent
+unsafe fn send_command(dest: IpiDest, command: u32) {
+    let (apic_id, shorthand) = match dest {
+        IpiDest::Apic(apic_id) => (apic_id, 0),
+        IpiDest::Current       => (0, ICR_SELF),
+        IpiDest::All           => (0, ICR_ALL),
+        IpiDest::Others        => (0, ICR_OTHERS),
+    };
+    let command = command | shorthand;
+
+    // The xAPIC command is written in two halves
+    let _interrupts = InterruptGuard::new();
+    if X2APIC.load(Ordering::Relaxed) {
+        cpu::wrmsr(X2APIC_MSR_BASE + REG_ICR_LOW / 16,
+                   (apic_id as u64) << 32 | command as u64);
+    } else {
+        write(REG_ICR_HIGH, apic_id << 24);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_PENDING != 0 {
+            spin_loop();
+        }
+    }
+}
+
+/// Send an interrupt with `vector` to `dest`
+pub fn send_ipi(dest: IpiDest, vector: u8) {
+    assert!(vector as usize >= interrupts::NUM_EXCEPTIONS,
+        "Can't send an exception as an IPI.");
+    unsafe { send_command(dest, ICR_FIXED | ICR_ASSERT | vector as u32); }
+}
+
+/// Send an NMI to `dest`
+pub fn send_nmi(dest: IpiDest) {
+    unsafe { send_command(dest, ICR_NMI | ICR_ASSERT); }
+}
+
+/// Send an INIT IPI to the core with `apic_id`, resetting it
+///
+/// # Safety
+///
+/// The core loses whatever it was running, it must not be in use.
+pub unsafe fn send_init(apic_id: u32) {
+    send_command(IpiDest::Apic(apic_id), ICR_INIT | ICR_ASSERT);
+}
+
+/// Send a startup IPI to the core with `apic_id`, making it execute real
+/// mode code at physical address `page * 4096`
+///
+/// # Safety
+///
+/// The core must be waiting for a startup IPI after an INIT, and `page` must
+/// hold code for it to run.
+pub unsafe fn send_startup(apic_id: u32, page: u8) {
+    send_command(IpiDest::Apic(apic_id),
+                 ICR_STARTUP | ICR_ASSERT | page as u32);
+}
+
+
+/// Wait for `count()` to change from `before`, panicking if it doesn't
+/// within `TEST_DEADLINE`
+fn wait_for(what: &str, before: u64, count: impl Fn() -> u64) {
+    let deadline = Instant::now() + TEST_DEADLINE;
+    while count() == before {
+        assert!(Instant::now() < deadline, "APIC: no {} arrived.", what);
+        spin_loop();
+    }
+}
+
+/// Check that a one-shot of the timer and an IPI interrupt the current core
+/// in time. Interrupts must be enabled.
+pub fn self_test() {
+    let fired = timer_interrupts();
+    timer_start(TimerMode::OneShot, TEST_DELAY.as_micros() as u64);
+    wait_for("timer interrupt", fired, timer_interrupts);
+
+    let vector = interrupts::alloc_vector(test_ipi_handler)
+        .expect("Out of interrupt vectors for the IPI test.");
+    let received = TEST_IPIS.load(Ordering::Relaxed);
+    send_ipi(IpiDest::Current, vector);
+    wait_for("IPI", received, || TEST_IPIS.load(Ordering::Relaxed));
+    interrupts::free_vector(vector);
+
+    print!("APIC: timer and IPI delivered\n");
+}
@@ -0,0 +1,146 @@
+//! Per-core data.
+//!
+//! Every core has a `CoreLocals` structure which its GS base points to, so a
+//! core can find it without knowing who it is. Statics declared with
+//! `core_local!` hold one value per core, indexed by the core ID in it.
+
+use core::arch::asm;
+use core::sync::atomic::{ AtomicU32, AtomicU64, Ordering };
+use crate::smp::MAX_CORES;
+
+/// Data of a single core
+#[repr(C)]
+pub struct CoreLocals {
+    /// Address of the structure itself, so it can be read through GS
+    address: AtomicU64,
+
+    /// ID of the core, in the order the cores came online. The bootstrap
+    /// processor is core 0.
+    id: AtomicU32,
+
+    /// APIC ID of the core
+    apic_id: AtomicU32,
+
+    /// Address of the task running on the core. There's no scheduler yet, so
+    /// this stays 0 for the boot thread of the core until there are tasks to
+    /// switch between.
+    current_task: AtomicU64,
+}
+
+impl CoreLocals {
+    /// Returns the ID of the core
+    pub fn id(&self) -> u32 {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    /// Returns the APIC ID of the core
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    /// Returns the address of the task running on the core, `None` for the
+    /// boot thread of the core
+    pub fn current_task(&self) -> Option<u64> {
+        Some(self.current_task.load(Ordering::Relaxed))
+            .filter(|&task| task != 0)
+    }
+
+    /// Record `task` as the task running on the core. Only the core itself
+    /// switches its tasks.
+    pub fn set_current_task(&self, task: Option<u64>) {
+        self.current_task.store(task.unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+/// The data of all the cores, indexed by core ID. Static so that it's usable
+/// before memory management is.
+static CORE_LOCALS: [CoreLocals; MAX_CORES] = [const {
+    CoreLocals {
+        address:      AtomicU64::new(0),
+        id:           AtomicU32::new(0),
+        apic_id:      AtomicU32::new(0),
+        current_task: AtomicU64::new(0),
+    }
+}; MAX_CORES];
+
+/// A static with one value per core, declared with `core_local!`
+pub struct CoreLocal<T> {
+    /// The values, indexed by core ID
+    values: [T; MAX_CORES],
+}
+
+// A core only gets to access its own value, unless `T` is `Sync`
+unsafe impl<T: Send> Sync for CoreLocal<T> {}
+
+impl<T> CoreLocal<T> {
+    /// Returns a static with the initial `values` of all the cores
+    pub const fn new(values: [T; MAX_CORES]) -> Self {
+        Self { values }
+    }
+
+    /// Returns the value of the current core
+    pub fn get(&self) -> &T {
+        &self.values[core_id() as usize]
+    }
+}
+
+impl<T: Sync> CoreLocal<T> {
+    /// Returns the values of all the cores, indexed by core ID
+    pub fn all(&self) -> &[T; MAX_CORES] {
+        &self.values
+    }
+}
+
+/// Declare a static holding a value of `$ty` for every core, each initialized
+/// to the constant `$init`. A core gets its value with `.get()`.
+#[macro_export]
+macro_rules! core_local {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::core_locals::CoreLocal<$ty> =
+            $crate::core_locals::CoreLocal::new(
+                [const { $init }; $crate::smp::MAX_CORES]);
+    };
+}
+
+/// Set up the data of the current core as core `id` and point GS base to it.
+/// This has to be the first thing a core does, everything taking a lock or
+/// allocating memory depends on it.
+pub fn init(id: u32) {
+    let locals = &CORE_LOCALS[id as usize];
+    assert!(locals.address.load(Ordering::SeqCst) == 0,
+        "Core locals initialized twice.");
+
+    locals.id.store(id, Ordering::SeqCst);
+    locals.apic_id.store(cpu::apic_id(), Ordering::SeqCst);
+    locals.address.store(locals as *const CoreLocals as u64, Ordering::SeqCst);
+    unsafe { cpu::write_gs_base(locals as *const CoreLocals as u64); }
+
+    // Let locks record their owners
+    if id == 0 {
+        spinlock::set_core_id_fn(core_id);
+    }
+}
+
+/// Returns the data of the current core
+#[inline]
+pub fn current() -> &'static CoreLocals {
+    let address: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) address,
+             options(nostack, readonly, preserves_flags));
+        &*(address as *const CoreLocals)
+    }
+}
+
+/// Returns the data of core `id`, if it's online
+pub fn of(id: u32) -> Option<&'static CoreLocals> {
+    let locals = CORE_LOCALS.get(id as usize)?;
+    (locals.address.load(Ordering::SeqCst) != 0).then_some(locals)
+}
+
+/// Returns the ID of the current core
+#[inline]
+pub fn core_id() -> u32 {
+    current().id()
+}
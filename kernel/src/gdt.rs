@@ -0,0 +1,146 @@
+//! Per-core GDT and 64-bit TSS.
+//!
+//! The TSS provides dedicated interrupt stacks (ISTs) for exceptions which
+//! can't trust the current stack, so that a kernel stack overflow results in a
+//! diagnostic instead of a triple fault.
+
+use core::arch::asm;
+use core::mem::size_of;
+use cpu::TableRegister;
+use crate::mm::{ self, VirtAddr };
+
+/// Selector of the kernel code segment
+pub const KERNEL_CS: u16 = 0x08;
+
+/// Selector of the kernel data segment
+pub const KERNEL_DS: u16 = 0x10;
+
+/// Selector of the TSS
+pub const TSS_SELECTOR: u16 = 0x18;
+
+/// IST used by the double fault handler
+pub const IST_DOUBLE_FAULT: u8 = 1;
+
+/// IST used by the non-maskable interrupt handler
+pub const IST_NMI: u8 = 2;
+
+/// IST used by the machine check handler
+pub const IST_MACHINE_CHECK: u8 = 3;
+
+/// Number of ISTs in use
+const NUM_ISTS: usize = 3;
+
+/// Size of a single IST stack
+const IST_STACK_SIZE: u64 = 32 * 1024;
+
+/// 64-bit code, present, DPL 0
+const CODE_DESCRIPTOR: u64 = 0x00209a0000000000;
+
+/// Data, present, writable, DPL 0
+const DATA_DESCRIPTOR: u64 = 0x0000920000000000;
+
+/// Present, available 64-bit TSS
+const TSS_ATTRIBUTES: u64 = 0x89;
+
+/// The 64-bit task state segment
+#[derive(Default)]
+#[repr(C, packed)]
+struct Tss {
+    reserved0: u32,
+
+    /// Stack pointers loaded on a privilege change to ring 0-2
+    rsp: [u64; 3],
+
+    reserved1: u64,
+
+    /// Interrupt stack table. IST 1 is at index 0.
+    ist: [u64; 7],
+
+    reserved2: u64,
+    reserved3: u16,
+
+    /// Offset to the I/O permission bitmap
+    iopb: u16,
+}
+
+/// The GDT of a single core, followed by its TSS
+#[repr(C)]
+struct CoreTables {
+    /// Null, kernel code, kernel data and the two halves of the TSS descriptor
+    gdt: [u64; 5],
+
+    /// The TSS referenced by the GDT
+    tss: Tss,
+}
+
+/// Build a GDT and a TSS for the current core and load them.
+///
+/// This must be done once on every core, before the IDT is loaded. The
+/// tables and the IST stacks are never freed.
+pub fn init() {
+    // Allocate the tables
+    let tables = mm::alloc_phys(size_of::<CoreTables>() as u64, 16)
+        .expect("Couldn't allocate the GDT.");
+    let tables = unsafe { &mut *mm::phys_ptr::<CoreTables>(tables) };
+
+    // Allocate the IST stacks, each above a guard page. The stacks grow
+    // down, so the ISTs point to their tops.
+    let mut ist = [0u64; 7];
+    for ist in ist.iter_mut().take(NUM_ISTS) {
+        let VirtAddr(top) = mm::alloc_stack(IST_STACK_SIZE)
+            .expect("Couldn't allocate an IST stack.");
+        *ist = top;
+    }
+
+    // Build the TSS. There is no I/O permission bitmap.
+    tables.tss = Tss {
+        ist,
+        iopb: size_of::<Tss>() as u16,
+        ..Default::default()
+    };
+
+    // Build the TSS descriptor
+    let base  = &tables.tss as *const Tss as u64;
+    let limit = size_of::<Tss>() as u64 - 1;
+    let tss_low = (limit & 0xffff) |
+        ((base & 0xffffff) << 16) |
+        (TSS_ATTRIBUTES << 40) |
+        (((limit >> 16) & 0xf) << 48) |
+        (((base >> 24) & 0xff) << 56);
+    let tss_high = base >> 32;
+
+    // Build the GDT
+    tables.gdt = [0, CODE_DESCRIPTOR, DATA_DESCRIPTOR, tss_low, tss_high];
+
+    // Load the GDT
+    let gdtr = TableRegister {
+        limit: (size_of::<[u64; 5]>() - 1) as u16,
+        base:  tables.gdt.as_ptr() as usize,
+    };
+
+    unsafe {
+        cpu::lgdt(&gdtr);
+
+        // Reload the code segment with a far return
+        asm!("push {cs}",
+             "lea {tmp}, [rip + 2f]",
+             "push {tmp}",
+             "retfq",
+             "2:",
+             cs = in(reg) KERNEL_CS as u64, tmp = lateout(reg) _);
+
+        // Reload the data segments. Loading GS resets its base, which points
+        // to the core locals, so it's restored after.
+        let gs_base = cpu::read_gs_base();
+        asm!("mov ds, {0:x}",
+             "mov es, {0:x}",
+             "mov fs, {0:x}",
+             "mov gs, {0:x}",
+             "mov ss, {0:x}",
+             in(reg) KERNEL_DS);
+        cpu::write_gs_base(gs_base);
+
+        // Load the TSS
+        cpu::ltr(TSS_SELECTOR);
+    }
+}
@@ -0,0 +1,335 @@
+//! High precision event timer driver.
+//!
+//! The HPET is found through the ACPI HPET table. Its main counter runs at a
+//! fixed frequency and serves as the reference the TSC is calibrated against.
+//! Its comparators can fire one-shot interrupts through the I/O APIC when the
+//! APIC timer can't be relied on.
+
+use core::ptr;
+use core::hint::spin_loop;
+use core::sync::atomic::{ AtomicBool, AtomicU32, AtomicU64, Ordering };
+use acpi::fadt::GenericAddress;
+use cpu::InterruptGuard;
+use time::Duration;
+use crate::{ apic, ioapic };
+use crate::ioapic::{ Line, Polarity, Trigger };
+use crate::mm::{ self, PhysAddr };
+
+/// Size of the register block
+const HPET_SIZE: u64 = 1024;
+
+/// General capabilities and ID register
+const REG_CAPABILITIES: usize = 0x000;
+
+/// General configuration register
+const REG_CONFIG: usize = 0x010;
+
+/// Main counter value register
+const REG_COUNTER: usize = 0x0f0;
+
+/// Configuration and capabilities register of timer `n`
+const fn reg_timer_config(n: u32) -> usize {
+    0x100 + 0x20 * n as usize
+}
+
+/// Comparator value register of timer `n`
+const fn reg_timer_comparator(n: u32) -> usize {
+    0x108 + 0x20 * n as usize
+}
+
+/// The main counter is 64 bits wide instead of 32
+const CAP_COUNTER_64: u64 = 1 << 13;
+
+/// Enables the main counter and the timer interrupts
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+/// Routes timers 0 and 1 to the legacy PIT and RTC interrupts
+const CONFIG_LEGACY: u64 = 1 << 1;
+
+/// The timer interrupt is level triggered instead of edge triggered
+const TIMER_LEVEL: u64 = 1 << 1;
+
+/// The timer interrupt is enabled
+const TIMER_ENABLE: u64 = 1 << 2;
+
+/// The timer fires periodically
+const TIMER_PERIODIC: u64 = 1 << 3;
+
+/// The timer comparator is 64 bits wide instead of 32
+const TIMER_CAP_64: u64 = 1 << 5;
+
+/// Forces the timer comparator to 32 bits
+const TIMER_32BIT: u64 = 1 << 8;
+
+/// Shift of the I/O APIC input the timer interrupt is routed to
+const TIMER_ROUTE_SHIFT: u32 = 9;
+
+/// Interrupt delivery through front-side bus messages
+const TIMER_FSB: u64 = 1 << 14;
+
+/// Maximum number of timers of an HPET
+const MAX_TIMERS: usize = 32;
+
+/// Marks a timer which isn't routed to an I/O APIC input yet
+const NO_GSI: u32 = u32::MAX;
+
+/// TSC cycles without the main counter advancing after which it's considered
+/// stuck. Even at 10 MHz, the slowest allowed rate, it advances every 100 ns.
+const STUCK_CYCLES: u64 = 1 << 32;
+
+/// Shortest delay a one-shot is armed with, covering the register writes
+const MIN_DELAY: Duration = Duration::from_micros(10);
+
+/// Femtoseconds in a second
+const FEMTOS_PER_SEC: u128 = 1_000_000_000_000_000;
+
+/// Virtual address of the registers, 0 if there is no HPET
+static MMIO: AtomicU64 = AtomicU64::new(0);
+
+/// Period of the main counter in femtoseconds
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of comparators
+static TIMERS: AtomicU32 = AtomicU32::new(0);
+
+/// I/O APIC input of every timer, `NO_GSI` until its first one-shot
+static TIMER_GSI: [AtomicU32; MAX_TIMERS] =
+    [const { AtomicU32::new(NO_GSI) }; MAX_TIMERS];
+
+/// I/O APIC inputs taken by the timers
+static CLAIMED_GSIS: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the main counter is only 32 bits wide
+static COUNTER_32: AtomicBool = AtomicBool::new(false);
+
+/// Highest counter value returned so far, extended to 64 bits for 32-bit
+/// counters
+static LAST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Read the register at `offset`
+unsafe fn read(offset: usize) -> u64 {
+    let mmio = MMIO.load(Ordering::Relaxed) as *const u8;
+    ptr::read_volatile(mmio.add(offset) as *const u64)
+}
+
+/// Write `val` to the register at `offset`
+unsafe fn write(offset: usize, val: u64) {
+    let mmio = MMIO.load(Ordering::Relaxed) as *mut u8;
+    ptr::write_volatile(mmio.add(offset) as *mut u64, val);
+}
+
+/// Find the HPET in the ACPI tables, map it and start its main counter with
+/// all of its timers disabled
+pub fn init() {
+    let Some(hpet) = crate::acpi::tables().and_then(|tables| tables.hpet())
+    else {
+        print!("HPET: not present\n");
+        return;
+    };
+    assert!(hpet.base.space == GenericAddress::MEMORY,
+        "The HPET registers aren't memory mapped.");
+
+    let mmio = mm::map_mmio(PhysAddr(hpet.base.addr), HPET_SIZE)
+        .expect("Couldn't map the HPET registers.");
+    MMIO.store(mmio.0, Ordering::SeqCst);
+
+    unsafe {
+        let caps   = read(REG_CAPABILITIES);
+        let period = caps >> 32;
+        let timers = ((caps >> 8) & 0x1f) as u32 + 1;
+        assert!(period != 0 && period <= 100_000_000,
+            "The HPET reports an invalid period.");
+
+        PERIOD_FS.store(period, Ordering::SeqCst);
+        TIMERS.store(timers, Ordering::SeqCst);
+        COUNTER_32.store(caps & CAP_COUNTER_64 == 0, Ordering::SeqCst);
+
+        // Stop the counter and disable the timers and the legacy routing
+        write(REG_CONFIG, read(REG_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY));
+        for timer in 0..timers {
+            let config = read(reg_timer_config(timer));
+            write(reg_timer_config(timer),
+                  config & !(TIMER_ENABLE | TIMER_PERIODIC | TIMER_FSB));
+        }
+
+        // Restart the counter from 0
+        write(REG_COUNTER, 0);
+        write(REG_CONFIG, read(REG_CONFIG) | CONFIG_ENABLE);
+    }
+
+    print!("HPET: {} timers, {}-bit counter at {} kHz\n",
+           TIMERS.load(Ordering::SeqCst),
+           if COUNTER_32.load(Ordering::SeqCst) { 32 } else { 64 },
+           frequency() / 1000);
+}
+
+/// Returns whether there is an HPET
+pub fn present() -> bool {
+    MMIO.load(Ordering::Relaxed) != 0
+}
+
+/// Returns the frequency of the main counter in Hz
+pub fn frequency() -> u64 {
+    (FEMTOS_PER_SEC / PERIOD_FS.load(Ordering::Relaxed) as u128) as u64
+}
+
+/// Returns the value of the main counter, if there is an HPET. It never goes
+/// backwards, a 32-bit counter is extended to 64 bits as long as it's read
+/// at least once every wrap around.
+pub fn counter() -> Option<u64> {
+    if !present() {
+        return None;
+    }
+
+    let count = unsafe { read(REG_COUNTER) };
+    if !COUNTER_32.load(Ordering::Relaxed) {
+        return Some(count);
+    }
+
+    // Carry the wrap arounds seen so far into the 32-bit value
+    let last = LAST_COUNT.load(Ordering::SeqCst);
+    let mut count = (last & !0xffff_ffff) | (count & 0xffff_ffff);
+    if count < last {
+        count += 1 << 32;
+    }
+    Some(LAST_COUNT.fetch_max(count, Ordering::SeqCst).max(count))
+}
+
+/// Convert `duration` to ticks of the main counter
+fn ticks(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * 1_000_000 /
+        PERIOD_FS.load(Ordering::Relaxed) as u128;
+    ticks.try_into().unwrap_or(u64::MAX)
+}
+
+/// Convert `ticks` of the main counter to a duration
+pub fn duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * PERIOD_FS.load(Ordering::Relaxed) as u128 /
+        1_000_000;
+    Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX))
+}
+
+/// Measure the frequency of the TSC in Hz against the main counter, if there
+/// is an HPET and its counter runs
+pub fn measure_tsc(over: Duration) -> Option<u64> {
+    let _interrupts = InterruptGuard::new();
+
+    let start    = counter()?;
+    let tsc      = cpu::rdtsc();
+    let deadline = start + ticks(over);
+    let mut now  = start;
+    let mut progress = tsc;
+    while now < deadline {
+        spin_loop();
+
+        // Give up on a counter which doesn't advance
+        let next = counter()?;
+        if next != now {
+            progress = cpu::rdtsc();
+        } else if cpu::rdtsc() - progress > STUCK_CYCLES {
+            print!("HPET: the main counter is stuck at {:#x}\n", now);
+            return None;
+        }
+        now = next;
+    }
+    let tsc = cpu::rdtsc() - tsc;
+
+    let femtos = (now - start) as u128 * PERIOD_FS.load(Ordering::Relaxed)
+        as u128;
+    Some((tsc as u128 * FEMTOS_PER_SEC / femtos) as u64)
+}
+
+/// Returns the I/O APIC input of `timer` with the supported inputs `routes`,
+/// claiming the lowest free one on first use. Only inputs past the ISA
+/// interrupts are used, those are routed to the ISA devices.
+fn timer_gsi(timer: u32, routes: u32) -> Option<u32> {
+    let gsi = TIMER_GSI[timer as usize].load(Ordering::SeqCst);
+    if gsi != NO_GSI {
+        return Some(gsi);
+    }
+
+    loop {
+        let claimed = CLAIMED_GSIS.load(Ordering::SeqCst);
+        let free = routes & !0xffff & !claimed;
+        if free == 0 {
+            return None;
+        }
+
+        let gsi = free.trailing_zeros();
+        if !ioapic::handles(gsi) {
+            return None;
+        }
+        if CLAIMED_GSIS.compare_exchange(claimed, claimed | 1 << gsi,
+                Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            TIMER_GSI[timer as usize].store(gsi, Ordering::SeqCst);
+            return Some(gsi);
+        }
+    }
+}
+
+/// Fire `vector` on the current core once, `delay` from now, using timer
+/// `timer`. The timer keeps an I/O APIC input of its own, so every timer can
+/// serve a different core. Returns `None` if there is no such timer or no
+/// free input for it.
+pub fn oneshot(timer: u32, delay: Duration, vector: u8) -> Option<()> {
+    if !present() || timer >= TIMERS.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    unsafe {
+        let config = read(reg_timer_config(timer));
+        let gsi = timer_gsi(timer, (config >> 32) as u32)?;
+
+        // Route the input before the timer can fire on it
+        ioapic::route(Line {
+            gsi,
+            polarity: Polarity::ActiveHigh,
+            trigger:  Trigger::Edge,
+        }, vector, apic::id());
+
+        // Set up the timer with its comparator in the width of the counter
+        let narrow = config & TIMER_CAP_64 == 0 ||
+            COUNTER_32.load(Ordering::Relaxed);
+        let mut config = config &
+            !(TIMER_LEVEL | TIMER_PERIODIC | TIMER_FSB | TIMER_32BIT |
+              (0x1f << TIMER_ROUTE_SHIFT));
+        config |= (gsi as u64) << TIMER_ROUTE_SHIFT;
+        if narrow {
+            config |= TIMER_32BIT;
+        }
+        write(reg_timer_config(timer), config);
+
+        // Arm the comparator, far enough ahead for the writes to land before
+        // the counter gets there. A comparator the counter passed before the
+        // timer was enabled would only match after the counter wraps, so it's
+        // moved further ahead. A timer which fired just before the check
+        // fires again, an early interrupt is harmless where a lost one isn't.
+        let mask = if narrow { 0xffff_ffff } else { u64::MAX };
+        let mut ahead = ticks(delay).max(ticks(MIN_DELAY)).max(1);
+        loop {
+            let target = read(REG_COUNTER).wrapping_add(ahead) & mask;
+            write(reg_timer_comparator(timer), target);
+            write(reg_timer_config(timer), config | TIMER_ENABLE);
+
+            let left = target.wrapping_sub(read(REG_COUNTER)) & mask;
+            if left <= ahead {
+                break;
+            }
+            ahead = ahead.saturating_mul(2);
+        }
+    }
+
+    Some(())
+}
+
+/// Disable the interrupt of timer `timer`
+pub fn cancel(timer: u32) {
+    if !present() || timer >= TIMERS.load(Ordering::Relaxed) {
+        return;
+    }
+
+    unsafe {
+        let config = read(reg_timer_config(timer));
+        write(reg_timer_config(timer), config & !TIMER_ENABLE);
+    }
+}
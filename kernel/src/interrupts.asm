@@ -0,0 +1,104 @@
+; Interrupt entry stubs.
+; Every vector gets a stub which normalizes the stack (pushes a dummy error
+; code if the CPU doesn't push one), pushes the vector number and jumps to
+; `interrupt_common`, which saves the whole state and calls
+; `interrupt_handler()` in interrupts.rs.
+
+[bits 64]
+
+extern interrupt_handler
+
+section .text
+
+; Generate a stub for every vector
+%assign vector 0
+%rep 256
+interrupt_stub_%+vector:
+    ; Only some exceptions push an error code. Push a dummy one for the rest.
+%assign has_error 0
+%if vector == 8 || vector == 17 || vector == 21
+%assign has_error 1
+%elif (vector >= 10 && vector <= 14) || vector == 29 || vector == 30
+%assign has_error 1
+%endif
+%if has_error == 0
+    push qword 0
+%endif
+    push qword vector
+    jmp interrupt_common
+%assign vector vector+1
+%endrep
+
+interrupt_common:
+    ; Save the general purpose registers
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    ; The saved state is the first argument to `interrupt_handler()`
+    mov rdi, rsp
+
+    ; Save the stack pointer and align the stack for the FXSAVE area and the
+    ; call. RBX is callee-saved, so it survives the call.
+    mov rbx, rsp
+    and rsp, ~0xf
+
+    ; Save the floating point and SSE state, the handler is free to use it
+    sub rsp, 512
+    fxsave64 [rsp]
+
+    ; Call the handler
+    cld
+    call interrupt_handler
+
+    ; Restore the floating point and SSE state
+    fxrstor64 [rsp]
+
+    ; Restore the stack pointer
+    mov rsp, rbx
+
+    ; Restore the general purpose registers
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    ; Pop the vector and the error code
+    add rsp, 16
+
+    iretq
+
+section .rodata
+
+; Addresses of the stubs, indexed by vector
+global interrupt_stubs
+align 8
+interrupt_stubs:
+%assign vector 0
+%rep 256
+    dq interrupt_stub_%+vector
+%assign vector vector+1
+%endrep
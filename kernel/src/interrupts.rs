@@ -0,0 +1,378 @@
+//! Interrupt descriptor table, exception handlers and interrupt dispatch.
+//!
+//! Every vector enters through a stub in `interrupts.asm` which saves the
+//! whole register state and calls `interrupt_handler()`. Exceptions print the
+//! state and panic, other vectors are dispatched to registered handlers.
+
+use core::mem::size_of;
+use core::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+use spinlock::SpinLock;
+use cpu::TableRegister;
+use crate::{ gdt, apic };
+
+/// Number of entries in the IDT
+const IDT_ENTRIES: usize = 256;
+
+/// Number of vectors reserved by the CPU for exceptions
+pub const NUM_EXCEPTIONS: usize = 32;
+
+/// Vector of the non-maskable interrupt
+pub const NMI: u8 = 2;
+
+/// Vector of the invalid opcode exception
+pub const INVALID_OPCODE: u8 = 6;
+
+/// Vector of the double fault
+pub const DOUBLE_FAULT: u8 = 8;
+
+/// Vector of the general protection fault
+pub const GENERAL_PROTECTION: u8 = 13;
+
+/// Vector of the page fault
+pub const PAGE_FAULT: u8 = 14;
+
+/// Vector of the machine check exception
+pub const MACHINE_CHECK: u8 = 18;
+
+/// First vector handed out by `alloc_vector()`, past the remapped PICs
+const DYNAMIC_VECTOR_START: u8 = 0x30;
+
+/// Last vector handed out by `alloc_vector()`, below the vectors of the APIC
+const DYNAMIC_VECTOR_END: u8 = 0xef;
+
+/// Present, DPL 0, 64-bit interrupt gate
+const INTERRUPT_GATE: u8 = 0x8E;
+
+/// Names of the exceptions, indexed by vector
+const EXCEPTION_NAMES: [&str; NUM_EXCEPTIONS] = [
+    "Divide Error",
+    "Debug",
+    "Non-Maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "BOUND Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection",
+    "Page Fault",
+    "Reserved",
+    "x87 FPU Floating-Point Error",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+extern "C" {
+    /// Addresses of the interrupt stubs in `interrupts.asm`, indexed by vector
+    static interrupt_stubs: [u64; IDT_ENTRIES];
+}
+
+/// General purpose registers saved by the interrupt stubs
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct AllRegs {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9:  u64,
+    pub r8:  u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// The frame pushed by the CPU when an interrupt arrives
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct InterruptFrame {
+    pub rip:    u64,
+    pub cs:     u64,
+    pub rflags: u64,
+    pub rsp:    u64,
+    pub ss:     u64,
+}
+
+/// The whole state of an interrupted context, as laid out on the stack by the
+/// interrupt stubs. Modifications are restored when the handler returns.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct InterruptState {
+    /// The general purpose registers
+    pub regs: AllRegs,
+
+    /// The interrupt vector
+    pub vector: u64,
+
+    /// The error code pushed by the CPU, or 0 if there was none
+    pub error: u64,
+
+    /// The frame pushed by the CPU
+    pub frame: InterruptFrame,
+}
+
+/// A handler of a device interrupt vector
+pub type InterruptHandler = fn(state: &mut InterruptState);
+
+/// An entry in the IDT
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct IdtEntry {
+    offset_low:  u16,
+    selector:    u16,
+    ist:         u8,
+    attributes:  u8,
+    offset_mid:  u16,
+    offset_high: u32,
+    reserved:    u32,
+}
+
+impl IdtEntry {
+    /// Returns a new interrupt gate which jumps to `handler` through the code
+    /// segment `selector`
+    fn new(handler: u64, selector: u16) -> Self {
+        Self {
+            offset_low:  handler as u16,
+            selector,
+            ist:         0,
+            attributes:  INTERRUPT_GATE,
+            offset_mid:  (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved:    0,
+        }
+    }
+}
+
+/// The IDT shared by all cores. `None` until it's been built
+static IDT: SpinLock<Option<[IdtEntry; IDT_ENTRIES]>> = SpinLock::new(None);
+
+/// Registered device interrupt handlers, indexed by vector.
+/// These are raw `InterruptHandler` pointers, 0 if the vector is unhandled.
+/// Atomics are used instead of a lock because they are read in interrupt
+/// context.
+static HANDLERS: [AtomicUsize; IDT_ENTRIES] =
+    [const { AtomicUsize::new(0) }; IDT_ENTRIES];
+
+/// Number of interrupts which arrived without a handler, indexed by vector.
+/// Counted rather than printed, as the print lock may be held by the
+/// interrupted code.
+static UNHANDLED: [AtomicU64; IDT_ENTRIES] =
+    [const { AtomicU64::new(0) }; IDT_ENTRIES];
+
+/// Address to resume execution at after the next exception.
+/// Used to deliberately trigger exceptions without killing the kernel.
+#[cfg(feature = "fault_test")]
+static FAULT_FIXUP: AtomicUsize = AtomicUsize::new(0);
+
+/// Build the IDT, if it hasn't been built yet, and load it on the current core.
+/// The GDT and TSS of the core must already be loaded.
+pub fn init() {
+    let mut idt = IDT.lock();
+
+    // Build the IDT
+    if idt.is_none() {
+        // Point every vector to its stub
+        let mut entries = [IdtEntry::default(); IDT_ENTRIES];
+        for (vector, entry) in entries.iter_mut().enumerate() {
+            *entry = IdtEntry::new(unsafe { interrupt_stubs[vector] },
+                                   gdt::KERNEL_CS);
+        }
+
+        // Exceptions which can't trust the current stack get their own
+        entries[NMI as usize].ist           = gdt::IST_NMI;
+        entries[DOUBLE_FAULT as usize].ist  = gdt::IST_DOUBLE_FAULT;
+        entries[MACHINE_CHECK as usize].ist = gdt::IST_MACHINE_CHECK;
+
+        *idt = Some(entries);
+    }
+
+    // Load the IDT. It lives in a static so it's never freed.
+    let entries = idt.as_ref().unwrap();
+    let idtr = TableRegister {
+        limit: (size_of::<[IdtEntry; IDT_ENTRIES]>() - 1) as u16,
+        base:  entries.as_ptr() as usize,
+    };
+    unsafe { cpu::lidt(&idtr); }
+}
+
+/// Register `handler` for the device interrupt `vector`.
+/// A vector can only have one handler at a time.
+pub fn register(vector: u8, handler: InterruptHandler) {
+    // Exceptions are handled by the kernel itself
+    assert!(vector as usize >= NUM_EXCEPTIONS,
+        "Can't register a handler for an exception.");
+
+    // Install the handler only if there isn't one already
+    let installed = HANDLERS[vector as usize]
+        .compare_exchange(0, handler as usize, Ordering::SeqCst,
+                          Ordering::SeqCst);
+    assert!(installed.is_ok(), "Interrupt vector already has a handler.");
+}
+
+/// Allocate a free device interrupt vector and register `handler` for it.
+/// Returns `None` if all the vectors are taken.
+pub fn alloc_vector(handler: InterruptHandler) -> Option<u8> {
+    (DYNAMIC_VECTOR_START..=DYNAMIC_VECTOR_END).find(|&vector| {
+        HANDLERS[vector as usize]
+            .compare_exchange(0, handler as usize, Ordering::SeqCst,
+                              Ordering::SeqCst).is_ok()
+    })
+}
+
+/// Free a vector allocated with `alloc_vector()`
+pub fn free_vector(vector: u8) {
+    assert!((DYNAMIC_VECTOR_START..=DYNAMIC_VECTOR_END).contains(&vector),
+        "Freeing a vector which isn't dynamically allocated.");
+    unregister(vector);
+}
+
+/// Remove the handler of the device interrupt `vector`
+pub fn unregister(vector: u8) {
+    HANDLERS[vector as usize].store(0, Ordering::SeqCst);
+}
+
+/// Print the state of an interrupted context.
+/// The serial lock is shattered, because we might have interrupted its owner.
+fn dump_state(state: &InterruptState) {
+    let regs  = &state.regs;
+    let frame = &state.frame;
+
+    print_shatter!("RAX {:016x} RBX {:016x} RCX {:016x} RDX {:016x}\n",
+                   regs.rax, regs.rbx, regs.rcx, regs.rdx);
+    print_shatter!("RSI {:016x} RDI {:016x} RBP {:016x} RSP {:016x}\n",
+                   regs.rsi, regs.rdi, regs.rbp, frame.rsp);
+    print_shatter!("R8  {:016x} R9  {:016x} R10 {:016x} R11 {:016x}\n",
+                   regs.r8, regs.r9, regs.r10, regs.r11);
+    print_shatter!("R12 {:016x} R13 {:016x} R14 {:016x} R15 {:016x}\n",
+                   regs.r12, regs.r13, regs.r14, regs.r15);
+    print_shatter!("RIP {:016x} RFL {:016x} CS  {:04x} SS  {:04x}\n",
+                   frame.rip, frame.rflags, frame.cs, frame.ss);
+    print_shatter!("CR0 {:016x} CR2 {:016x} CR3 {:016x} CR4 {:016x}\n",
+                   cpu::read_cr0(), cpu::read_cr2(), cpu::read_cr3(),
+                   cpu::read_cr4());
+}
+
+/// Common entry point of all the interrupt stubs
+#[no_mangle]
+unsafe extern "C" fn interrupt_handler(state: &mut InterruptState) {
+    let vector = state.vector as usize;
+
+    // Exceptions are fatal
+    if vector < NUM_EXCEPTIONS {
+        print_shatter!("\n---- EXCEPTION {} ({}) ---- error code {:#x} \
+                        ---- CR2 {:#x} ----\n",
+                       vector, EXCEPTION_NAMES[vector], state.error,
+                       cpu::read_cr2());
+        dump_state(state);
+
+        // The double fault has its own stack, most likely because the
+        // kernel stack has overflown
+        if vector == DOUBLE_FAULT as usize {
+            print_shatter!("Double fault, possibly a kernel stack overflow\n");
+        }
+
+        // If the exception was triggered on purpose, skip over it
+        #[cfg(feature = "fault_test")]
+        {
+            let fixup = FAULT_FIXUP.swap(0, Ordering::SeqCst);
+            if fixup != 0 {
+                state.frame.rip = fixup as u64;
+                return;
+            }
+        }
+
+        panic!("Unhandled exception.");
+    }
+
+    // Spurious interrupts aren't acknowledged
+    if vector == apic::SPURIOUS_VECTOR as usize {
+        return;
+    }
+
+    // Interrupts without a handler are acknowledged right away and counted
+    let handler = HANDLERS[vector].load(Ordering::SeqCst);
+    if handler == 0 {
+        apic::eoi();
+        UNHANDLED[vector].fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    // Dispatch the interrupt to its handler
+    let handler: InterruptHandler = core::mem::transmute(handler);
+    handler(state);
+
+    // Acknowledge the interrupt
+    apic::eoi();
+}
+
+/// Print the vectors which received interrupts without a handler
+pub fn print_stats() {
+    for (vector, count) in UNHANDLED.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+        if count != 0 {
+            print!("Unhandled interrupt vector {:#x}: {} times\n",
+                   vector, count);
+        }
+    }
+}
+
+/// Deliberately trigger #UD, #PF and #GP. Each one of them is reported and
+/// execution continues after the faulting instruction.
+#[cfg(feature = "fault_test")]
+pub fn fault_test() {
+    use core::arch::asm;
+
+    print!("Triggering #UD\n");
+    unsafe {
+        asm!("lea {tmp}, [rip + 2f]",
+             "mov [{fixup}], {tmp}",
+             "ud2",
+             "2:",
+             fixup = in(reg) FAULT_FIXUP.as_ptr(), tmp = out(reg) _);
+    }
+
+    print!("Triggering #PF\n");
+    unsafe {
+        asm!("lea {tmp}, [rip + 2f]",
+             "mov [{fixup}], {tmp}",
+             "mov {tmp}, [{addr}]",
+             "2:",
+             fixup = in(reg) FAULT_FIXUP.as_ptr(), tmp = out(reg) _,
+             addr = in(reg) 0x0000_7fff_dead_0000u64);
+    }
+
+    print!("Triggering #GP\n");
+    unsafe {
+        asm!("lea {tmp}, [rip + 2f]",
+             "mov [{fixup}], {tmp}",
+             "mov {tmp}, [{addr}]",
+             "2:",
+             fixup = in(reg) FAULT_FIXUP.as_ptr(), tmp = out(reg) _,
+             addr = in(reg) 0x8000_0000_0000_0000u64);
+    }
+
+    print!("Survived all the faults\n");
+}
@@ -0,0 +1,312 @@
+//! I/O APIC driver.
+//!
+//! The I/O APICs and the overrides of the ISA IRQs are found in the MADT.
+//! Every global system interrupt (GSI) can be routed to a vector on a core,
+//! all of them start out masked. PCI INTx interrupts are found through the
+//! ISA IRQ the firmware connected them to.
+
+use core::ptr;
+use alloc::vec::Vec;
+use spinlock::SpinLock;
+use cpu::InterruptGuard;
+use acpi::madt::MadtEntry;
+use crate::mm::{ self, PhysAddr, VirtAddr };
+
+/// Size of the register window of an I/O APIC
+const IOAPIC_SIZE: u64 = 0x20;
+
+/// Offset of the register select register
+const IOREGSEL: usize = 0x00;
+
+/// Offset of the register data window
+const IOWIN: usize = 0x10;
+
+/// Version register, holding the index of the last redirection entry
+const REG_VERSION: u32 = 0x01;
+
+/// First register of the redirection table. Every entry takes two registers.
+const REG_REDIRECTION: u32 = 0x10;
+
+/// The interrupt is active low
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+/// The interrupt is level triggered
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+
+/// The interrupt is masked
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Number of ISA IRQs
+const ISA_IRQS: usize = 16;
+
+/// ISA IRQ of the PIT
+pub const ISA_PIT: u8 = 0;
+
+/// ISA IRQ of the PS/2 keyboard
+pub const ISA_KEYBOARD: u8 = 1;
+
+/// ISA IRQ of COM2 and COM4
+pub const ISA_COM2: u8 = 3;
+
+/// ISA IRQ of COM1 and COM3
+pub const ISA_COM1: u8 = 4;
+
+/// Polarity of an interrupt line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Trigger mode of an interrupt line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// The global system interrupt an interrupt line is connected to, and how
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Line {
+    /// The global system interrupt
+    pub gsi: u32,
+
+    /// Polarity of the line
+    pub polarity: Polarity,
+
+    /// Trigger mode of the line
+    pub trigger: Trigger,
+}
+
+impl Line {
+    /// Returns the line of an ISA interrupt connected to `gsi`. ISA
+    /// interrupts are edge triggered and active high.
+    const fn isa(gsi: u32) -> Self {
+        Self {
+            gsi,
+            polarity: Polarity::ActiveHigh,
+            trigger:  Trigger::Edge,
+        }
+    }
+
+    /// Returns the line of a PCI INTx interrupt connected to `gsi`. PCI
+    /// interrupts are level triggered and active low.
+    const fn pci(gsi: u32) -> Self {
+        Self {
+            gsi,
+            polarity: Polarity::ActiveLow,
+            trigger:  Trigger::Level,
+        }
+    }
+}
+
+/// An I/O APIC
+struct IoApic {
+    /// ID of the I/O APIC
+    id: u8,
+
+    /// Virtual address of the registers
+    regs: VirtAddr,
+
+    /// First global system interrupt handled by the I/O APIC
+    gsi_base: u32,
+
+    /// Number of redirection entries
+    entries: u32,
+}
+
+impl IoApic {
+    /// Read the register `reg`
+    unsafe fn read(&self, reg: u32) -> u32 {
+        let regs = self.regs.0 as *mut u8;
+        ptr::write_volatile(regs.add(IOREGSEL) as *mut u32, reg);
+        ptr::read_volatile(regs.add(IOWIN) as *const u32)
+    }
+
+    /// Write `val` to the register `reg`
+    unsafe fn write(&self, reg: u32, val: u32) {
+        let regs = self.regs.0 as *mut u8;
+        ptr::write_volatile(regs.add(IOREGSEL) as *mut u32, reg);
+        ptr::write_volatile(regs.add(IOWIN) as *mut u32, val);
+    }
+
+    /// Read the redirection entry `entry`
+    unsafe fn read_entry(&self, entry: u32) -> u64 {
+        let reg = REG_REDIRECTION + entry * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    /// Write `val` to the redirection entry `entry`. The entry is masked
+    /// while it's being written.
+    unsafe fn write_entry(&self, entry: u32, val: u64) {
+        let reg = REG_REDIRECTION + entry * 2;
+        self.write(reg, REDIRECTION_MASKED as u32);
+        self.write(reg + 1, (val >> 32) as u32);
+        self.write(reg, val as u32);
+    }
+
+    /// Check whether the I/O APIC handles `gsi`
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries
+    }
+}
+
+/// The I/O APICs and the ISA IRQ lines
+struct State {
+    /// The I/O APICs of the system
+    ioapics: Vec<IoApic>,
+
+    /// The MADT overrides of the ISA IRQs, as their GSIs and MPS INTI flags
+    overrides: [Option<(u32, u16)>; ISA_IRQS],
+}
+
+impl State {
+    /// Returns the I/O APIC handling `gsi` and the index of its redirection
+    /// entry
+    fn entry(&self, gsi: u32) -> (&IoApic, u32) {
+        let ioapic = self.ioapics.iter().find(|ioapic| ioapic.handles(gsi))
+            .expect("No I/O APIC handles the global system interrupt.");
+        (ioapic, gsi - ioapic.gsi_base)
+    }
+}
+
+/// The state of the I/O APICs. `None` until `init()`.
+static STATE: SpinLock<Option<State>> = SpinLock::new(None);
+
+/// Returns the polarity and trigger mode of an override with MPS INTI
+/// `flags`, defaulting to the ones of `line`
+fn apply_flags(line: Line, flags: u16) -> Line {
+    let polarity = match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _    => line.polarity,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => Trigger::Edge,
+        0b11 => Trigger::Level,
+        _    => line.trigger,
+    };
+
+    Line { polarity, trigger, ..line }
+}
+
+/// Find the I/O APICs and the ISA IRQ overrides in the MADT and mask all the
+/// interrupts
+pub fn init() {
+    let madt = crate::acpi::tables().and_then(|tables| tables.madt())
+        .expect("I/O APICs can't be found without a MADT.");
+
+    let mut state = State {
+        ioapics:   Vec::new(),
+        overrides: [None; ISA_IRQS],
+    };
+
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::IoApic { id, addr, gsi_base } => {
+                // Map the registers and get the number of entries
+                let regs = mm::map_mmio(PhysAddr(addr as u64), IOAPIC_SIZE)
+                    .expect("Couldn't map the I/O APIC registers.");
+                let mut ioapic = IoApic { id, regs, gsi_base, entries: 0 };
+                ioapic.entries =
+                    (unsafe { ioapic.read(REG_VERSION) } >> 16 & 0xff) + 1;
+
+                // Mask all the interrupts
+                for entry in 0..ioapic.entries {
+                    unsafe { ioapic.write_entry(entry, REDIRECTION_MASKED); }
+                }
+
+                print!("I/O APIC {}: GSIs {}-{}\n", ioapic.id,
+                       ioapic.gsi_base,
+                       ioapic.gsi_base + ioapic.entries - 1);
+                state.ioapics.push(ioapic);
+            }
+            MadtEntry::InterruptOverride { source, gsi, flags, .. }
+                    if (source as usize) < ISA_IRQS => {
+                state.overrides[source as usize] = Some((gsi, flags));
+            }
+            _ => {}
+        }
+    }
+
+    assert!(!state.ioapics.is_empty(), "The MADT doesn't list an I/O APIC.");
+
+    let _interrupts = InterruptGuard::new();
+    let mut global = STATE.lock();
+    assert!(global.is_none(), "I/O APICs initialized twice.");
+    *global = Some(state);
+}
+
+/// Returns the line of ISA IRQ `irq` with the defaults of `default`, with
+/// the MADT override applied. `None` if `irq` isn't an ISA IRQ.
+fn override_line(irq: u8, default: fn(u32) -> Line) -> Option<Line> {
+    let _interrupts = InterruptGuard::new();
+    let state = STATE.lock();
+    let overrides = &state.as_ref()
+        .expect("The I/O APICs aren't initialized.").overrides;
+
+    Some(match *overrides.get(irq as usize)? {
+        Some((gsi, flags)) => apply_flags(default(gsi), flags),
+        None               => default(irq as u32),
+    })
+}
+
+/// Returns the line of ISA IRQ `irq`, with the MADT overrides applied
+pub fn isa_line(irq: u8) -> Line {
+    override_line(irq, Line::isa).expect("Not an ISA IRQ.")
+}
+
+/// Returns the line of a PCI INTx interrupt the firmware connected to ISA
+/// IRQ `irq`, with the MADT overrides applied. `None` if `irq` isn't an ISA
+/// IRQ, like the 0xff of an unconnected interrupt line register.
+pub fn pci_line(irq: u8) -> Option<Line> {
+    override_line(irq, Line::pci)
+}
+
+/// Returns whether an I/O APIC handles `gsi`. `false` until `init()`.
+pub fn handles(gsi: u32) -> bool {
+    let _interrupts = InterruptGuard::new();
+    let state = STATE.lock();
+    state.as_ref().is_some_and(|state| {
+        state.ioapics.iter().any(|ioapic| ioapic.handles(gsi))
+    })
+}
+
+/// Route `line` to `vector` on the core with `apic_id` and unmask it
+pub fn route(line: Line, vector: u8, apic_id: u32) {
+    assert!(apic_id <= 0xff,
+        "I/O APICs can't target APIC IDs above 255 without remapping.");
+
+    let mut entry = vector as u64 | (apic_id as u64) << 56;
+    if line.polarity == Polarity::ActiveLow { entry |= REDIRECTION_ACTIVE_LOW; }
+    if line.trigger  == Trigger::Level      { entry |= REDIRECTION_LEVEL; }
+
+    let _interrupts = InterruptGuard::new();
+    let state = STATE.lock();
+    let (ioapic, idx) = state.as_ref()
+        .expect("The I/O APICs aren't initialized.").entry(line.gsi);
+    unsafe { ioapic.write_entry(idx, entry); }
+}
+
+/// Route ISA IRQ `irq` to `vector` on the core with `apic_id` and unmask it
+pub fn route_isa(irq: u8, vector: u8, apic_id: u32) {
+    route(isa_line(irq), vector, apic_id);
+}
+
+/// Mask or unmask `gsi`, keeping its routing
+pub fn set_masked(gsi: u32, masked: bool) {
+    let _interrupts = InterruptGuard::new();
+    let state = STATE.lock();
+    let (ioapic, idx) = state.as_ref()
+        .expect("The I/O APICs aren't initialized.").entry(gsi);
+
+    unsafe {
+        let entry = ioapic.read_entry(idx);
+        let entry = if masked {
+            entry | REDIRECTION_MASKED
+        } else {
+            entry & !REDIRECTION_MASKED
+        };
+        ioapic.write_entry(idx, entry);
+    }
+}
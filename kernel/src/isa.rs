@@ -0,0 +1,168 @@
+//! Interrupts of the legacy ISA devices.
+//!
+//! The PIT, the PS/2 keyboard and the COM ports are routed through the I/O
+//! APIC to the bootstrap processor. The PIT keeps the mode the BIOS left it
+//! in and only its ticks are counted. Received bytes and scancodes are
+//! buffered until they're read.
+
+use core::sync::atomic::{ AtomicU64, Ordering };
+use spinlock::SpinLock;
+use cpu::{ InterruptGuard, PortRange };
+use serial_driver::Serial;
+use crate::{ apic, ioapic, interrupts, boot_kern };
+use crate::interrupts::InterruptState;
+
+/// Size of the input buffers
+const BUFFER_SIZE: usize = 256;
+
+/// Data port of the PS/2 controller
+const PS2_DATA: u16 = 0x60;
+
+/// Status port of the PS/2 controller
+const PS2_STATUS: u16 = 0x64;
+
+/// A byte is waiting in the data port of the PS/2 controller
+const PS2_OUTPUT_FULL: u8 = 1 << 0;
+
+/// Bytes received from a device and not read yet. The oldest bytes are
+/// dropped when it's full.
+struct InputBuffer {
+    /// The bytes
+    bytes: [u8; BUFFER_SIZE],
+
+    /// Index of the oldest byte
+    head: usize,
+
+    /// Number of bytes in the buffer
+    len: usize,
+}
+
+impl InputBuffer {
+    /// Returns a new, empty buffer
+    const fn new() -> Self {
+        Self { bytes: [0; BUFFER_SIZE], head: 0, len: 0 }
+    }
+
+    /// Append `byte`, dropping the oldest byte if the buffer is full
+    fn push(&mut self, byte: u8) {
+        if self.len == BUFFER_SIZE {
+            self.head = (self.head + 1) % BUFFER_SIZE;
+            self.len -= 1;
+        }
+
+        self.bytes[(self.head + self.len) % BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+
+    /// Remove and return the oldest byte
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// Number of PIT interrupts received
+static PIT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Scancodes received from the keyboard
+static KEYBOARD: SpinLock<InputBuffer> = SpinLock::new(InputBuffer::new());
+
+/// Bytes received from the COM ports
+static SERIAL: SpinLock<InputBuffer> = SpinLock::new(InputBuffer::new());
+
+/// The COM ports found by the bootloader. Set once in `init()`.
+static mut COM_PORTS: [Option<PortRange>; 4] = [None; 4];
+
+/// Count a PIT tick
+fn pit_interrupt(_state: &mut InterruptState) {
+    PIT_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Buffer the scancodes waiting in the PS/2 controller
+fn keyboard_interrupt(_state: &mut InterruptState) {
+    drain_keyboard();
+}
+
+/// Buffer the bytes waiting in all the COM ports
+fn serial_interrupt(_state: &mut InterruptState) {
+    drain_serial();
+}
+
+/// Move the scancodes waiting in the PS/2 controller to the buffer
+fn drain_keyboard() {
+    let _interrupts = InterruptGuard::new();
+    let mut keyboard = KEYBOARD.lock();
+    unsafe {
+        while cpu::in8(PS2_STATUS) & PS2_OUTPUT_FULL != 0 {
+            keyboard.push(cpu::in8(PS2_DATA));
+        }
+    }
+}
+
+/// Move the bytes waiting in all the COM ports to the buffer. COM1 and COM3
+/// share an IRQ, and so do COM2 and COM4, so all of them are drained.
+fn drain_serial() {
+    let _interrupts = InterruptGuard::new();
+    let mut serial = SERIAL.lock();
+    for &port in unsafe { (*core::ptr::addr_of!(COM_PORTS)).iter() }
+            .flatten() {
+        while let Some(byte) = unsafe { Serial::read_port(port) } {
+            serial.push(byte);
+        }
+    }
+}
+
+/// Route `irq` to a newly allocated vector on the current core, handled by
+/// `handler`
+fn route(irq: u8, handler: interrupts::InterruptHandler) {
+    let vector = interrupts::alloc_vector(handler)
+        .expect("Out of interrupt vectors for the ISA devices.");
+    ioapic::route_isa(irq, vector, apic::id());
+}
+
+/// Route the interrupts of the ISA devices to the current core and enable
+/// the receive interrupts of the COM ports. The I/O APICs must be initialized.
+pub fn init() {
+    // Get the COM ports and let them raise IRQs
+    {
+        let _interrupts = InterruptGuard::new();
+        let mut serial = boot_kern().serial.lock();
+        if let Some(serial) = serial.as_mut() {
+            unsafe { COM_PORTS = serial.devices; }
+            serial.enable_interrupts();
+        }
+    }
+
+    route(ioapic::ISA_PIT,      pit_interrupt);
+    route(ioapic::ISA_KEYBOARD, keyboard_interrupt);
+    route(ioapic::ISA_COM1,     serial_interrupt);
+    route(ioapic::ISA_COM2,     serial_interrupt);
+
+    // The IRQs are edge triggered, anything received before they were routed
+    // wouldn't raise another one
+    drain_keyboard();
+    drain_serial();
+}
+
+/// Returns the number of PIT interrupts received
+pub fn pit_ticks() -> u64 {
+    PIT_TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the oldest scancode received from the keyboard
+pub fn read_scancode() -> Option<u8> {
+    let _interrupts = InterruptGuard::new();
+    KEYBOARD.lock().pop()
+}
+
+/// Returns the oldest byte received from the COM ports
+pub fn read_serial() -> Option<u8> {
+    let _interrupts = InterruptGuard::new();
+    SERIAL.lock().pop()
+}
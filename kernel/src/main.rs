@@ -2,23 +2,128 @@
 #![no_main]
 
 #![feature(lang_items)]
+#![allow(internal_features)]
 
+extern crate alloc;
 extern crate core_reqs;
 
+#[macro_use] pub mod print;
+#[macro_use] pub mod core_locals;
+pub mod mm;
+pub mod acpi;
+pub mod time;
+pub mod hpet;
+pub mod gdt;
+pub mod interrupts;
+pub mod apic;
+pub mod ioapic;
+pub mod isa;
+pub mod smp;
+pub mod pci;
+pub mod net;
+
 use core::panic::PanicInfo;
 use core::hint::spin_loop;
+use boot_kern_common::BootKernCommon;
+
+/// Variables shared between the bootloader and the kernel.
+/// Set once on entry, before anything else runs.
+static mut BOOT_KERN: Option<&'static BootKernCommon> = None;
+
+/// Returns the variables shared between the bootloader and the kernel
+pub fn boot_kern() -> &'static BootKernCommon {
+    unsafe { BOOT_KERN.expect("Kernel entered without the shared variables.") }
+}
 
 #[lang = "eh_personality"]
 fn eh_personality() {}
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    // Print the panic header
+    print_shatter!("\n---- PANIC! ---- ");
+
+    // Print the location information
+    if let Some(info) = info.location() {
+        print_shatter!(
+            "{} {}:{} ----",
+            info.file(),
+            info.line(),
+            info.column(),
+        );
+    }
+
+    // Print the panic payload
+    print_shatter!(" {}  ----\n", info.message());
+
+    // Halt
     unsafe { core::arch::asm!("cli", "hlt"); }
     loop { spin_loop(); }
 }
 
-#[no_mangle]
 #[export_name="_start"]
-extern fn entry() -> ! {
-    panic!();
+extern "C" fn entry(boot_kern: &'static BootKernCommon) -> ! {
+    // Save the variables shared with the bootloader
+    unsafe { BOOT_KERN = Some(boot_kern); }
+
+    // Set up the data of the bootstrap processor, everything else uses it
+    core_locals::init(0);
+
+    // Take over the memory management
+    mm::init();
+
+    // Set up the GDT and the TSS
+    gdt::init();
+
+    // Set up the interrupt handlers
+    interrupts::init();
+
+    // Find the ACPI tables
+    acpi::init();
+
+    // Start the HPET, the most precise reference for the clock
+    hpet::init();
+
+    // Calibrate the clock, the APIC timer is measured against it
+    time::init();
+
+    // Prefer allocating memory local to the NUMA node of the core
+    mm::numa::init();
+
+    // Switch from the legacy PICs to the local APIC
+    apic::init();
+
+    // Find the I/O APICs, with all of their interrupts masked
+    ioapic::init();
+
+    // Route the interrupts of the PIT, the keyboard and the COM ports
+    isa::init();
+
+    // Take interrupts from now on, and check they're delivered
+    unsafe { cpu::enable_interrupts(); }
+    apic::self_test();
+
+    // Find the PCI devices
+    pci::init();
+
+    // Bring up the network devices
+    net::init();
+
+    // Check TCP against an echo server on the host
+    #[cfg(feature = "tcp_test")]
+    net::tcp::self_test();
+
+    // Bring up the other cores
+    smp::init();
+
+    // Deliberately trigger exceptions to test the handlers
+    #[cfg(feature = "fault_test")]
+    interrupts::fault_test();
+
+    // Report the memory usage of the boot
+    mm::heap::print_stats();
+    mm::frames::print_stats();
+    interrupts::print_stats();
+
+    panic!("Nothing left to do.");
 }
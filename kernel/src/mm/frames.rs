@@ -0,0 +1,292 @@
+//! Physical page-frame allocator.
+//!
+//! The kernel takes ownership of the free physical memory at boot and keeps it
+//! in a global pool. 4 KiB and 2 MiB frames are handed out from per-core
+//! caches, which are refilled from and flushed to the pool in batches, so the
+//! pool lock is only taken once every few allocations. Memory of the NUMA
+//! node of the allocating core is preferred when the topology is known.
+
+use core::sync::atomic::{ AtomicU64, Ordering };
+use spinlock::{ SpinLock, SpinLockGuard };
+use range_set::{ RangeSet, Range };
+use cpu::InterruptGuard;
+use crate::boot_kern;
+use crate::mm::{ numa, PhysAddr };
+
+/// Maximum number of frames held by a single cache of a core
+const CACHE_CAPACITY: usize = 64;
+
+/// Sizes of frames served from the per-core caches
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameSize {
+    Frame4K,
+    Frame2M,
+}
+
+impl FrameSize {
+    /// Returns the size of the frame in bytes
+    pub const fn bytes(&self) -> u64 {
+        match self {
+            FrameSize::Frame4K => 4 * 1024,
+            FrameSize::Frame2M => 2 * 1024 * 1024,
+        }
+    }
+
+    /// Returns the number of frames moved between a cache and the pool at once
+    const fn batch(&self) -> usize {
+        match self {
+            FrameSize::Frame4K => 32,
+            FrameSize::Frame2M => 4,
+        }
+    }
+
+    /// Returns the maximum number of frames a core may cache
+    const fn limit(&self) -> usize {
+        match self {
+            FrameSize::Frame4K => CACHE_CAPACITY,
+            FrameSize::Frame2M => 8,
+        }
+    }
+}
+
+/// A stack of free frames of a single size
+struct FrameStack {
+    /// Physical addresses of the frames
+    frames: [u64; CACHE_CAPACITY],
+
+    /// Number of frames on the stack
+    len: usize,
+}
+
+/// Allocation statistics of a single core
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CoreStats {
+    /// Number of frames allocated
+    pub allocs: u64,
+
+    /// Number of frames freed
+    pub frees: u64,
+
+    /// Number of times the cache had to be refilled from the pool
+    pub refills: u64,
+
+    /// Number of times the cache had to be flushed to the pool
+    pub flushes: u64,
+}
+
+/// The frame caches of a single core
+struct CoreCache {
+    /// Free 4 KiB frames
+    small: FrameStack,
+
+    /// Free 2 MiB frames
+    large: FrameStack,
+
+    /// Allocation statistics
+    stats: CoreStats,
+}
+
+impl CoreCache {
+    /// Returns a new, empty cache
+    const fn new() -> Self {
+        Self {
+            small: FrameStack { frames: [0; CACHE_CAPACITY], len: 0 },
+            large: FrameStack { frames: [0; CACHE_CAPACITY], len: 0 },
+            stats: CoreStats { allocs: 0, frees: 0, refills: 0, flushes: 0 },
+        }
+    }
+
+    /// Returns the stack holding frames of `size`
+    fn stack(&mut self, size: FrameSize) -> &mut FrameStack {
+        match size {
+            FrameSize::Frame4K => &mut self.small,
+            FrameSize::Frame2M => &mut self.large,
+        }
+    }
+}
+
+/// The free physical memory owned by the kernel. `None` until `init()`.
+static POOL: SpinLock<Option<RangeSet>> = SpinLock::new(None);
+
+core_local! {
+    /// Frame caches of the cores.
+    /// The locks are only contended when statistics are being read.
+    static CACHES: SpinLock<CoreCache> = SpinLock::new(CoreCache::new());
+}
+
+/// Number of times the pool has been locked
+static POOL_LOCKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of times the pool was already locked by someone else
+static POOL_CONTENDED: AtomicU64 = AtomicU64::new(0);
+
+/// Take ownership of the free physical memory shared with the bootloader.
+/// The bootloader can't allocate any more memory after this.
+pub fn init() {
+    let memory = unsafe { boot_kern().free_memory_ref().lock().take() }
+        .expect("The bootloader didn't hand over any free memory.");
+
+    let mut pool = POOL.lock();
+    assert!(pool.is_none(), "Frame allocator initialized twice.");
+    *pool = Some(memory);
+}
+
+/// Lock the pool, keeping track of the contention
+fn lock_pool() -> SpinLockGuard<'static, Option<RangeSet>> {
+    POOL_LOCKS.fetch_add(1, Ordering::Relaxed);
+
+    // Try to take the lock first to find out whether it's contended
+    if let Some(pool) = POOL.try_lock() {
+        return pool;
+    }
+
+    POOL_CONTENDED.fetch_add(1, Ordering::Relaxed);
+    POOL.lock()
+}
+
+/// Allocate `size` bytes aligned to `align` straight from the pool
+pub fn alloc_pool(size: u64, align: u64) -> Option<PhysAddr> {
+    // Look up the local memory before taking the lock, to keep it short
+    let local = numa::local_memory();
+
+    let _interrupts = InterruptGuard::new();
+    let mut pool = lock_pool();
+
+    pool.as_mut()?.allocate(size, align, local)
+        .map(|addr| PhysAddr(addr as u64))
+}
+
+/// Return `size` bytes at `addr` straight to the pool
+///
+/// # Safety
+///
+/// The memory must have been allocated with `alloc_pool()` and must not be
+/// used anymore.
+pub unsafe fn free_pool(addr: PhysAddr, size: u64) {
+    let _interrupts = InterruptGuard::new();
+    let mut pool = lock_pool();
+
+    let end = addr.0.checked_add(size.checked_sub(1).unwrap()).unwrap();
+    pool.as_mut()
+        .expect("The frame allocator isn't initialized. Can't free memory.")
+        .insert(Range::new(addr.0, end));
+}
+
+/// Move a batch of frames of `size` from the pool to `stack`
+fn refill(stack: &mut FrameStack, size: FrameSize) -> Option<()> {
+    let local = numa::local_memory();
+    let mut pool = lock_pool();
+    let pool = pool.as_mut()?;
+
+    // Allocate the whole batch at once if possible, otherwise a single frame
+    let (addr, frames) = pool
+        .allocate(size.bytes() * size.batch() as u64, size.bytes(), local)
+        .map(|addr| (addr as u64, size.batch()))
+        .or_else(|| {
+            pool.allocate(size.bytes(), size.bytes(), local)
+                .map(|addr| (addr as u64, 1))
+        })?;
+
+    // Split the allocation into frames
+    for frame in 0..frames as u64 {
+        stack.frames[stack.len] = addr + frame * size.bytes();
+        stack.len += 1;
+    }
+
+    Some(())
+}
+
+/// Move a batch of frames of `size` from `stack` back to the pool
+fn flush(stack: &mut FrameStack, size: FrameSize) {
+    let mut pool = lock_pool();
+    let pool = pool.as_mut()
+        .expect("The frame allocator isn't initialized. Can't free memory.");
+
+    for _ in 0..size.batch() {
+        stack.len -= 1;
+        let frame = stack.frames[stack.len];
+        pool.insert(Range::new(frame, frame + size.bytes() - 1));
+    }
+}
+
+/// Allocate a frame of `size`, aligned to its size. The frame isn't zeroed.
+pub fn alloc(size: FrameSize) -> Option<PhysAddr> {
+    let _interrupts = InterruptGuard::new();
+    let mut cache = CACHES.get().lock();
+
+    // Refill the cache if it's empty
+    if cache.stack(size).len == 0 {
+        refill(cache.stack(size), size)?;
+        cache.stats.refills += 1;
+    }
+
+    // Pop a frame
+    let stack = cache.stack(size);
+    stack.len -= 1;
+    let frame = stack.frames[stack.len];
+
+    cache.stats.allocs += 1;
+    Some(PhysAddr(frame))
+}
+
+/// Free a frame of `size` at `addr`
+///
+/// # Safety
+///
+/// The frame must have been allocated with `alloc()` with the same `size` and
+/// must not be used anymore.
+pub unsafe fn free(addr: PhysAddr, size: FrameSize) {
+    let _interrupts = InterruptGuard::new();
+    let mut cache = CACHES.get().lock();
+
+    // Flush some frames to the pool if the cache is full
+    if cache.stack(size).len == size.limit() {
+        flush(cache.stack(size), size);
+        cache.stats.flushes += 1;
+    }
+
+    // Push the frame
+    let stack = cache.stack(size);
+    stack.frames[stack.len] = addr.0;
+    stack.len += 1;
+
+    cache.stats.frees += 1;
+}
+
+/// Print the statistics of the frame allocator over serial
+pub fn print_stats() {
+    // Get the state of the pool
+    let (free_bytes, ranges) = {
+        let _interrupts = InterruptGuard::new();
+        let pool = POOL.lock();
+        pool.as_ref().map(|pool| {
+            let free = pool.entries().iter()
+                .map(|range| range.end - range.start + 1)
+                .sum::<u64>();
+            (free, pool.entries().len())
+        }).unwrap_or((0, 0))
+    };
+
+    print!("Frames: {} free bytes in {} ranges, pool locked {} times, \
+            {} contended\n",
+           free_bytes, ranges, POOL_LOCKS.load(Ordering::Relaxed),
+           POOL_CONTENDED.load(Ordering::Relaxed));
+
+    // Print the statistics of all the cores which have allocated anything
+    for (core, cache) in CACHES.all().iter().enumerate() {
+        let (stats, small, large) = {
+            let _interrupts = InterruptGuard::new();
+            let cache = cache.lock();
+            (cache.stats, cache.small.len, cache.large.len)
+        };
+
+        if stats.allocs == 0 && stats.frees == 0 {
+            continue;
+        }
+
+        print!("    Core {:3}: {} allocs, {} frees, {} refills, {} flushes, \
+                {}/{} cached 4K/2M\n",
+               core, stats.allocs, stats.frees, stats.refills,
+               stats.flushes, small, large);
+    }
+}
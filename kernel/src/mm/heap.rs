@@ -0,0 +1,233 @@
+//! The kernel heap.
+//!
+//! Small allocations are served from slabs of per-size-class objects, large
+//! ones are rounded up to whole pages and come straight from physical memory.
+//! Every NUMA node has its own free lists, allocations are served from the
+//! lists of the node of the core and slabs are carved out of memory local to
+//! it. Freed objects go back to the free list of the node their memory is
+//! attached to and freed pages go back to the physical memory.
+
+use core::cmp;
+use core::ptr;
+use core::alloc::{ GlobalAlloc, Layout };
+use spinlock::SpinLock;
+use cpu::InterruptGuard;
+use crate::mm::{ self, numa, PhysAddr };
+
+/// Object sizes served from slabs. Every class is a power of two, so objects
+/// are naturally aligned to their size.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Size of a slab carved into objects of a single size class
+const SLAB_SIZE: u64 = 64 * 1024;
+
+/// Granularity of large allocations
+const PAGE_SIZE: u64 = 4096;
+
+/// A free object in a slab
+struct FreeObject {
+    /// Next free object of the same size class
+    next: *mut FreeObject,
+}
+
+/// Usage statistics of the heap
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    /// Number of allocations made
+    pub allocs: u64,
+
+    /// Number of allocations freed
+    pub frees: u64,
+
+    /// Bytes of physical memory carved into slabs
+    pub slab_bytes: u64,
+
+    /// Number of objects in use, per size class
+    pub objects_in_use: [u64; SIZE_CLASSES.len()],
+
+    /// Bytes of physical memory in use by large allocations
+    pub large_bytes: u64,
+}
+
+/// Free lists of a single NUMA node
+struct Heap {
+    /// Free lists of the size classes
+    free: [*mut FreeObject; SIZE_CLASSES.len()],
+}
+
+// The free lists are only accessed through the lock
+unsafe impl Send for Heap {}
+unsafe impl Sync for Heap {}
+
+impl Heap {
+    /// Allocate an object of size class `class`
+    unsafe fn alloc_small(&mut self, class: usize) -> Option<*mut u8> {
+        // Carve a new slab if we're out of free objects
+        if self.free[class].is_null() {
+            self.refill(class)?;
+        }
+
+        // Pop a free object
+        let object = self.free[class];
+        self.free[class] = (*object).next;
+        Some(object as *mut u8)
+    }
+
+    /// Free an object of size class `class`
+    unsafe fn free_small(&mut self, ptr: *mut u8, class: usize) {
+        // Push the object to the free list
+        let object = ptr as *mut FreeObject;
+        (*object).next = self.free[class];
+        self.free[class] = object;
+    }
+
+    /// Allocate a new slab for size class `class` and put all of its objects
+    /// on the free list. The slab is preferably local to the current core.
+    unsafe fn refill(&mut self, class: usize) -> Option<()> {
+        let size = SIZE_CLASSES[class];
+        let slab = mm::alloc_phys(SLAB_SIZE, PAGE_SIZE)?;
+        let slab = mm::phys_ptr::<u8>(slab);
+
+        // Link the objects, in reverse so that they are handed out in order
+        for offset in (0..SLAB_SIZE as usize).step_by(size).rev() {
+            let object = slab.add(offset) as *mut FreeObject;
+            (*object).next = self.free[class];
+            self.free[class] = object;
+        }
+
+        STATS.lock().slab_bytes += SLAB_SIZE;
+        Some(())
+    }
+}
+
+/// The heaps of the NUMA nodes, indexed by node. Interrupts are disabled
+/// while they're locked, so that an interrupt handler allocating memory can't
+/// deadlock the core.
+static HEAPS: [SpinLock<Heap>; numa::MAX_NODES] = [const {
+    SpinLock::new(Heap { free: [ptr::null_mut(); SIZE_CLASSES.len()] })
+}; numa::MAX_NODES];
+
+/// Usage statistics of all the heaps
+static STATS: SpinLock<HeapStats> = SpinLock::new(HeapStats {
+    allocs:         0,
+    frees:          0,
+    slab_bytes:     0,
+    objects_in_use: [0; SIZE_CLASSES.len()],
+    large_bytes:    0,
+});
+
+/// Returns the heap of the NUMA node with index `node`. Unknown nodes and
+/// nodes past `MAX_NODES` share the first heap.
+fn heap(node: Option<usize>) -> &'static SpinLock<Heap> {
+    &HEAPS[node.filter(|&node| node < numa::MAX_NODES).unwrap_or(0)]
+}
+
+/// Allocate whole pages for `layout`, preferably local to the current core
+unsafe fn alloc_large(layout: Layout) -> Option<*mut u8> {
+    let size  = round_to_pages(layout.size());
+    let align = cmp::max(layout.align() as u64, PAGE_SIZE);
+
+    let addr = mm::alloc_phys(size, align)?;
+
+    STATS.lock().large_bytes += size;
+    Some(mm::phys_ptr(addr))
+}
+
+/// Free the pages of an allocation made with `layout`
+unsafe fn free_large(ptr: *mut u8, layout: Layout) {
+    let size = round_to_pages(layout.size());
+    mm::free_phys(PhysAddr(ptr as u64), size);
+
+    STATS.lock().large_bytes -= size;
+}
+
+/// Round `size` up to whole pages
+fn round_to_pages(size: usize) -> u64 {
+    (size as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE
+}
+
+/// Returns the size class which can satisfy `layout`, or `None` if the
+/// allocation has to be served with whole pages
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = cmp::max(layout.size(), layout.align());
+    SIZE_CLASSES.iter().position(|&class| size <= class)
+}
+
+/// The global allocator of the kernel
+#[global_allocator]
+static GLOBAL_ALLOCATOR: GlobalAllocator = GlobalAllocator;
+
+/// The structure used in `GLOBAL_ALLOCATOR` that implements the `GlobalAlloc`
+/// trait.
+struct GlobalAllocator;
+
+unsafe impl GlobalAlloc for GlobalAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _interrupts = InterruptGuard::new();
+
+        let class = size_class(&layout);
+        let ptr = match class {
+            Some(class) => {
+                heap(numa::local_node()).lock().alloc_small(class)
+            }
+            None => alloc_large(layout),
+        };
+
+        if ptr.is_some() {
+            let mut stats = STATS.lock();
+            stats.allocs += 1;
+            if let Some(class) = class {
+                stats.objects_in_use[class] += 1;
+            }
+        }
+        ptr.unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _interrupts = InterruptGuard::new();
+
+        // Objects go back to the node their memory is attached to
+        let class = size_class(&layout);
+        match class {
+            Some(class) => {
+                heap(numa::node_of_addr(ptr as u64)).lock()
+                    .free_small(ptr, class);
+            }
+            None => free_large(ptr, layout),
+        }
+
+        let mut stats = STATS.lock();
+        stats.frees += 1;
+        if let Some(class) = class {
+            stats.objects_in_use[class] -= 1;
+        }
+    }
+}
+
+/// Returns the current usage statistics of the heap
+pub fn stats() -> HeapStats {
+    let _interrupts = InterruptGuard::new();
+    *STATS.lock()
+}
+
+/// Print the usage statistics of the heap over serial
+pub fn print_stats() {
+    let stats = stats();
+
+    // Compute the bytes used by slab objects
+    let slab_used: u64 = SIZE_CLASSES.iter().zip(stats.objects_in_use.iter())
+        .map(|(&size, &objects)| size as u64 * objects)
+        .sum();
+
+    print!("Heap: {} allocs, {} frees, {} large bytes, \
+            {}/{} slab bytes in use\n",
+           stats.allocs, stats.frees, stats.large_bytes,
+           slab_used, stats.slab_bytes);
+
+    // Print the per size class usage
+    for (size, objects) in SIZE_CLASSES.iter().zip(stats.objects_in_use) {
+        if objects != 0 {
+            print!("    {:5} byte objects: {}\n", size, objects);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Kernel memory management.
+//!
+//! The bootloader identity maps the first 4 GiB of physical memory and all of
+//! the free memory above them, so those physical addresses can be accessed
+//! directly through `phys_ptr()`. The only hole is the 2 MiB aligned window
+//! the kernel is linked at, whose physical memory is never handed out. Device
+//! memory elsewhere is mapped with `map_mmio()`.
+
+pub mod page_table;
+pub mod heap;
+pub mod frames;
+pub mod numa;
+
+use core::sync::atomic::{ AtomicU64, Ordering };
+use spinlock::SpinLock;
+use boot_kern_common::{
+    KERNEL_STACKS_BASE, KERNEL_STACKS_SIZE, STACK_GUARD_SIZE, BSP_STACK_SIZE,
+};
+use page_table::{ PageTable, PageSize, Permissions, CacheType };
+use frames::FrameSize;
+
+/// Start of the virtual region device memory is mapped into
+const MMIO_BASE: u64 = 0xffff_ff00_0000_0000;
+
+/// Size of the virtual region device memory is mapped into
+const MMIO_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+/// Granularity of device memory mappings
+const MMIO_PAGE_SIZE: u64 = 4096;
+
+/// The page table the kernel runs in. `None` until `init()` is called.
+pub static KERNEL_PAGE_TABLE: SpinLock<Option<PageTable>> = SpinLock::new(None);
+
+/// Next free virtual address in the device memory region. Device mappings are
+/// never removed.
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_BASE);
+
+/// Next free virtual address in the kernel stack region, past the stack of
+/// the bootstrap processor mapped by the bootloader. Stacks are never freed.
+static NEXT_STACK: AtomicU64 =
+    AtomicU64::new(KERNEL_STACKS_BASE + STACK_GUARD_SIZE + BSP_STACK_SIZE);
+
+/// A physical address
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct PhysAddr(pub u64);
+
+/// A virtual address
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct VirtAddr(pub u64);
+
+impl VirtAddr {
+    /// Check whether the address is canonical, that is whether bits 48-63
+    /// are copies of bit 47
+    pub fn is_canonical(&self) -> bool {
+        let high = self.0 >> 47;
+        high == 0 || high == 0x1ffff
+    }
+}
+
+/// Initialize the kernel memory management on the bootstrap processor.
+///
+/// Takes over the free physical memory and the page table set up by the
+/// bootloader.
+pub fn init() {
+    // Take over the free physical memory
+    frames::init();
+
+    // Allow mapping non-executable pages
+    init_core();
+
+    // Take over the current page table
+    let mut page_table = KERNEL_PAGE_TABLE.lock();
+    assert!(page_table.is_none(), "Memory management initialized twice.");
+    *page_table = Some(unsafe { PageTable::from_cr3() });
+}
+
+/// Initialize the paging features used by the kernel on the current core
+pub fn init_core() {
+    // Enable the no-execute bit if the CPU supports it
+    if cpu::has_nx() {
+        unsafe { cpu::write_efer(cpu::read_efer() | cpu::EFER_NXE); }
+        page_table::enable_nx();
+    }
+}
+
+/// Returns a pointer through which the physical memory at `addr` can be
+/// accessed
+pub fn phys_ptr<T>(addr: PhysAddr) -> *mut T {
+    addr.0 as *mut T
+}
+
+/// Returns the frame size which can satisfy an allocation of `size` bytes
+/// aligned to `align`, if the allocation is exactly one frame
+fn frame_size(size: u64, align: u64) -> Option<FrameSize> {
+    [FrameSize::Frame4K, FrameSize::Frame2M].into_iter()
+        .find(|frame| size == frame.bytes() && align <= frame.bytes())
+}
+
+/// Allocate `size` bytes of zeroed physical memory aligned to `align`.
+///
+/// Single frames come from the per-core caches, everything else straight
+/// from the pool of free physical memory.
+pub fn alloc_phys(size: u64, align: u64) -> Option<PhysAddr> {
+    // Allocate the memory
+    let addr = match frame_size(size, align) {
+        Some(frame) => frames::alloc(frame)?,
+        None        => frames::alloc_pool(size, align)?,
+    };
+
+    // Zero it out
+    unsafe { core::ptr::write_bytes(phys_ptr::<u8>(addr), 0, size as usize); }
+
+    Some(addr)
+}
+
+/// Free `size` bytes of physical memory at `addr`, allocated with
+/// `alloc_phys()`
+///
+/// # Safety
+///
+/// The memory must not be used anymore, and `size` must be the size it was
+/// allocated with.
+pub unsafe fn free_phys(addr: PhysAddr, size: u64) {
+    match frame_size(size, size) {
+        Some(frame) => frames::free(addr, frame),
+        None        => frames::free_pool(addr, size),
+    }
+}
+
+/// Map `size` bytes of device memory at `addr` as uncacheable and return the
+/// virtual address they are accessible at
+pub fn map_mmio(addr: PhysAddr, size: u64) -> Option<VirtAddr> {
+    // Round the mapping to whole pages
+    let offset = addr.0 % MMIO_PAGE_SIZE;
+    let start  = addr.0 - offset;
+    let size   = (offset.checked_add(size)?).div_ceil(MMIO_PAGE_SIZE) *
+        MMIO_PAGE_SIZE;
+
+    // Reserve the virtual memory
+    let vaddr = NEXT_MMIO.fetch_add(size, Ordering::SeqCst);
+    if vaddr.checked_add(size)? > MMIO_BASE + MMIO_SIZE {
+        return None;
+    }
+
+    // Map the pages
+    let perms = Permissions {
+        write:   true,
+        execute: false,
+        user:    false,
+        cache:   CacheType::Uncacheable,
+    };
+    let _interrupts = cpu::InterruptGuard::new();
+    let mut page_table = KERNEL_PAGE_TABLE.lock();
+    let page_table = page_table.as_mut()?;
+    for page in (0..size).step_by(MMIO_PAGE_SIZE as usize) {
+        unsafe {
+            page_table.map(VirtAddr(vaddr + page), PhysAddr(start + page),
+                           PageSize::Page4K, perms)?;
+        }
+    }
+
+    Some(VirtAddr(vaddr + offset))
+}
+
+/// Map a kernel stack of `size` bytes, rounded up to whole pages, above an
+/// unmapped guard page, so that an overflow faults instead of corrupting the
+/// memory below.
+///
+/// Returns the top of the stack, which is where the stack pointer starts.
+pub fn alloc_stack(size: u64) -> Option<VirtAddr> {
+    const PAGE_SIZE: u64 = PageSize::Page4K.bytes();
+
+    // Reserve the virtual memory, guard page included
+    let size  = size.checked_next_multiple_of(PAGE_SIZE)?;
+    let guard = NEXT_STACK.fetch_add(STACK_GUARD_SIZE.checked_add(size)?,
+                                     Ordering::SeqCst);
+    let stack = guard + STACK_GUARD_SIZE;
+    if stack.checked_add(size)? > KERNEL_STACKS_BASE + KERNEL_STACKS_SIZE {
+        return None;
+    }
+
+    // Back the stack with memory, leaving the guard page unmapped
+    let perms = Permissions {
+        write:   true,
+        execute: false,
+        user:    false,
+        cache:   CacheType::WriteBack,
+    };
+    for page in (0..size).step_by(PAGE_SIZE as usize) {
+        let frame = alloc_phys(PAGE_SIZE, PAGE_SIZE)?;
+
+        let _interrupts = cpu::InterruptGuard::new();
+        let mut page_table = KERNEL_PAGE_TABLE.lock();
+        unsafe {
+            page_table.as_mut()?.map(VirtAddr(stack + page), frame,
+                                     PageSize::Page4K, perms)?;
+        }
+    }
+
+    Some(VirtAddr(stack + size))
+}
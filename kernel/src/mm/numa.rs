@@ -0,0 +1,175 @@
+//! NUMA topology from the ACPI SRAT and SLIT tables.
+//!
+//! Every proximity domain becomes a node with a `RangeSet` of the memory
+//! attached to it. Physical allocations prefer the memory of the node of the
+//! allocating core and fall back to any memory if it's exhausted.
+
+use core::ptr;
+use core::sync::atomic::{ AtomicPtr, AtomicUsize, Ordering };
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use range_set::{ RangeSet, Range };
+use acpi::{ Srat, Slit };
+use acpi::srat::SratEntry;
+use crate::core_locals;
+
+/// Number of nodes with their own kernel heap. Nodes past these share the
+/// heap of the first node.
+pub const MAX_NODES: usize = 8;
+
+/// Marks a core whose node hasn't been looked up yet
+const UNKNOWN_NODE: usize = usize::MAX;
+
+/// A NUMA node
+pub struct Node {
+    /// ACPI proximity domain of the node
+    pub domain: u32,
+
+    /// APIC IDs of the cores in the node
+    pub cores: Vec<u32>,
+
+    /// Physical memory attached to the node
+    pub memory: RangeSet,
+}
+
+/// The NUMA topology of the system
+pub struct Topology {
+    /// The nodes of the system
+    pub nodes: Vec<Node>,
+
+    /// Relative distances between proximity domains, if there is a SLIT
+    slit: Option<Slit<'static>>,
+}
+
+impl Topology {
+    /// Returns the node with proximity domain `domain`, creating it if it
+    /// doesn't exist yet
+    fn node(&mut self, domain: u32) -> &mut Node {
+        let idx = match self.nodes.iter().position(|n| n.domain == domain) {
+            Some(idx) => idx,
+            None => {
+                self.nodes.push(Node {
+                    domain,
+                    cores:  Vec::new(),
+                    memory: RangeSet::new(),
+                });
+                self.nodes.len() - 1
+            }
+        };
+
+        &mut self.nodes[idx]
+    }
+
+    /// Returns the node of the core with `apic_id`
+    pub fn node_of(&self, apic_id: u32) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.cores.contains(&apic_id))
+    }
+
+    /// Returns the relative distance between proximity domains `from` and
+    /// `to`. The distance of a domain to itself is 10.
+    pub fn distance(&self, from: u32, to: u32) -> Option<u8> {
+        self.slit?.distance(from, to)
+    }
+}
+
+/// The topology, never freed once it's set. Null if it's unknown.
+static TOPOLOGY: AtomicPtr<Topology> = AtomicPtr::new(ptr::null_mut());
+
+/// Returns the NUMA topology, if it's known
+pub fn topology() -> Option<&'static Topology> {
+    unsafe { TOPOLOGY.load(Ordering::Acquire).as_ref() }
+}
+
+core_local! {
+    /// Index of the node of the core in the topology, looked up on first use
+    static LOCAL_NODE: AtomicUsize = AtomicUsize::new(UNKNOWN_NODE);
+}
+
+/// Returns the index of the node of the current core in the topology, if it's
+/// known
+pub fn local_node() -> Option<usize> {
+    let cached = LOCAL_NODE.get().load(Ordering::Relaxed);
+    if cached != UNKNOWN_NODE {
+        return Some(cached);
+    }
+
+    // Look the node up once the topology is known
+    let apic_id = core_locals::current().apic_id();
+    let node = topology()?.nodes.iter()
+        .position(|node| node.cores.contains(&apic_id))?;
+    LOCAL_NODE.get().store(node, Ordering::Relaxed);
+    Some(node)
+}
+
+/// Returns the index of the node the physical memory at `addr` is attached
+/// to, if it's known
+pub fn node_of_addr(addr: u64) -> Option<usize> {
+    let range = Range::new(addr, addr);
+    topology()?.nodes.iter()
+        .position(|node| node.memory.entries().iter()
+            .any(|memory| memory.contains(&range)))
+}
+
+/// Returns the memory of the node of the current core, if it's known
+pub fn local_memory() -> Option<&'static RangeSet> {
+    topology()?.nodes.get(local_node()?).map(|node| &node.memory)
+}
+
+/// Add the enabled entries of the SRAT to `topology`
+fn parse_srat(srat: Srat, topology: &mut Topology) {
+    for entry in srat.entries().filter(SratEntry::enabled) {
+        match entry {
+            SratEntry::LocalApic { domain, apic_id, .. } => {
+                topology.node(domain).cores.push(apic_id as u32);
+            }
+            SratEntry::LocalX2Apic { domain, x2apic_id, .. } => {
+                topology.node(domain).cores.push(x2apic_id);
+            }
+            SratEntry::Memory { domain, base, length, .. } if length > 0 => {
+                let end = base.saturating_add(length - 1);
+                topology.node(domain).memory.insert(Range::new(base, end));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Discover the NUMA topology from the ACPI tables and print it.
+/// Without an SRAT, the topology stays unknown and allocations aren't NUMA
+/// aware.
+pub fn init() {
+    // Parse the SRAT, the SLIT is optional
+    let tables = crate::acpi::tables();
+    let Some(srat) = tables.and_then(|tables| tables.srat()) else {
+        print!("NUMA: no SRAT, allocations aren't NUMA aware\n");
+        return;
+    };
+
+    let mut topology = Topology {
+        nodes: Vec::new(),
+        slit:  tables.and_then(|tables| tables.slit()),
+    };
+    parse_srat(srat, &mut topology);
+
+    // Print the topology
+    for node in &topology.nodes {
+        let memory: u64 = node.memory.entries().iter()
+            .map(|range| range.end - range.start + 1)
+            .sum();
+        print!("NUMA node {}: {} cores, {} MiB of memory, distances",
+               node.domain, node.cores.len(), memory / (1024 * 1024));
+
+        for other in &topology.nodes {
+            match topology.distance(node.domain, other.domain) {
+                Some(distance) => print!(" {}", distance),
+                None           => print!(" ?"),
+            }
+        }
+        print!("\n");
+    }
+
+    // Publish the topology
+    let topology = Box::into_raw(Box::new(topology));
+    let old = TOPOLOGY.swap(topology, Ordering::AcqRel);
+    assert!(old.is_null(), "NUMA topology initialized twice.");
+}
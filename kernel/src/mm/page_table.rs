@@ -0,0 +1,404 @@
+//! 4-level x86_64 page tables.
+//!
+//! Tables are allocated from the free physical memory and are accessed through
+//! `phys_ptr()`. Intermediate tables are created as permissive as possible, the
+//! permissions of a mapping are decided solely by its leaf entry.
+//!
+//! Changing or removing a mapping only flushes the TLB of the current core,
+//! there is no shootdown. Callers must make sure no other core has the old
+//! mapping cached, which holds for the kernel as it only ever adds mappings
+//! while other cores run.
+
+use core::sync::atomic::{ AtomicBool, Ordering };
+use crate::mm::{ self, PhysAddr, VirtAddr };
+
+/// The entry is present
+const PTE_PRESENT: u64 = 1 << 0;
+
+/// The page is writable
+const PTE_WRITE: u64 = 1 << 1;
+
+/// The page is accessible from ring 3
+const PTE_USER: u64 = 1 << 2;
+
+/// Page-level write-through
+const PTE_PWT: u64 = 1 << 3;
+
+/// Page-level cache disable
+const PTE_PCD: u64 = 1 << 4;
+
+/// The entry maps a large page instead of referencing a table
+const PTE_HUGE: u64 = 1 << 7;
+
+/// The page is not executable
+const PTE_NX: u64 = 1 << 63;
+
+/// Mask of the physical address in a table-referencing entry
+const PTE_TABLE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Number of entries in a table
+const ENTRIES: u64 = 512;
+
+/// Size of a single table
+const TABLE_SIZE: u64 = 4096;
+
+/// Whether EFER.NXE is enabled. Without it the NX bit is reserved and setting
+/// it faults, so non-executable mappings are executable instead.
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Allow mapping non-executable pages. EFER.NXE must be enabled on every core
+/// before the first one calls this.
+pub fn enable_nx() {
+    NX_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Sizes of pages which can be mapped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Page4K,
+    Page2M,
+    Page1G,
+}
+
+impl PageSize {
+    /// Returns the size of the page in bytes
+    pub const fn bytes(&self) -> u64 {
+        match self {
+            PageSize::Page4K => 4 * 1024,
+            PageSize::Page2M => 2 * 1024 * 1024,
+            PageSize::Page1G => 1024 * 1024 * 1024,
+        }
+    }
+
+    /// Returns the number of tables walked before reaching the entry which maps
+    /// a page of this size
+    const fn depth(&self) -> usize {
+        match self {
+            PageSize::Page4K => 3,
+            PageSize::Page2M => 2,
+            PageSize::Page1G => 1,
+        }
+    }
+
+    /// Returns the page size mapped by an entry after `depth` table walks
+    const fn from_depth(depth: usize) -> Self {
+        match depth {
+            3 => PageSize::Page4K,
+            2 => PageSize::Page2M,
+            _ => PageSize::Page1G,
+        }
+    }
+}
+
+/// Memory types which can be selected through PWT and PCD with the default PAT
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    WriteThrough,
+    UncacheableMinus,
+    Uncacheable,
+}
+
+impl CacheType {
+    /// Returns the PWT and PCD bits selecting this memory type
+    const fn bits(&self) -> u64 {
+        match self {
+            CacheType::WriteBack        => 0,
+            CacheType::WriteThrough     => PTE_PWT,
+            CacheType::UncacheableMinus => PTE_PCD,
+            CacheType::Uncacheable      => PTE_PCD | PTE_PWT,
+        }
+    }
+
+    /// Returns the memory type selected by the PWT and PCD bits in `entry`
+    const fn from_bits(entry: u64) -> Self {
+        match (entry & PTE_PCD != 0, entry & PTE_PWT != 0) {
+            (false, false) => CacheType::WriteBack,
+            (false, true)  => CacheType::WriteThrough,
+            (true,  false) => CacheType::UncacheableMinus,
+            (true,  true)  => CacheType::Uncacheable,
+        }
+    }
+}
+
+/// Permissions of a mapping. Mappings are always readable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Permissions {
+    /// The mapping is writable
+    pub write: bool,
+
+    /// The mapping is executable
+    pub execute: bool,
+
+    /// The mapping is accessible from ring 3
+    pub user: bool,
+
+    /// Memory type of the mapping
+    pub cache: CacheType,
+}
+
+impl Permissions {
+    /// Returns the bits of a leaf entry granting these permissions
+    fn bits(&self) -> u64 {
+        let mut bits = self.cache.bits();
+        if self.write    { bits |= PTE_WRITE; }
+        if !self.execute && NX_ENABLED.load(Ordering::Relaxed) {
+            bits |= PTE_NX;
+        }
+        if self.user     { bits |= PTE_USER; }
+        bits
+    }
+
+    /// Returns the permissions granted by the leaf entry `entry`
+    fn from_bits(entry: u64) -> Self {
+        Self {
+            write:   entry & PTE_WRITE != 0,
+            execute: entry & PTE_NX == 0,
+            user:    entry & PTE_USER != 0,
+            cache:   CacheType::from_bits(entry),
+        }
+    }
+}
+
+/// A resolved mapping of a virtual address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Translation {
+    /// The physical address the virtual address translates to
+    pub phys: PhysAddr,
+
+    /// The physical address of the start of the page
+    pub page: PhysAddr,
+
+    /// Size of the page containing the address
+    pub size: PageSize,
+
+    /// Permissions of the page
+    pub perms: Permissions,
+}
+
+/// A 4-level page table
+pub struct PageTable {
+    /// Physical address of the PML4
+    table: PhysAddr,
+}
+
+impl PageTable {
+    /// Returns a new, empty page table
+    pub fn new() -> Option<Self> {
+        Some(Self { table: mm::alloc_phys(TABLE_SIZE, TABLE_SIZE)? })
+    }
+
+    /// Returns the page table currently loaded in CR3.
+    ///
+    /// # Safety
+    ///
+    /// Only one `PageTable` may manage a given table at a time.
+    pub unsafe fn from_cr3() -> Self {
+        Self { table: PhysAddr(cpu::read_cr3() as u64 & PTE_TABLE_MASK) }
+    }
+
+    /// Returns the physical address of the PML4, suitable for CR3
+    pub fn table(&self) -> PhysAddr {
+        self.table
+    }
+
+    /// Check whether this page table is loaded on the current core
+    fn is_active(&self) -> bool {
+        cpu::read_cr3() as u64 & PTE_TABLE_MASK == self.table.0
+    }
+
+    /// Flush the TLB entry of `vaddr` if this page table is active.
+    /// Other cores are not notified, see the module documentation.
+    fn flush(&self, vaddr: VirtAddr) {
+        if self.is_active() {
+            cpu::invlpg(vaddr.0 as usize);
+        }
+    }
+
+    /// Returns the table indices used to translate `vaddr`, starting with the
+    /// PML4 index
+    fn indices(vaddr: VirtAddr) -> [u64; 4] {
+        [
+            (vaddr.0 >> 39) % ENTRIES,
+            (vaddr.0 >> 30) % ENTRIES,
+            (vaddr.0 >> 21) % ENTRIES,
+            (vaddr.0 >> 12) % ENTRIES,
+        ]
+    }
+
+    /// Returns a pointer to the entry which maps `vaddr` with a page of
+    /// `size`. Missing tables are allocated if `create` is set.
+    ///
+    /// Returns `None` if a table is missing and can't be created, or if a
+    /// larger page already maps `vaddr`.
+    unsafe fn entry(&mut self, vaddr: VirtAddr, size: PageSize, create: bool)
+            -> Option<*mut u64> {
+        let indices = Self::indices(vaddr);
+        let mut table = self.table;
+
+        // Walk the tables down to the requested level
+        for &index in &indices[..size.depth()] {
+            let entry = mm::phys_ptr::<u64>(table).add(index as usize);
+
+            if *entry & PTE_PRESENT == 0 {
+                // Create a new table if requested
+                if !create {
+                    return None;
+                }
+
+                let new_table = mm::alloc_phys(TABLE_SIZE, TABLE_SIZE)?;
+                *entry = new_table.0 | PTE_PRESENT | PTE_WRITE | PTE_USER;
+            } else if *entry & PTE_HUGE != 0 {
+                // A large page is in the way
+                return None;
+            }
+
+            table = PhysAddr(*entry & PTE_TABLE_MASK);
+        }
+
+        Some(mm::phys_ptr::<u64>(table).add(indices[size.depth()] as usize))
+    }
+
+    /// Check that `vaddr` and `paddr` can be mapped with a page of `size`
+    fn check_mapping(vaddr: VirtAddr, paddr: PhysAddr, size: PageSize)
+            -> Option<()> {
+        // Make sure the addresses are aligned to the page
+        if !vaddr.0.is_multiple_of(size.bytes()) ||
+                !paddr.0.is_multiple_of(size.bytes()) {
+            return None;
+        }
+
+        // Make sure the virtual address is canonical
+        if !vaddr.is_canonical() {
+            return None;
+        }
+
+        // Make sure the CPU can map pages this large
+        if size == PageSize::Page1G && !cpu::has_1gib_pages() {
+            return None;
+        }
+
+        Some(())
+    }
+
+    /// Returns the leaf entry mapping `paddr` with a page of `size`
+    fn leaf(paddr: PhysAddr, size: PageSize, perms: Permissions) -> u64 {
+        let huge = if size == PageSize::Page4K { 0 } else { PTE_HUGE };
+        paddr.0 | PTE_PRESENT | huge | perms.bits()
+    }
+
+    /// Map the page of `size` at `vaddr` to `paddr` with permissions `perms`.
+    ///
+    /// Fails if anything is already mapped at `vaddr`, if the addresses aren't
+    /// aligned to the page size or if a table can't be allocated.
+    ///
+    /// # Safety
+    ///
+    /// `paddr` must not be memory in use for something else, or it becomes
+    /// accessible through `vaddr`.
+    pub unsafe fn map(&mut self, vaddr: VirtAddr, paddr: PhysAddr,
+                      size: PageSize, perms: Permissions) -> Option<()> {
+        Self::check_mapping(vaddr, paddr, size)?;
+
+        // Get the entry, making sure it's not in use
+        let entry = self.entry(vaddr, size, true)?;
+        if *entry & PTE_PRESENT != 0 {
+            return None;
+        }
+
+        // Map the page. A non-present entry can't be cached by the TLB.
+        *entry = Self::leaf(paddr, size, perms);
+        Some(())
+    }
+
+    /// Map the page of `size` at `vaddr` to `paddr` with permissions `perms`,
+    /// replacing the current mapping of the same size if there is one.
+    ///
+    /// Returns the physical address of the page which was mapped before.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the old mapping anymore, on any core, and `paddr` must
+    /// not be memory in use for something else.
+    pub unsafe fn remap(&mut self, vaddr: VirtAddr, paddr: PhysAddr,
+                        size: PageSize, perms: Permissions)
+            -> Option<Option<PhysAddr>> {
+        Self::check_mapping(vaddr, paddr, size)?;
+
+        // Get the entry, making sure there isn't a table in it
+        let entry = self.entry(vaddr, size, true)?;
+        let old = *entry;
+        if old & PTE_PRESENT != 0 && size != PageSize::Page4K &&
+                old & PTE_HUGE == 0 {
+            return None;
+        }
+
+        // Replace the mapping
+        *entry = Self::leaf(paddr, size, perms);
+        if old & PTE_PRESENT == 0 {
+            return Some(None);
+        }
+
+        self.flush(vaddr);
+        Some(Some(PhysAddr(old & PTE_TABLE_MASK & !(size.bytes() - 1))))
+    }
+
+    /// Unmap the page containing `vaddr`.
+    ///
+    /// Returns the physical address and the size of the unmapped page, or
+    /// `None` if `vaddr` wasn't mapped. Tables are not freed.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the mapping anymore, on any core.
+    pub unsafe fn unmap(&mut self, vaddr: VirtAddr)
+            -> Option<(PhysAddr, PageSize)> {
+        let translation = self.translate(vaddr)?;
+
+        // Clear the entry
+        let entry = self.entry(vaddr, translation.size, false)?;
+        *entry = 0;
+
+        self.flush(vaddr);
+        Some((translation.page, translation.size))
+    }
+
+    /// Translate `vaddr` into its physical address, page size and permissions
+    pub fn translate(&self, vaddr: VirtAddr) -> Option<Translation> {
+        if !vaddr.is_canonical() {
+            return None;
+        }
+
+        let indices = Self::indices(vaddr);
+        let mut table = self.table;
+
+        // Walk the tables until we find the leaf
+        for (depth, &index) in indices.iter().enumerate() {
+            let entry = unsafe {
+                *mm::phys_ptr::<u64>(table).add(index as usize)
+            };
+
+            if entry & PTE_PRESENT == 0 {
+                return None;
+            }
+
+            // The last level and large pages are leaves
+            if depth == 3 || (depth > 0 && entry & PTE_HUGE != 0) {
+                let size   = PageSize::from_depth(depth);
+                let offset = vaddr.0 & (size.bytes() - 1);
+                let page   = entry & PTE_TABLE_MASK & !(size.bytes() - 1);
+
+                return Some(Translation {
+                    phys:  PhysAddr(page + offset),
+                    page:  PhysAddr(page),
+                    size,
+                    perms: Permissions::from_bits(entry),
+                });
+            }
+
+            table = PhysAddr(entry & PTE_TABLE_MASK);
+        }
+
+        unreachable!();
+    }
+}
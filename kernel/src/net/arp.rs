@@ -0,0 +1,124 @@
+//! ARP for IPv4 over Ethernet.
+//!
+//! Entries are learnt from the requests to us and the replies we get, and
+//! refreshed whenever their host sends any ARP packet.
+
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+use spinlock::SpinLock;
+use cpu::InterruptGuard;
+use time::{ Duration, Instant };
+use super::ip::{ self, Ipv4Addr };
+use super::{ Mac, ETHERTYPE_ARP, ETHERTYPE_IPV4 };
+
+/// Hardware type of Ethernet
+const HTYPE_ETHERNET: u16 = 1;
+
+/// Operations
+const OP_REQUEST: u16 = 1;
+const OP_REPLY:   u16 = 2;
+
+/// Size of a packet for IPv4 over Ethernet
+const PACKET_SIZE: usize = 28;
+
+/// How long entries are used for before they're resolved again
+const ENTRY_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Number of requests sent when resolving an address
+const ATTEMPTS: usize = 4;
+
+/// How long to wait for a reply to a request
+const REPLY_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Entries of the cache, with the time they were learnt
+static CACHE: SpinLock<BTreeMap<Ipv4Addr, (Mac, Instant)>> =
+    SpinLock::new(BTreeMap::new());
+
+/// Returns the MAC address of `addr`, if it's in the cache and not expired
+pub fn lookup(addr: Ipv4Addr) -> Option<Mac> {
+    let _interrupts = InterruptGuard::new();
+    let mut cache = CACHE.lock();
+    let &(mac, learnt) = cache.get(&addr)?;
+    if learnt.elapsed() > ENTRY_LIFETIME {
+        cache.remove(&addr);
+        return None;
+    }
+    Some(mac)
+}
+
+/// Add `addr` at `mac` to the cache
+pub fn insert(addr: Ipv4Addr, mac: Mac) {
+    let _interrupts = InterruptGuard::new();
+    CACHE.lock().insert(addr, (mac, Instant::now()));
+}
+
+/// Send an `op` packet to `target_mac` for `target_ip`, with `dst` as the
+/// destination of the frame
+fn send(op: u16, dst: Mac, target_mac: Mac, target_ip: Ipv4Addr) -> bool {
+    let Some(device) = super::interface() else {
+        return false;
+    };
+
+    let mut packet = Vec::with_capacity(PACKET_SIZE);
+    packet.extend_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+    packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet.extend_from_slice(&[6, 4]);
+    packet.extend_from_slice(&op.to_be_bytes());
+    packet.extend_from_slice(&device.mac().0);
+    packet.extend_from_slice(&ip::addr().0);
+    packet.extend_from_slice(&target_mac.0);
+    packet.extend_from_slice(&target_ip.0);
+
+    super::send_frame(dst, ETHERTYPE_ARP, &packet, None)
+}
+
+/// Returns the MAC address of `addr`, asking for it if it isn't in the
+/// cache. `None` if nobody answered.
+pub fn resolve(addr: Ipv4Addr) -> Option<Mac> {
+    if let Some(mac) = lookup(addr) {
+        return Some(mac);
+    }
+
+    for _ in 0..ATTEMPTS {
+        send(OP_REQUEST, Mac::BROADCAST, Mac::default(), addr);
+
+        // Wait for the reply
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        while Instant::now() < deadline {
+            super::poll();
+            if let Some(mac) = lookup(addr) {
+                return Some(mac);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    None
+}
+
+/// Handle the received ARP `packet`
+pub(super) fn handle(packet: &[u8]) {
+    if packet.len() < PACKET_SIZE ||
+            u16::from_be_bytes([packet[0], packet[1]]) != HTYPE_ETHERNET ||
+            u16::from_be_bytes([packet[2], packet[3]]) != ETHERTYPE_IPV4 ||
+            packet[4] != 6 || packet[5] != 4 {
+        return;
+    }
+
+    let op         = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac = Mac(packet[8..14].try_into().unwrap());
+    let sender_ip  = Ipv4Addr(packet[14..18].try_into().unwrap());
+    let target_ip  = Ipv4Addr(packet[24..28].try_into().unwrap());
+
+    // Learn the hosts talking to us, and refresh the ones we know
+    let ours = ip::addr();
+    let for_us = ours != Ipv4Addr::UNSPECIFIED && target_ip == ours;
+    if sender_ip != Ipv4Addr::UNSPECIFIED &&
+            (for_us || lookup(sender_ip).is_some()) {
+        insert(sender_ip, sender_mac);
+    }
+
+    if for_us && op == OP_REQUEST {
+        send(OP_REPLY, sender_mac, sender_mac, sender_ip);
+    }
+}
@@ -0,0 +1,591 @@
+//! DHCP client.
+//!
+//! `acquire()` gets a lease with DISCOVER, OFFER, REQUEST and ACK and
+//! configures the interface with it, `adopt_pxe()` takes over the one the PXE
+//! ROM got. The lease is renewed with the server that gave it once half of
+//! it has passed, and rebound with any server past seven eighths of it, by
+//! `maintain()` which `net::poll()` calls. A refused or expired lease is
+//! dropped and a new one acquired, which is retried until a server gives
+//! one.
+
+use core::sync::atomic::{ AtomicBool, Ordering };
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+use spinlock::SpinLock;
+use cpu::InterruptGuard;
+use time::{ Duration, Instant };
+use boot_kern_common::PxeLease;
+use super::ip::{ self, Ipv4Addr };
+use super::udp::UdpSocket;
+use super::Mac;
+
+/// Ports of the servers and the clients
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+/// BOOTP operations
+const OP_REQUEST: u8 = 1;
+const OP_REPLY:   u8 = 2;
+
+/// Hardware type of Ethernet
+const HTYPE_ETHERNET: u8 = 1;
+
+/// The server has to broadcast its replies
+const FLAG_BROADCAST: u16 = 1 << 15;
+
+/// Cookie in front of the options
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Offset of the options, after the fixed fields and the cookie
+const OPTIONS_OFFSET: usize = 240;
+
+/// Smallest message BOOTP relays and servers are required to take
+const MIN_MESSAGE_SIZE: usize = 300;
+
+/// Options
+pub const OPTION_PAD:            u8 = 0;
+pub const OPTION_SUBNET_MASK:    u8 = 1;
+pub const OPTION_ROUTER:         u8 = 3;
+pub const OPTION_DNS:            u8 = 6;
+pub const OPTION_HOST_NAME:      u8 = 12;
+pub const OPTION_DOMAIN_NAME:    u8 = 15;
+pub const OPTION_BROADCAST:      u8 = 28;
+pub const OPTION_NTP:            u8 = 42;
+pub const OPTION_VENDOR:         u8 = 43;
+pub const OPTION_REQUESTED_IP:   u8 = 50;
+pub const OPTION_LEASE_TIME:     u8 = 51;
+pub const OPTION_MESSAGE_TYPE:   u8 = 53;
+pub const OPTION_SERVER_ID:      u8 = 54;
+pub const OPTION_PARAMETERS:     u8 = 55;
+pub const OPTION_MAX_SIZE:       u8 = 57;
+pub const OPTION_RENEWAL_TIME:   u8 = 58;
+pub const OPTION_REBINDING_TIME: u8 = 59;
+pub const OPTION_CLIENT_ID:      u8 = 61;
+pub const OPTION_END:            u8 = 255;
+
+/// Message types
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER:    u8 = 2;
+const DHCPREQUEST:  u8 = 3;
+const DHCPACK:      u8 = 5;
+const DHCPNAK:      u8 = 6;
+
+/// Options always asked for
+const PARAMETERS: &[u8] = &[
+    OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS, OPTION_HOST_NAME,
+    OPTION_DOMAIN_NAME, OPTION_BROADCAST, OPTION_NTP, OPTION_VENDOR,
+    OPTION_LEASE_TIME, OPTION_SERVER_ID, OPTION_RENEWAL_TIME,
+    OPTION_REBINDING_TIME,
+];
+
+/// Number of times a message is sent before giving up
+const ATTEMPTS: u32 = 4;
+
+/// Time waited for the first reply, doubled on every attempt
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shortest time between two attempts at extending a lease
+const MIN_RETRY: Duration = Duration::from_secs(60);
+
+/// A lease, along with all the options of the ACK
+#[derive(Clone, Debug)]
+pub struct Lease {
+    /// Address leased
+    pub addr: Ipv4Addr,
+
+    /// Server which gave the lease
+    pub server_id: Ipv4Addr,
+
+    /// Mask of the local subnet
+    pub netmask: Ipv4Addr,
+
+    /// The first router, if there's one
+    pub router: Option<Ipv4Addr>,
+
+    /// Next server to boot from, `siaddr` of the ACK
+    pub next_server: Option<Ipv4Addr>,
+
+    /// When the lease was obtained
+    pub obtained: Instant,
+
+    /// Duration of the lease, `None` if it's infinite
+    pub lease_time: Option<Duration>,
+
+    /// Time after which the lease is renewed with `server_id`
+    pub renewal_time: Option<Duration>,
+
+    /// Time after which the lease is rebound with any server
+    pub rebinding_time: Option<Duration>,
+
+    /// The options of the ACK, by code
+    pub options: BTreeMap<u8, Vec<u8>>,
+}
+
+impl Lease {
+    /// Returns the value of option `code`, if the server sent it
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options.get(&code).map(|value| value.as_slice())
+    }
+
+    /// Returns the value of the vendor specific sub-option `code`, if the
+    /// vendor option has the usual encapsulated format and the server sent
+    /// it
+    pub fn vendor_option(&self, code: u8) -> Option<Vec<u8>> {
+        parse_options(self.option(OPTION_VENDOR)?).remove(&code)
+    }
+}
+
+/// The lease the interface is configured with, if it got one from DHCP
+static LEASE: SpinLock<Option<Lease>> = SpinLock::new(None);
+
+/// When `maintain()` has to act on the lease next, or to try acquiring one
+/// again after losing it. `None` with an infinite lease, or before any.
+static NEXT_ACTION: SpinLock<Option<Instant>> = SpinLock::new(None);
+
+/// Whether `maintain()` is running. Waiting for a reply polls the stack,
+/// which would call it again.
+static MAINTAINING: AtomicBool = AtomicBool::new(false);
+
+/// Returns the lease the interface is configured with, if there's one
+pub fn lease() -> Option<Lease> {
+    let _interrupts = InterruptGuard::new();
+    LEASE.lock().clone()
+}
+
+/// Parse the `code`, length, value options of `options`, concatenating
+/// repeated ones as RFC 3396 wants
+fn parse_options(mut options: &[u8]) -> BTreeMap<u8, Vec<u8>> {
+    let mut parsed: BTreeMap<u8, Vec<u8>> = BTreeMap::new();
+    while let [code, rest @ ..] = options {
+        match *code {
+            OPTION_PAD => { options = rest; continue; }
+            OPTION_END => break,
+            _          => {}
+        }
+
+        let [len, rest @ ..] = rest else { break; };
+        let Some(value) = rest.get(..*len as usize) else { break; };
+        parsed.entry(*code).or_default().extend_from_slice(value);
+        options = &rest[*len as usize..];
+    }
+    parsed
+}
+
+/// A reply from a server
+struct Reply {
+    /// Message type
+    kind: u8,
+
+    /// `yiaddr`, the address offered or acknowledged
+    yiaddr: Ipv4Addr,
+
+    /// `siaddr`, the next server to boot from
+    siaddr: Ipv4Addr,
+
+    /// The options
+    options: BTreeMap<u8, Vec<u8>>,
+}
+
+impl Reply {
+    /// Returns the address in option `code`
+    fn addr(&self, code: u8) -> Option<Ipv4Addr> {
+        Some(Ipv4Addr(self.options.get(&code)?.get(..4)?.try_into().ok()?))
+    }
+
+    /// Returns the number of seconds in option `code`
+    fn seconds(&self, code: u8) -> Option<u32> {
+        Some(u32::from_be_bytes(
+            self.options.get(&code)?.get(..4)?.try_into().ok()?))
+    }
+}
+
+/// How the servers answered a request
+enum Answer {
+    /// The address was acknowledged, with the new lease
+    Ack(Lease),
+
+    /// The address was refused and can't be used anymore
+    Nak,
+
+    /// Nobody answered
+    Silence,
+}
+
+/// The state of an exchange with the servers
+struct Client {
+    /// The socket bound to the client port
+    socket: UdpSocket,
+
+    /// MAC address of the interface
+    mac: Mac,
+
+    /// Transaction ID
+    xid: u32,
+}
+
+impl Client {
+    /// Bind the client port, `None` if there's no interface or another
+    /// client has it
+    fn new() -> Option<Self> {
+        let mac = super::interface()?.mac();
+
+        // Mix the MAC in so clients booted at once pick different IDs
+        let seed = mac.0.iter().fold(0u32, |acc, &b| acc.rotate_left(8) ^
+            b as u32);
+        Some(Self {
+            socket: UdpSocket::bind(CLIENT_PORT)?,
+            mac,
+            xid: cpu::rdtsc() as u32 ^ seed,
+        })
+    }
+
+    /// Build a message of `kind` with `ciaddr` and the `extra` options
+    fn message(&self, kind: u8, ciaddr: Ipv4Addr, extra: &[(u8, &[u8])])
+            -> Vec<u8> {
+        let mut msg = Vec::with_capacity(MIN_MESSAGE_SIZE);
+        msg.extend_from_slice(&[OP_REQUEST, HTYPE_ETHERNET, 6, 0]);
+        msg.extend_from_slice(&self.xid.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes());
+
+        // Broadcast replies only reach us before we have an address
+        let flags = if ciaddr == Ipv4Addr::UNSPECIFIED { FLAG_BROADCAST }
+            else { 0 };
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&ciaddr.0);
+        msg.resize(28, 0);
+        msg.extend_from_slice(&self.mac.0);
+        msg.resize(236, 0);
+        msg.extend_from_slice(&MAGIC_COOKIE);
+
+        // The options, identifying the client by its MAC
+        let mut client_id = [HTYPE_ETHERNET; 7];
+        client_id[1..].copy_from_slice(&self.mac.0);
+        // The largest message counts the IP and UDP headers
+        let max_size = (ip::HEADER_SIZE + ip::MAX_PAYLOAD) as u16;
+        let max_size = max_size.to_be_bytes();
+        let options = [
+            (OPTION_MESSAGE_TYPE, &[kind][..]),
+            (OPTION_CLIENT_ID,    &client_id[..]),
+            (OPTION_MAX_SIZE,     &max_size[..]),
+            (OPTION_PARAMETERS,   PARAMETERS),
+        ];
+        for (code, value) in options.iter().chain(extra) {
+            msg.extend_from_slice(&[*code, value.len() as u8]);
+            msg.extend_from_slice(value);
+        }
+        msg.push(OPTION_END);
+
+        if msg.len() < MIN_MESSAGE_SIZE {
+            msg.resize(MIN_MESSAGE_SIZE, OPTION_PAD);
+        }
+        msg
+    }
+
+    /// Parse `msg`, if it's a reply to us
+    fn parse(&self, msg: &[u8]) -> Option<Reply> {
+        if msg.len() < OPTIONS_OFFSET || msg[0] != OP_REPLY ||
+                msg[4..8] != self.xid.to_be_bytes() ||
+                msg[28..34] != self.mac.0 ||
+                msg[236..240] != MAGIC_COOKIE {
+            return None;
+        }
+
+        let options = parse_options(&msg[OPTIONS_OFFSET..]);
+        Some(Reply {
+            kind:    *options.get(&OPTION_MESSAGE_TYPE)?.first()?,
+            yiaddr:  Ipv4Addr(msg[16..20].try_into().ok()?),
+            siaddr:  Ipv4Addr(msg[20..24].try_into().ok()?),
+            options,
+        })
+    }
+
+    /// Send `msg` to `dst` until a reply `accept` takes comes, with the
+    /// timeout doubling on every attempt
+    fn exchange(&self, msg: &[u8], dst: Ipv4Addr,
+                accept: impl Fn(&Reply) -> bool) -> Option<Reply> {
+        let mut timeout = INITIAL_TIMEOUT;
+        for _ in 0..ATTEMPTS {
+            self.socket.send_to(msg, dst, SERVER_PORT);
+
+            let deadline = Instant::now() + timeout;
+            while Instant::now() < deadline {
+                let left = deadline.duration_since(Instant::now());
+                let Some(datagram) = self.socket.recv(left) else {
+                    break;
+                };
+                if let Some(reply) = self.parse(&datagram.data) {
+                    if accept(&reply) {
+                        return Some(reply);
+                    }
+                }
+            }
+            timeout *= 2;
+        }
+
+        None
+    }
+
+    /// Request `addr` from `server_id` and wait for its answer, sending to
+    /// `dst`. `ciaddr` is set when renewing or rebinding.
+    fn request(&self, addr: Ipv4Addr, server_id: Option<Ipv4Addr>,
+               ciaddr: Ipv4Addr, dst: Ipv4Addr) -> Answer {
+        // Selecting an offer names the address and the server, renewing and
+        // rebinding only sets `ciaddr`
+        let mut extra: Vec<(u8, &[u8])> = Vec::new();
+        if ciaddr == Ipv4Addr::UNSPECIFIED {
+            extra.push((OPTION_REQUESTED_IP, &addr.0));
+            if let Some(server_id) = &server_id {
+                extra.push((OPTION_SERVER_ID, &server_id.0));
+            }
+        }
+
+        let msg = self.message(DHCPREQUEST, ciaddr, &extra);
+        let reply = self.exchange(&msg, dst, |reply| {
+            (reply.kind == DHCPACK || reply.kind == DHCPNAK) &&
+                server_id.is_none_or(|id| reply.addr(OPTION_SERVER_ID)
+                    .is_none_or(|reply_id| reply_id == id))
+        });
+
+        match reply {
+            Some(reply) if reply.kind == DHCPNAK => Answer::Nak,
+            Some(reply) if reply.yiaddr == addr  => {
+                Answer::Ack(lease_from_ack(reply, server_id))
+            }
+            _ => Answer::Silence,
+        }
+    }
+}
+
+/// Returns the mask of the class of `addr`, for leases without a mask
+fn class_netmask(addr: Ipv4Addr) -> Ipv4Addr {
+    Ipv4Addr(match addr.0[0] {
+        0..=127   => [255, 0, 0, 0],
+        128..=191 => [255, 255, 0, 0],
+        _         => [255, 255, 255, 0],
+    })
+}
+
+/// Build the lease of `ack`, which came from `server_id` if it's known
+fn lease_from_ack(ack: Reply, server_id: Option<Ipv4Addr>) -> Lease {
+    let seconds = |code| ack.seconds(code)
+        .filter(|&secs| secs != u32::MAX)
+        .map(|secs| Duration::from_secs(secs as u64));
+
+    // The timers default to half and seven eighths of the lease
+    let lease_time = seconds(OPTION_LEASE_TIME);
+    let renewal_time = seconds(OPTION_RENEWAL_TIME)
+        .or(lease_time.map(|time| time / 2));
+    let rebinding_time = seconds(OPTION_REBINDING_TIME)
+        .or(lease_time.map(|time| time * 7 / 8));
+
+    // Without a subnet mask, use the one of the class of the address
+    let netmask = ack.addr(OPTION_SUBNET_MASK)
+        .unwrap_or_else(|| class_netmask(ack.yiaddr));
+
+    Lease {
+        addr:        ack.yiaddr,
+        server_id:   ack.addr(OPTION_SERVER_ID).or(server_id)
+            .unwrap_or(Ipv4Addr::UNSPECIFIED),
+        netmask,
+        router:      ack.addr(OPTION_ROUTER),
+        next_server: Some(ack.siaddr)
+            .filter(|&addr| addr != Ipv4Addr::UNSPECIFIED),
+        obtained:    Instant::now(),
+        lease_time,
+        renewal_time,
+        rebinding_time,
+        options:     ack.options,
+    }
+}
+
+/// Configure the interface with `lease` and keep it, or remove the
+/// configuration with `None`. The lease is maintained from its first timer.
+fn apply(lease: Option<Lease>) {
+    let config = lease.as_ref().map(|lease| ip::Config {
+        addr:    lease.addr,
+        netmask: lease.netmask,
+        gateway: lease.router,
+        server:  lease.next_server.or(Some(lease.server_id))
+            .filter(|&addr| addr != Ipv4Addr::UNSPECIFIED),
+    });
+    ip::configure(config);
+
+    let next = lease.as_ref().and_then(|lease| {
+        lease.renewal_time.or(lease.rebinding_time).or(lease.lease_time)
+            .map(|time| lease.obtained + time)
+    });
+
+    let _interrupts = InterruptGuard::new();
+    *LEASE.lock() = lease;
+    *NEXT_ACTION.lock() = next;
+}
+
+/// Take over the lease the PXE ROM got and configure the interface with it.
+/// The lease is counted from now, a little after the server gave it. Returns
+/// the lease, `None` if it has no address.
+pub fn adopt_pxe(pxe: &PxeLease) -> Option<Lease> {
+    let nonzero = |addr: [u8; 4]| {
+        Some(Ipv4Addr(addr)).filter(|&addr| addr != Ipv4Addr::UNSPECIFIED)
+    };
+    let addr = nonzero(pxe.client_ip)?;
+
+    // Without a lease time, the lease is taken as infinite
+    let lease_time = Some(pxe.lease_time)
+        .filter(|&secs| secs != 0 && secs != u32::MAX)
+        .map(|secs| Duration::from_secs(secs as u64));
+
+    let lease = Lease {
+        addr,
+        server_id:      nonzero(pxe.server_id)
+            .unwrap_or(Ipv4Addr::UNSPECIFIED),
+        netmask:        nonzero(pxe.subnet_mask)
+            .unwrap_or_else(|| class_netmask(addr)),
+        router:         nonzero(pxe.gateway),
+        next_server:    nonzero(pxe.server_ip),
+        obtained:       Instant::now(),
+        lease_time,
+        renewal_time:   lease_time.map(|time| time / 2),
+        rebinding_time: lease_time.map(|time| time * 7 / 8),
+        options:        BTreeMap::new(),
+    };
+
+    print!("DHCP: reusing the PXE lease of {} from {}\n", lease.addr,
+           lease.server_id);
+    apply(Some(lease.clone()));
+    Some(lease)
+}
+
+/// Get a lease from any server and configure the interface with it. Returns
+/// the lease, `None` if no server gave one.
+pub fn acquire() -> Option<Lease> {
+    let client = Client::new()?;
+
+    // The configuration is dropped, so the replies to the broadcasts are
+    // taken whatever their destination
+    apply(None);
+
+    for _ in 0..ATTEMPTS {
+        // Take the first offer
+        let discover = client.message(DHCPDISCOVER, Ipv4Addr::UNSPECIFIED,
+                                      &[]);
+        let offer = client.exchange(&discover, Ipv4Addr::BROADCAST,
+                                    |reply| reply.kind == DHCPOFFER)?;
+
+        // Request it, starting over on a NAK
+        if let Answer::Ack(lease) = client.request(offer.yiaddr,
+                offer.addr(OPTION_SERVER_ID), Ipv4Addr::UNSPECIFIED,
+                Ipv4Addr::BROADCAST) {
+            print!("DHCP: leased {} from {}\n", lease.addr, lease.server_id);
+            apply(Some(lease.clone()));
+            return Some(lease);
+        }
+    }
+
+    None
+}
+
+/// Acquire a new lease after losing the old one, and have `maintain()` try
+/// again later if no server gives one
+fn reacquire() -> Option<Lease> {
+    let lease = acquire();
+    if lease.is_none() {
+        print!("DHCP: no lease, trying again in {:?}\n", MIN_RETRY);
+
+        let _interrupts = InterruptGuard::new();
+        *NEXT_ACTION.lock() = Some(Instant::now() + MIN_RETRY);
+    }
+    lease
+}
+
+/// Handle the `answer` to extending `lease`, which had to be done by
+/// `limit`. An ACK replaces the lease. A NAK means the address can't be used
+/// anymore, so it's dropped and a new lease acquired. Without an answer, the
+/// next attempt is after half of the time left, as RFC 2131 wants.
+fn extended(lease: &Lease, answer: Answer, limit: Option<Duration>)
+        -> Option<Lease> {
+    match answer {
+        Answer::Ack(new) => {
+            apply(Some(new.clone()));
+            Some(new)
+        }
+        Answer::Nak => {
+            print!("DHCP: lease of {} refused, starting over\n", lease.addr);
+            apply(None);
+            reacquire()
+        }
+        Answer::Silence => {
+            let now   = Instant::now();
+            let limit = limit.map(|limit| lease.obtained + limit);
+            let retry = limit.map_or(MIN_RETRY, |limit| {
+                (limit.duration_since(now) / 2).max(MIN_RETRY)
+            });
+            let next = limit.map_or(now + retry,
+                                    |limit| limit.min(now + retry));
+
+            let _interrupts = InterruptGuard::new();
+            *NEXT_ACTION.lock() = Some(next);
+            None
+        }
+    }
+}
+
+/// Extend the lease with the server which gave it, or with any server if
+/// it's unknown. Returns the new lease, `None` if the server didn't answer
+/// and the old one is kept. The address is dropped on a NAK.
+pub fn renew() -> Option<Lease> {
+    let lease = lease()?;
+    if lease.server_id == Ipv4Addr::UNSPECIFIED {
+        return rebind();
+    }
+
+    let client = Client::new()?;
+    let answer = client.request(lease.addr, Some(lease.server_id),
+                                lease.addr, lease.server_id);
+    drop(client);
+    extended(&lease, answer, lease.rebinding_time)
+}
+
+/// Extend the lease with any server. Returns the new lease, `None` if no
+/// server answered and the old one is kept. The address is dropped on a
+/// NAK.
+pub fn rebind() -> Option<Lease> {
+    let lease = lease()?;
+    let client = Client::new()?;
+    let answer = client.request(lease.addr, None, lease.addr,
+                                Ipv4Addr::BROADCAST);
+    drop(client);
+    extended(&lease, answer, lease.lease_time)
+}
+
+/// Renew, rebind or reacquire the lease as its timers expire, or acquire one
+/// again if it was lost. Called on every poll of the stack, it returns right
+/// away until a timer is due.
+pub fn maintain() {
+    {
+        let _interrupts = InterruptGuard::new();
+        let due = NEXT_ACTION.lock().is_some_and(|next| Instant::now() >= next);
+        if !due {
+            return;
+        }
+    }
+    if MAINTAINING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    if let Some(lease) = lease() {
+        let elapsed = lease.obtained.elapsed();
+        if lease.lease_time.is_some_and(|time| elapsed >= time) {
+            print!("DHCP: lease of {} expired\n", lease.addr);
+            apply(None);
+            reacquire();
+        } else if lease.rebinding_time.is_some_and(|time| elapsed >= time) {
+            rebind();
+        } else {
+            renew();
+        }
+    } else {
+        reacquire();
+    }
+
+    MAINTAINING.store(false, Ordering::SeqCst);
+}
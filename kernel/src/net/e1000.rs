@@ -0,0 +1,504 @@
+//! Intel 8254x (e1000) and 8257x (e1000e) driver.
+//!
+//! Legacy descriptors in one receive and one transmit ring, with a buffer of
+//! `BUFFER_SIZE` bytes for every descriptor. The MAC address comes from the
+//! EEPROM, or from the receive address registers on parts without one.
+//! Received frames are taken off the ring by the MSI handler when the device
+//! has MSI, and by `recv()` otherwise. Checksums are filled in in software.
+
+use core::ptr;
+use core::sync::atomic::{ fence, Ordering };
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use spinlock::SpinLock;
+use cpu::InterruptGuard;
+use time::{ Duration, Instant };
+use crate::core_locals;
+use crate::interrupts::InterruptState;
+use crate::mm::{ self, PhysAddr };
+use crate::pci::{ Bar, Device };
+use crate::pci::msi;
+use super::{ Mac, NetDevice, TxChecksum, MAX_FRAME_SIZE };
+use super::{ PendingFrames, InterruptDriven };
+
+/// Intel's vendor ID
+const VENDOR_INTEL: u16 = 0x8086;
+
+/// How the EEPROM is read through `REG_EERD`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Eeprom {
+    /// 8254x layout: address at bit 8, done at bit 4
+    Legacy,
+
+    /// 82541 and later layout: address at bit 2, done at bit 1
+    Extended,
+}
+
+/// The devices supported, with their names and EEPROM interfaces
+const DEVICES: &[(u16, &str, Eeprom)] = &[
+    (0x1004, "82543GC", Eeprom::Legacy),
+    (0x100e, "82540EM", Eeprom::Legacy),
+    (0x100f, "82545EM", Eeprom::Legacy),
+    (0x1026, "82545GM", Eeprom::Legacy),
+    (0x1076, "82541GI", Eeprom::Extended),
+    (0x107c, "82541PI", Eeprom::Extended),
+    (0x105e, "82571EB", Eeprom::Extended),
+    (0x107d, "82572EI", Eeprom::Extended),
+    (0x10d3, "82574L",  Eeprom::Extended),
+    (0x150c, "82583V",  Eeprom::Extended),
+];
+
+/// Registers
+const REG_CTRL:   usize = 0x0000;
+const REG_STATUS: usize = 0x0008;
+const REG_EERD:   usize = 0x0014;
+const REG_ICR:    usize = 0x00c0;
+const REG_IMS:    usize = 0x00d0;
+const REG_IMC:    usize = 0x00d8;
+const REG_RCTL:   usize = 0x0100;
+const REG_TCTL:   usize = 0x0400;
+const REG_TIPG:   usize = 0x0410;
+const REG_RDBAL:  usize = 0x2800;
+const REG_RDBAH:  usize = 0x2804;
+const REG_RDLEN:  usize = 0x2808;
+const REG_RDH:    usize = 0x2810;
+const REG_RDT:    usize = 0x2818;
+const REG_TDBAL:  usize = 0x3800;
+const REG_TDBAH:  usize = 0x3804;
+const REG_TDLEN:  usize = 0x3808;
+const REG_TDH:    usize = 0x3810;
+const REG_TDT:    usize = 0x3818;
+const REG_MTA:    usize = 0x5200;
+const REG_RAL:    usize = 0x5400;
+const REG_RAH:    usize = 0x5404;
+
+/// Device control bits
+const CTRL_LRST:    u32 = 1 << 3;
+const CTRL_ASDE:    u32 = 1 << 5;
+const CTRL_SLU:     u32 = 1 << 6;
+const CTRL_ILOS:    u32 = 1 << 7;
+const CTRL_RST:     u32 = 1 << 26;
+const CTRL_VME:     u32 = 1 << 30;
+const CTRL_PHY_RST: u32 = 1 << 31;
+
+/// The link is up, in the device status
+const STATUS_LU: u32 = 1 << 1;
+
+/// Starts an EEPROM read
+const EERD_START: u32 = 1 << 0;
+
+/// Interrupt causes: link status change, receive descriptors running low,
+/// receive overrun and receive timer
+const INT_LSC:    u32 = 1 << 2;
+const INT_RXDMT0: u32 = 1 << 4;
+const INT_RXO:    u32 = 1 << 6;
+const INT_RXT0:   u32 = 1 << 7;
+
+/// Receive control bits: enable, accept broadcasts and strip the CRC. The
+/// buffer size bits are left 0, for 2048 byte buffers.
+const RCTL_EN:    u32 = 1 << 1;
+const RCTL_BAM:   u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+
+/// Transmit control bits: enable and pad short packets, with the collision
+/// threshold and the full duplex collision distance
+const TCTL_EN:   u32 = 1 << 1;
+const TCTL_PSP:  u32 = 1 << 3;
+const TCTL_CT:   u32 = 0x0f << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+/// Inter packet gap for copper links
+const TIPG_COPPER: u32 = 10 | (8 << 10) | (6 << 20);
+
+/// The receive address is valid, in `REG_RAH`
+const RAH_AV: u32 = 1 << 31;
+
+/// Number of dwords of the multicast table
+const MTA_ENTRIES: usize = 128;
+
+/// Number of descriptors in a ring
+const RING_SIZE: usize = 256;
+
+/// Size of a descriptor
+const DESC_SIZE: usize = 16;
+
+/// Size of the buffer of a descriptor, matching the receive control
+const BUFFER_SIZE: usize = 2048;
+
+/// Descriptor status bits: descriptor done and end of packet
+const DESC_DD:  u8 = 1 << 0;
+const DESC_EOP: u8 = 1 << 1;
+
+/// Transmit descriptor commands: end of packet, insert the CRC and report
+/// the status
+const CMD_EOP:  u8 = 1 << 0;
+const CMD_IFCS: u8 = 1 << 1;
+const CMD_RS:   u8 = 1 << 3;
+
+/// Longest time a reset or an EEPROM read takes
+const TIMEOUT: Duration = Duration::from_millis(10);
+
+/// The memory mapped registers of a device
+#[derive(Clone, Copy)]
+struct Regs(*mut u8);
+
+impl Regs {
+    /// Read register `reg`
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.0.add(reg) as *const u32) }
+    }
+
+    /// Write `val` to register `reg`
+    fn write(&self, reg: usize, val: u32) {
+        unsafe { ptr::write_volatile(self.0.add(reg) as *mut u32, val); }
+    }
+
+    /// Spin until `cond` holds for register `reg`, giving up after `TIMEOUT`.
+    /// Returns the last value read, if `cond` held.
+    fn wait(&self, reg: usize, cond: impl Fn(u32) -> bool) -> Option<u32> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let val = self.read(reg);
+            if cond(val) {
+                return Some(val);
+            }
+            if Instant::now() > deadline {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Read EEPROM word `word`
+    fn eeprom_read(&self, eeprom: Eeprom, word: u8) -> Option<u16> {
+        let (shift, done) = match eeprom {
+            Eeprom::Legacy   => (8, 1 << 4),
+            Eeprom::Extended => (2, 1 << 1),
+        };
+
+        self.write(REG_EERD, ((word as u32) << shift) | EERD_START);
+        let val = self.wait(REG_EERD, |val| val & done != 0)?;
+        Some((val >> 16) as u16)
+    }
+}
+
+/// A descriptor ring with a buffer for every descriptor
+struct Ring {
+    /// Physical address of the descriptors
+    descs: u64,
+
+    /// Physical address of the buffers, `BUFFER_SIZE` bytes each
+    buffers: u64,
+}
+
+impl Ring {
+    /// Allocate a ring, pointing every descriptor to its buffer
+    fn new() -> Option<Self> {
+        let descs   = mm::alloc_phys((RING_SIZE * DESC_SIZE) as u64, 4096)?;
+        let buffers = mm::alloc_phys((RING_SIZE * BUFFER_SIZE) as u64, 4096)?;
+
+        let ring = Self { descs: descs.0, buffers: buffers.0 };
+        for desc in 0..RING_SIZE {
+            unsafe {
+                ptr::write_volatile(ring.desc(desc) as *mut u64,
+                    ring.buffers + (desc * BUFFER_SIZE) as u64);
+            }
+        }
+
+        Some(ring)
+    }
+
+    /// Returns a pointer to descriptor `desc`
+    fn desc(&self, desc: usize) -> *mut u8 {
+        mm::phys_ptr(PhysAddr(self.descs + (desc * DESC_SIZE) as u64))
+    }
+
+    /// Returns a pointer to the buffer of descriptor `desc`
+    fn buffer(&self, desc: usize) -> *mut u8 {
+        mm::phys_ptr(PhysAddr(self.buffers + (desc * BUFFER_SIZE) as u64))
+    }
+
+    /// Read the status byte of descriptor `desc`
+    fn status(&self, desc: usize) -> u8 {
+        unsafe { ptr::read_volatile(self.desc(desc).add(12)) }
+    }
+}
+
+/// The rings of a device
+struct Rings {
+    rx: Ring,
+    tx: Ring,
+
+    /// Next receive descriptor the device hands back
+    rx_next: usize,
+
+    /// Next transmit descriptor to fill
+    tx_tail: usize,
+
+    /// Oldest transmit descriptor the device may not be done with
+    tx_clean: usize,
+
+    /// Frames taken off the receive ring and not returned by `recv()` yet
+    pending: PendingFrames,
+}
+
+impl Rings {
+    /// Move the frames received to `pending` and give their descriptors back
+    /// to the device
+    fn drain_rx(&mut self, regs: Regs) {
+        let mut returned = None;
+        loop {
+            let desc = self.rx_next;
+            let status = self.rx.status(desc);
+            if status & DESC_DD == 0 {
+                break;
+            }
+            fence(Ordering::Acquire);
+
+            // Frames spanning descriptors or with errors are dropped
+            let (len, errors) = unsafe {
+                let ptr = self.rx.desc(desc);
+                (ptr::read_volatile(ptr.add(8) as *const u16) as usize,
+                 ptr::read_volatile(ptr.add(13)))
+            };
+            if status & DESC_EOP != 0 && errors == 0 && len <= BUFFER_SIZE {
+                let data = unsafe {
+                    core::slice::from_raw_parts(self.rx.buffer(desc), len)
+                };
+
+                self.pending.push(data.to_vec());
+            }
+
+            unsafe { ptr::write_volatile(self.rx.desc(desc).add(12), 0u8); }
+            returned = Some(desc);
+            self.rx_next = (desc + 1) % RING_SIZE;
+        }
+
+        // The tail is the last descriptor the device may fill
+        if let Some(desc) = returned {
+            regs.write(REG_RDT, desc as u32);
+        }
+    }
+
+    /// Reclaim the descriptors of the frames the device has sent
+    fn reclaim_tx(&mut self) {
+        while self.tx_clean != self.tx_tail &&
+                self.tx.status(self.tx_clean) & DESC_DD != 0 {
+            self.tx_clean = (self.tx_clean + 1) % RING_SIZE;
+        }
+    }
+}
+
+/// An e1000 device
+struct E1000 {
+    /// The registers of the device
+    regs: Regs,
+
+    /// MAC address of the device
+    mac: Mac,
+
+    /// The rings
+    rings: SpinLock<Rings>,
+}
+
+// The registers are only read outside of the lock, and written to under it
+unsafe impl Send for E1000 {}
+unsafe impl Sync for E1000 {}
+
+/// The devices with interrupt driven receive rings
+static INTERRUPT_DRIVEN: InterruptDriven<E1000> = InterruptDriven::new();
+
+/// Acknowledge the interrupt and drain the receive ring of the device whose
+/// MSI fired
+fn interrupt(state: &mut InterruptState) {
+    INTERRUPT_DRIVEN.dispatch(state.vector as u8, |device| {
+        device.regs.read(REG_ICR);
+        device.rings.lock().drain_rx(device.regs);
+    });
+}
+
+impl NetDevice for E1000 {
+    fn mac(&self) -> Mac {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.regs.read(REG_STATUS) & STATUS_LU != 0
+    }
+
+    fn send(&self, frame: &[u8], checksum: Option<TxChecksum>) -> bool {
+        assert!(frame.len() <= MAX_FRAME_SIZE, "Sending an oversized frame.");
+
+        let _interrupts = InterruptGuard::new();
+        let mut rings = self.rings.lock();
+        rings.reclaim_tx();
+
+        // One descriptor is always left empty to tell a full ring apart
+        let desc = rings.tx_tail;
+        let next = (desc + 1) % RING_SIZE;
+        if next == rings.tx_clean {
+            return false;
+        }
+
+        // Copy the frame to the buffer and fill in its checksum
+        let data = unsafe {
+            core::slice::from_raw_parts_mut(rings.tx.buffer(desc), frame.len())
+        };
+        data.copy_from_slice(frame);
+        if let Some(csum) = checksum {
+            super::complete_checksum(data, csum);
+        }
+
+        unsafe {
+            let ptr = rings.tx.desc(desc);
+            ptr::write_volatile(ptr.add(8) as *mut u16, frame.len() as u16);
+            ptr::write_volatile(ptr.add(11), CMD_EOP | CMD_IFCS | CMD_RS);
+            ptr::write_volatile(ptr.add(12), 0u8);
+        }
+
+        // Hand the descriptor to the device
+        fence(Ordering::SeqCst);
+        rings.tx_tail = next;
+        self.regs.write(REG_TDT, next as u32);
+        true
+    }
+
+    fn recv(&self) -> Option<Vec<u8>> {
+        let _interrupts = InterruptGuard::new();
+        let mut rings = self.rings.lock();
+        if rings.pending.is_empty() {
+            rings.drain_rx(self.regs);
+        }
+        rings.pending.pop()
+    }
+}
+
+/// Reset the device, leaving its interrupts masked. Returns whether the
+/// reset completed.
+fn reset(regs: Regs) -> bool {
+    regs.write(REG_IMC, !0);
+    regs.write(REG_CTRL, regs.read(REG_CTRL) | CTRL_RST);
+    time::sleep(Duration::from_millis(1));
+    if regs.wait(REG_CTRL, |ctrl| ctrl & CTRL_RST == 0).is_none() {
+        return false;
+    }
+
+    // The reset unmasks nothing, but may leave causes pending
+    regs.write(REG_IMC, !0);
+    regs.read(REG_ICR);
+    true
+}
+
+/// Read the MAC address from the EEPROM, or from the first receive address
+/// when there's no EEPROM
+fn read_mac(regs: Regs, eeprom: Eeprom) -> Option<Mac> {
+    let mut mac = [0u8; 6];
+    let words: Option<Vec<u16>> =
+        (0..3).map(|word| regs.eeprom_read(eeprom, word)).collect();
+    if let Some(words) = words {
+        for (bytes, word) in mac.chunks_exact_mut(2).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        return Some(Mac(mac));
+    }
+
+    let (low, high) = (regs.read(REG_RAL), regs.read(REG_RAH));
+    if high & RAH_AV == 0 {
+        return None;
+    }
+    mac[..4].copy_from_slice(&low.to_le_bytes());
+    mac[4..].copy_from_slice(&high.to_le_bytes()[..2]);
+    Some(Mac(mac))
+}
+
+/// Set up `device` if it's a supported e1000 device and register it
+pub fn probe(device: &Device) {
+    if device.vendor != VENDOR_INTEL {
+        return;
+    }
+    let Some(&(_, name, eeprom)) =
+            DEVICES.iter().find(|(id, ..)| *id == device.device) else {
+        return;
+    };
+
+    // The registers are in the memory BAR 0
+    let Some(Bar::Memory { addr, size, .. }) = device.bars[0] else {
+        print!("e1000: {} has no register BAR\n", device.addr);
+        return;
+    };
+    device.enable();
+    let regs = Regs(mm::map_mmio(PhysAddr(addr), size)
+        .expect("Failed to map e1000 registers").0 as *mut u8);
+
+    if !reset(regs) {
+        print!("e1000: {} didn't come out of reset\n", device.addr);
+        return;
+    }
+
+    let Some(mac) = read_mac(regs, eeprom) else {
+        print!("e1000: {} has no MAC address\n", device.addr);
+        return;
+    };
+
+    // Bring the link up, with the speed and duplex autodetected
+    let ctrl = regs.read(REG_CTRL);
+    regs.write(REG_CTRL, (ctrl | CTRL_SLU | CTRL_ASDE) &
+               !(CTRL_LRST | CTRL_ILOS | CTRL_VME | CTRL_PHY_RST));
+
+    // Only take frames for our own MAC address and broadcasts
+    let m = &mac.0;
+    regs.write(REG_RAL, u32::from_le_bytes([m[0], m[1], m[2], m[3]]));
+    regs.write(REG_RAH, u32::from_le_bytes([m[4], m[5], 0, 0]) | RAH_AV);
+    for entry in 0..MTA_ENTRIES {
+        regs.write(REG_MTA + entry * 4, 0);
+    }
+
+    let (Some(rx), Some(tx)) = (Ring::new(), Ring::new()) else {
+        print!("e1000: couldn't allocate the rings of {}\n", device.addr);
+        return;
+    };
+
+    // Give all but one of the receive descriptors to the device
+    let ring_len = (RING_SIZE * DESC_SIZE) as u32;
+    regs.write(REG_RDBAL, rx.descs as u32);
+    regs.write(REG_RDBAH, (rx.descs >> 32) as u32);
+    regs.write(REG_RDLEN, ring_len);
+    regs.write(REG_RDH, 0);
+    regs.write(REG_RDT, RING_SIZE as u32 - 1);
+    regs.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+    // The transmit ring starts empty
+    regs.write(REG_TDBAL, tx.descs as u32);
+    regs.write(REG_TDBAH, (tx.descs >> 32) as u32);
+    regs.write(REG_TDLEN, ring_len);
+    regs.write(REG_TDH, 0);
+    regs.write(REG_TDT, 0);
+    regs.write(REG_TIPG, TIPG_COPPER);
+    regs.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+
+    // Interrupt on received frames on the current core, if there's MSI. The
+    // device doesn't raise any until they're unmasked below.
+    let vector = msi::enable_msi(device, core_locals::core_id(), interrupt);
+
+    let net: &'static E1000 = Box::leak(Box::new(E1000 {
+        regs,
+        mac,
+        rings: SpinLock::new(Rings {
+            rx,
+            tx,
+            rx_next:  0,
+            tx_tail:  0,
+            tx_clean: 0,
+            pending:  PendingFrames::new(),
+        }),
+    }));
+
+    if let Some(vector) = vector {
+        INTERRUPT_DRIVEN.add(vector, net);
+        regs.write(REG_IMS, INT_LSC | INT_RXDMT0 | INT_RXO | INT_RXT0);
+    }
+
+    print!("e1000: {} is an {}, {}\n", device.addr, name,
+           if vector.is_some() { "interrupt driven" } else { "polled" });
+    super::register(net);
+}
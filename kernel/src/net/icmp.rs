@@ -0,0 +1,29 @@
+//! ICMP, only answering echo requests.
+
+use super::ip::{ self, Ipv4Addr, PROTO_ICMP };
+
+/// Message types
+const TYPE_ECHO_REPLY:   u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+/// Size of the header of an echo message
+const ECHO_HEADER_SIZE: usize = 8;
+
+/// Handle the received ICMP `message` from `src` to `dst`
+pub(super) fn handle(src: Ipv4Addr, dst: Ipv4Addr, message: &[u8]) {
+    if message.len() < ECHO_HEADER_SIZE || super::checksum(message, 0) != 0 {
+        return;
+    }
+
+    // Answer echo requests to our own address, not broadcast ones
+    if message[0] == TYPE_ECHO_REQUEST && message[1] == 0 && dst == ip::addr()
+    {
+        let mut reply = message.to_vec();
+        reply[0] = TYPE_ECHO_REPLY;
+        reply[2..4].copy_from_slice(&[0, 0]);
+        let sum = super::checksum(&reply, 0);
+        reply[2..4].copy_from_slice(&sum.to_be_bytes());
+
+        ip::send(src, PROTO_ICMP, &reply, None);
+    }
+}
@@ -0,0 +1,239 @@
+//! IPv4.
+//!
+//! Packets are sent straight to hosts on the local subnet and through the
+//! gateway otherwise. Fragments and options are never sent, and received
+//! fragments are dropped.
+
+use core::fmt;
+use core::sync::atomic::{ AtomicU16, Ordering };
+use alloc::vec::Vec;
+use spinlock::SpinLock;
+use cpu::InterruptGuard;
+use super::{ arp, icmp, tcp, udp, Mac, TxChecksum };
+use super::{ ETHERNET_HEADER_SIZE, ETHERTYPE_IPV4, MAX_FRAME_SIZE };
+
+/// Protocol numbers
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP:  u8 = 6;
+pub const PROTO_UDP:  u8 = 17;
+
+/// Size of a header without options
+pub const HEADER_SIZE: usize = 20;
+
+/// Largest payload of a packet, which is never fragmented
+pub const MAX_PAYLOAD: usize = MAX_FRAME_SIZE - ETHERNET_HEADER_SIZE -
+    HEADER_SIZE;
+
+/// Time to live of the packets sent
+const DEFAULT_TTL: u8 = 64;
+
+/// Flags and fragment offset bits of a fragment: more fragments and the
+/// offset
+const FRAGMENT_MASK: u16 = 0x3fff;
+
+/// Don't fragment flag
+const FLAG_DF: u16 = 1 << 14;
+
+/// An IPv4 address
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    /// The unspecified address, 0.0.0.0
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+
+    /// The limited broadcast address, 255.255.255.255
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([0xff; 4]);
+
+    /// Returns the address as a host order integer
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    /// Returns the address of the host order integer `addr`
+    pub fn from_u32(addr: u32) -> Self {
+        Self(addr.to_be_bytes())
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let a = &self.0;
+        write!(f, "{}.{}.{}.{}", a[0], a[1], a[2], a[3])
+    }
+}
+
+/// The configuration of the interface
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Address of the interface
+    pub addr: Ipv4Addr,
+
+    /// Mask of the local subnet
+    pub netmask: Ipv4Addr,
+
+    /// Router for the hosts outside of the local subnet
+    pub gateway: Option<Ipv4Addr>,
+
+    /// The server the kernel was booted from
+    pub server: Option<Ipv4Addr>,
+}
+
+impl Config {
+    /// Returns whether `addr` is on the local subnet
+    pub fn on_link(&self, addr: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_u32();
+        addr.to_u32() & mask == self.addr.to_u32() & mask
+    }
+
+    /// Returns the broadcast address of the local subnet
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.addr.to_u32() | !self.netmask.to_u32())
+    }
+}
+
+/// The configuration of the interface, `None` until it has an address
+static CONFIG: SpinLock<Option<Config>> = SpinLock::new(None);
+
+/// Identification of the next packet sent
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+/// Set the configuration of the interface, or remove it with `None`
+pub fn configure(config: Option<Config>) {
+    if let Some(config) = config {
+        print!("IPv4: address {} netmask {}", config.addr, config.netmask);
+        if let Some(gateway) = config.gateway {
+            print!(" gateway {}", gateway);
+        }
+        if let Some(server) = config.server {
+            print!(" server {}", server);
+        }
+        print!("\n");
+    }
+
+    let _interrupts = InterruptGuard::new();
+    *CONFIG.lock() = config;
+}
+
+/// Returns the configuration of the interface, if it has one
+pub fn config() -> Option<Config> {
+    let _interrupts = InterruptGuard::new();
+    *CONFIG.lock()
+}
+
+/// Returns the address of the interface, unspecified if it has none
+pub fn addr() -> Ipv4Addr {
+    config().map(|config| config.addr).unwrap_or(Ipv4Addr::UNSPECIFIED)
+}
+
+/// Returns the partial sum of the pseudo header of a `protocol` packet of
+/// `len` bytes from `src` to `dst`, to seed its checksum with
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8,
+                         len: u16) -> u16 {
+    let mut sum = protocol as u32 + len as u32;
+    for addr in [src, dst] {
+        sum += u16::from_be_bytes([addr.0[0], addr.0[1]]) as u32;
+        sum += u16::from_be_bytes([addr.0[2], addr.0[3]]) as u32;
+    }
+
+    // Fold the carries back in
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Returns the MAC address packets to `dst` are sent to
+fn next_hop(dst: Ipv4Addr) -> Option<Mac> {
+    let config = config();
+    if dst == Ipv4Addr::BROADCAST ||
+            config.is_some_and(|config| dst == config.broadcast()) {
+        return Some(Mac::BROADCAST);
+    }
+
+    let config = config?;
+    if config.on_link(dst) {
+        arp::resolve(dst)
+    } else {
+        arp::resolve(config.gateway?)
+    }
+}
+
+/// Send `payload` of `protocol` to `dst`, having `checksum` filled in, with
+/// offsets relative to the payload. Returns whether the packet was queued,
+/// which it isn't if the payload is too large, there's no route to `dst` or
+/// the transmit queue is full.
+pub fn send(dst: Ipv4Addr, protocol: u8, payload: &[u8],
+            checksum: Option<TxChecksum>) -> bool {
+    if payload.len() > MAX_PAYLOAD {
+        return false;
+    }
+    let Some(mac) = next_hop(dst) else {
+        return false;
+    };
+
+    // Build the header
+    let len = (HEADER_SIZE + payload.len()) as u16;
+    let id  = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut packet = Vec::with_capacity(len as usize);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&len.to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&FLAG_DF.to_be_bytes());
+    packet.extend_from_slice(&[DEFAULT_TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&addr().0);
+    packet.extend_from_slice(&dst.0);
+    let sum = super::checksum(&packet, 0);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+
+    super::send_frame(mac, ETHERTYPE_IPV4, &packet,
+        checksum.map(|csum| TxChecksum {
+            start:  csum.start + HEADER_SIZE as u16,
+            offset: csum.offset,
+        }))
+}
+
+/// Handle the received IPv4 `packet`
+pub(super) fn handle(packet: &[u8]) {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+
+    // Validate the header, and drop the padding of short frames
+    let header_len = (packet[0] & 0xf) as usize * 4;
+    let total_len  = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if header_len < HEADER_SIZE || total_len < header_len ||
+            total_len > packet.len() ||
+            super::checksum(&packet[..header_len], 0) != 0 {
+        return;
+    }
+    let packet = &packet[..total_len];
+
+    // Fragments aren't reassembled
+    if u16::from_be_bytes([packet[6], packet[7]]) & FRAGMENT_MASK != 0 {
+        return;
+    }
+
+    let src = Ipv4Addr(packet[12..16].try_into().unwrap());
+    let dst = Ipv4Addr(packet[16..20].try_into().unwrap());
+
+    // Take packets to us and broadcasts, and everything while there's no
+    // address yet, for DHCP
+    let ours = match config() {
+        Some(config) => dst == config.addr || dst == Ipv4Addr::BROADCAST ||
+            dst == config.broadcast(),
+        None => true,
+    };
+    if !ours {
+        return;
+    }
+
+    let payload = &packet[header_len..];
+    match packet[9] {
+        PROTO_ICMP => icmp::handle(src, dst, payload),
+        PROTO_TCP  => tcp::handle(src, dst, payload),
+        PROTO_UDP  => udp::handle(src, dst, payload),
+        _ => {}
+    }
+}
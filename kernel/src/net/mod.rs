@@ -0,0 +1,340 @@
+//! Networking.
+//!
+//! Network drivers expose their devices through the `NetDevice` trait, which
+//! sends and receives raw Ethernet frames. The devices are found on the PCI
+//! bus by `init()` and registered for the rest of the kernel to use.
+//!
+//! A small IPv4 stack runs on one of them, the interface. There's no
+//! background processing: the frames received are handled and the TCP and
+//! DHCP timers run when the stack is polled, which waiting for a reply or a
+//! datagram does.
+
+pub mod virtio_net;
+pub mod e1000;
+pub mod arp;
+pub mod ip;
+pub mod icmp;
+pub mod udp;
+pub mod tcp;
+pub mod dhcp;
+pub mod tftp;
+
+use core::fmt;
+use core::sync::atomic::{ AtomicU16, Ordering };
+use alloc::vec::Vec;
+use alloc::collections::VecDeque;
+use spinlock::SpinLock;
+use cpu::InterruptGuard;
+
+/// Size of an Ethernet header
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// Largest Ethernet frame handled, without the frame check sequence
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+/// EtherTypes of the protocols handled
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP:  u16 = 0x0806;
+
+/// Range of the ports given out by `EphemeralPorts`
+const EPHEMERAL_START: u16 = 49152;
+const EPHEMERAL_END:   u16 = 65535;
+
+/// Largest number of frames kept received by a driver before the oldest
+/// ones are dropped
+const MAX_PENDING_FRAMES: usize = 1024;
+
+/// A MAC address
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Mac(pub [u8; 6]);
+
+impl Mac {
+    /// The broadcast address
+    pub const BROADCAST: Mac = Mac([0xff; 6]);
+}
+
+impl fmt::Display for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = &self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+               m[0], m[1], m[2], m[3], m[4], m[5])
+    }
+}
+
+/// A checksum for the device to fill in on transmission: the internet
+/// checksum from `start` to the end of the frame, seeded with the 16 bits
+/// already at `start + offset`, is stored at `start + offset`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxChecksum {
+    pub start:  u16,
+    pub offset: u16,
+}
+
+/// A network device sending and receiving Ethernet frames
+pub trait NetDevice: Send + Sync {
+    /// Returns the MAC address of the device
+    fn mac(&self) -> Mac;
+
+    /// Returns whether the link is up
+    fn link_up(&self) -> bool;
+
+    /// Queue `frame` for transmission, having the device fill in `checksum`
+    /// if it can, or filling it in before otherwise. Returns `false` if the
+    /// transmit queue is full.
+    fn send(&self, frame: &[u8], checksum: Option<TxChecksum>) -> bool;
+
+    /// Returns the next frame received, if there is one
+    fn recv(&self) -> Option<Vec<u8>>;
+}
+
+/// The ephemeral ports of a protocol, given out in turn so that a port
+/// isn't reused right after it's freed
+pub struct EphemeralPorts {
+    /// Next port to try
+    next: AtomicU16,
+}
+
+impl EphemeralPorts {
+    /// Returns the ports, starting from the first one
+    pub const fn new() -> Self {
+        Self { next: AtomicU16::new(EPHEMERAL_START) }
+    }
+
+    /// Returns the next port for which `taken` is false, trying every port
+    /// once. `None` if they're all taken.
+    pub fn alloc(&self, taken: impl Fn(u16) -> bool) -> Option<u16> {
+        (EPHEMERAL_START..=EPHEMERAL_END).find_map(|_| {
+            let port = self.next.fetch_update(Ordering::Relaxed,
+                Ordering::Relaxed, |port| Some(if port == EPHEMERAL_END {
+                    EPHEMERAL_START
+                } else {
+                    port + 1
+                })).unwrap();
+            (!taken(port)).then_some(port)
+        })
+    }
+}
+
+/// Frames received by a driver and not returned by `recv()` yet. The
+/// oldest ones are dropped when there are `MAX_PENDING_FRAMES`.
+#[derive(Default)]
+pub struct PendingFrames {
+    /// The frames, oldest first
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl PendingFrames {
+    /// Returns an empty queue
+    pub const fn new() -> Self {
+        Self { frames: VecDeque::new() }
+    }
+
+    /// Queue `frame`, dropping the oldest frame if the queue is full
+    pub fn push(&mut self, frame: Vec<u8>) {
+        if self.frames.len() >= MAX_PENDING_FRAMES {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    /// Remove and return the oldest frame
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+
+    /// Returns whether there are no frames
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+impl Default for EphemeralPorts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The devices of a driver which are interrupt driven, with the vector each
+/// of them interrupts on
+pub struct InterruptDriven<T: 'static> {
+    /// The devices and their vectors
+    devices: SpinLock<Vec<(u8, &'static T)>>,
+}
+
+impl<T: Sync> InterruptDriven<T> {
+    /// Returns an empty list of devices
+    pub const fn new() -> Self {
+        Self { devices: SpinLock::new(Vec::new()) }
+    }
+
+    /// Add `device`, interrupting on `vector`. Its interrupts can be enabled
+    /// once it's added.
+    pub fn add(&self, vector: u8, device: &'static T) {
+        let _interrupts = InterruptGuard::new();
+        self.devices.lock().push((vector, device));
+    }
+
+    /// Run `handle` on the devices interrupting on `vector`, from their
+    /// interrupt handler
+    pub fn dispatch(&self, vector: u8, handle: impl Fn(&'static T)) {
+        let devices = self.devices.lock();
+        for &(_, device) in devices.iter().filter(|(vec, _)| *vec == vector) {
+            handle(device);
+        }
+    }
+}
+
+impl<T: Sync> Default for InterruptDriven<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The network devices found
+static DEVICES: SpinLock<Vec<&'static dyn NetDevice>> =
+    SpinLock::new(Vec::new());
+
+/// The device the stack runs on
+static INTERFACE: SpinLock<Option<&'static dyn NetDevice>> =
+    SpinLock::new(None);
+
+/// Returns the internet checksum of `data`, added to the partial sum `sum`
+pub fn checksum(data: &[u8], sum: u32) -> u16 {
+    let mut sum = sum as u64;
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u64;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u64) << 8;
+    }
+
+    // Fold the carries back in
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Fill in `csum` of `frame` in software
+pub fn complete_checksum(frame: &mut [u8], csum: TxChecksum) {
+    let start = csum.start as usize;
+    let field = start + csum.offset as usize;
+    if field + 2 > frame.len() {
+        return;
+    }
+
+    // The field holds the partial sum of the pseudo header
+    let seed = u16::from_be_bytes([frame[field], frame[field + 1]]) as u32;
+    frame[field..field + 2].copy_from_slice(&[0, 0]);
+    let sum = checksum(&frame[start..], seed);
+    frame[field..field + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Register `device` for the rest of the kernel to use. Drivers leak their
+/// devices, which are never removed.
+pub fn register(device: &'static dyn NetDevice) {
+    print!("Net: device {} with MAC {}, link {}\n",
+           devices().len(), device.mac(),
+           if device.link_up() { "up" } else { "down" });
+
+    let _interrupts = InterruptGuard::new();
+    DEVICES.lock().push(device);
+}
+
+/// Returns the network devices found
+pub fn devices() -> Vec<&'static dyn NetDevice> {
+    let _interrupts = InterruptGuard::new();
+    DEVICES.lock().clone()
+}
+
+/// Returns the first network device, if there is one
+pub fn device() -> Option<&'static dyn NetDevice> {
+    let _interrupts = InterruptGuard::new();
+    DEVICES.lock().first().copied()
+}
+
+/// Returns the device the stack runs on, if there is one
+pub fn interface() -> Option<&'static dyn NetDevice> {
+    let _interrupts = InterruptGuard::new();
+    *INTERFACE.lock()
+}
+
+/// Send `payload` of `ethertype` to `dst` from the interface, having
+/// `checksum` filled in, with offsets relative to the payload. Returns
+/// whether the frame was queued.
+pub fn send_frame(dst: Mac, ethertype: u16, payload: &[u8],
+                  checksum: Option<TxChecksum>) -> bool {
+    let Some(device) = interface() else {
+        return false;
+    };
+
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&dst.0);
+    frame.extend_from_slice(&device.mac().0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    device.send(&frame, checksum.map(|csum| TxChecksum {
+        start:  csum.start + ETHERNET_HEADER_SIZE as u16,
+        offset: csum.offset,
+    }))
+}
+
+/// Handle all the frames received on the interface and run the TCP and DHCP
+/// timers
+pub fn poll() {
+    let Some(device) = interface() else {
+        return;
+    };
+
+    while let Some(frame) = device.recv() {
+        if frame.len() < ETHERNET_HEADER_SIZE {
+            continue;
+        }
+
+        let payload = &frame[ETHERNET_HEADER_SIZE..];
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP  => arp::handle(payload),
+            ETHERTYPE_IPV4 => ip::handle(payload),
+            _ => {}
+        }
+    }
+
+    tcp::timers();
+    dhcp::maintain();
+}
+
+/// Probe the PCI devices for network devices the kernel has drivers for, and
+/// bring the stack up on one of them
+pub fn init() {
+    for device in crate::pci::devices() {
+        virtio_net::probe(device);
+        e1000::probe(device);
+    }
+
+    // Prefer the device the bootloader was PXE booted from, reusing its
+    // lease
+    let lease = crate::boot_kern().pxe_lease();
+    let devices = devices();
+    let pxe = lease.and_then(|lease| {
+        devices.iter().position(|device| device.mac().0 == lease.mac)
+    });
+    let Some(&device) = devices.get(pxe.unwrap_or(0)) else {
+        print!("Net: no network devices found\n");
+        return;
+    };
+
+    {
+        let _interrupts = InterruptGuard::new();
+        *INTERFACE.lock() = Some(device);
+    }
+    print!("Net: interface is the device with MAC {}\n", device.mac());
+
+    // Without a lease from PXE, get one ourselves
+    let adopted = lease.filter(|_| pxe.is_some())
+        .and_then(|lease| dhcp::adopt_pxe(&lease));
+    if adopted.is_none() && dhcp::acquire().is_none() {
+        print!("Net: no DHCP server answered\n");
+    }
+}
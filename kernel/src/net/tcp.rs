@@ -0,0 +1,1069 @@
+//! TCP stream sockets.
+//!
+//! Connections are driven by the stack being polled: the segments received
+//! are handled and the retransmission timers checked whenever
+//! `net::poll()` runs, which every socket operation does. Lost segments are
+//! retransmitted go-back-N from the oldest unacknowledged byte, after a
+//! timeout estimated from the round trip times. There's no congestion
+//! control, only the window the peer advertises is respected. A closed
+//! window is probed from a persist timer, which backs off but never gives
+//! up on the connection.
+//!
+//! Segments are never sent with the connection table locked, as resolving
+//! the next hop polls the stack.
+
+use alloc::vec::Vec;
+use alloc::collections::{ BTreeMap, VecDeque };
+use spinlock::SpinLock;
+use cpu::InterruptGuard;
+use time::{ Duration, Instant };
+use super::ip::{ self, Ipv4Addr, PROTO_TCP };
+use super::{ TxChecksum, EphemeralPorts };
+
+/// Size of a header without options
+const HEADER_SIZE: usize = 20;
+
+/// Flags
+const FLAG_FIN: u8 = 1 << 0;
+const FLAG_SYN: u8 = 1 << 1;
+const FLAG_RST: u8 = 1 << 2;
+const FLAG_PSH: u8 = 1 << 3;
+const FLAG_ACK: u8 = 1 << 4;
+
+/// Options
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// Largest segment payload we take, which fills a frame
+const MSS: u16 = (ip::MAX_PAYLOAD - HEADER_SIZE) as u16;
+
+/// Largest segment payload assumed for peers not sending the MSS option
+const DEFAULT_MSS: u16 = 536;
+
+/// Size of the buffers of a connection
+const SEND_BUFFER: usize = 64 * 1024;
+const RECV_BUFFER: usize = 65535;
+
+/// Bounds of the retransmission timeout, and its value before the first
+/// round trip time is measured
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO:     Duration = Duration::from_millis(200);
+const MAX_RTO:     Duration = Duration::from_secs(60);
+
+/// Number of retransmissions in a row before a connection is aborted
+const MAX_RETRIES:     u32 = 8;
+const MAX_SYN_RETRIES: u32 = 5;
+
+/// How long closed connections stay in TIME-WAIT
+const TIME_WAIT: Duration = Duration::from_secs(30);
+
+/// Largest number of established connections waiting to be accepted on a
+/// listener
+const MAX_BACKLOG: usize = 16;
+
+/// State of a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+/// A connection by local port, remote address and remote port
+type Id = (u16, Ipv4Addr, u16);
+
+/// Returns whether sequence number `a` is before `b`
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Returns whether sequence number `a` is before or at `b`
+fn seq_le(a: u32, b: u32) -> bool {
+    !seq_lt(b, a)
+}
+
+/// Returns an initial sequence number
+fn iss() -> u32 {
+    cpu::rdtsc() as u32
+}
+
+/// A segment to send
+struct Segment {
+    /// Destination of the segment
+    dst: Ipv4Addr,
+
+    /// Ports
+    src_port: u16,
+    dst_port: u16,
+
+    /// Sequence and acknowledgement numbers
+    seq: u32,
+    ack: u32,
+
+    /// Flags
+    flags: u8,
+
+    /// Receive window advertised
+    window: u16,
+
+    /// MSS option, sent on SYNs
+    mss: Option<u16>,
+
+    /// The payload
+    payload: Vec<u8>,
+}
+
+impl Segment {
+    /// Returns a reset answering `seg` to `id`, for segments which don't
+    /// belong to a connection
+    fn reset(id: Id, seg: &Incoming) -> Self {
+        let (seq, ack, flags) = if seg.flags & FLAG_ACK != 0 {
+            (seg.ack, 0, FLAG_RST)
+        } else {
+            (0, seg.seq.wrapping_add(seg.len()), FLAG_RST | FLAG_ACK)
+        };
+
+        Self {
+            dst:      id.1,
+            src_port: id.0,
+            dst_port: id.2,
+            seq,
+            ack,
+            flags,
+            window:   0,
+            mss:      None,
+            payload:  Vec::new(),
+        }
+    }
+
+    /// Send the segment, with the checksum seeded with the pseudo header and
+    /// filled in by the device
+    fn send(&self) {
+        let options = if self.mss.is_some() { 4 } else { 0 };
+        let len = HEADER_SIZE + options + self.payload.len();
+        let seed = ip::pseudo_header_sum(ip::addr(), self.dst, PROTO_TCP,
+                                         len as u16);
+
+        let mut segment = Vec::with_capacity(len);
+        segment.extend_from_slice(&self.src_port.to_be_bytes());
+        segment.extend_from_slice(&self.dst_port.to_be_bytes());
+        segment.extend_from_slice(&self.seq.to_be_bytes());
+        segment.extend_from_slice(&self.ack.to_be_bytes());
+        segment.push((((HEADER_SIZE + options) / 4) as u8) << 4);
+        segment.push(self.flags);
+        segment.extend_from_slice(&self.window.to_be_bytes());
+        segment.extend_from_slice(&seed.to_be_bytes());
+        segment.extend_from_slice(&[0, 0]);
+        if let Some(mss) = self.mss {
+            segment.extend_from_slice(&[OPTION_MSS, 4]);
+            segment.extend_from_slice(&mss.to_be_bytes());
+        }
+        segment.extend_from_slice(&self.payload);
+
+        ip::send(self.dst, PROTO_TCP, &segment,
+                 Some(TxChecksum { start: 0, offset: 16 }));
+    }
+}
+
+/// Send `segments`, with the connection table unlocked
+fn transmit(segments: Vec<Segment>) {
+    for segment in segments {
+        segment.send();
+    }
+}
+
+/// A segment received
+struct Incoming<'a> {
+    seq:    u32,
+    ack:    u32,
+    flags:  u8,
+    window: u16,
+
+    /// MSS option, if there was one
+    mss: Option<u16>,
+
+    /// The payload
+    payload: &'a [u8],
+}
+
+impl Incoming<'_> {
+    /// Returns the sequence space taken by the segment
+    fn len(&self) -> u32 {
+        self.payload.len() as u32 +
+            (self.flags & FLAG_SYN != 0) as u32 +
+            (self.flags & FLAG_FIN != 0) as u32
+    }
+}
+
+/// A connection
+struct Connection {
+    /// The connection
+    id: Id,
+
+    /// State of the connection
+    state: State,
+
+    /// Initial send sequence number
+    iss: u32,
+
+    /// Oldest unacknowledged and next sequence numbers to send
+    snd_una: u32,
+    snd_nxt: u32,
+
+    /// Window the peer advertised
+    snd_wnd: u32,
+
+    /// Largest segment payload to send
+    mss: u16,
+
+    /// Bytes from `snd_una` on, sent or not
+    send_buf: VecDeque<u8>,
+
+    /// Whether a FIN goes after the data, and whether it was sent
+    fin_queued: bool,
+    fin_sent:   bool,
+
+    /// Next sequence number to receive
+    rcv_nxt: u32,
+
+    /// Bytes received and not read yet
+    recv_buf: VecDeque<u8>,
+
+    /// Whether the peer sent everything it had
+    fin_received: bool,
+
+    /// Whether the connection was reset or timed out
+    reset: bool,
+
+    /// Retransmission timeout
+    rto: Duration,
+
+    /// Smoothed round trip time and its variation, once measured
+    srtt:   Option<Duration>,
+    rttvar: Duration,
+
+    /// Sequence number whose acknowledgement is being timed, and when it
+    /// was sent
+    rtt_sample: Option<(u32, Instant)>,
+
+    /// When the oldest unacknowledged segment is retransmitted
+    retransmit_at: Option<Instant>,
+
+    /// Number of retransmissions since the last acknowledgement
+    retries: u32,
+
+    /// When the closed window is probed next, and the timeout after that
+    persist_at:      Option<Instant>,
+    persist_timeout: Duration,
+
+    /// When the connection leaves TIME-WAIT
+    time_wait_until: Option<Instant>,
+
+    /// Port of the listener the connection was accepted from
+    listener: Option<u16>,
+
+    /// Whether there's no socket for the connection anymore
+    orphan: bool,
+}
+
+impl Connection {
+    /// Create a connection in `state`
+    fn new(id: Id, state: State) -> Self {
+        let iss = iss();
+        Self {
+            id,
+            state,
+            iss,
+            snd_una:         iss,
+            snd_nxt:         iss,
+            snd_wnd:         0,
+            mss:             DEFAULT_MSS,
+            send_buf:        VecDeque::new(),
+            fin_queued:      false,
+            fin_sent:        false,
+            rcv_nxt:         0,
+            recv_buf:        VecDeque::new(),
+            fin_received:    false,
+            reset:           false,
+            rto:             INITIAL_RTO,
+            srtt:            None,
+            rttvar:          Duration::ZERO,
+            rtt_sample:      None,
+            retransmit_at:   None,
+            retries:         0,
+            persist_at:      None,
+            persist_timeout: INITIAL_RTO,
+            time_wait_until: None,
+            listener:        None,
+            orphan:          false,
+        }
+    }
+
+    /// Move to `state`
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        if state == State::TimeWait {
+            self.time_wait_until = Some(Instant::now() + TIME_WAIT);
+            self.retransmit_at = None;
+            self.persist_at = None;
+        }
+    }
+
+    /// Close the connection on a reset or a timeout
+    fn abort(&mut self) {
+        self.state = State::Closed;
+        self.reset = true;
+        self.send_buf.clear();
+        self.retransmit_at = None;
+        self.persist_at = None;
+    }
+
+    /// Returns the receive window to advertise
+    fn window(&self) -> u16 {
+        (RECV_BUFFER - self.recv_buf.len()).min(u16::MAX as usize) as u16
+    }
+
+    /// Returns a segment with `flags` and `payload` at `seq`, acknowledging
+    /// everything received
+    fn segment(&self, seq: u32, flags: u8, payload: Vec<u8>) -> Segment {
+        let syn = flags & FLAG_SYN != 0;
+        Segment {
+            dst:      self.id.1,
+            src_port: self.id.0,
+            dst_port: self.id.2,
+            seq,
+            ack:      if flags & FLAG_ACK != 0 { self.rcv_nxt } else { 0 },
+            flags,
+            window:   self.window(),
+            mss:      if syn { Some(MSS) } else { None },
+            payload,
+        }
+    }
+
+    /// Returns a bare acknowledgement
+    fn ack(&self) -> Segment {
+        self.segment(self.snd_nxt, FLAG_ACK, Vec::new())
+    }
+
+    /// Queue the SYN, the data and the FIN the window allows on `out`
+    fn output(&mut self, out: &mut Vec<Segment>) {
+        match self.state {
+            State::SynSent | State::SynReceived => {
+                if self.snd_nxt == self.iss {
+                    let flags = if self.state == State::SynSent { FLAG_SYN }
+                        else { FLAG_SYN | FLAG_ACK };
+                    out.push(self.segment(self.iss, flags, Vec::new()));
+                    self.snd_nxt = self.iss.wrapping_add(1);
+                }
+            }
+            State::TimeWait | State::Closed => return,
+            _ => {
+                loop {
+                    let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+                    let window = self.snd_wnd;
+                    let offset = (in_flight as usize).min(self.send_buf.len());
+                    if offset >= self.send_buf.len() || in_flight >= window {
+                        break;
+                    }
+
+                    let len = (self.send_buf.len() - offset)
+                        .min(self.mss as usize)
+                        .min((window - in_flight) as usize);
+                    let payload: Vec<u8> = self.send_buf
+                        .range(offset..offset + len).copied().collect();
+                    out.push(self.segment(self.snd_nxt,
+                                          FLAG_ACK | FLAG_PSH, payload));
+
+                    self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+                    if self.rtt_sample.is_none() {
+                        self.rtt_sample = Some((self.snd_nxt, Instant::now()));
+                    }
+                }
+
+                // The FIN goes once all the data is sent
+                let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+                if self.fin_queued && !self.fin_sent &&
+                        in_flight as usize == self.send_buf.len() {
+                    out.push(self.segment(self.snd_nxt, FLAG_FIN | FLAG_ACK,
+                                          Vec::new()));
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                    self.fin_sent = true;
+                }
+            }
+        }
+
+        if self.snd_nxt != self.snd_una && self.retransmit_at.is_none() {
+            self.retransmit_at = Some(Instant::now() + self.rto);
+        }
+
+        // Data held back by a closed window with nothing in flight waits on
+        // the persist timer rather than the retransmission one
+        let stalled = self.snd_wnd == 0 && self.snd_nxt == self.snd_una &&
+            !self.send_buf.is_empty();
+        if !stalled {
+            self.persist_at = None;
+        } else if self.persist_at.is_none() {
+            self.persist_timeout = self.rto;
+            self.persist_at = Some(Instant::now() + self.persist_timeout);
+        }
+    }
+
+    /// Probe a closed window with an acknowledgement for a sequence number
+    /// below it, which the peer answers with its window, and back off the
+    /// persist timer. Probes are never counted as retries.
+    fn probe(&mut self, out: &mut Vec<Segment>) {
+        out.push(self.segment(self.snd_una.wrapping_sub(1), FLAG_ACK,
+                              Vec::new()));
+        self.persist_timeout = (self.persist_timeout * 2).min(MAX_RTO);
+        self.persist_at = Some(Instant::now() + self.persist_timeout);
+    }
+
+    /// Send everything from the oldest unacknowledged byte again, backing
+    /// off the timeout, or abort after too many retries
+    fn retransmit(&mut self, out: &mut Vec<Segment>) {
+        let limit = match self.state {
+            State::SynSent | State::SynReceived => MAX_SYN_RETRIES,
+            _                                   => MAX_RETRIES,
+        };
+        self.retries += 1;
+        if self.retries > limit {
+            self.abort();
+            return;
+        }
+
+        // The sample is ambiguous once anything is sent twice
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.rtt_sample = None;
+        self.retransmit_at = None;
+        self.snd_nxt = self.snd_una;
+        self.fin_sent = false;
+        self.output(out);
+    }
+
+    /// Update the retransmission timeout with the round trip time `rtt`, as
+    /// RFC 6298 does
+    fn update_rto(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Take the acknowledgement `ack` and the window `window` of a segment
+    fn take_ack(&mut self, ack: u32, window: u16) {
+        if seq_lt(self.snd_una, ack) && seq_le(ack, self.snd_nxt) {
+            // The SYN takes a sequence number but no byte of the buffer
+            let mut acked = ack.wrapping_sub(self.snd_una) as usize;
+            if self.snd_una == self.iss {
+                acked -= 1;
+            }
+            let data = acked.min(self.send_buf.len());
+            self.send_buf.drain(..data);
+            self.snd_una = ack;
+
+            if let Some((seq, sent)) = self.rtt_sample {
+                if seq_le(seq, ack) {
+                    self.update_rto(sent.elapsed());
+                    self.rtt_sample = None;
+                }
+            }
+
+            self.retransmit_at = (self.snd_una != self.snd_nxt)
+                .then(|| Instant::now() + self.rto);
+        }
+
+        // Any acceptable acknowledgement shows the peer is alive, even when
+        // it only updates the window
+        if seq_le(self.snd_una, ack) && seq_le(ack, self.snd_nxt) {
+            self.snd_wnd = window as u32;
+            self.retries = 0;
+        }
+    }
+
+    /// Handle `seg` in SYN-SENT
+    fn input_syn_sent(&mut self, seg: &Incoming, out: &mut Vec<Segment>) {
+        let has_ack = seg.flags & FLAG_ACK != 0;
+        if has_ack && seg.ack != self.iss.wrapping_add(1) {
+            if seg.flags & FLAG_RST == 0 {
+                out.push(Segment::reset(self.id, seg));
+            }
+            return;
+        }
+        if seg.flags & FLAG_RST != 0 {
+            if has_ack {
+                self.abort();
+            }
+            return;
+        }
+
+        // Simultaneous opens aren't supported
+        if seg.flags & FLAG_SYN == 0 || !has_ack {
+            return;
+        }
+
+        self.rcv_nxt = seg.seq.wrapping_add(1);
+        self.mss = seg.mss.unwrap_or(DEFAULT_MSS).min(MSS);
+        self.take_ack(seg.ack, seg.window);
+        self.set_state(State::Established);
+        out.push(self.ack());
+        self.output(out);
+    }
+
+    /// Handle `seg`. Returns whether it established a connection from
+    /// SYN-RECEIVED.
+    fn input(&mut self, seg: &Incoming, out: &mut Vec<Segment>) -> bool {
+        if self.state == State::SynSent {
+            self.input_syn_sent(seg, out);
+            return false;
+        }
+
+        // A retransmitted SYN means our SYN-ACK got lost
+        if self.state == State::SynReceived && seg.flags & FLAG_SYN != 0 &&
+                seg.seq.wrapping_add(1) == self.rcv_nxt {
+            self.snd_nxt = self.iss;
+            self.output(out);
+            return false;
+        }
+
+        // The segment has to overlap the receive window
+        let len = seg.len();
+        let window = self.window() as u32;
+        let in_window = |seq: u32| {
+            seq_le(self.rcv_nxt, seq) &&
+                seq_lt(seq, self.rcv_nxt.wrapping_add(window))
+        };
+        let acceptable = if len == 0 {
+            seg.seq == self.rcv_nxt || (window > 0 && in_window(seg.seq))
+        } else {
+            window > 0 && (in_window(seg.seq) ||
+                in_window(seg.seq.wrapping_add(len - 1)))
+        };
+        if !acceptable {
+            if seg.flags & FLAG_RST == 0 {
+                out.push(self.ack());
+            }
+            return false;
+        }
+
+        if seg.flags & FLAG_RST != 0 {
+            self.abort();
+            return false;
+        }
+        if seg.flags & FLAG_SYN != 0 {
+            out.push(Segment::reset(self.id, seg));
+            self.abort();
+            return false;
+        }
+        if seg.flags & FLAG_ACK == 0 {
+            return false;
+        }
+
+        // The handshake completes with the ACK of our SYN
+        let mut established = false;
+        if self.state == State::SynReceived {
+            if seq_lt(seg.ack, self.snd_una) || seq_lt(self.snd_nxt, seg.ack) {
+                out.push(Segment::reset(self.id, seg));
+                return false;
+            }
+            self.set_state(State::Established);
+            established = true;
+        }
+
+        self.take_ack(seg.ack, seg.window);
+        if seq_lt(self.snd_nxt, seg.ack) {
+            out.push(self.ack());
+            return established;
+        }
+
+        // Our FIN is acknowledged once everything is
+        if self.fin_sent && self.snd_una == self.snd_nxt {
+            match self.state {
+                State::FinWait1 => self.set_state(State::FinWait2),
+                State::Closing  => self.set_state(State::TimeWait),
+                State::LastAck  => self.set_state(State::Closed),
+                _ => {}
+            }
+        }
+
+        // Take the data in order, dropping what's already received and what
+        // doesn't fit
+        let mut need_ack = false;
+        if matches!(self.state,
+                State::Established | State::FinWait1 | State::FinWait2) &&
+                !seg.payload.is_empty() {
+            let skip = if seq_lt(seg.seq, self.rcv_nxt) {
+                (self.rcv_nxt.wrapping_sub(seg.seq) as usize)
+                    .min(seg.payload.len())
+            } else {
+                0
+            };
+            if seg.seq.wrapping_add(skip as u32) == self.rcv_nxt {
+                let data = &seg.payload[skip..];
+                let room = RECV_BUFFER - self.recv_buf.len();
+                let take = data.len().min(room);
+
+                // Nobody reads the data of orphans
+                if !self.orphan {
+                    self.recv_buf.extend(&data[..take]);
+                }
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(take as u32);
+            }
+            need_ack = true;
+        }
+
+        // The FIN is taken once everything before it is
+        let fin_seq = seg.seq.wrapping_add(seg.payload.len() as u32);
+        if seg.flags & FLAG_FIN != 0 && fin_seq == self.rcv_nxt {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            need_ack = true;
+            match self.state {
+                State::Established => self.set_state(State::CloseWait),
+                State::FinWait1    => self.set_state(State::Closing),
+                State::FinWait2    => self.set_state(State::TimeWait),
+                _ => {}
+            }
+        }
+
+        if need_ack {
+            out.push(self.ack());
+        }
+        self.output(out);
+        established
+    }
+
+    /// Queue a FIN after the data, or drop the connection if it isn't
+    /// synchronized yet
+    fn close(&mut self, out: &mut Vec<Segment>) {
+        match self.state {
+            State::SynSent => self.set_state(State::Closed),
+            State::SynReceived | State::Established => {
+                self.fin_queued = true;
+                self.set_state(State::FinWait1);
+            }
+            State::CloseWait => {
+                self.fin_queued = true;
+                self.set_state(State::LastAck);
+            }
+            _ => {}
+        }
+        self.output(out);
+    }
+}
+
+/// The connections
+static CONNECTIONS: SpinLock<BTreeMap<Id, Connection>> =
+    SpinLock::new(BTreeMap::new());
+
+/// The listening ports, with the connections established on them waiting
+/// to be accepted. Locked after `CONNECTIONS` when both are.
+static LISTENERS: SpinLock<BTreeMap<u16, VecDeque<Id>>> =
+    SpinLock::new(BTreeMap::new());
+
+/// The local ports of outgoing connections
+static EPHEMERAL: EphemeralPorts = EphemeralPorts::new();
+
+/// Run `f` on connection `id` and send the segments it queued. `None` if the
+/// connection doesn't exist.
+fn with_connection<R>(id: Id,
+                      f: impl FnOnce(&mut Connection, &mut Vec<Segment>) -> R)
+        -> Option<R> {
+    let mut out = Vec::new();
+    let result = {
+        let _interrupts = InterruptGuard::new();
+        let mut connections = CONNECTIONS.lock();
+        f(connections.get_mut(&id)?, &mut out)
+    };
+    transmit(out);
+    Some(result)
+}
+
+/// Remove connection `id` if it's closed
+fn remove_if_closed(id: Id) {
+    let _interrupts = InterruptGuard::new();
+    let mut connections = CONNECTIONS.lock();
+    if connections.get(&id).is_some_and(|conn| conn.state == State::Closed) {
+        connections.remove(&id);
+    }
+}
+
+/// A TCP connection, closed when dropped
+pub struct TcpStream {
+    /// The connection
+    id: Id,
+}
+
+impl TcpStream {
+    /// Connect to `port` at `addr`. `None` if the connection was refused or
+    /// timed out.
+    pub fn connect(addr: Ipv4Addr, port: u16) -> Option<Self> {
+        let id = {
+            let _interrupts = InterruptGuard::new();
+            let mut connections = CONNECTIONS.lock();
+            let listeners = LISTENERS.lock();
+
+            // Find a free local port
+            let local = EPHEMERAL.alloc(|port| {
+                listeners.contains_key(&port) ||
+                    connections.keys().any(|id| id.0 == port)
+            })?;
+
+            let id = (local, addr, port);
+            connections.insert(id, Connection::new(id, State::SynSent));
+            id
+        };
+        let stream = Self { id };
+        with_connection(id, |conn, out| conn.output(out));
+
+        // Wait for the handshake to complete or fail
+        loop {
+            super::poll();
+            match stream.state() {
+                State::SynSent => core::hint::spin_loop(),
+                State::Closed  => return None,
+                _              => return Some(stream),
+            }
+        }
+    }
+
+    /// Returns the state of the connection
+    pub fn state(&self) -> State {
+        with_connection(self.id, |conn, _| conn.state)
+            .unwrap_or(State::Closed)
+    }
+
+    /// Returns the local port of the connection
+    pub fn local_port(&self) -> u16 {
+        self.id.0
+    }
+
+    /// Returns the address and the port of the peer
+    pub fn peer(&self) -> (Ipv4Addr, u16) {
+        (self.id.1, self.id.2)
+    }
+
+    /// Queue as much of `data` as the send buffer takes. Returns the number
+    /// of bytes queued, `None` if the connection can't send anymore.
+    pub fn send(&self, data: &[u8]) -> Option<usize> {
+        super::poll();
+        with_connection(self.id, |conn, out| {
+            if conn.fin_queued ||
+                    !matches!(conn.state, State::Established |
+                              State::CloseWait) {
+                return None;
+            }
+
+            let len = data.len().min(SEND_BUFFER - conn.send_buf.len());
+            conn.send_buf.extend(&data[..len]);
+            conn.output(out);
+            Some(len)
+        }).flatten()
+    }
+
+    /// Queue all of `data`, waiting for room in the send buffer. Returns
+    /// `false` if the connection can't send anymore.
+    pub fn send_all(&self, mut data: &[u8]) -> bool {
+        while !data.is_empty() {
+            let Some(sent) = self.send(data) else {
+                return false;
+            };
+            data = &data[sent..];
+        }
+        true
+    }
+
+    /// Wait for up to `timeout` for data and read it into `buf`. Returns the
+    /// number of bytes read, 0 once the peer closed its side, `None` on a
+    /// timeout or if the connection was reset.
+    pub fn recv(&self, buf: &mut [u8], timeout: Duration) -> Option<usize> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            super::poll();
+
+            // `Some` once there's an answer
+            let answer = with_connection(self.id, |conn, out| {
+                if !conn.recv_buf.is_empty() {
+                    let closed = conn.window() < conn.mss;
+                    let len = buf.len().min(conn.recv_buf.len());
+                    for (dst, src) in buf.iter_mut()
+                            .zip(conn.recv_buf.drain(..len)) {
+                        *dst = src;
+                    }
+
+                    // Tell the peer about a window that reopened
+                    if closed && conn.window() >= conn.mss {
+                        out.push(conn.ack());
+                    }
+                    Some(Some(len))
+                } else if conn.fin_received {
+                    Some(Some(0))
+                } else if conn.reset || conn.state == State::Closed {
+                    Some(None)
+                } else {
+                    None
+                }
+            }).unwrap_or(Some(None));
+
+            if let Some(answer) = answer {
+                return answer;
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Send a FIN after the data queued. Receiving keeps working until the
+    /// peer closes its side.
+    pub fn shutdown(&self) {
+        with_connection(self.id, |conn, out| conn.close(out));
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        // The stack finishes closing the connection
+        with_connection(self.id, |conn, out| {
+            conn.orphan = true;
+            conn.recv_buf.clear();
+            conn.close(out);
+        });
+        remove_if_closed(self.id);
+    }
+}
+
+/// A listening TCP port, unbound when dropped
+pub struct TcpListener {
+    /// The port listened on
+    port: u16,
+}
+
+impl TcpListener {
+    /// Listen on `port`. `None` if another listener has it.
+    pub fn bind(port: u16) -> Option<Self> {
+        let _interrupts = InterruptGuard::new();
+        let mut listeners = LISTENERS.lock();
+        if listeners.contains_key(&port) {
+            return None;
+        }
+        listeners.insert(port, VecDeque::new());
+        Some(Self { port })
+    }
+
+    /// Returns the port listened on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for up to `timeout` for a connection to be established
+    pub fn accept(&self, timeout: Duration) -> Option<TcpStream> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            super::poll();
+
+            let id = {
+                let _interrupts = InterruptGuard::new();
+                LISTENERS.lock().get_mut(&self.port)
+                    .and_then(|queue| queue.pop_front())
+            };
+            if let Some(id) = id {
+                return Some(TcpStream { id });
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let queue = {
+            let _interrupts = InterruptGuard::new();
+            LISTENERS.lock().remove(&self.port)
+        };
+
+        // Close the connections nobody accepted
+        for id in queue.into_iter().flatten() {
+            drop(TcpStream { id });
+        }
+    }
+}
+
+/// Retransmit what timed out, probe the closed windows, and forget the
+/// connections which finished closing
+pub(super) fn timers() {
+    let now = Instant::now();
+    let mut out = Vec::new();
+    {
+        let _interrupts = InterruptGuard::new();
+        let mut connections = CONNECTIONS.lock();
+        for conn in connections.values_mut() {
+            if conn.retransmit_at.is_some_and(|at| now >= at) {
+                conn.retransmit(&mut out);
+            }
+            if conn.persist_at.is_some_and(|at| now >= at) {
+                conn.probe(&mut out);
+            }
+            if conn.time_wait_until.is_some_and(|until| now >= until) {
+                conn.time_wait_until = None;
+                conn.set_state(State::Closed);
+            }
+        }
+
+        connections.retain(|_, conn| {
+            conn.state != State::Closed || !conn.orphan
+        });
+    }
+    transmit(out);
+}
+
+/// Handle the received TCP `segment` from `src` to `dst`
+pub(super) fn handle(src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8]) {
+    if segment.len() < HEADER_SIZE {
+        return;
+    }
+    let seed = ip::pseudo_header_sum(src, dst, PROTO_TCP,
+                                     segment.len() as u16);
+    if super::checksum(segment, seed as u32) != 0 {
+        return;
+    }
+
+    let header_len = (segment[12] >> 4) as usize * 4;
+    if header_len < HEADER_SIZE || header_len > segment.len() {
+        return;
+    }
+
+    // Only the MSS option is used
+    let mut mss = None;
+    let mut options = &segment[HEADER_SIZE..header_len];
+    while let [kind, rest @ ..] = options {
+        match *kind {
+            OPTION_END => break,
+            OPTION_NOP => { options = rest; continue; }
+            _          => {}
+        }
+
+        let [len, ..] = rest else { break; };
+        let Some(option) = options.get(..*len as usize).filter(|_| *len >= 2)
+        else {
+            break;
+        };
+        if *kind == OPTION_MSS && option.len() == 4 {
+            mss = Some(u16::from_be_bytes([option[2], option[3]]));
+        }
+        options = &options[option.len()..];
+    }
+
+    let src_port = u16::from_be_bytes([segment[0], segment[1]]);
+    let dst_port = u16::from_be_bytes([segment[2], segment[3]]);
+    let seg = Incoming {
+        seq:     u32::from_be_bytes(segment[4..8].try_into().unwrap()),
+        ack:     u32::from_be_bytes(segment[8..12].try_into().unwrap()),
+        flags:   segment[13],
+        window:  u16::from_be_bytes([segment[14], segment[15]]),
+        mss,
+        payload: &segment[header_len..],
+    };
+
+    let id = (dst_port, src, src_port);
+    let mut out = Vec::new();
+    {
+        let _interrupts = InterruptGuard::new();
+        let mut connections = CONNECTIONS.lock();
+
+        if let Some(conn) = connections.get_mut(&id) {
+            if conn.input(&seg, &mut out) {
+                // Hand the connection to its listener, or close it if the
+                // listener is gone or has too many waiting
+                let mut listeners = LISTENERS.lock();
+                match conn.listener.and_then(|port| listeners.get_mut(&port)) {
+                    Some(queue) if queue.len() < MAX_BACKLOG => {
+                        queue.push_back(id);
+                    }
+                    _ => {
+                        conn.orphan = true;
+                        conn.close(&mut out);
+                    }
+                }
+            }
+        } else if seg.flags & (FLAG_SYN | FLAG_ACK | FLAG_RST) == FLAG_SYN &&
+                dst == ip::addr() && LISTENERS.lock().contains_key(&dst_port) {
+            // A new connection to a listener
+            let mut conn = Connection::new(id, State::SynReceived);
+            conn.rcv_nxt  = seg.seq.wrapping_add(1);
+            conn.snd_wnd  = seg.window as u32;
+            conn.mss      = seg.mss.unwrap_or(DEFAULT_MSS).min(MSS);
+            conn.listener = Some(dst_port);
+            conn.output(&mut out);
+            connections.insert(id, conn);
+        } else if seg.flags & FLAG_RST == 0 {
+            out.push(Segment::reset(id, &seg));
+        }
+    }
+    transmit(out);
+}
+
+/// Connect to a TCP echo server on port `TEST_PORT` of the gateway, which is
+/// the host with QEMU user networking, and check that the data comes back.
+/// Run one on the host with `socat TCP-LISTEN:7777,fork EXEC:cat`.
+#[cfg(feature = "tcp_test")]
+pub fn self_test() {
+    /// Port of the echo server
+    const TEST_PORT: u16 = 7777;
+
+    /// Number of bytes echoed
+    const TEST_SIZE: usize = 256 * 1024;
+
+    let Some(gateway) = ip::config().and_then(|config| config.gateway) else {
+        print!("TCP test: no gateway\n");
+        return;
+    };
+    let Some(stream) = TcpStream::connect(gateway, TEST_PORT) else {
+        print!("TCP test: couldn't connect to {}:{}\n", gateway, TEST_PORT);
+        return;
+    };
+
+    // Send and read back concurrently, so neither side's window fills up
+    let data: Vec<u8> = (0..TEST_SIZE).map(|ii| (ii * 7 + ii / 251) as u8)
+        .collect();
+    let started = Instant::now();
+    let mut sent = 0;
+    let mut echoed = Vec::with_capacity(TEST_SIZE);
+    let mut buf = [0u8; 4096];
+    while echoed.len() < TEST_SIZE {
+        if sent < TEST_SIZE {
+            let Some(len) = stream.send(&data[sent..]) else { break; };
+            sent += len;
+        }
+        match stream.recv(&mut buf, Duration::from_millis(1)) {
+            Some(0) => break,
+            Some(len) => echoed.extend_from_slice(&buf[..len]),
+            None if stream.state() == State::Closed => break,
+            None => {}
+        }
+    }
+
+    let ok = echoed == data;
+    print!("TCP test: {} bytes echoed by {}:{} in {} ms, {}\n", echoed.len(),
+           gateway, TEST_PORT, started.elapsed().as_millis(),
+           if ok { "matching" } else { "MISMATCHED" });
+}
@@ -0,0 +1,442 @@
+//! TFTP client.
+//!
+//! Files are read and written in octet mode. The largest blocks which fit a
+//! frame are asked for (RFC 2348), along with windows of several blocks per
+//! acknowledgement (RFC 7440), and servers which don't know the options fall
+//! back to 512 byte blocks acknowledged one at a time. Lost blocks are sent
+//! again from the last one acknowledged, after a timeout or when the
+//! receiver acknowledges part of a window.
+
+use alloc::vec::Vec;
+use alloc::format;
+use alloc::string::String;
+use time::{ Duration, Instant };
+use super::ip::{ self, Ipv4Addr };
+use super::udp::{ self, UdpSocket };
+
+/// Port of the servers
+const SERVER_PORT: u16 = 69;
+
+/// Opcodes
+const OP_RRQ:   u16 = 1;
+const OP_WRQ:   u16 = 2;
+const OP_DATA:  u16 = 3;
+const OP_ACK:   u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK:  u16 = 6;
+
+/// Error codes
+const ERROR_ILLEGAL_OP:  u16 = 4;
+const ERROR_UNKNOWN_TID: u16 = 5;
+const ERROR_BAD_OPTIONS: u16 = 8;
+
+/// Size of the header of DATA packets
+const DATA_HEADER_SIZE: usize = 4;
+
+/// Size of the blocks without the blksize option
+const DEFAULT_BLKSIZE: usize = 512;
+
+/// Smallest block size a server can pick
+const MIN_BLKSIZE: usize = 8;
+
+/// Largest block size, which fills a frame
+pub const MAX_BLKSIZE: usize = udp::MAX_DATAGRAM - DATA_HEADER_SIZE;
+
+/// Number of blocks per acknowledgement asked for
+const WINDOWSIZE: u16 = 16;
+
+/// Time waited for an answer before sending again
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of timeouts in a row before a transfer is abandoned
+const ATTEMPTS: u32 = 5;
+
+/// A packet received
+enum Packet<'a> {
+    /// A block of the file
+    Data { block: u16, data: &'a [u8] },
+
+    /// Acknowledgement of the blocks up to `block`
+    Ack(u16),
+
+    /// The server gave up
+    Error { code: u16, message: &'a [u8] },
+
+    /// The options the server took, as NUL terminated names and values
+    Oack(&'a [u8]),
+}
+
+impl<'a> Packet<'a> {
+    /// Parse `packet`, `None` if it's malformed or of an unknown type
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        let opcode = u16::from_be_bytes(packet.get(..2)?.try_into().ok()?);
+        let number = || packet.get(2..4)
+            .map(|number| u16::from_be_bytes([number[0], number[1]]));
+        Some(match opcode {
+            OP_DATA => Packet::Data {
+                block: number()?,
+                data:  &packet[DATA_HEADER_SIZE..],
+            },
+            OP_ACK   => Packet::Ack(number()?),
+            OP_ERROR => {
+                let message = packet.get(4..)?;
+                let end = message.iter().position(|&b| b == 0)
+                    .unwrap_or(message.len());
+                Packet::Error { code: number()?, message: &message[..end] }
+            }
+            OP_OACK => Packet::Oack(&packet[2..]),
+            _ => return None,
+        })
+    }
+}
+
+/// Returns the value of option `name` in the options `options`, if it's
+/// there and a number
+fn option(options: &[u8], name: &str) -> Option<u64> {
+    let mut fields = options.split(|&b| b == 0);
+    while let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+        if key.eq_ignore_ascii_case(name.as_bytes()) {
+            return core::str::from_utf8(value).ok()?.parse().ok();
+        }
+    }
+    None
+}
+
+/// Returns a RRQ or a WRQ for `filename`, asking for the options. `tsize`
+/// is the size of the file written.
+fn request(opcode: u16, filename: &str, tsize: Option<usize>) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&opcode.to_be_bytes());
+    let mut field = |field: &str| {
+        packet.extend_from_slice(field.as_bytes());
+        packet.push(0);
+    };
+    field(filename);
+    field("octet");
+    field("blksize");
+    field(&format!("{}", MAX_BLKSIZE));
+    field("windowsize");
+    field(&format!("{}", WINDOWSIZE));
+    if let Some(tsize) = tsize {
+        field("tsize");
+        field(&format!("{}", tsize));
+    }
+    packet
+}
+
+/// Returns an ACK of `block`
+fn ack(block: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(4);
+    packet.extend_from_slice(&OP_ACK.to_be_bytes());
+    packet.extend_from_slice(&block.to_be_bytes());
+    packet
+}
+
+/// A transfer with a server
+struct Transfer {
+    /// Socket of the transfer
+    socket: UdpSocket,
+
+    /// The server
+    server: Ipv4Addr,
+
+    /// Port the server answered from, which the rest of the transfer goes
+    /// through
+    port: Option<u16>,
+
+    /// Block size and window size in use
+    blksize:    usize,
+    windowsize: u16,
+}
+
+impl Transfer {
+    /// Start a transfer with `server`, `None` if no port is free
+    fn new(server: Ipv4Addr) -> Option<Self> {
+        Some(Self {
+            socket:     UdpSocket::bind(0)?,
+            server,
+            port:       None,
+            blksize:    DEFAULT_BLKSIZE,
+            windowsize: 1,
+        })
+    }
+
+    /// Send `packet` to the server
+    fn send(&self, packet: &[u8]) {
+        self.socket.send_to(packet, self.server,
+                            self.port.unwrap_or(SERVER_PORT));
+    }
+
+    /// Send an error `code` with `message` to `port` of the server
+    fn send_error(&self, port: u16, code: u16, message: &str) {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&OP_ERROR.to_be_bytes());
+        packet.extend_from_slice(&code.to_be_bytes());
+        packet.extend_from_slice(message.as_bytes());
+        packet.push(0);
+        self.socket.send_to(&packet, self.server, port);
+    }
+
+    /// Returns the next packet from the server, waiting for up to `TIMEOUT`
+    /// for one. The first answer picks the port of the server, and packets
+    /// from any other are refused.
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            let left = deadline.duration_since(Instant::now());
+            let datagram = self.socket.recv(left)?;
+            if datagram.src != self.server {
+                continue;
+            }
+
+            match self.port {
+                None => self.port = Some(datagram.src_port),
+                Some(port) if port != datagram.src_port => {
+                    self.send_error(datagram.src_port, ERROR_UNKNOWN_TID,
+                                    "Unknown transfer ID");
+                    continue;
+                }
+                Some(_) => {}
+            }
+            return Some(datagram.data);
+        }
+        None
+    }
+
+    /// Take the options of `oack`. Returns `false`, after telling the
+    /// server, if it picked values we didn't allow.
+    fn negotiate(&mut self, oack: &[u8]) -> bool {
+        let blksize = option(oack, "blksize")
+            .unwrap_or(DEFAULT_BLKSIZE as u64);
+        let windowsize = option(oack, "windowsize").unwrap_or(1);
+        if !(MIN_BLKSIZE as u64..=MAX_BLKSIZE as u64).contains(&blksize) ||
+                !(1..=WINDOWSIZE as u64).contains(&windowsize) {
+            self.send_error(self.port.unwrap(), ERROR_BAD_OPTIONS,
+                            "Bad options");
+            return false;
+        }
+
+        self.blksize    = blksize as usize;
+        self.windowsize = windowsize as u16;
+        true
+    }
+
+    /// Report the error `code` with `message` the server sent
+    fn report(&self, code: u16, message: &[u8]) {
+        print!("TFTP: error {} from {}: {}\n", code, self.server,
+               String::from_utf8_lossy(message));
+    }
+
+    /// Give up on an unexpected packet
+    fn illegal(&self) {
+        self.send_error(self.port.unwrap(), ERROR_ILLEGAL_OP,
+                        "Unexpected packet");
+        print!("TFTP: unexpected packet from {}\n", self.server);
+    }
+}
+
+/// Returns the server the kernel was booted from, if it's known
+pub fn boot_server() -> Option<Ipv4Addr> {
+    ip::config()?.server
+}
+
+/// Read `filename` from `server`. `None` if the server refused or stopped
+/// answering.
+pub fn read(server: Ipv4Addr, filename: &str) -> Option<Vec<u8>> {
+    if filename.contains('\0') {
+        return None;
+    }
+    let mut xfer = Transfer::new(server)?;
+
+    // The packet sent again on a timeout, asking for the blocks after the
+    // last one received in order
+    let mut retry = request(OP_RRQ, filename, None);
+    xfer.send(&retry);
+
+    let mut file = Vec::new();
+    let mut started = false;
+    let mut block: u16 = 0;
+    let mut unacked: u16 = 0;
+    let mut resynced = false;
+    let mut timeouts = 0;
+    loop {
+        let Some(packet) = xfer.recv() else {
+            timeouts += 1;
+            if timeouts >= ATTEMPTS {
+                print!("TFTP: {} stopped answering\n", server);
+                return None;
+            }
+            xfer.send(&retry);
+            unacked = 0;
+            continue;
+        };
+
+        match Packet::parse(&packet) {
+            // The server took some options, acknowledged with block 0.
+            // It's sent again if our acknowledgement got lost.
+            Some(Packet::Oack(options)) if !started => {
+                if !xfer.negotiate(options) {
+                    return None;
+                }
+                timeouts = 0;
+                retry = ack(0);
+                xfer.send(&retry);
+            }
+            Some(Packet::Data { block: number, data })
+                    if number == block.wrapping_add(1) &&
+                    data.len() <= xfer.blksize => {
+                started = true;
+                timeouts = 0;
+                resynced = false;
+                block = number;
+                unacked += 1;
+                file.extend_from_slice(data);
+                retry = ack(block);
+
+                // A short block ends the file. If its acknowledgement gets
+                // lost, the server gives up after sending everything.
+                if data.len() < xfer.blksize {
+                    xfer.send(&retry);
+                    print!("TFTP: read {} bytes of {} from {}\n", file.len(),
+                           filename, server);
+                    return Some(file);
+                }
+                if unacked >= xfer.windowsize {
+                    xfer.send(&retry);
+                    unacked = 0;
+                }
+            }
+            Some(Packet::Data { block: number, .. }) => {
+                // A block went missing, acknowledge the ones before it once
+                // for the server to go on from there. Blocks already
+                // received are ignored.
+                let ahead = number.wrapping_sub(block);
+                if started && !resynced && ahead > 1 &&
+                        ahead <= xfer.windowsize {
+                    xfer.send(&retry);
+                    unacked = 0;
+                    resynced = true;
+                }
+            }
+            Some(Packet::Error { code, message }) => {
+                xfer.report(code, message);
+                return None;
+            }
+            _ => {
+                xfer.illegal();
+                return None;
+            }
+        }
+    }
+}
+
+/// Write `data` to `filename` on `server`. Returns whether the server took
+/// all of it.
+pub fn write(server: Ipv4Addr, filename: &str, data: &[u8]) -> bool {
+    if filename.contains('\0') {
+        return false;
+    }
+    let Some(mut xfer) = Transfer::new(server) else {
+        return false;
+    };
+
+    // Wait for the server to take the request, with an OACK or the ACK of
+    // block 0
+    let wrq = request(OP_WRQ, filename, Some(data.len()));
+    let mut timeouts = 0;
+    loop {
+        xfer.send(&wrq);
+        let Some(packet) = xfer.recv() else {
+            timeouts += 1;
+            if timeouts >= ATTEMPTS {
+                print!("TFTP: {} stopped answering\n", server);
+                return false;
+            }
+            continue;
+        };
+
+        match Packet::parse(&packet) {
+            Some(Packet::Oack(options)) => {
+                if !xfer.negotiate(options) {
+                    return false;
+                }
+                break;
+            }
+            Some(Packet::Ack(0)) => break,
+            Some(Packet::Error { code, message }) => {
+                xfer.report(code, message);
+                return false;
+            }
+            _ => {
+                xfer.illegal();
+                return false;
+            }
+        }
+    }
+
+    // The file ends with a short block, empty if the size is a multiple of
+    // the block size
+    let blksize = xfer.blksize;
+    let blocks = (data.len() / blksize + 1) as u64;
+    let block_data = |index: u64| {
+        let start = index as usize * blksize;
+        &data[start..data.len().min(start + blksize)]
+    };
+
+    // Send a window from the first block not acknowledged, and wait for an
+    // acknowledgement moving it
+    let mut acked: u64 = 0;
+    let mut timeouts = 0;
+    'window: loop {
+        let end = (acked + xfer.windowsize as u64).min(blocks);
+        for index in acked..end {
+            let mut packet = Vec::with_capacity(DATA_HEADER_SIZE + blksize);
+            packet.extend_from_slice(&OP_DATA.to_be_bytes());
+            packet.extend_from_slice(&((index + 1) as u16).to_be_bytes());
+            packet.extend_from_slice(block_data(index));
+            xfer.send(&packet);
+        }
+
+        loop {
+            let Some(packet) = xfer.recv() else {
+                timeouts += 1;
+                if timeouts >= ATTEMPTS {
+                    print!("TFTP: {} stopped answering\n", server);
+                    return false;
+                }
+                continue 'window;
+            };
+
+            match Packet::parse(&packet) {
+                // Duplicate acknowledgements are ignored, so they don't
+                // multiply the blocks sent again
+                Some(Packet::Ack(number)) => {
+                    let new = number.wrapping_sub(acked as u16) as u64;
+                    if new == 0 || new > end - acked {
+                        continue;
+                    }
+
+                    acked += new;
+                    timeouts = 0;
+                    if acked == blocks {
+                        print!("TFTP: wrote {} bytes of {} to {}\n",
+                               data.len(), filename, server);
+                        return true;
+                    }
+                    continue 'window;
+                }
+
+                // The server didn't get the first window
+                Some(Packet::Oack(_)) if acked == 0 => continue 'window,
+                Some(Packet::Error { code, message }) => {
+                    xfer.report(code, message);
+                    return false;
+                }
+                _ => {
+                    xfer.illegal();
+                    return false;
+                }
+            }
+        }
+    }
+}
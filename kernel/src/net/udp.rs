@@ -0,0 +1,162 @@
+//! UDP sockets.
+//!
+//! Datagrams are queued on the socket bound to their destination port as
+//! the stack is polled, which receiving on a socket does.
+
+use alloc::vec::Vec;
+use alloc::collections::{ BTreeMap, VecDeque };
+use spinlock::SpinLock;
+use cpu::InterruptGuard;
+use time::{ Duration, Instant };
+use super::ip::{ self, Ipv4Addr, PROTO_UDP };
+use super::{ TxChecksum, EphemeralPorts };
+
+/// Size of the header
+const HEADER_SIZE: usize = 8;
+
+/// Largest datagram which can be sent
+pub const MAX_DATAGRAM: usize = ip::MAX_PAYLOAD - HEADER_SIZE;
+
+/// Largest number of datagrams queued on a socket before the oldest ones
+/// are dropped
+const MAX_QUEUED: usize = 64;
+
+/// A datagram received
+#[derive(Clone, Debug)]
+pub struct Datagram {
+    /// Address the datagram came from
+    pub src: Ipv4Addr,
+
+    /// Port the datagram came from
+    pub src_port: u16,
+
+    /// The payload
+    pub data: Vec<u8>,
+}
+
+/// The datagrams queued on the bound ports
+static SOCKETS: SpinLock<BTreeMap<u16, VecDeque<Datagram>>> =
+    SpinLock::new(BTreeMap::new());
+
+/// The ports bound when asked for port 0
+static EPHEMERAL: EphemeralPorts = EphemeralPorts::new();
+
+/// A bound UDP socket, unbound when dropped
+pub struct UdpSocket {
+    /// The port the socket is bound to
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Bind a socket to `port`, or to a free ephemeral port if it's 0.
+    /// `None` if the port is taken.
+    pub fn bind(port: u16) -> Option<Self> {
+        let _interrupts = InterruptGuard::new();
+        let mut sockets = SOCKETS.lock();
+
+        let port = if port != 0 {
+            port
+        } else {
+            EPHEMERAL.alloc(|port| sockets.contains_key(&port))?
+        };
+
+        if sockets.contains_key(&port) {
+            return None;
+        }
+        sockets.insert(port, VecDeque::new());
+        Some(Self { port })
+    }
+
+    /// Returns the port the socket is bound to
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send `data` to `port` at `dst`. Returns whether it was queued, which
+    /// it isn't if it's larger than `MAX_DATAGRAM`, `dst` can't be reached
+    /// or the transmit queue is full.
+    pub fn send_to(&self, data: &[u8], dst: Ipv4Addr, port: u16) -> bool {
+        if data.len() > MAX_DATAGRAM {
+            return false;
+        }
+
+        // The checksum is seeded with the pseudo header and filled in by the
+        // device
+        let len = (HEADER_SIZE + data.len()) as u16;
+        let seed = ip::pseudo_header_sum(ip::addr(), dst, PROTO_UDP, len);
+        let mut datagram = Vec::with_capacity(len as usize);
+        datagram.extend_from_slice(&self.port.to_be_bytes());
+        datagram.extend_from_slice(&port.to_be_bytes());
+        datagram.extend_from_slice(&len.to_be_bytes());
+        datagram.extend_from_slice(&seed.to_be_bytes());
+        datagram.extend_from_slice(data);
+
+        ip::send(dst, PROTO_UDP, &datagram,
+                 Some(TxChecksum { start: 0, offset: 6 }))
+    }
+
+    /// Returns the next datagram received, if there is one
+    pub fn try_recv(&self) -> Option<Datagram> {
+        super::poll();
+
+        let _interrupts = InterruptGuard::new();
+        SOCKETS.lock().get_mut(&self.port)?.pop_front()
+    }
+
+    /// Returns the next datagram received, waiting for up to `timeout` for
+    /// one
+    pub fn recv(&self, timeout: Duration) -> Option<Datagram> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(datagram) = self.try_recv() {
+                return Some(datagram);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let _interrupts = InterruptGuard::new();
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+/// Handle the received UDP `datagram` from `src` to `dst`
+pub(super) fn handle(src: Ipv4Addr, dst: Ipv4Addr, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+
+    let src_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let dst_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let len      = u16::from_be_bytes([datagram[4], datagram[5]]);
+    if (len as usize) < HEADER_SIZE || len as usize > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len as usize];
+
+    // A checksum of 0 means the sender didn't compute one
+    if datagram[6..8] != [0, 0] {
+        let seed = ip::pseudo_header_sum(src, dst, PROTO_UDP, len);
+        if super::checksum(datagram, seed as u32) != 0 {
+            return;
+        }
+    }
+
+    let _interrupts = InterruptGuard::new();
+    if let Some(queue) = SOCKETS.lock().get_mut(&dst_port) {
+        if queue.len() >= MAX_QUEUED {
+            queue.pop_front();
+        }
+        queue.push_back(Datagram {
+            src,
+            src_port,
+            data: datagram[HEADER_SIZE..].to_vec(),
+        });
+    }
+}
@@ -0,0 +1,360 @@
+//! virtio-net driver.
+//!
+//! One receive and one transmit queue, with a buffer of `BUFFER_SIZE` bytes
+//! for every descriptor. Received frames are taken off the queue by the MSI-X
+//! interrupt of the receive queue when the device has MSI-X, and by `recv()`
+//! otherwise. Sent buffers are reclaimed by the next `send()`. A device
+//! returning buffers it doesn't hold is marked failed and not used anymore.
+
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use spinlock::SpinLock;
+use cpu::InterruptGuard;
+use virtio::{ Buffer, DeviceType, Dma, Platform, Transport, Virtqueue };
+use virtio::pci::NO_VECTOR;
+use crate::core_locals;
+use crate::interrupts::InterruptState;
+use crate::pci::{ self, Device };
+use crate::pci::msi::MsiX;
+use crate::pci::virtio::KernelPlatform;
+use super::{ Mac, NetDevice, TxChecksum, MAX_FRAME_SIZE };
+use super::{ PendingFrames, InterruptDriven };
+
+/// The device fills in the checksums given to it on transmission
+const F_CSUM: u64 = 1 << 0;
+
+/// The driver accepts received frames with checksums left to fill in
+const F_GUEST_CSUM: u64 = 1 << 1;
+
+/// The device configuration has a MAC address
+const F_MAC: u64 = 1 << 5;
+
+/// The device configuration has a link status
+const F_STATUS: u64 = 1 << 16;
+
+/// Offsets in the device configuration
+const CONFIG_MAC:    usize = 0;
+const CONFIG_STATUS: usize = 6;
+
+/// The link is up, in the status of the device configuration
+const STATUS_LINK_UP: u16 = 1 << 0;
+
+/// Indices of the queues
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// Largest number of descriptors in a queue
+const QUEUE_SIZE: u16 = 256;
+
+/// Size of the header in front of every frame
+const HEADER_SIZE: usize = 12;
+
+/// Size of the buffer of a descriptor, fitting a header and a whole frame
+const BUFFER_SIZE: usize = 2048;
+
+/// The checksum described by the header is left to fill in
+const HDR_F_NEEDS_CSUM: u8 = 1 << 0;
+
+/// A queue with a buffer for every descriptor
+struct Ring {
+    /// The queue
+    queue: Virtqueue,
+
+    /// The buffers, `BUFFER_SIZE` bytes each
+    buffers: Dma,
+
+    /// Buffer of every chain on the queue, indexed by chain ID
+    slot_of: Vec<u16>,
+
+    /// Buffers which aren't on the queue
+    free: Vec<u16>,
+}
+
+// The DMA pointers are to memory owned by the ring
+unsafe impl Send for Ring {}
+
+impl Ring {
+    /// Set up queue `index` of `transport` and allocate its buffers
+    fn new(transport: &mut Transport<KernelPlatform>, index: u16)
+            -> Option<Self> {
+        let queue = transport.setup_queue(index, QUEUE_SIZE)?;
+        let size  = queue.size();
+        let buffers = transport.platform()
+            .alloc_dma(size as usize * BUFFER_SIZE, 4096)?;
+
+        Some(Self {
+            queue,
+            buffers,
+            slot_of: alloc::vec![0; size as usize],
+            free:    (0..size).rev().collect(),
+        })
+    }
+
+    /// Returns the physical address and a pointer to buffer `slot`
+    fn buffer(&self, slot: u16) -> (u64, *mut u8) {
+        let offset = slot as usize * BUFFER_SIZE;
+        unsafe {
+            (self.buffers.phys + offset as u64, self.buffers.ptr.add(offset))
+        }
+    }
+
+    /// Put buffer `slot` on the queue with `len` bytes
+    fn add(&mut self, slot: u16, len: usize, writable: bool) {
+        let (addr, _) = self.buffer(slot);
+        let head = self.queue.add(&[Buffer {
+            addr,
+            len: len as u32,
+            writable,
+        }]).expect("virtio-net queue has fewer descriptors than buffers.");
+        self.slot_of[head as usize] = slot;
+    }
+
+    /// Take the next chain the device is done with off the queue and return
+    /// its buffer and the number of bytes written to it
+    fn pop(&mut self) -> Option<(u16, usize)> {
+        let (head, len) = self.queue.pop_used()?;
+        Some((self.slot_of[head as usize], len as usize))
+    }
+}
+
+/// The queues of a device
+struct Queues {
+    rx: Ring,
+    tx: Ring,
+
+    /// Frames taken off the receive queue and not returned by `recv()` yet
+    pending: PendingFrames,
+}
+
+impl Queues {
+    /// Move the frames received to `pending` and give their buffers back to
+    /// the device
+    fn drain_rx(&mut self) {
+        let mut refilled = false;
+        while let Some((slot, len)) = self.rx.pop() {
+            let (_, buffer) = self.rx.buffer(slot);
+            let len = len.clamp(HEADER_SIZE, BUFFER_SIZE);
+            let data = unsafe { core::slice::from_raw_parts(buffer, len) };
+
+            // Fill in the checksum the host left to us
+            let mut frame = data[HEADER_SIZE..].to_vec();
+            if data[0] & HDR_F_NEEDS_CSUM != 0 {
+                super::complete_checksum(&mut frame, TxChecksum {
+                    start:  u16::from_le_bytes([data[6], data[7]]),
+                    offset: u16::from_le_bytes([data[8], data[9]]),
+                });
+            }
+
+            self.pending.push(frame);
+
+            self.rx.add(slot, BUFFER_SIZE, true);
+            refilled = true;
+        }
+
+        if refilled {
+            self.rx.queue.notify();
+        }
+    }
+
+    /// Reclaim the buffers of the frames the device has sent
+    fn reclaim_tx(&mut self) {
+        while let Some((slot, _)) = self.tx.pop() {
+            self.tx.free.push(slot);
+        }
+    }
+
+    /// Returns whether the device broke one of the queues
+    fn broken(&self) -> bool {
+        self.rx.queue.is_broken() || self.tx.queue.is_broken()
+    }
+}
+
+/// A virtio-net device
+struct VirtioNet {
+    /// The transport of the device
+    transport: Transport<KernelPlatform>,
+
+    /// MAC address of the device
+    mac: Mac,
+
+    /// Features negotiated
+    features: u64,
+
+    /// The queues
+    queues: SpinLock<Queues>,
+}
+
+// The transport is only used for device configuration reads and to mark the
+// device failed after the setup, the queues are behind the lock
+unsafe impl Sync for VirtioNet {}
+
+impl VirtioNet {
+    /// Mark the device failed if it broke one of `queues`. Returns whether
+    /// it's broken.
+    fn check_broken(&self, queues: &Queues) -> bool {
+        if !queues.broken() {
+            return false;
+        }
+        if !self.transport.fail() {
+            print!("virtio-net: {} broke its queues, giving up on it\n",
+                   self.mac);
+        }
+        true
+    }
+}
+
+/// The devices with interrupt driven receive queues
+static INTERRUPT_DRIVEN: InterruptDriven<VirtioNet> = InterruptDriven::new();
+
+/// Drain the receive queue of the device whose interrupt fired
+fn rx_interrupt(state: &mut InterruptState) {
+    INTERRUPT_DRIVEN.dispatch(state.vector as u8, |device| {
+        let mut queues = device.queues.lock();
+        queues.drain_rx();
+        device.check_broken(&queues);
+    });
+}
+
+impl NetDevice for VirtioNet {
+    fn mac(&self) -> Mac {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        // Without a status, the link is always up
+        if self.features & F_STATUS == 0 {
+            return true;
+        }
+        self.transport.config_read::<u16>(CONFIG_STATUS)
+            .is_some_and(|status| status & STATUS_LINK_UP != 0)
+    }
+
+    fn send(&self, frame: &[u8], checksum: Option<TxChecksum>) -> bool {
+        assert!(frame.len() <= MAX_FRAME_SIZE, "Sending an oversized frame.");
+
+        let _interrupts = InterruptGuard::new();
+        let mut queues = self.queues.lock();
+        queues.reclaim_tx();
+        if self.check_broken(&queues) {
+            return false;
+        }
+        let Some(slot) = queues.tx.free.pop() else {
+            return false;
+        };
+
+        // Build the header and the frame in the buffer
+        let mut header = [0u8; HEADER_SIZE];
+        let (_, buffer) = queues.tx.buffer(slot);
+        let data = unsafe {
+            core::slice::from_raw_parts_mut(buffer, HEADER_SIZE + frame.len())
+        };
+        data[HEADER_SIZE..].copy_from_slice(frame);
+        match checksum {
+            Some(csum) if self.features & F_CSUM != 0 => {
+                header[0] = HDR_F_NEEDS_CSUM;
+                header[6..8].copy_from_slice(&csum.start.to_le_bytes());
+                header[8..10].copy_from_slice(&csum.offset.to_le_bytes());
+            }
+            Some(csum) => {
+                super::complete_checksum(&mut data[HEADER_SIZE..], csum);
+            }
+            None => {}
+        }
+        data[..HEADER_SIZE].copy_from_slice(&header);
+
+        queues.tx.add(slot, HEADER_SIZE + frame.len(), false);
+        queues.tx.queue.notify();
+        true
+    }
+
+    fn recv(&self) -> Option<Vec<u8>> {
+        let _interrupts = InterruptGuard::new();
+        let mut queues = self.queues.lock();
+        if queues.pending.is_empty() {
+            queues.drain_rx();
+        }
+        if self.check_broken(&queues) {
+            return None;
+        }
+        queues.pending.pop()
+    }
+}
+
+/// Set up `device` if it's a virtio-net device and register it
+pub fn probe(device: &Device) {
+    if device.vendor != virtio::VENDOR_ID ||
+            DeviceType::from_pci(device.device) != Some(DeviceType::Network) {
+        return;
+    }
+
+    let Some(mut transport) = pci::virtio::transport(device) else {
+        print!("virtio-net: {} is a legacy device, skipping\n", device.addr);
+        return;
+    };
+    let Some(features) =
+            transport.init(F_CSUM | F_GUEST_CSUM | F_MAC | F_STATUS) else {
+        print!("virtio-net: {} refused the features\n", device.addr);
+        return;
+    };
+
+    // Interrupt on received frames on the current core, if there's MSI-X.
+    // The entry stays masked until the handler can find the device.
+    let msix = MsiX::new(device);
+    let vector = msix.as_ref().and_then(|msix| {
+        let vector = msix.route(0, core_locals::core_id(), rx_interrupt)?;
+        msix.set_masked(0, true);
+        if transport.set_queue_vector(RX_QUEUE, 0) {
+            Some(vector)
+        } else {
+            msix.unroute(0, vector);
+            None
+        }
+    });
+    transport.set_queue_vector(TX_QUEUE, NO_VECTOR);
+    transport.set_config_vector(NO_VECTOR);
+
+    let (Some(mut rx), Some(tx)) =
+            (Ring::new(&mut transport, RX_QUEUE),
+             Ring::new(&mut transport, TX_QUEUE)) else {
+        print!("virtio-net: couldn't set up the queues of {}\n", device.addr);
+        transport.reset();
+        if let (Some(msix), Some(vector)) = (&msix, vector) {
+            msix.unroute(0, vector);
+        }
+        return;
+    };
+
+    // Give all the receive buffers to the device and start it
+    while let Some(slot) = rx.free.pop() {
+        rx.add(slot, BUFFER_SIZE, true);
+    }
+    transport.driver_ok();
+    rx.queue.notify();
+
+    // Without a MAC in the configuration, use a locally administered one
+    // unique to the PCI address
+    let addr = device.addr;
+    let mac = if features & F_MAC != 0 {
+        transport.config_read::<[u8; 6]>(CONFIG_MAC)
+    } else {
+        None
+    }.unwrap_or([0x02, (addr.segment >> 8) as u8, addr.segment as u8,
+                 addr.bus, addr.device, addr.function]);
+
+    let net: &'static VirtioNet = Box::leak(Box::new(VirtioNet {
+        transport,
+        mac: Mac(mac),
+        features,
+        queues: SpinLock::new(Queues { rx, tx, pending: PendingFrames::new() }),
+    }));
+
+    print!("virtio-net: {} {} checksum offload, {}\n", device.addr,
+           if features & F_CSUM != 0 { "with" } else { "without" },
+           if vector.is_some() { "interrupt driven" } else { "polled" });
+
+    if let (Some(msix), Some(vector)) = (&msix, vector) {
+        INTERRUPT_DRIVEN.add(vector, net);
+        msix.set_masked(0, false);
+    }
+    super::register(net);
+}
@@ -0,0 +1,691 @@
+//! PCI and PCI express bus enumeration.
+//!
+//! The configuration space is accessed through the memory mapped ECAM regions
+//! in the ACPI MCFG when there are any, and through the legacy 0xcf8/0xcfc
+//! I/O ports otherwise. The buses are walked from the host bridges through
+//! the PCI-to-PCI bridges once at boot, and the devices found are kept for
+//! the drivers to look up.
+
+pub mod msi;
+pub mod virtio;
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{ AtomicPtr, Ordering };
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use spinlock::SpinLock;
+use cpu::{ Port, InterruptGuard };
+use acpi::mcfg::McfgEntry;
+use crate::ioapic::{ self, Line };
+use crate::mm::{ self, PhysAddr, VirtAddr };
+
+/// Legacy configuration address port
+const CONFIG_ADDRESS: Port<u32> = Port::new(0xcf8);
+
+/// Legacy configuration data port, the byte at `offset & 3` of the selected
+/// dword is accessed at `CONFIG_DATA + (offset & 3)`
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// Enable bit of the legacy configuration address
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+/// Size of the configuration space of a function through ECAM
+const CONFIG_SIZE: u16 = 4096;
+
+/// Size of the configuration space of a function through the legacy ports
+const LEGACY_CONFIG_SIZE: u16 = 256;
+
+/// Size of the ECAM region of a bus
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+/// Configuration space registers of the common header
+pub const REG_VENDOR:         u16 = 0x00;
+pub const REG_DEVICE:         u16 = 0x02;
+pub const REG_COMMAND:        u16 = 0x04;
+pub const REG_STATUS:         u16 = 0x06;
+pub const REG_REVISION:       u16 = 0x08;
+pub const REG_PROG_IF:        u16 = 0x09;
+pub const REG_SUBCLASS:       u16 = 0x0a;
+pub const REG_CLASS:          u16 = 0x0b;
+pub const REG_HEADER_TYPE:    u16 = 0x0e;
+pub const REG_BAR0:           u16 = 0x10;
+pub const REG_CAPABILITIES:   u16 = 0x34;
+pub const REG_INTERRUPT_LINE: u16 = 0x3c;
+pub const REG_INTERRUPT_PIN:  u16 = 0x3d;
+
+/// Configuration space registers of the PCI-to-PCI bridge header
+const REG_PRIMARY_BUS:     u16 = 0x18;
+const REG_SECONDARY_BUS:   u16 = 0x19;
+const REG_SUBORDINATE_BUS: u16 = 0x1a;
+
+/// The device responds to I/O space accesses
+pub const COMMAND_IO: u16 = 1 << 0;
+
+/// The device responds to memory space accesses
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+
+/// The device can master the bus, for DMA and MSIs
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// The device can't assert its INTx interrupt
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// The device has a capabilities list
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// The device has more than one function
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+/// Header type of a PCI-to-PCI bridge
+const HEADER_BRIDGE: u8 = 0x01;
+
+/// Capability IDs
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI:              u8 = 0x05;
+pub const CAP_VENDOR:           u8 = 0x09;
+pub const CAP_PCI_EXPRESS:      u8 = 0x10;
+pub const CAP_MSIX:             u8 = 0x11;
+
+/// Vendor ID read from functions which aren't present
+const NO_VENDOR: u16 = 0xffff;
+
+/// The address of a function in the configuration space
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Address {
+    /// PCI segment group, always 0 without ECAM
+    pub segment: u16,
+
+    pub bus:      u8,
+    pub device:   u8,
+    pub function: u8,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}",
+               self.segment, self.bus, self.device, self.function)
+    }
+}
+
+/// Width of a configuration space access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Width {
+    Byte  = 1,
+    Word  = 2,
+    Dword = 4,
+}
+
+/// The configuration space access method
+struct Config {
+    /// ECAM regions from the MCFG, empty to use the legacy ports only
+    ecam: Vec<McfgEntry>,
+
+    /// Virtual addresses of the ECAM regions of the buses mapped so far,
+    /// keyed by segment and bus
+    buses: BTreeMap<(u16, u8), VirtAddr>,
+}
+
+impl Config {
+    /// Returns a pointer to `offset` in the ECAM region of `addr`, mapping
+    /// the region of the bus if needed. `None` if no ECAM region covers it.
+    fn ecam_ptr(&mut self, addr: Address, offset: u16) -> Option<*mut u8> {
+        let key = (addr.segment, addr.bus);
+        let bus = match self.buses.get(&key) {
+            Some(&bus) => bus,
+            None => {
+                let entry = self.ecam.iter().find(|entry| {
+                    entry.segment == addr.segment &&
+                        (entry.start_bus..=entry.end_bus).contains(&addr.bus)
+                })?;
+                let phys = entry.config_addr(addr.bus, 0, 0)?;
+                let bus  = mm::map_mmio(PhysAddr(phys), ECAM_BUS_SIZE)
+                    .expect("Couldn't map a PCI ECAM region.");
+                self.buses.insert(key, bus);
+                bus
+            }
+        };
+
+        Some((bus.0 + ((addr.device as u64) << 15 |
+                       (addr.function as u64) << 12 |
+                       offset as u64)) as *mut u8)
+    }
+}
+
+/// The configuration space access method, set up by `init()`
+static CONFIG: SpinLock<Option<Config>> = SpinLock::new(None);
+
+/// The devices found on the buses, set once by `init()`
+static DEVICES: AtomicPtr<Vec<Device>> = AtomicPtr::new(ptr::null_mut());
+
+/// Select `offset` in the configuration space of `addr` through the legacy
+/// ports and return the data port to access it through
+unsafe fn legacy_select(addr: Address, offset: u16) -> u16 {
+    CONFIG_ADDRESS.write(CONFIG_ENABLE |
+                         (addr.bus as u32) << 16 |
+                         (addr.device as u32) << 11 |
+                         (addr.function as u32) << 8 |
+                         (offset as u32 & 0xfc));
+    CONFIG_DATA + (offset & 3)
+}
+
+/// Read `width` bytes at `offset` in the configuration space of `addr`. All
+/// ones are returned for functions or offsets which can't be reached.
+unsafe fn config_read(addr: Address, offset: u16, width: Width) -> u32 {
+    assert!(offset.is_multiple_of(width as u16) && offset < CONFIG_SIZE,
+        "Invalid PCI configuration space access.");
+
+    let _interrupts = InterruptGuard::new();
+    let mut config = CONFIG.lock();
+    let config = config.as_mut().expect("PCI isn't initialized.");
+
+    if let Some(ptr) = config.ecam_ptr(addr, offset) {
+        match width {
+            Width::Byte  => ptr::read_volatile(ptr) as u32,
+            Width::Word  => ptr::read_volatile(ptr as *const u16) as u32,
+            Width::Dword => ptr::read_volatile(ptr as *const u32),
+        }
+    } else if addr.segment == 0 && offset < LEGACY_CONFIG_SIZE {
+        let port = legacy_select(addr, offset);
+        match width {
+            Width::Byte  => cpu::in8(port) as u32,
+            Width::Word  => cpu::in16(port) as u32,
+            Width::Dword => cpu::in32(port),
+        }
+    } else {
+        u32::MAX >> (32 - 8 * width as u32)
+    }
+}
+
+/// Write the low `width` bytes of `val` at `offset` in the configuration
+/// space of `addr`. Writes which can't reach the function are dropped.
+unsafe fn config_write(addr: Address, offset: u16, width: Width, val: u32) {
+    assert!(offset.is_multiple_of(width as u16) && offset < CONFIG_SIZE,
+        "Invalid PCI configuration space access.");
+
+    let _interrupts = InterruptGuard::new();
+    let mut config = CONFIG.lock();
+    let config = config.as_mut().expect("PCI isn't initialized.");
+
+    if let Some(ptr) = config.ecam_ptr(addr, offset) {
+        match width {
+            Width::Byte  => ptr::write_volatile(ptr, val as u8),
+            Width::Word  => ptr::write_volatile(ptr as *mut u16, val as u16),
+            Width::Dword => ptr::write_volatile(ptr as *mut u32, val),
+        }
+    } else if addr.segment == 0 && offset < LEGACY_CONFIG_SIZE {
+        let port = legacy_select(addr, offset);
+        match width {
+            Width::Byte  => cpu::out8(port, val as u8),
+            Width::Word  => cpu::out16(port, val as u16),
+            Width::Dword => cpu::out32(port, val),
+        }
+    }
+}
+
+impl Address {
+    /// Read the byte at `offset` in the configuration space
+    ///
+    /// # Safety
+    ///
+    /// Reads of some registers have side effects, like clearing status
+    /// bits, which the driver of the function must expect.
+    pub unsafe fn read8(&self, offset: u16) -> u8 {
+        config_read(*self, offset, Width::Byte) as u8
+    }
+
+    /// Read the word at `offset` in the configuration space
+    ///
+    /// # Safety
+    ///
+    /// The read must not have side effects the driver of the function
+    /// doesn't expect.
+    pub unsafe fn read16(&self, offset: u16) -> u16 {
+        config_read(*self, offset, Width::Word) as u16
+    }
+
+    /// Read the dword at `offset` in the configuration space
+    ///
+    /// # Safety
+    ///
+    /// The read must not have side effects the driver of the function
+    /// doesn't expect.
+    pub unsafe fn read32(&self, offset: u16) -> u32 {
+        config_read(*self, offset, Width::Dword)
+    }
+
+    /// Write the byte `val` at `offset` in the configuration space
+    ///
+    /// # Safety
+    ///
+    /// The write must not change the function under its driver, like
+    /// moving a BAR which is mapped or disabling decoding while it's used.
+    pub unsafe fn write8(&self, offset: u16, val: u8) {
+        config_write(*self, offset, Width::Byte, val as u32);
+    }
+
+    /// Write the word `val` at `offset` in the configuration space
+    ///
+    /// # Safety
+    ///
+    /// The write must not change the function under its driver.
+    pub unsafe fn write16(&self, offset: u16, val: u16) {
+        config_write(*self, offset, Width::Word, val as u32);
+    }
+
+    /// Write the dword `val` at `offset` in the configuration space
+    ///
+    /// # Safety
+    ///
+    /// The write must not change the function under its driver.
+    pub unsafe fn write32(&self, offset: u16, val: u32) {
+        config_write(*self, offset, Width::Dword, val);
+    }
+}
+
+/// A base address register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    /// A memory region
+    Memory {
+        /// Physical address of the region
+        addr: u64,
+
+        /// Size of the region in bytes
+        size: u64,
+
+        /// Whether the region has no read side effects
+        prefetchable: bool,
+
+        /// Whether the BAR is 64 bits wide, taking the next BAR slot too
+        wide: bool,
+    },
+
+    /// A range of I/O ports
+    Io {
+        /// First port of the range
+        port: u16,
+
+        /// Number of ports in the range
+        size: u16,
+    },
+}
+
+/// The bus numbers of a PCI-to-PCI bridge
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bridge {
+    /// Bus the bridge is on
+    pub primary: u8,
+
+    /// Bus directly behind the bridge
+    pub secondary: u8,
+
+    /// Highest bus behind the bridge
+    pub subordinate: u8,
+}
+
+/// An entry of the capabilities list
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capability {
+    /// ID of the capability
+    pub id: u8,
+
+    /// Offset of the capability in the configuration space
+    pub offset: u8,
+}
+
+/// A PCI function
+#[derive(Clone, Debug)]
+pub struct Device {
+    /// Address of the function
+    pub addr: Address,
+
+    /// Vendor and device IDs
+    pub vendor: u16,
+    pub device: u16,
+
+    /// Class code and revision
+    pub class:    u8,
+    pub subclass: u8,
+    pub prog_if:  u8,
+    pub revision: u8,
+
+    /// Header type, without the multifunction bit
+    pub header_type: u8,
+
+    /// INTx pin the function uses, 1 for INTA# to 4 for INTD#, 0 for none
+    pub interrupt_pin: u8,
+
+    /// ISA IRQ the firmware connected the INTx pin to, 0xff if it's unknown
+    pub interrupt_line: u8,
+
+    /// Base address registers, a 64-bit BAR leaves the next slot empty
+    pub bars: [Option<Bar>; 6],
+
+    /// The capabilities list
+    pub capabilities: Vec<Capability>,
+
+    /// Bus numbers if the function is a PCI-to-PCI bridge
+    pub bridge: Option<Bridge>,
+}
+
+impl Device {
+    /// Returns the offset of the first capability with `id`
+    pub fn capability(&self, id: u8) -> Option<u8> {
+        self.capabilities.iter().find(|cap| cap.id == id)
+            .map(|cap| cap.offset)
+    }
+
+    /// Returns the I/O APIC line of the INTx interrupt of the device, if it
+    /// has one. There's no AML interpreter to evaluate the `_PRT`, so this
+    /// relies on the firmware having routed the pin to the ISA IRQ in the
+    /// interrupt line register, which the MADT overrides map to a GSI.
+    pub fn intx_line(&self) -> Option<Line> {
+        if self.interrupt_pin == 0 {
+            return None;
+        }
+        ioapic::pci_line(self.interrupt_line)
+    }
+
+    /// Enable the decoding of the BARs of the device and let it master the
+    /// bus
+    pub fn enable(&self) {
+        unsafe {
+            let command = self.addr.read16(REG_COMMAND);
+            self.addr.write16(REG_COMMAND,
+                command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+        }
+    }
+
+    /// Returns a name for the class of the device
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _)    => "Storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _)    => "Network controller",
+            (0x03, _)    => "Display controller",
+            (0x04, _)    => "Multimedia controller",
+            (0x05, _)    => "Memory controller",
+            (0x06, 0x00) => "Host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _)    => "Bridge",
+            (0x07, _)    => "Communication controller",
+            (0x08, _)    => "System peripheral",
+            (0x0c, 0x03) => "USB controller",
+            (0x0c, 0x05) => "SMBus controller",
+            (0x0c, _)    => "Serial bus controller",
+            _            => "Unknown device",
+        }
+    }
+}
+
+/// Size the BARs of `addr` by writing all ones to them, and decode them
+unsafe fn probe_bars(addr: Address, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+
+    // Stop the decoding while the BARs hold the size masks
+    let command = addr.read16(REG_COMMAND);
+    addr.write16(REG_COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+    let mut idx = 0;
+    while idx < count {
+        let reg = REG_BAR0 + idx as u16 * 4;
+        let low = addr.read32(reg);
+        addr.write32(reg, u32::MAX);
+        let mask = addr.read32(reg);
+        addr.write32(reg, low);
+
+        if low & 1 != 0 {
+            // I/O BAR, the upper bits may not be implemented
+            let mask = mask & 0xfffc;
+            if mask != 0 {
+                bars[idx] = Some(Bar::Io {
+                    port: (low & 0xfffc) as u16,
+                    size: (!mask).wrapping_add(1) as u16,
+                });
+            }
+            idx += 1;
+            continue;
+        }
+
+        // Memory BAR, 64-bit ones take the next slot for the high half
+        let wide = (low >> 1) & 3 == 2 && idx + 1 < count;
+        let (base, mask) = if wide {
+            let high = addr.read32(reg + 4);
+            addr.write32(reg + 4, u32::MAX);
+            let mask_high = addr.read32(reg + 4);
+            addr.write32(reg + 4, high);
+
+            ((high as u64) << 32 | (low & !0xf) as u64,
+             (mask_high as u64) << 32 | (mask & !0xf) as u64)
+        } else {
+            ((low & !0xf) as u64, 0xffff_ffff_0000_0000 | (mask & !0xf) as u64)
+        };
+
+        if mask as u32 != 0 || (wide && mask != 0) {
+            bars[idx] = Some(Bar::Memory {
+                addr:         base,
+                size:         (!mask).wrapping_add(1),
+                prefetchable: low & 0x8 != 0,
+                wide,
+            });
+        }
+        idx += if wide { 2 } else { 1 };
+    }
+
+    addr.write16(REG_COMMAND, command);
+    bars
+}
+
+/// Walk the capabilities list of `addr`
+unsafe fn read_capabilities(addr: Address) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if addr.read16(REG_STATUS) & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+
+    // The list lives in the first 256 bytes, at dword aligned offsets. Cap
+    // the walk in case the list loops.
+    let mut offset = addr.read8(REG_CAPABILITIES) & 0xfc;
+    while offset >= 0x40 && capabilities.len() < 48 {
+        capabilities.push(Capability {
+            id: addr.read8(offset as u16),
+            offset,
+        });
+        offset = addr.read8(offset as u16 + 1) & 0xfc;
+    }
+
+    capabilities
+}
+
+/// Read the header of the function at `addr`, if it's present
+unsafe fn probe_function(addr: Address) -> Option<Device> {
+    let vendor = addr.read16(REG_VENDOR);
+    if vendor == NO_VENDOR {
+        return None;
+    }
+
+    let header_type = addr.read8(REG_HEADER_TYPE) & !HEADER_MULTIFUNCTION;
+    let bridge = (header_type == HEADER_BRIDGE).then(|| Bridge {
+        primary:     addr.read8(REG_PRIMARY_BUS),
+        secondary:   addr.read8(REG_SECONDARY_BUS),
+        subordinate: addr.read8(REG_SUBORDINATE_BUS),
+    });
+
+    // Normal functions have 6 BARs and bridges 2. Card bus bridges have
+    // none.
+    let bars = match header_type {
+        0x00          => probe_bars(addr, 6),
+        HEADER_BRIDGE => probe_bars(addr, 2),
+        _             => [None; 6],
+    };
+
+    Some(Device {
+        addr,
+        vendor,
+        device:         addr.read16(REG_DEVICE),
+        class:          addr.read8(REG_CLASS),
+        subclass:       addr.read8(REG_SUBCLASS),
+        prog_if:        addr.read8(REG_PROG_IF),
+        revision:       addr.read8(REG_REVISION),
+        header_type,
+        interrupt_pin:  addr.read8(REG_INTERRUPT_PIN),
+        interrupt_line: addr.read8(REG_INTERRUPT_LINE),
+        bars,
+        capabilities:   read_capabilities(addr),
+        bridge,
+    })
+}
+
+/// Find the functions on `bus` of `segment` and behind its bridges
+unsafe fn scan_bus(segment: u16, bus: u8, scanned: &mut Vec<(u16, u8)>,
+                   devices: &mut Vec<Device>) {
+    // Buses can be reached twice through misconfigured bridges
+    if scanned.contains(&(segment, bus)) {
+        return;
+    }
+    scanned.push((segment, bus));
+
+    for device in 0..32 {
+        let addr = Address { segment, bus, device, function: 0 };
+        if addr.read16(REG_VENDOR) == NO_VENDOR {
+            continue;
+        }
+
+        // Only probe the other functions of multifunction devices
+        let functions = if addr.read8(REG_HEADER_TYPE) &
+                HEADER_MULTIFUNCTION != 0 { 8 } else { 1 };
+        for function in 0..functions {
+            let Some(func) = probe_function(Address { function, ..addr })
+            else {
+                continue;
+            };
+
+            let secondary = func.bridge.map(|bridge| bridge.secondary);
+            devices.push(func);
+
+            if let Some(secondary) = secondary.filter(|&sec| sec > bus) {
+                scan_bus(segment, secondary, scanned, devices);
+            }
+        }
+    }
+}
+
+/// Print the devices found
+fn print_devices(devices: &[Device], method: &str) {
+    print!("PCI: {} functions through {}\n", devices.len(), method);
+    for device in devices {
+        print!("PCI: {} {:04x}:{:04x} {}\n", device.addr,
+               device.vendor, device.device, device.class_name());
+
+        for (idx, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory { addr, size, prefetchable, wide }) => {
+                    print!("PCI:     BAR{} memory {:#x} size {:#x}{}{}\n",
+                           idx, addr, size,
+                           if *wide { ", 64-bit" } else { "" },
+                           if *prefetchable { ", prefetchable" } else { "" });
+                }
+                Some(Bar::Io { port, size }) => {
+                    print!("PCI:     BAR{} I/O {:#x} size {:#x}\n",
+                           idx, port, size);
+                }
+                None => {}
+            }
+        }
+
+        if let Some(line) = device.intx_line() {
+            print!("PCI:     INT{}# on GSI {}, {:?} triggered, {:?}\n",
+                   (b'A' + device.interrupt_pin - 1) as char, line.gsi,
+                   line.trigger, line.polarity);
+        }
+
+        if let Some(bridge) = device.bridge {
+            print!("PCI:     bridge to buses {:#x}-{:#x}\n",
+                   bridge.secondary, bridge.subordinate);
+        }
+
+        if !device.capabilities.is_empty() {
+            print!("PCI:     capabilities");
+            for cap in &device.capabilities {
+                print!(" {:#04x}@{:#x}", cap.id, cap.offset);
+            }
+            print!("\n");
+        }
+    }
+}
+
+/// Set up the configuration space access, enumerate the buses and print the
+/// devices found
+pub fn init() {
+    // Use ECAM for the segments in the MCFG
+    let ecam: Vec<McfgEntry> = crate::acpi::tables()
+        .and_then(|tables| tables.mcfg())
+        .map(|mcfg| mcfg.entries().collect())
+        .unwrap_or_default();
+
+    // Walk the buses from the host bridges, the legacy ports only reach
+    // segment 0
+    let mut roots: Vec<(u16, u8)> = ecam.iter()
+        .map(|entry| (entry.segment, entry.start_bus)).collect();
+    if roots.is_empty() {
+        roots.push((0, 0));
+    }
+    let method = if ecam.is_empty() { "legacy I/O ports" } else { "ECAM" };
+
+    {
+        let _interrupts = InterruptGuard::new();
+        let mut config = CONFIG.lock();
+        assert!(config.is_none(), "PCI initialized twice.");
+        *config = Some(Config { ecam, buses: BTreeMap::new() });
+    }
+
+    let mut devices = Vec::new();
+    let mut scanned = Vec::new();
+    unsafe {
+        for (segment, bus) in roots {
+            // A multifunction host bridge has one function per root bus
+            let host = Address { segment, bus, device: 0, function: 0 };
+            if host.read8(REG_HEADER_TYPE) & HEADER_MULTIFUNCTION == 0 {
+                scan_bus(segment, bus, &mut scanned, &mut devices);
+                continue;
+            }
+
+            for function in 0..8 {
+                let host = Address { function, ..host };
+                if host.read16(REG_VENDOR) != NO_VENDOR {
+                    scan_bus(segment, bus.wrapping_add(function),
+                             &mut scanned, &mut devices);
+                }
+            }
+        }
+    }
+
+    devices.sort_by_key(|device| device.addr);
+    print_devices(&devices, method);
+
+    let old = DEVICES.swap(Box::into_raw(Box::new(devices)),
+                           Ordering::AcqRel);
+    assert!(old.is_null(), "PCI devices enumerated twice.");
+}
+
+/// Returns the devices found, empty before `init()`
+pub fn devices() -> &'static [Device] {
+    unsafe {
+        DEVICES.load(Ordering::Acquire).as_ref()
+            .map(|devices| devices.as_slice()).unwrap_or(&[])
+    }
+}
+
+/// Returns the devices with `vendor` and `device` IDs
+pub fn find(vendor: u16, device: u16)
+        -> impl Iterator<Item = &'static Device> {
+    devices().iter().filter(move |dev| {
+        dev.vendor == vendor && dev.device == device
+    })
+}
@@ -0,0 +1,265 @@
+//! MSI and MSI-X interrupts of PCI devices.
+//!
+//! A message signalled interrupt is a memory write by the device to the local
+//! APIC address range, carrying the vector in its data. Every message gets
+//! its own vector from `interrupts::alloc_vector()` and targets one core, so
+//! drivers can give every queue its own vector on its own core.
+
+use core::ptr;
+use crate::interrupts::{ self, InterruptHandler };
+use crate::core_locals;
+use crate::mm::{ self, PhysAddr, VirtAddr };
+use super::{ Address, Bar, Device, CAP_MSI, CAP_MSIX, REG_COMMAND };
+use super::{ COMMAND_MEMORY, COMMAND_BUS_MASTER, COMMAND_INTX_DISABLE };
+
+/// Base of the address range messages to the local APICs are written to
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+/// Shift of the destination APIC ID in a message address
+const MSI_DEST_SHIFT: u32 = 12;
+
+/// Offset of the message control register in both capabilities
+const REG_CONTROL: u16 = 0x02;
+
+/// MSI is enabled
+const MSI_ENABLE: u16 = 1 << 0;
+
+/// Mask of the number of messages enabled
+const MSI_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+
+/// The MSI capability has a 64-bit message address
+const MSI_64BIT: u16 = 1 << 7;
+
+/// The MSI capability has per-vector mask bits
+const MSI_PER_VECTOR_MASK: u16 = 1 << 8;
+
+/// Offset of the MSI-X table location register
+const REG_MSIX_TABLE: u16 = 0x04;
+
+/// Mask of the table size in the MSI-X message control register
+const MSIX_TABLE_SIZE: u16 = 0x7ff;
+
+/// All the MSI-X vectors are masked
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+
+/// MSI-X is enabled
+const MSIX_ENABLE: u16 = 1 << 15;
+
+/// Size of an MSI-X table entry
+const MSIX_ENTRY_SIZE: u64 = 16;
+
+/// The entry is masked, in the vector control of an MSI-X table entry
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// A message the device writes to signal an interrupt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Message {
+    /// Address the message is written to
+    pub addr: u64,
+
+    /// Data written
+    pub data: u32,
+}
+
+impl Message {
+    /// Returns the message delivering `vector` to the core with `apic_id`,
+    /// edge triggered in fixed delivery mode. `None` if the APIC ID doesn't
+    /// fit a message without interrupt remapping.
+    pub fn new(vector: u8, apic_id: u32) -> Option<Self> {
+        (apic_id <= 0xff).then_some(Self {
+            addr: MSI_ADDRESS_BASE | (apic_id as u64) << MSI_DEST_SHIFT,
+            data: vector as u32,
+        })
+    }
+}
+
+/// Allocate a vector for `handler` and return it with the message delivering
+/// it to `core`
+fn alloc_message(core: u32, handler: InterruptHandler)
+        -> Option<(u8, Message)> {
+    let apic_id = core_locals::of(core)?.apic_id();
+    let vector  = interrupts::alloc_vector(handler)?;
+    match Message::new(vector, apic_id) {
+        Some(message) => Some((vector, message)),
+        None => {
+            interrupts::free_vector(vector);
+            None
+        }
+    }
+}
+
+/// Disable INTx of `addr`, enable its memory BARs, which hold the MSI-X
+/// table, and let it master the bus, which it needs to write messages
+unsafe fn prepare(addr: Address) {
+    let command = addr.read16(REG_COMMAND);
+    addr.write16(REG_COMMAND, command | COMMAND_MEMORY | COMMAND_BUS_MASTER |
+                 COMMAND_INTX_DISABLE);
+}
+
+/// Route the single MSI of `device` to `handler` on `core`, disabling its
+/// INTx interrupt. Returns the vector allocated, `None` if the device
+/// doesn't support MSI or no vector is free.
+pub fn enable_msi(device: &Device, core: u32, handler: InterruptHandler)
+        -> Option<u8> {
+    let cap  = device.capability(CAP_MSI)? as u16;
+    let addr = device.addr;
+    let (vector, message) = alloc_message(core, handler)?;
+
+    unsafe {
+        // Disable MSI while the message is changed, with a single message
+        let control = addr.read16(cap + REG_CONTROL) &
+            !(MSI_ENABLE | MSI_MULTIPLE_ENABLE);
+        addr.write16(cap + REG_CONTROL, control);
+
+        // The data and the mask bits move with the width of the address
+        addr.write32(cap + 0x04, message.addr as u32);
+        let data = if control & MSI_64BIT != 0 {
+            addr.write32(cap + 0x08, (message.addr >> 32) as u32);
+            cap + 0x0c
+        } else {
+            cap + 0x08
+        };
+        addr.write16(data, message.data as u16);
+        if control & MSI_PER_VECTOR_MASK != 0 {
+            addr.write32(data + 0x04, 0);
+        }
+
+        prepare(addr);
+        addr.write16(cap + REG_CONTROL, control | MSI_ENABLE);
+    }
+
+    Some(vector)
+}
+
+/// Disable the MSI of `device` and free its `vector`
+pub fn disable_msi(device: &Device, vector: u8) {
+    if let Some(cap) = device.capability(CAP_MSI) {
+        unsafe {
+            let control = device.addr.read16(cap as u16 + REG_CONTROL);
+            device.addr.write16(cap as u16 + REG_CONTROL,
+                                control & !MSI_ENABLE);
+        }
+    }
+
+    interrupts::free_vector(vector);
+}
+
+/// The MSI-X table of a device
+pub struct MsiX {
+    /// Address of the device
+    addr: Address,
+
+    /// Offset of the MSI-X capability
+    cap: u16,
+
+    /// Virtual address of the table
+    table: VirtAddr,
+
+    /// Number of entries in the table
+    len: u16,
+}
+
+impl MsiX {
+    /// Map the MSI-X table of `device`, mask all of its entries and enable
+    /// MSI-X in place of MSI and INTx. `None` if the device doesn't support
+    /// MSI-X.
+    pub fn new(device: &Device) -> Option<Self> {
+        let cap  = device.capability(CAP_MSIX)? as u16;
+        let addr = device.addr;
+
+        let (control, table) = unsafe {
+            (addr.read16(cap + REG_CONTROL), addr.read32(cap + REG_MSIX_TABLE))
+        };
+        let len = (control & MSIX_TABLE_SIZE) + 1;
+
+        // The table lives in one of the memory BARs
+        let bir    = (table & 0x7) as usize;
+        let offset = (table & !0x7) as u64;
+        let Some(Bar::Memory { addr: bar, .. }) = device.bars.get(bir)?
+        else {
+            return None;
+        };
+        let table = mm::map_mmio(PhysAddr(bar + offset),
+                                 len as u64 * MSIX_ENTRY_SIZE)?;
+
+        let msix = Self { addr, cap, table, len };
+        unsafe {
+            // Enable MSI-X with all the vectors masked, turning off MSI
+            if let Some(msi) = device.capability(CAP_MSI) {
+                let control = addr.read16(msi as u16 + REG_CONTROL);
+                addr.write16(msi as u16 + REG_CONTROL, control & !MSI_ENABLE);
+            }
+            addr.write16(cap + REG_CONTROL,
+                         control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+            prepare(addr);
+
+            for entry in 0..len {
+                msix.set_masked(entry, true);
+            }
+            addr.write16(cap + REG_CONTROL,
+                         (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+        }
+
+        Some(msix)
+    }
+
+    /// Returns the number of entries in the table
+    pub fn entries(&self) -> u16 {
+        self.len
+    }
+
+    /// Returns a pointer to the dword at `offset` in `entry`
+    fn entry_ptr(&self, entry: u16, offset: u64) -> *mut u32 {
+        assert!(entry < self.len, "MSI-X entry out of bounds.");
+        (self.table.0 + entry as u64 * MSIX_ENTRY_SIZE + offset) as *mut u32
+    }
+
+    /// Mask or unmask `entry`
+    pub fn set_masked(&self, entry: u16, masked: bool) {
+        let control = self.entry_ptr(entry, 12);
+        unsafe {
+            let val = ptr::read_volatile(control);
+            ptr::write_volatile(control, if masked {
+                val | MSIX_ENTRY_MASKED
+            } else {
+                val & !MSIX_ENTRY_MASKED
+            });
+        }
+    }
+
+    /// Program `entry` with `message` and unmask it
+    pub fn set(&self, entry: u16, message: Message) {
+        self.set_masked(entry, true);
+        unsafe {
+            ptr::write_volatile(self.entry_ptr(entry, 0), message.addr as u32);
+            ptr::write_volatile(self.entry_ptr(entry, 4),
+                                (message.addr >> 32) as u32);
+            ptr::write_volatile(self.entry_ptr(entry, 8), message.data);
+        }
+        self.set_masked(entry, false);
+    }
+
+    /// Route `entry` to `handler` on `core`. Returns the vector allocated,
+    /// `None` if no vector is free.
+    pub fn route(&self, entry: u16, core: u32, handler: InterruptHandler)
+            -> Option<u8> {
+        let (vector, message) = alloc_message(core, handler)?;
+        self.set(entry, message);
+        Some(vector)
+    }
+
+    /// Mask `entry` and free its `vector`
+    pub fn unroute(&self, entry: u16, vector: u8) {
+        self.set_masked(entry, true);
+        interrupts::free_vector(vector);
+    }
+
+    /// Disable MSI-X. The vectors routed have to be freed with `unroute()`
+    /// first, the table stays mapped.
+    pub fn disable(self) {
+        unsafe {
+            let control = self.addr.read16(self.cap + REG_CONTROL);
+            self.addr.write16(self.cap + REG_CONTROL, control & !MSIX_ENABLE);
+        }
+    }
+}
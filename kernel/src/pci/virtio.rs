@@ -0,0 +1,52 @@
+//! Glue between the `virtio` crate and the kernel.
+//!
+//! Queue memory comes from the physical allocator and is accessed through
+//! the identity map, device memory is mapped with `mm::map_mmio()`.
+
+use virtio::{ Dma, Platform, PciFunction, Transport };
+use crate::mm::{ self, PhysAddr };
+use super::{ Bar, Device };
+
+/// The kernel as the environment virtio devices are driven from
+#[derive(Clone, Copy, Debug)]
+pub struct KernelPlatform;
+
+impl Platform for KernelPlatform {
+    fn alloc_dma(&self, size: usize, align: usize) -> Option<Dma> {
+        let phys = mm::alloc_phys(size as u64, align as u64)?;
+        Some(Dma { phys: phys.0, ptr: mm::phys_ptr(phys), size })
+    }
+
+    fn map_mmio(&self, phys: u64, size: usize) -> Option<*mut u8> {
+        mm::map_mmio(PhysAddr(phys), size as u64)
+            .map(|vaddr| vaddr.0 as *mut u8)
+    }
+}
+
+impl PciFunction for Device {
+    fn read8(&self, offset: u16) -> u8 {
+        unsafe { self.addr.read8(offset) }
+    }
+
+    fn read32(&self, offset: u16) -> u32 {
+        unsafe { self.addr.read32(offset) }
+    }
+
+    fn memory_bar(&self, bar: u8) -> Option<u64> {
+        match self.bars.get(bar as usize)? {
+            Some(Bar::Memory { addr, .. }) => Some(*addr),
+            _ => None,
+        }
+    }
+}
+
+/// Enable the virtio `device` and map its configuration structures. `None`
+/// if it isn't a modern virtio device.
+pub fn transport(device: &Device) -> Option<Transport<KernelPlatform>> {
+    if device.vendor != virtio::VENDOR_ID {
+        return None;
+    }
+
+    device.enable();
+    Transport::new(KernelPlatform, device)
+}
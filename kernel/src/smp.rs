@@ -0,0 +1,207 @@
+//! Bring-up of the application processors.
+//!
+//! The cores listed in the MADT are started one at a time with INIT-SIPI-SIPI
+//! on the trampoline in trampoline.asm, which drops them into `ap_entry()` in
+//! long mode on their own stack.
+
+use core::ptr;
+use core::hint::spin_loop;
+use core::sync::atomic::{ AtomicU32, Ordering, fence };
+use crate::{ apic, gdt, interrupts, core_locals };
+use crate::mm::{ self, PhysAddr, VirtAddr };
+use time::{ Duration, Instant };
+
+/// Maximum number of cores brought online
+pub const MAX_CORES: usize = 256;
+
+/// Physical address the trampoline is copied to. It's conventional memory
+/// below the bootloader stack, and a startup IPI can only start a core on a
+/// page below 1 MiB.
+const TRAMPOLINE_ADDR: u64 = 0x1000;
+
+/// Size of the page the trampoline is copied to
+const TRAMPOLINE_SIZE: usize = 4096;
+
+/// Size of the stack of an application processor
+const AP_STACK_SIZE: u64 = 64 * 1024;
+
+/// Time to wait after the INIT IPI
+const INIT_DELAY: Duration = Duration::from_millis(10);
+
+/// Time to wait after every startup IPI
+const STARTUP_DELAY: Duration = Duration::from_micros(200);
+
+/// Time to wait for a started core to come online
+const ONLINE_TIMEOUT: Duration = Duration::from_millis(100);
+
+extern "C" {
+    static trampoline_start:     u8;
+    static trampoline_long_mode: u8;
+    static trampoline_gdt:       u8;
+    static trampoline_params:    u8;
+    static trampoline_end:       u8;
+}
+
+/// Parameters of the trampoline, laid out like `trampoline_params` in
+/// trampoline.asm
+#[repr(C, packed)]
+struct TrampolineParams {
+    /// GDTR of the temporary GDT
+    gdtr_limit: u16,
+    gdtr_base:  u32,
+
+    /// Far pointer to the long mode code
+    far_offset:   u32,
+    far_selector: u16,
+
+    /// CR0 to run with, with protected mode and paging enabled
+    cr0: u32,
+
+    /// Page table to run in, must be below 4 GiB
+    cr3: u32,
+
+    /// CR4 to run with, with PAE enabled
+    cr4: u32,
+
+    /// EFER to run with
+    efer: u64,
+
+    /// Top of the stack of the core
+    stack: u64,
+
+    /// Address of `ap_entry()`
+    entry: u64,
+
+    /// Argument to `ap_entry()`, the index of the core
+    argument: u64,
+}
+
+/// Number of cores online, including the bootstrap processor
+static ONLINE: AtomicU32 = AtomicU32::new(1);
+
+/// Returns the number of cores online
+pub fn cores_online() -> u32 {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// Returns the physical address `symbol` of the trampoline is copied to
+fn trampoline_addr(symbol: &u8) -> u64 {
+    let start = unsafe { &trampoline_start as *const u8 as u64 };
+    TRAMPOLINE_ADDR + (symbol as *const u8 as u64 - start)
+}
+
+/// Entry point of the application processors, called by the trampoline
+extern "C" fn ap_entry(core: u64) -> ! {
+    // Set up the core like the bootstrap processor
+    core_locals::init(core as u32);
+    mm::init_core();
+    gdt::init();
+    interrupts::init();
+    apic::init_core();
+    unsafe { cpu::enable_interrupts(); }
+
+    // Report that we're online
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+
+    // Nothing to do yet but handle interrupts
+    loop {
+        cpu::hlt();
+    }
+}
+
+/// Start the core with `apic_id` as `core` and wait for it to come online
+fn start(apic_id: u32, core: u32) -> bool {
+    // Allocate the stack of the core, above a guard page
+    let VirtAddr(stack) = mm::alloc_stack(AP_STACK_SIZE)
+        .expect("Couldn't allocate an application processor stack.");
+
+    // Fill in the parameters of the trampoline
+    let entry: extern "C" fn(u64) -> ! = ap_entry;
+    let params = unsafe {
+        TrampolineParams {
+            gdtr_limit:   (3 * core::mem::size_of::<u64>() - 1) as u16,
+            gdtr_base:    trampoline_addr(&trampoline_gdt) as u32,
+            far_offset:   trampoline_addr(&trampoline_long_mode) as u32,
+            far_selector: gdt::KERNEL_CS,
+            cr0:          cpu::read_cr0() as u32,
+            cr3:          cpu::read_cr3() as u32,
+            cr4:          (cpu::read_cr4() & !cpu::CR4_PCIDE) as u32,
+            efer:         (cpu::read_efer() | cpu::EFER_LME) &
+                !cpu::EFER_LMA,
+            stack,
+            entry:        entry as usize as u64,
+            argument:     core as u64,
+        }
+    };
+    unsafe {
+        let addr = PhysAddr(trampoline_addr(&trampoline_params));
+        ptr::write_unaligned(mm::phys_ptr(addr), params);
+    }
+    fence(Ordering::SeqCst);
+
+    // INIT-SIPI-SIPI, the second startup IPI only if the first one was missed
+    unsafe { apic::send_init(apic_id); }
+    time::sleep(INIT_DELAY);
+    for _ in 0..2 {
+        if cores_online() > core {
+            break;
+        }
+
+        unsafe { apic::send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8); }
+        time::sleep(STARTUP_DELAY);
+    }
+
+    // Wait for the core to come online
+    let started = Instant::now();
+    while cores_online() == core && started.elapsed() < ONLINE_TIMEOUT {
+        spin_loop();
+    }
+
+    // The stack is leaked if the core didn't come online, it might still be
+    // starting up
+    cores_online() > core
+}
+
+/// Start all the application processors listed in the MADT and report the
+/// number of cores online
+pub fn init() {
+    let Some(madt) = crate::acpi::tables().and_then(|tables| tables.madt())
+    else {
+        print!("SMP: no MADT, only the bootstrap processor is online\n");
+        return;
+    };
+
+    // The trampoline loads CR3 in real mode
+    assert!((cpu::read_cr3() as u64) < (1 << 32),
+        "The kernel page table is above 4 GiB, APs can't be started.");
+
+    // Copy the trampoline
+    unsafe {
+        let start = &trampoline_start as *const u8;
+        let len   = &trampoline_end as *const u8 as usize - start as usize;
+        assert!(len <= TRAMPOLINE_SIZE, "The trampoline doesn't fit a page.");
+
+        ptr::copy_nonoverlapping(start,
+            mm::phys_ptr::<u8>(PhysAddr(TRAMPOLINE_ADDR)), len);
+    }
+
+    // Start the cores one at a time, they share the trampoline
+    let bsp = apic::id();
+    for apic_id in madt.apic_ids().filter(|&apic_id| apic_id != bsp) {
+        let core = cores_online();
+        if core as usize >= MAX_CORES {
+            print!("SMP: too many cores, ignoring the rest\n");
+            break;
+        }
+
+        // A core which didn't come online might still read the parameters
+        // of the trampoline later, so they can't be reused for another core
+        if !start(apic_id, core) {
+            print!("SMP: APIC {} didn't come online, not starting the rest\n",
+                   apic_id);
+            break;
+        }
+    }
+
+    print!("SMP: {} cores online\n", cores_online());
+}
@@ -0,0 +1,40 @@
+//! Timekeeping of the kernel.
+//!
+//! The clock itself lives in the `time` crate, shared with the bootloader.
+//! The kernel calibrates it against the HPET if there is one, and finds the
+//! RTC century register in the FADT.
+
+use time::Duration;
+use time::rtc::{ self, DateTime };
+use crate::hpet;
+
+/// Duration of the TSC calibration against the HPET
+const CALIBRATION_TIME: Duration = Duration::from_millis(50);
+
+/// Calibrate the TSC and print the frequency and the wall-clock time
+pub fn init() {
+    // Prefer the HPET as the reference, it's more precise than the PIT
+    let (hz, reference) = match hpet::measure_tsc(CALIBRATION_TIME) {
+        Some(hz) => {
+            time::set_tsc_hz(hz);
+            (hz, "HPET")
+        }
+        None => (time::calibrate(), "PIT"),
+    };
+    if !cpu::has_invariant_tsc() {
+        print!("Time: the TSC isn't invariant, the clock may drift\n");
+    }
+
+    print!("Time: TSC at {}.{:03} MHz against the {}, RTC reads {}\n",
+           hz / 1_000_000, hz / 1000 % 1000, reference, wall_clock());
+}
+
+/// Returns the current date and time from the RTC
+pub fn wall_clock() -> DateTime {
+    let century = crate::acpi::tables()
+        .and_then(|tables| tables.fadt())
+        .map(|fadt| fadt.century)
+        .filter(|&century| century != 0);
+
+    rtc::read(century)
+}
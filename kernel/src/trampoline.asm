@@ -0,0 +1,104 @@
+; Application processor trampoline.
+; smp.rs copies it to a page below 1 MiB, fills in `trampoline_params` and
+; starts the core on it with a startup IPI. The core goes straight from real
+; mode to long mode in the kernel page table and calls `ap_entry()` in smp.rs
+; on its own stack.
+;
+; The code runs wherever it was copied to, so the real mode code only uses
+; offsets from `trampoline_start` and the long mode code RIP-relative
+; addressing.
+
+section .text
+
+global trampoline_start
+global trampoline_long_mode
+global trampoline_gdt
+global trampoline_params
+global trampoline_end
+
+[bits 16]
+
+trampoline_start:
+    ; Interrupts stay disabled until the kernel enables them
+    cli
+    cld
+
+    ; The startup IPI sets CS to the page of the trampoline and IP to 0.
+    ; Access the parameters through the same segment.
+    mov ax, cs
+    mov ds, ax
+
+    ; Load the temporary GDT, with a 32-bit base
+    o32 lgdt [trampoline_params.gdtr_limit - trampoline_start]
+
+    ; Load the CR4 of the kernel, enabling PAE as long mode requires
+    mov eax, [trampoline_params.cr4 - trampoline_start]
+    mov cr4, eax
+
+    ; Load the kernel page table
+    mov eax, [trampoline_params.cr3 - trampoline_start]
+    mov cr3, eax
+
+    ; Load the EFER of the kernel, enabling long mode
+    mov ecx, 0xc0000080
+    mov eax, [trampoline_params.efer - trampoline_start]
+    mov edx, [trampoline_params.efer - trampoline_start + 4]
+    wrmsr
+
+    ; Load the CR0 of the kernel, enabling protected mode and paging at
+    ; once and activating long mode. This also enables the caches, which are
+    ; disabled after INIT.
+    mov eax, [trampoline_params.cr0 - trampoline_start]
+    mov cr0, eax
+
+    ; Far jump into the 64-bit code segment
+    o32 jmp far [trampoline_params.far_offset - trampoline_start]
+
+[bits 64]
+
+trampoline_long_mode:
+    ; Load the data segments
+    mov ax, 0x10 ; 0x10 is the data entry in the GDT
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+
+    ; Switch to the stack of the core and call `ap_entry(argument)`.
+    ; The stack is 16-byte aligned before the call, as the ABI requires.
+    mov rsp, [rel trampoline_params.stack]
+    mov rdi, [rel trampoline_params.argument]
+    mov rax, [rel trampoline_params.entry]
+    call rax
+
+    ; `ap_entry()` never returns
+.halt:
+    cli
+    hlt
+    jmp .halt
+
+align 8
+
+; Temporary GDT, with the same selectors as the kernel GDT
+trampoline_gdt:
+    dq 0x0000000000000000 ; 0x00, null
+    dq 0x00209a0000000000 ; 0x08, 64-bit code, present, DPL 0
+    dq 0x0000920000000000 ; 0x10, data, present, writable, DPL 0
+
+; Filled in by smp.rs for every core, see `TrampolineParams`
+trampoline_params:
+.gdtr_limit:   dw 0
+.gdtr_base:    dd 0
+.far_offset:   dd 0
+.far_selector: dw 0
+.cr0:          dd 0
+.cr3:          dd 0
+.cr4:          dd 0
+.efer:         dq 0
+.stack:        dq 0
+.entry:        dq 0
+.argument:     dq 0
+
+trampoline_end:
@@ -3,6 +3,11 @@
 //! Requirements:
 //!     nasm
 //!     ld.lld
+//!
+//! `clean` removes the build directories, and `serve` serves the netboot
+//! directory over TFTP and proxy DHCP, see `serve.rs`.
+
+mod serve;
 
 use std::path::Path;
 use std::process::Command;
@@ -55,8 +60,7 @@ fn flatten_elf<P: AsRef<Path>>(file_path: P) -> Option<(u32, u32, Vec<u8>)> {
     let image_end   = image_end?;
 
     // Calculate the flat image size
-    let image_size: usize = image_end.checked_sub(image_start)?.checked_add(1)?
-        .try_into().ok()?;
+    let image_size = image_end.checked_sub(image_start)?.checked_add(1)?;
 
     // Allocate space for the flat image
     let mut flat_image: Vec<u8> = vec![0u8; image_size];
@@ -64,8 +68,8 @@ fn flatten_elf<P: AsRef<Path>>(file_path: P) -> Option<(u32, u32, Vec<u8>)> {
     // Flatten the image
     elf.headers(|vaddr, memsz, bytes, _read, _write, _execute| {
         // Find the offset for this segment in the flat image
-        let flat_off: usize = (vaddr - image_start).try_into().ok()?;
-        let size:     usize = memsz.try_into().ok()?;
+        let flat_off = vaddr - image_start;
+        let size     = memsz;
 
         // Compute the number of bytes to initialize
         let to_copy = std::cmp::min(size, bytes.len());
@@ -122,14 +126,20 @@ fn main() -> Result<(), Box<dyn Error>>{
         return Ok(());
     }
 
+    // If we get `serve`, netboot from the netboot directory until killed
+    if args().nth(1) == Some("serve".to_string()) {
+        let args: Vec<String> = args().skip(2).collect();
+        return serve::serve(&netboot_path, &args);
+    }
+
     // Create the needed directories.
     // Directories not created here should already exist by the time this script
     // is run.
     create_dir_all(netboot_path.clone()).unwrap();
-    create_dir_all(build_path.clone()).unwrap();
+    create_dir_all(build_path).unwrap();
 
     // Get the path to the realmode.asm assembly and the assembled binary
-    let realmode_bin  = build_path.clone().join("realmode");
+    let realmode_bin  = build_path.join("realmode");
     let realmode_path = bootloader_path.join("src").join("realmode.asm");
 
     // Convert the paths to strings
@@ -170,7 +180,7 @@ fn main() -> Result<(), Box<dyn Error>>{
     std::fs::write(build_path.join("bootloader"), &flat_bytes)?;
 
     // Get the path to the stage0 assembly and the assembled binary
-    let stage0_bin  = build_path.clone().join("stage0");
+    let stage0_bin  = build_path.join("stage0");
     let stage0_path = bootloader_path.join("src").join("stage0.asm");
 
     // Convert the paths to strings
@@ -203,6 +213,34 @@ fn main() -> Result<(), Box<dyn Error>>{
     // Copy it to the netboot directory
     std::fs::copy(stage0_bin, netboot_path.join("bootloader.0"))?;
 
+    // Get the path to the kernel interrupt stubs and the assembled object
+    let interrupts_obj  = build_path.join("interrupts");
+    let interrupts_path = kernel_path.join("src").join("interrupts.asm");
+
+    // Convert the paths to strings
+    let interrupts_obj  = interrupts_obj.to_str().unwrap();
+    let interrupts_path = interrupts_path.to_str().unwrap();
+
+    // Assemble the interrupt stubs. They are linked into the kernel.
+    Command::new("nasm")
+        .args(["-f", "elf64", "-o", interrupts_obj, interrupts_path])
+        .status()?;
+
+    // Get the path to the application processor trampoline and the assembled
+    // object
+    let trampoline_obj  = build_path.join("trampoline");
+    let trampoline_path = kernel_path.join("src").join("trampoline.asm");
+
+    // Convert the paths to strings
+    let trampoline_obj  = trampoline_obj.to_str().unwrap();
+    let trampoline_path = trampoline_path.to_str().unwrap();
+
+    // Assemble the trampoline. It's linked into the kernel, which copies it
+    // below 1 MiB.
+    Command::new("nasm")
+        .args(["-f", "elf64", "-o", trampoline_obj, trampoline_path])
+        .status()?;
+
     // Create the path to the kernel output directories
     let kernel_bin = kernel_path.join("target")
         .join("x86_64-unknown-linux-gnu")
@@ -0,0 +1,729 @@
+//! TFTP and proxy DHCP server for netbooting from the netboot directory.
+//!
+//! The TFTP server hands out the files of the directory, read only, with the
+//! blksize and tsize options (RFC 2348, RFC 2349). The proxy DHCP responder
+//! tells PXE clients to boot `bootloader.0` from us, leaving the addresses
+//! to the DHCP server of the network, like the proxy mode of dnsmasq.
+//!
+//! Usage:
+//!     serve [--bind <addr>] [--tftp-port <port>]
+//!           [--proxy-dhcp <boot server addr>] [--dhcp-port <port>]
+//!
+//! Binding ports 67 and 69 takes root. With other ports and `--bind
+//! 127.0.0.1` everything runs on loopback, as replies go to where requests
+//! came from unless that's 0.0.0.0.
+
+use std::path::{ Component, Path, PathBuf };
+use std::net::{ Ipv4Addr, SocketAddr, UdpSocket };
+use std::collections::HashSet;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use std::error::Error;
+use std::io;
+
+/// Name of the file PXE clients boot
+const BOOT_FILE: &str = "bootloader.0";
+
+/// Port of the TFTP servers
+const TFTP_PORT: u16 = 69;
+
+/// TFTP opcodes
+const OP_RRQ:   u16 = 1;
+const OP_WRQ:   u16 = 2;
+const OP_DATA:  u16 = 3;
+const OP_ACK:   u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK:  u16 = 6;
+
+/// TFTP error codes
+const ERROR_UNDEFINED:  u16 = 0;
+const ERROR_NOT_FOUND:  u16 = 1;
+const ERROR_ACCESS:     u16 = 2;
+const ERROR_ILLEGAL_OP: u16 = 4;
+
+/// Size of the blocks without the blksize option
+const DEFAULT_BLKSIZE: usize = 512;
+
+/// Bounds of the blksize option
+const MIN_BLKSIZE: usize = 8;
+const MAX_BLKSIZE: usize = 65464;
+
+/// Time waited for an acknowledgement before sending a block again
+const TFTP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of times a block is sent before the transfer is abandoned
+const TFTP_ATTEMPTS: u32 = 5;
+
+/// Ports of the DHCP servers and clients
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+/// Port PXE clients send their requests for the boot file to
+const PXE_PORT: u16 = 4011;
+
+/// BOOTP operations
+const BOOTP_REQUEST: u8 = 1;
+const BOOTP_REPLY:   u8 = 2;
+
+/// Cookie in front of the DHCP options
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Offset of the DHCP options, after the fixed fields and the cookie
+const OPTIONS_OFFSET: usize = 240;
+
+/// Offsets and sizes of the server name and the boot file name fields
+const SNAME_OFFSET: usize = 44;
+const FILE_OFFSET:  usize = 108;
+const FILE_SIZE:    usize = 128;
+
+/// Smallest message BOOTP relays and clients are required to take
+const MIN_MESSAGE_SIZE: usize = 300;
+
+/// DHCP options
+const OPTION_PAD:          u8 = 0;
+const OPTION_VENDOR:       u8 = 43;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID:    u8 = 54;
+const OPTION_CLASS_ID:     u8 = 60;
+const OPTION_CLIENT_UUID:  u8 = 97;
+const OPTION_END:          u8 = 255;
+
+/// DHCP message types
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER:    u8 = 2;
+const DHCPREQUEST:  u8 = 3;
+const DHCPACK:      u8 = 5;
+
+/// Class identifier of PXE clients, followed by their architecture
+const PXE_CLASS: &[u8] = b"PXEClient";
+
+/// PXE discovery control vendor sub-option, and its value telling the
+/// client to boot the file of the offer without any boot server discovery
+const PXE_DISCOVERY_CONTROL: u8 = 6;
+const PXE_USE_BOOT_FILE:     u8 = 1 << 3;
+
+/// What to serve, from the command line
+struct Config {
+    /// Address the sockets are bound to
+    bind: Ipv4Addr,
+
+    /// Port of the TFTP server
+    tftp_port: u16,
+
+    /// Boot server advertised to PXE clients, `None` without proxy DHCP
+    proxy_dhcp: Option<Ipv4Addr>,
+
+    /// Port of the proxy DHCP responder
+    dhcp_port: u16,
+}
+
+impl Config {
+    /// Parse the arguments after `serve`, `None` if they're invalid
+    fn parse(args: &[String]) -> Option<Self> {
+        let mut config = Self {
+            bind:       Ipv4Addr::UNSPECIFIED,
+            tftp_port:  TFTP_PORT,
+            proxy_dhcp: None,
+            dhcp_port:  DHCP_SERVER_PORT,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = args.next()?;
+            match arg.as_str() {
+                "--bind"       => config.bind = value.parse().ok()?,
+                "--tftp-port"  => config.tftp_port = value.parse().ok()?,
+                "--proxy-dhcp" => config.proxy_dhcp = Some(value.parse().ok()?),
+                "--dhcp-port"  => config.dhcp_port = value.parse().ok()?,
+                _ => return None,
+            }
+        }
+        Some(config)
+    }
+}
+
+/// Serve `root` as asked for by the arguments after `serve`, until an error
+/// stops the TFTP server
+pub fn serve(root: &Path, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some(config) = Config::parse(args) else {
+        println!("usage: serve [--bind <addr>] [--tftp-port <port>]");
+        println!("             [--proxy-dhcp <boot server addr>] \
+                  [--dhcp-port <port>]");
+        return Err("invalid arguments".into());
+    };
+    if !root.join(BOOT_FILE).is_file() {
+        println!("Warning: {:?} doesn't exist yet, build first",
+                 root.join(BOOT_FILE));
+    }
+
+    // Answer DISCOVERs on the DHCP port and REQUESTs on the PXE port
+    if let Some(server) = config.proxy_dhcp {
+        for port in [config.dhcp_port, PXE_PORT] {
+            let socket = UdpSocket::bind((config.bind, port))?;
+            socket.set_broadcast(true)?;
+            println!("Proxy DHCP: offering {BOOT_FILE} from {server} on {}",
+                     socket.local_addr()?);
+            std::thread::spawn(move || {
+                if let Err(err) = proxy_dhcp(&socket, server) {
+                    println!("Proxy DHCP: stopped: {err}");
+                }
+            });
+        }
+    }
+
+    let socket = UdpSocket::bind((config.bind, config.tftp_port))?;
+    println!("TFTP: serving {root:?} on {}", socket.local_addr()?);
+    Ok(tftp_server(&socket, root, config.bind)?)
+}
+
+/// Serve the TFTP requests coming to `socket` from `root` until an error
+/// stops it. Every transfer gets a thread and a socket bound to `bind` of
+/// its own.
+fn tftp_server(socket: &UdpSocket, root: &Path, bind: Ipv4Addr)
+        -> io::Result<()> {
+    // Clients with a transfer running. A request sent again while its
+    // transfer runs doesn't start another one.
+    let active: Arc<Mutex<HashSet<SocketAddr>>> = Arc::default();
+
+    let mut buf = [0u8; 2048];
+    loop {
+        let (len, client) = socket.recv_from(&mut buf)?;
+        if !active.lock().unwrap().insert(client) {
+            continue;
+        }
+
+        let request = buf[..len].to_vec();
+        let root    = root.to_path_buf();
+        let active  = active.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = tftp_transfer(&root, bind, client, &request) {
+                println!("TFTP: transfer with {client} failed: {err}");
+            }
+            active.lock().unwrap().remove(&client);
+        });
+    }
+}
+
+/// Returns the path of `filename` in `root`, `None` if it's absolute or
+/// would leave it
+fn resolve(root: &Path, filename: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for component in Path::new(filename).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Send an error `code` with `message` on `socket`
+fn send_error(socket: &UdpSocket, code: u16, message: &str)
+        -> io::Result<()> {
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&OP_ERROR.to_be_bytes());
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    socket.send(&packet)?;
+    Ok(())
+}
+
+/// Serve the TFTP `request` from `client`
+fn tftp_transfer(root: &Path, bind: Ipv4Addr, client: SocketAddr,
+                 request: &[u8]) -> io::Result<()> {
+    // The port of the socket identifies the transfer
+    let socket = UdpSocket::bind((bind, 0))?;
+    socket.connect(client)?;
+
+    // Split the request into the filename, the mode and the options
+    let Some(opcode) = request.get(..2) else {
+        return Ok(());
+    };
+    let opcode = u16::from_be_bytes([opcode[0], opcode[1]]);
+    let fields: Vec<String> = request[2..].split(|&b| b == 0)
+        .map(|field| String::from_utf8_lossy(field).into_owned())
+        .collect();
+    let [filename, mode, options @ ..] = fields.as_slice() else {
+        return send_error(&socket, ERROR_ILLEGAL_OP, "Malformed request");
+    };
+
+    match opcode {
+        OP_RRQ => {}
+        OP_WRQ => return send_error(&socket, ERROR_ACCESS, "Read only"),
+        _ => return send_error(&socket, ERROR_ILLEGAL_OP, "Not a request"),
+    }
+    if !mode.eq_ignore_ascii_case("octet") {
+        return send_error(&socket, ERROR_UNDEFINED, "Only octet mode");
+    }
+
+    let Some(data) = resolve(root, filename)
+            .and_then(|path| std::fs::read(path).ok()) else {
+        println!("TFTP: {client} asked for missing {filename:?}");
+        return send_error(&socket, ERROR_NOT_FOUND, "File not found");
+    };
+
+    // Take the options we know, the trailing empty field aside
+    let mut blksize = DEFAULT_BLKSIZE;
+    let mut oack = Vec::new();
+    for option in options.chunks_exact(2) {
+        let (name, value) = (&option[0], &option[1]);
+        let value = if name.eq_ignore_ascii_case("blksize") {
+            let Ok(asked) = value.parse::<usize>() else { continue; };
+            if asked < MIN_BLKSIZE {
+                continue;
+            }
+            blksize = asked.min(MAX_BLKSIZE);
+            blksize
+        } else if name.eq_ignore_ascii_case("tsize") {
+            data.len()
+        } else {
+            continue;
+        };
+
+        oack.extend_from_slice(name.as_bytes());
+        oack.push(0);
+        oack.extend_from_slice(value.to_string().as_bytes());
+        oack.push(0);
+    }
+    println!("TFTP: {client} reads {filename:?}, {} bytes in {blksize} byte \
+              blocks", data.len());
+
+    // The OACK is acknowledged with block 0
+    if !oack.is_empty() {
+        let mut packet = OP_OACK.to_be_bytes().to_vec();
+        packet.extend_from_slice(&oack);
+        if !exchange(&socket, &packet, 0)? {
+            return Ok(());
+        }
+    }
+
+    // The file ends with a short block, empty if the size is a multiple of
+    // the block size
+    let started = Instant::now();
+    for (index, block) in data.chunks(blksize)
+            .chain((data.len() % blksize == 0).then_some(&[][..]))
+            .enumerate() {
+        let number = (index + 1) as u16;
+        let mut packet = Vec::with_capacity(4 + block.len());
+        packet.extend_from_slice(&OP_DATA.to_be_bytes());
+        packet.extend_from_slice(&number.to_be_bytes());
+        packet.extend_from_slice(block);
+        if !exchange(&socket, &packet, number)? {
+            return Ok(());
+        }
+    }
+
+    println!("TFTP: sent {filename:?} to {client} in {:?}",
+             started.elapsed());
+    Ok(())
+}
+
+/// Send `packet` until the client acknowledges `block`. Returns `false` if
+/// the client gave up or stopped answering.
+fn exchange(socket: &UdpSocket, packet: &[u8], block: u16)
+        -> io::Result<bool> {
+    let mut buf = [0u8; 1024];
+    for _ in 0..TFTP_ATTEMPTS {
+        socket.send(packet)?;
+
+        // Duplicate acknowledgements are ignored, so they don't multiply
+        // the blocks sent again
+        let deadline = Instant::now() + TFTP_TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(left))?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(err) if matches!(err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+
+            let reply = &buf[..len];
+            if len < 4 {
+                continue;
+            }
+            let opcode = u16::from_be_bytes([reply[0], reply[1]]);
+            let number = u16::from_be_bytes([reply[2], reply[3]]);
+            match opcode {
+                OP_ACK if number == block => return Ok(true),
+                OP_ACK => {}
+                OP_ERROR => {
+                    let message = reply[4..].split(|&b| b == 0).next()
+                        .unwrap_or_default();
+                    println!("TFTP: error {number} from {}: {}",
+                             socket.peer_addr()?,
+                             String::from_utf8_lossy(message));
+                    return Ok(false);
+                }
+                _ => {
+                    send_error(socket, ERROR_ILLEGAL_OP,
+                               "Unexpected packet")?;
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    println!("TFTP: {} stopped answering", socket.peer_addr()?);
+    Ok(false)
+}
+
+/// Returns the value of DHCP option `code` in `options`
+fn dhcp_option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+    while let [kind, rest @ ..] = options {
+        match *kind {
+            OPTION_PAD => { options = rest; continue; }
+            OPTION_END => break,
+            _ => {}
+        }
+
+        let (&len, rest) = rest.split_first()?;
+        let value = rest.get(..len as usize)?;
+        if *kind == code {
+            return Some(value);
+        }
+        options = &rest[len as usize..];
+    }
+    None
+}
+
+/// Answer the DISCOVERs of PXE clients on `socket` with offers of the boot
+/// file on `server`, and their REQUESTs to us with acknowledgements
+fn proxy_dhcp(socket: &UdpSocket, server: Ipv4Addr) -> io::Result<()> {
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, src) = socket.recv_from(&mut buf)?;
+        let request = &buf[..len];
+        if len < OPTIONS_OFFSET || request[0] != BOOTP_REQUEST ||
+                request[236..240] != MAGIC_COOKIE {
+            continue;
+        }
+
+        // Only PXE clients are answered
+        let options = &request[OPTIONS_OFFSET..];
+        if !dhcp_option(options, OPTION_CLASS_ID)
+                .is_some_and(|class| class.starts_with(PXE_CLASS)) {
+            continue;
+        }
+
+        // Requests are answered when they're to us, on the PXE port or
+        // naming us as the server
+        let for_us = socket.local_addr()?.port() == PXE_PORT ||
+            dhcp_option(options, OPTION_SERVER_ID) == Some(&server.octets());
+        let kind = match dhcp_option(options, OPTION_MESSAGE_TYPE) {
+            Some(&[DHCPDISCOVER]) => DHCPOFFER,
+            Some(&[DHCPREQUEST]) if for_us => DHCPACK,
+            _ => continue,
+        };
+
+        // Copy the transaction, the flags, `ciaddr`, `giaddr` and `chaddr`,
+        // and give our address and the boot file
+        let mut reply = vec![0u8; OPTIONS_OFFSET];
+        reply[0] = BOOTP_REPLY;
+        reply[1..3].copy_from_slice(&request[1..3]);
+        reply[4..8].copy_from_slice(&request[4..8]);
+        reply[10..16].copy_from_slice(&request[10..16]);
+        reply[20..24].copy_from_slice(&server.octets());
+        reply[24..SNAME_OFFSET].copy_from_slice(&request[24..SNAME_OFFSET]);
+        assert!(BOOT_FILE.len() < FILE_SIZE);
+        reply[FILE_OFFSET..FILE_OFFSET + BOOT_FILE.len()]
+            .copy_from_slice(BOOT_FILE.as_bytes());
+        reply[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut option = |code: u8, value: &[u8]| {
+            reply.push(code);
+            reply.push(value.len() as u8);
+            reply.extend_from_slice(value);
+        };
+        option(OPTION_MESSAGE_TYPE, &[kind]);
+        option(OPTION_SERVER_ID, &server.octets());
+        option(OPTION_CLASS_ID, PXE_CLASS);
+        if let Some(uuid) = dhcp_option(options, OPTION_CLIENT_UUID) {
+            option(OPTION_CLIENT_UUID, uuid);
+        }
+        option(OPTION_VENDOR,
+               &[PXE_DISCOVERY_CONTROL, 1, PXE_USE_BOOT_FILE, OPTION_END]);
+        reply.push(OPTION_END);
+        if reply.len() < MIN_MESSAGE_SIZE {
+            reply.resize(MIN_MESSAGE_SIZE, OPTION_PAD);
+        }
+
+        // Clients without an address yet are broadcast to
+        let dst: SocketAddr = if src.ip().is_unspecified() {
+            (Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT).into()
+        } else {
+            src
+        };
+        let mac = request[28..34].iter().map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>().join(":");
+        println!("Proxy DHCP: {} {BOOT_FILE} to {mac} at {dst}",
+                 if kind == DHCPOFFER { "offering" } else { "acknowledging" });
+        socket.send_to(&reply, dst)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an empty directory for the test `name`
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("serve-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Serve `root` on a loopback port and return its address
+    fn start_server(root: &Path) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr   = socket.local_addr().unwrap();
+        let root   = root.to_path_buf();
+        std::thread::spawn(move || {
+            tftp_server(&socket, &root, Ipv4Addr::LOCALHOST)
+        });
+        addr
+    }
+
+    /// Returns a loopback client socket
+    fn client() -> UdpSocket {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    }
+
+    /// Returns a read request for `filename` with the `options`
+    fn rrq(filename: &str, options: &[(&str, &str)]) -> Vec<u8> {
+        let options = options.iter().flat_map(|&(name, value)| [name, value]);
+        let mut packet = OP_RRQ.to_be_bytes().to_vec();
+        for field in [filename, "octet"].into_iter().chain(options) {
+            packet.extend_from_slice(field.as_bytes());
+            packet.push(0);
+        }
+        packet
+    }
+
+    /// Send an acknowledgement of `block` to `dst`
+    fn ack(socket: &UdpSocket, block: u16, dst: SocketAddr) {
+        let mut packet = OP_ACK.to_be_bytes().to_vec();
+        packet.extend_from_slice(&block.to_be_bytes());
+        socket.send_to(&packet, dst).unwrap();
+    }
+
+    #[test]
+    fn resolve_stays_in_root() {
+        let root = Path::new("/srv/netboot");
+        assert_eq!(resolve(root, "kernel"), Some(root.join("kernel")));
+        assert_eq!(resolve(root, "./pxe/bootloader.0"),
+                   Some(root.join("pxe/bootloader.0")));
+
+        assert_eq!(resolve(root, ".."), None);
+        assert_eq!(resolve(root, "../etc/passwd"), None);
+        assert_eq!(resolve(root, "pxe/../../etc/passwd"), None);
+        assert_eq!(resolve(root, "/etc/passwd"), None);
+        assert_eq!(resolve(root, "//etc/passwd"), None);
+    }
+
+    #[test]
+    fn read_with_options() {
+        // Three full blocks and a short one
+        let root = test_dir("options");
+        let data: Vec<u8> = (0..3 * 1024 + 100).map(|n| n as u8).collect();
+        std::fs::write(root.join("kernel"), &data).unwrap();
+
+        let server = start_server(&root);
+        let socket = client();
+        socket.send_to(&rrq("kernel", &[("blksize", "1024"), ("tsize", "0")]),
+                       server).unwrap();
+
+        // The options are acknowledged from the port of the transfer
+        let mut buf = [0u8; 2048];
+        let (len, tid) = socket.recv_from(&mut buf).unwrap();
+        assert_ne!(tid, server);
+        let mut oack = OP_OACK.to_be_bytes().to_vec();
+        oack.extend_from_slice(b"blksize\x001024\x00tsize\x00");
+        oack.extend_from_slice(format!("{}\x00", data.len()).as_bytes());
+        assert_eq!(&buf[..len], &oack[..]);
+        ack(&socket, 0, tid);
+
+        // Then come the blocks, until the short one
+        let mut received = Vec::new();
+        for block in 1.. {
+            let (len, src) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(src, tid);
+            assert_eq!(buf[..4], [0, OP_DATA as u8, 0, block as u8]);
+            received.extend_from_slice(&buf[4..len]);
+            ack(&socket, block, tid);
+
+            if len - 4 < 1024 {
+                break;
+            }
+        }
+        assert_eq!(received, data);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn duplicate_request_is_ignored() {
+        let root = test_dir("duplicate");
+        std::fs::write(root.join("kernel"), [0xaa; 100]).unwrap();
+
+        // The request is sent twice before the transfer is acknowledged
+        let server = start_server(&root);
+        let socket = client();
+        let request = rrq("kernel", &[("tsize", "0")]);
+        socket.send_to(&request, server).unwrap();
+        socket.send_to(&request, server).unwrap();
+
+        // Only one transfer answers before the OACK is sent again
+        socket.set_read_timeout(Some(TFTP_TIMEOUT / 2)).unwrap();
+        let mut buf = [0u8; 2048];
+        let mut sources = HashSet::new();
+        while let Ok((_, src)) = socket.recv_from(&mut buf) {
+            sources.insert(src);
+        }
+        assert_eq!(sources.len(), 1);
+
+        // The transfer still completes
+        let tid = *sources.iter().next().unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        ack(&socket, 0, tid);
+        let (len, _) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[&[0, OP_DATA as u8, 0, 1][..],
+                                  &[0xaa; 100][..]].concat());
+        ack(&socket, 1, tid);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Send `request` to `server` from a client of its own, as a client is
+    /// ignored until its last transfer ends, and return the code of the error
+    /// it's refused with
+    fn refused(request: &[u8], server: SocketAddr) -> u16 {
+        let socket = client();
+        socket.send_to(request, server).unwrap();
+        let mut buf = [0u8; 2048];
+        let (len, _) = socket.recv_from(&mut buf).unwrap();
+        assert!(len >= 5 && buf[len - 1] == 0);
+        assert_eq!(buf[..2], OP_ERROR.to_be_bytes());
+        u16::from_be_bytes([buf[2], buf[3]])
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        // The file outside the root exists, but isn't served
+        let root = test_dir("errors");
+        let outside = test_dir("errors-outside");
+        std::fs::write(outside.join("secret"), [0x55; 10]).unwrap();
+        let escape = format!("../{}/secret",
+                             outside.file_name().unwrap().to_str().unwrap());
+
+        let server = start_server(&root);
+        assert_eq!(refused(&rrq("missing", &[]), server), ERROR_NOT_FOUND);
+        assert_eq!(refused(&rrq(&escape, &[]), server), ERROR_NOT_FOUND);
+
+        // Writes are refused whatever the file
+        let mut wrq = rrq("kernel", &[]);
+        wrq[..2].copy_from_slice(&OP_WRQ.to_be_bytes());
+        assert_eq!(refused(&wrq, server), ERROR_ACCESS);
+
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::remove_dir_all(outside).unwrap();
+    }
+
+    /// Address of the boot server the proxy DHCP responder advertises
+    const BOOT_SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+
+    /// Start the proxy DHCP responder on a loopback port and return its
+    /// address
+    fn start_proxy_dhcp() -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr   = socket.local_addr().unwrap();
+        std::thread::spawn(move || proxy_dhcp(&socket, BOOT_SERVER));
+        addr
+    }
+
+    /// Returns a DHCP message of `kind` for the transaction `xid`, with the
+    /// vendor class `class` and the server identifier `server_id`
+    fn dhcp_message(kind: u8, xid: u32, class: &[u8],
+                    server_id: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut message = vec![0u8; OPTIONS_OFFSET];
+        message[0] = BOOTP_REQUEST;
+        message[1] = 1;
+        message[2] = 6;
+        message[4..8].copy_from_slice(&xid.to_be_bytes());
+        message[28..34].copy_from_slice(&[0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        message[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind]);
+        message.extend_from_slice(&[OPTION_CLASS_ID, class.len() as u8]);
+        message.extend_from_slice(class);
+        if let Some(server_id) = server_id {
+            message.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+            message.extend_from_slice(&server_id.octets());
+        }
+        message.push(OPTION_END);
+        message
+    }
+
+    /// Receive a reply on `socket`, check it offers the boot file for the
+    /// transaction `xid`, and return its message type
+    fn boot_offer(socket: &UdpSocket, xid: u32) -> u8 {
+        let mut buf = [0u8; 1500];
+        let (len, _) = socket.recv_from(&mut buf).unwrap();
+        let reply = &buf[..len];
+        assert!(len >= MIN_MESSAGE_SIZE);
+        assert_eq!(reply[0], BOOTP_REPLY);
+        assert_eq!(reply[4..8], xid.to_be_bytes());
+        assert_eq!(reply[20..24], BOOT_SERVER.octets());
+        assert_eq!(reply[28..34], [0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+
+        let file = &reply[FILE_OFFSET..FILE_OFFSET + FILE_SIZE];
+        let end = file.iter().position(|&b| b == 0).unwrap();
+        assert_eq!(&file[..end], BOOT_FILE.as_bytes());
+
+        let options = &reply[OPTIONS_OFFSET..];
+        assert_eq!(reply[236..240], MAGIC_COOKIE);
+        assert_eq!(dhcp_option(options, OPTION_SERVER_ID),
+                   Some(&BOOT_SERVER.octets()[..]));
+        assert_eq!(dhcp_option(options, OPTION_CLASS_ID), Some(PXE_CLASS));
+        assert_eq!(dhcp_option(options, OPTION_VENDOR),
+                   Some(&[PXE_DISCOVERY_CONTROL, 1, PXE_USE_BOOT_FILE,
+                          OPTION_END][..]));
+        dhcp_option(options, OPTION_MESSAGE_TYPE).unwrap()[0]
+    }
+
+    #[test]
+    fn proxy_dhcp_offers_boot_file() {
+        let server = start_proxy_dhcp();
+        let socket = client();
+        let class = b"PXEClient:Arch:00000:UNDI:002001";
+
+        // A client which isn't PXE gets nothing, so the first reply is to
+        // the PXE client asking after it
+        socket.send_to(&dhcp_message(DHCPDISCOVER, 1, b"udhcp 1.36.1",
+                                     None), server).unwrap();
+        socket.send_to(&dhcp_message(DHCPDISCOVER, 2, class, None), server)
+            .unwrap();
+        assert_eq!(boot_offer(&socket, 2), DHCPOFFER);
+
+        // Requests are only acknowledged when they name us, off the PXE
+        // port
+        socket.send_to(&dhcp_message(DHCPREQUEST, 3, class,
+                                     Some(Ipv4Addr::new(10, 0, 2, 3))),
+                       server).unwrap();
+        socket.send_to(&dhcp_message(DHCPREQUEST, 4, class,
+                                     Some(BOOT_SERVER)), server).unwrap();
+        assert_eq!(boot_offer(&socket, 4), DHCPACK);
+    }
+}